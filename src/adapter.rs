@@ -0,0 +1,203 @@
+use crate::rerank::TEIRankResult;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::str::FromStr;
+
+/// Declarative mapping to an upstream rerank API that doesn't speak TEI's
+/// format. The request body is built from a JSON template and scores are
+/// read back from the response by path, so unusual backends can be used
+/// without code changes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Adapter {
+    /// Appended to the backend URL, e.g. `/v1/rerank`.
+    pub path: String,
+    /// Request body. A string that is exactly `{{query}}`, `{{texts}}` or
+    /// `{{model}}` is replaced by that value, keeping its JSON type; in
+    /// longer strings `{{query}}` and `{{model}}` are substituted as text.
+    pub request: Value,
+    /// Shape of each entry of `{{texts}}`, with `{{text}}` standing for the
+    /// document and `{{index}}` for its position, as a string; unset sends
+    /// the texts as plain strings.
+    pub document: Option<Value>,
+    /// Dot-separated path to the results array in the response, e.g.
+    /// `data.results`; empty when the response itself is the array.
+    pub results: String,
+    /// Path within each result to the document's index, a number or a
+    /// numeric string; empty when results are returned in document order.
+    pub index: String,
+    /// Path within each result to its score; empty when each result is a
+    /// bare number.
+    pub score: String,
+}
+
+impl Default for Adapter {
+    /// TEI's own format.
+    fn default() -> Self {
+        Self {
+            path: "/rerank".to_string(),
+            request: json!({ "query": "{{query}}", "texts": "{{texts}}" }),
+            document: None,
+            results: String::new(),
+            index: "index".to_string(),
+            score: "score".to_string(),
+        }
+    }
+}
+
+/// Adapters are given inline as JSON in the environment or on the command
+/// line, or as a file through `TEI_ADAPTER_FILE`.
+impl FromStr for Adapter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(spec).map_err(|e| format!("invalid adapter ({})", e))
+    }
+}
+
+impl Adapter {
+    /// Problems that would make every request fail.
+    pub fn check(&self) -> Result<(), String> {
+        if !self.path.starts_with('/') {
+            return Err(format!("path '{}' must start with '/'", self.path));
+        }
+        for placeholder in ["{{query}}", "{{texts}}"] {
+            if !mentions(&self.request, placeholder) {
+                return Err(format!("request must contain {}", placeholder));
+            }
+        }
+        if let Some(document) = &self.document {
+            if !mentions(document, "{{text}}") {
+                return Err("document must contain {{text}}".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the upstream request body. Forwarded client parameters are
+    /// added to it when it's an object.
+    pub fn request(
+        &self,
+        query: &str,
+        texts: &[&str],
+        model: Option<&str>,
+        params: &Map<String, Value>,
+    ) -> Value {
+        let texts = Value::Array(
+            texts
+                .iter()
+                .enumerate()
+                .map(|(index, &text)| match &self.document {
+                    Some(document) => render(document, &|name| match name {
+                        "text" => Some(Value::from(text)),
+                        // APIs that echo back record IDs want them as strings
+                        "index" => Some(Value::from(index.to_string())),
+                        _ => None,
+                    }),
+                    None => Value::from(text),
+                })
+                .collect(),
+        );
+        let mut body = render(&self.request, &|name| match name {
+            "query" => Some(Value::from(query)),
+            "texts" => Some(texts.clone()),
+            "model" => Some(model.map_or(Value::Null, Value::from)),
+            _ => None,
+        });
+        if let Value::Object(fields) = &mut body {
+            for (name, value) in params {
+                fields.insert(name.clone(), value.clone());
+            }
+        }
+        body
+    }
+
+    /// Read the results out of an upstream response, with indices into
+    /// the texts sent.
+    pub fn results(&self, response: &Value) -> Result<Vec<TEIRankResult>, String> {
+        let results = lookup(response, &self.results)
+            .and_then(Value::as_array)
+            .ok_or_else(|| format!("no results array at '{}'", self.results))?;
+        results
+            .iter()
+            .enumerate()
+            .map(|(position, result)| {
+                let index = if self.index.is_empty() {
+                    position
+                } else {
+                    lookup(result, &self.index)
+                        .and_then(|index| match index {
+                            Value::String(index) => index.parse().ok(),
+                            index => index.as_u64(),
+                        })
+                        .ok_or_else(|| {
+                            format!("result {} has no index at '{}'", position, self.index)
+                        })? as usize
+                };
+                let score = lookup(result, &self.score)
+                    .and_then(Value::as_f64)
+                    .ok_or_else(|| {
+                        format!("result {} has no score at '{}'", position, self.score)
+                    })?;
+                Ok(TEIRankResult { index, score })
+            })
+            .collect()
+    }
+}
+
+/// Copy `template`, filling in `{{name}}` placeholders from `vars`.
+fn render(template: &Value, vars: &dyn Fn(&str) -> Option<Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            if let Some(value) = text
+                .strip_prefix("{{")
+                .and_then(|rest| rest.strip_suffix("}}"))
+                .and_then(vars)
+            {
+                return value;
+            }
+            // Substitute string values inside longer text
+            let mut text = text.clone();
+            for name in ["query", "model", "text", "index"] {
+                let placeholder = format!("{{{{{}}}}}", name);
+                if text.contains(&placeholder) {
+                    if let Some(Value::String(value)) = vars(name) {
+                        text = text.replace(&placeholder, &value);
+                    }
+                }
+            }
+            Value::String(text)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|item| render(item, vars)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), render(value, vars)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Whether `placeholder` appears anywhere in `template`.
+fn mentions(template: &Value, placeholder: &str) -> bool {
+    match template {
+        Value::String(text) => text.contains(placeholder),
+        Value::Array(items) => items.iter().any(|item| mentions(item, placeholder)),
+        Value::Object(fields) => fields.values().any(|value| mentions(value, placeholder)),
+        _ => false,
+    }
+}
+
+/// Follow a dot-separated path of object keys and array indices; an empty
+/// path is `value` itself.
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    if path.is_empty() {
+        return Some(value);
+    }
+    path.split('.')
+        .try_fold(value, |value, segment| match value {
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => value.get(segment),
+        })
+}
@@ -0,0 +1,475 @@
+use crate::config::{RouteGroup, RouteLimits};
+use crate::context::{self, RequestContext};
+use crate::error::ApiError;
+use crate::queue::Priority;
+use crate::ratelimit;
+use crate::server;
+use crate::state::AppState;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use warp::Filter;
+
+/// How often the latency shedder re-evaluates the upstream p99.
+const LATENCY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latency samples kept at most, however long the window.
+const MAX_LATENCY_SAMPLES: usize = 10_000;
+
+/// Fewer samples than this in the window never trigger shedding.
+const MIN_LATENCY_SAMPLES: usize = 20;
+
+/// Approximate memory held by requests that are being buffered or are
+/// waiting on TEI, and the budget above which new requests are shed.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    /// 0 disables shedding; usage is still tracked for metrics.
+    limit: u64,
+    used: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Account for `bytes` until the returned reservation is dropped, or
+    /// refuse if that would exceed the budget.
+    pub fn reserve(self: &Arc<Self>, bytes: u64) -> Option<Reservation> {
+        let fits = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let total = used.saturating_add(bytes);
+                (self.limit == 0 || total <= self.limit).then_some(total)
+            })
+            .is_ok();
+
+        if !fits {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Reservation {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Requests rejected for lack of memory so far.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+/// Memory accounted to one request; released on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Replace the estimate once the real size is known. Never refuses:
+    /// the memory is already in use by then.
+    pub fn resize(&mut self, bytes: u64) {
+        if bytes >= self.bytes {
+            self.budget
+                .used
+                .fetch_add(bytes - self.bytes, Ordering::AcqRel);
+        } else {
+            self.budget
+                .used
+                .fetch_sub(self.bytes - bytes, Ordering::AcqRel);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// Requests in flight per client IP address, and the cap above which a
+/// client's new requests are refused, so one runaway client can't take up
+/// every worker.
+#[derive(Debug, Default)]
+pub struct ClientLimit {
+    /// 0 disables the cap.
+    limit: usize,
+    in_flight: Mutex<HashMap<IpAddr, usize>>,
+    limited: AtomicU64,
+}
+
+impl ClientLimit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Count a request from `ip` until the returned slot is dropped, or
+    /// refuse if the address already has `limit` requests in flight.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ClientSlot> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot {
+            limit: self.clone(),
+            ip,
+        })
+    }
+
+    /// Requests refused for exceeding the cap so far.
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+}
+
+/// One request counted against its IP address; released on drop.
+#[derive(Debug)]
+pub struct ClientSlot {
+    limit: Arc<ClientLimit>,
+    ip: IpAddr,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut in_flight = self.limit.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Requests in flight on each route group with a `max_concurrent_requests`
+/// of its own, so slow rerank batches can't starve tokenizer or admin
+/// requests of workers, or the other way around.
+#[derive(Debug, Default)]
+pub struct RouteSlots {
+    groups: BTreeMap<RouteGroup, (usize, Arc<Semaphore>)>,
+    limited: Mutex<BTreeMap<RouteGroup, u64>>,
+}
+
+impl RouteSlots {
+    pub fn new(limits: &BTreeMap<RouteGroup, RouteLimits>) -> Self {
+        let groups = limits
+            .iter()
+            .filter_map(|(&route, limits)| {
+                let limit = limits.max_concurrent_requests?;
+                Some((route, (limit, Arc::new(Semaphore::new(limit)))))
+            })
+            .collect();
+        Self {
+            groups,
+            ..Self::default()
+        }
+    }
+
+    /// Take one of `route`'s slots until the returned permit is dropped;
+    /// `Ok(None)` when the group has no cap, `Err` with the cap when it's
+    /// reached.
+    pub fn acquire(&self, route: RouteGroup) -> Result<Option<OwnedSemaphorePermit>, usize> {
+        let Some((limit, semaphore)) = self.groups.get(&route) else {
+            return Ok(None);
+        };
+        match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => {
+                *self.limited.lock().unwrap().entry(route).or_default() += 1;
+                Err(*limit)
+            }
+        }
+    }
+
+    /// Requests refused for their group's cap so far, by group.
+    pub fn limited(&self) -> BTreeMap<RouteGroup, u64> {
+        self.limited.lock().unwrap().clone()
+    }
+}
+
+/// Tracks upstream latency and, while the p99 over the trailing window is
+/// above the threshold, rejects a fixed fraction of new requests early.
+#[derive(Debug, Default)]
+pub struct LatencyShedder {
+    threshold: Duration,
+    window: Duration,
+    fraction: f64,
+    samples: Mutex<VecDeque<(Instant, Duration)>>,
+    /// Last computed p99 in microseconds.
+    p99_micros: AtomicU64,
+    active: AtomicBool,
+    seen: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl LatencyShedder {
+    /// A shedder for `threshold`; a zero threshold disables it.
+    pub fn new(threshold: Duration, window: Duration, fraction: f64) -> Self {
+        Self {
+            threshold,
+            window,
+            fraction,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.threshold.is_zero()
+    }
+
+    /// Record the latency of a completed upstream request.
+    pub fn record(&self, latency: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() >= MAX_LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back((Instant::now(), latency));
+    }
+
+    /// Recompute the windowed p99 and switch shedding on or off.
+    fn evaluate(&self) {
+        let mut latencies: Vec<Duration> = {
+            let mut samples = self.samples.lock().unwrap();
+            let cutoff = Instant::now().checked_sub(self.window);
+            while samples
+                .front()
+                .is_some_and(|(at, _)| cutoff.is_some_and(|cutoff| *at < cutoff))
+            {
+                samples.pop_front();
+            }
+            samples.iter().map(|(_, latency)| *latency).collect()
+        };
+
+        if latencies.len() < MIN_LATENCY_SAMPLES {
+            self.p99_micros.store(0, Ordering::Relaxed);
+            self.set_active(false, Duration::ZERO);
+            return;
+        }
+
+        latencies.sort();
+        let rank = (latencies.len() as f64 * 0.99).ceil() as usize;
+        let p99 = latencies[rank.clamp(1, latencies.len()) - 1];
+        self.p99_micros
+            .store(p99.as_micros() as u64, Ordering::Relaxed);
+        self.set_active(p99 > self.threshold, p99);
+    }
+
+    fn set_active(&self, active: bool, p99: Duration) {
+        if self.active.swap(active, Ordering::Relaxed) != active {
+            if active {
+                warn!(
+                    "🐢 Upstream p99 {:?} is above {:?}, shedding {:.0}% of requests",
+                    p99,
+                    self.threshold,
+                    self.fraction * 100.0
+                );
+            } else {
+                info!("Upstream latency recovered, no longer shedding requests");
+            }
+        }
+    }
+
+    /// Whether to reject this request. Spreads rejections evenly rather
+    /// than randomly: every request adds `fraction` and one is shed each time
+    /// the running total crosses a whole number.
+    fn should_shed(&self) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            return false;
+        }
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        let shed = ((n + 1.0) * self.fraction).floor() > (n * self.fraction).floor();
+        if shed {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        shed
+    }
+
+    /// Last computed upstream p99; zero without enough samples.
+    pub fn p99(&self) -> Duration {
+        Duration::from_micros(self.p99_micros.load(Ordering::Relaxed))
+    }
+
+    /// Requests rejected because of upstream latency so far.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+/// Start re-evaluating upstream latency in the background. Does nothing when
+/// latency shedding is disabled.
+pub fn spawn_latency_monitor(state: Arc<AppState>) {
+    if !state.latency.is_enabled() {
+        return;
+    }
+
+    info!(
+        "Shedding {:.0}% of requests while upstream p99 over {}s exceeds {}ms",
+        state.config.shed_latency_fraction * 100.0,
+        state.config.shed_latency_window_secs,
+        state.config.shed_latency_p99_ms
+    );
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(LATENCY_CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            state.latency.evaluate();
+        }
+    });
+}
+
+/// Decide whether to take on a request before reading its body: refuse it
+/// with 429 when the client is over its rate limit, shed it with 503 when
+/// upstream latency is degraded or the memory budget is exhausted, otherwise
+/// reserve memory for the body. Clients at their cap of concurrent requests
+/// are refused with 429 as well. High-priority requests are never
+/// shed for latency. Bodies without a `Content-Length` are charged once
+/// parsed.
+pub fn admit(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (RequestContext, Reservation, Option<ClientSlot>), Error = warp::Rejection>
+       + Clone {
+    context::extract(state.clone())
+        .and(ratelimit::client(state.clone()))
+        .and(server::remote())
+        .and(warp::header::optional::<u64>("content-length"))
+        .and_then(
+            move |ctx: RequestContext,
+                  client: String,
+                  remote: Option<SocketAddr>,
+                  length: Option<u64>| {
+                let state = state.clone();
+                async move {
+                    limit_rate(&state, &client)?;
+                    let slot = match remote {
+                        Some(remote) => acquire_slot(&state, remote.ip())?,
+                        // No peer address, no one to cap
+                        None => None,
+                    };
+
+                    if ctx.priority < Priority::High && state.latency.should_shed() {
+                        warn!(
+                            "Shedding {} priority request: upstream p99 {:?} is degraded",
+                            ctx.priority,
+                            state.latency.p99()
+                        );
+                        return Err(warp::reject::custom(ApiError::Unavailable(
+                            "TEI backend is degraded, shedding load; retry later".to_string(),
+                        )));
+                    }
+
+                    let reservation =
+                        state.memory.reserve(length.unwrap_or(0)).ok_or_else(|| {
+                            warn!(
+                                "Shedding request: memory budget of {} bytes exhausted ({} in use)",
+                                state.config.memory_budget_bytes,
+                                state.memory.used()
+                            );
+                            warp::reject::custom(ApiError::Unavailable(
+                                "Server is over its memory budget, retry later".to_string(),
+                            ))
+                        })?;
+                    Ok((ctx, reservation, slot))
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Hold a slot of the route group the request is for while it's handled,
+/// refusing it with 503 when the group already has its
+/// `max_concurrent_requests` in flight. Requests outside any group, like
+/// `/health`, take none.
+pub fn route_slot(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (Option<OwnedSemaphorePermit>,), Error = warp::Rejection> + Clone {
+    warp::path::full().and_then(move |path: warp::path::FullPath| {
+        let state = state.clone();
+        async move {
+            let Some(route) = RouteGroup::of(path.as_str()) else {
+                return Ok(None);
+            };
+            state.route_slots.acquire(route).map_err(|limit| {
+                warn!(
+                    "Refusing {} request: {} already in flight on {} routes",
+                    path.as_str(),
+                    limit,
+                    route
+                );
+                warp::reject::custom(ApiError::Unavailable(format!(
+                    "Too many concurrent {} requests (at most {}), retry later",
+                    route, limit
+                )))
+            })
+        }
+    })
+}
+
+/// Count the request against the client's rate limit, if one is set.
+fn limit_rate(state: &AppState, client: &str) -> Result<(), warp::Rejection> {
+    if !state.rate_limiter.is_enabled() {
+        return Ok(());
+    }
+    state
+        .rate_limiter
+        .acquire(client)
+        .map(drop)
+        .map_err(|quota| {
+            warn!("Rate limiting {}: {} requests used", client, quota.limit);
+            warp::reject::custom(ApiError::TooManyRequests(
+                format!(
+                    "Rate limit of {} requests per {}s exceeded",
+                    quota.limit,
+                    state.rate_limiter.window().as_secs()
+                ),
+                Some(Duration::from_secs(quota.reset_secs())),
+            ))
+        })
+}
+
+/// Count the request against its IP address's cap on requests in flight,
+/// if one is set.
+fn acquire_slot(state: &AppState, ip: IpAddr) -> Result<Option<ClientSlot>, warp::Rejection> {
+    let limit = state.config.max_requests_per_ip;
+    if limit == 0 {
+        return Ok(None);
+    }
+    let slot = state.client_limit.acquire(ip).ok_or_else(|| {
+        warn!(
+            "Refusing request from {}: {} requests already in flight",
+            ip, limit
+        );
+        warp::reject::custom(ApiError::TooManyRequests(
+            format!(
+                "Too many concurrent requests from {} (at most {})",
+                ip, limit
+            ),
+            None,
+        ))
+    })?;
+    Ok(Some(slot))
+}
@@ -0,0 +1,113 @@
+use crate::config::Config;
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank::{OpenWebUIRequest, OpenWebUIResponse};
+use anyhow::Context;
+use log::warn;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Append-only JSON Lines log of every rerank request, for compliance
+/// review. Queries are stored as SHA-256 hashes and documents not at all.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+/// One line of the audit log.
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp_ms: u128,
+    request_id: &'a str,
+    /// The API key's name (or prefix), never the key itself.
+    api_key: Option<String>,
+    query_sha256: String,
+    model: Option<&'a str>,
+    documents: usize,
+    top_n: Option<usize>,
+    status: u16,
+    /// Results returned, or the error code.
+    results: Option<usize>,
+    error: Option<&'static str>,
+    /// Index and score of the best-ranked document.
+    top_index: Option<usize>,
+    top_score: Option<f64>,
+    duration_ms: u128,
+}
+
+impl AuditLog {
+    pub fn open(config: &Config) -> anyhow::Result<Self> {
+        let file = match &config.audit_log_path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open audit log {}", path.display()))?,
+            )),
+            None => None,
+        };
+        Ok(Self { file })
+    }
+
+    /// Append the outcome of one request.
+    pub fn record(
+        &self,
+        ctx: &RequestContext,
+        req: &OpenWebUIRequest,
+        outcome: Result<&OpenWebUIResponse, &ApiError>,
+        duration: Duration,
+    ) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let (status, results, error) = match outcome {
+            Ok(response) => (200, Some(response.results.len()), None),
+            Err(e) => {
+                let (status, error) = e.status();
+                (status, None, Some(error))
+            }
+        };
+        let top = outcome.ok().and_then(|response| response.results.first());
+
+        let record = Record {
+            timestamp_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            request_id: &ctx.request_id,
+            api_key: ctx.api_key.as_ref().map(|key| key.label()),
+            query_sha256: Sha256::digest(req.query.as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            model: req.model.as_deref(),
+            documents: req.documents.len(),
+            top_n: req.top_n,
+            status,
+            results,
+            error,
+            top_index: top.map(|result| result.index),
+            top_score: top.map(|result| result.relevance_score),
+            duration_ms: duration.as_millis(),
+        };
+
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        // One write per record so concurrent appends never interleave
+        if let Err(e) = file.lock().unwrap().write_all(&line) {
+            warn!("Failed to write audit record: {}", e);
+        }
+    }
+}
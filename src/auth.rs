@@ -0,0 +1,91 @@
+use crate::config::ApiKey;
+use crate::error::ApiError;
+use crate::state::AppState;
+use log::{debug, warn};
+use std::sync::Arc;
+use warp::http::HeaderMap;
+use warp::Filter;
+
+/// Reject requests without a valid `Authorization: Bearer <key>` (or
+/// `Api-Key: <key>`) header when API keys are configured, extracting the
+/// matched key; pass everything through otherwise.
+pub fn authorize(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (Option<ApiKey>,), Error = warp::Rejection> + Clone {
+    warp::header::headers_cloned().and_then(move |headers: HeaderMap| {
+        let state = state.clone();
+        async move {
+            if state.config.api_keys.is_empty() {
+                return Ok(None);
+            }
+
+            let Some(token) = presented(&headers) else {
+                warn!("Missing API key");
+                return Err(warp::reject::custom(ApiError::Unauthorized(
+                    "Missing API key".to_string(),
+                )));
+            };
+
+            match matching(&state.config.api_keys, token) {
+                Some(key) => {
+                    debug!("🔑 Authenticated as {}", key.label());
+                    Ok(Some(key.clone()))
+                }
+                None => {
+                    warn!("Invalid API key");
+                    Err(warp::reject::custom(ApiError::Unauthorized(
+                        "Invalid API key".to_string(),
+                    )))
+                }
+            }
+        }
+    })
+}
+
+/// Like [`authorize`], but only admin keys get through when API keys are
+/// configured.
+pub fn require_admin(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    authorize(state)
+        .and_then(|key: Option<ApiKey>| async move {
+            match key {
+                Some(key) if !key.admin => {
+                    warn!("{} is not an admin key", key.label());
+                    Err(warp::reject::custom(ApiError::Forbidden(
+                        "This API key may not use admin endpoints".to_string(),
+                    )))
+                }
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+/// The configured key a request carries, if any.
+pub fn find_key<'a>(keys: &'a [ApiKey], headers: &HeaderMap) -> Option<&'a ApiKey> {
+    matching(keys, presented(headers)?)
+}
+
+/// The key a request presents: a bearer token, or an `Api-Key` header as
+/// Pinecone's clients send it.
+fn presented(headers: &HeaderMap) -> Option<&str> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| header("api-key"))
+        .map(str::trim)
+}
+
+fn matching<'a>(keys: &'a [ApiKey], token: &str) -> Option<&'a ApiKey> {
+    keys.iter()
+        .find(|k| constant_time_eq(k.key.as_bytes(), token.as_bytes()))
+}
+
+/// Compare without short-circuiting on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,447 @@
+use crate::info::TeiInfo;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Smoothing factor for the latency moving average; higher reacts faster.
+const EWMA_ALPHA: f64 = 0.3;
+
+/// How requests are spread across the backend pool.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    /// Fewest requests currently in flight.
+    LeastInFlight,
+    /// Round-robin in proportion to `backend_weights`.
+    Weighted,
+    /// Lowest moving-average latency, scaled by requests in flight.
+    Latency,
+    /// Same query, same backend, so per-backend caches stay warm.
+    ConsistentHash,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-in-flight" => Ok(Strategy::LeastInFlight),
+            "weighted" => Ok(Strategy::Weighted),
+            "latency" => Ok(Strategy::Latency),
+            "consistent-hash" => Ok(Strategy::ConsistentHash),
+            other => Err(format!(
+                "unknown strategy '{}' (expected round-robin, least-in-flight, weighted, latency or consistent-hash)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strategy::RoundRobin => "round-robin",
+            Strategy::LeastInFlight => "least-in-flight",
+            Strategy::Weighted => "weighted",
+            Strategy::Latency => "latency",
+            Strategy::ConsistentHash => "consistent-hash",
+        })
+    }
+}
+
+/// When a failing backend is taken out of rotation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Breaker {
+    /// Consecutive failures that open the breaker; 0 never opens it.
+    pub failures: u32,
+    /// How long an open breaker keeps the backend out of rotation before
+    /// it gets another try.
+    pub cooldown: Duration,
+}
+
+/// Why a backend can't take requests, as reported to clients when none can.
+#[derive(Serialize, Debug, Clone)]
+pub struct BackendStatus {
+    pub url: String,
+    /// The backend's last failure, if any.
+    pub error: Option<String>,
+    /// Seconds until its breaker lets requests through again.
+    pub retry_in_secs: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    open_until: Option<Instant>,
+}
+
+/// A single TEI instance requests can be sent to.
+#[derive(Debug)]
+pub struct Backend {
+    pub url: String,
+    pub weight: u32,
+    in_flight: AtomicUsize,
+    /// Moving average of successful request latency in microseconds; 0
+    /// until the first sample.
+    ewma_micros: AtomicU64,
+    /// Limits last read from the backend's `/info`.
+    info: RwLock<Option<TeiInfo>>,
+    health: Mutex<Health>,
+}
+
+impl Backend {
+    fn new(url: String, weight: u32) -> Self {
+        Self {
+            url,
+            weight,
+            in_flight: AtomicUsize::new(0),
+            ewma_micros: AtomicU64::new(0),
+            info: RwLock::new(None),
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// Whether the backend's breaker lets requests through.
+    fn is_available(&self, now: Instant) -> bool {
+        let health = self.health.lock().unwrap();
+        health.open_until.is_none_or(|until| now >= until)
+    }
+
+    /// Close the breaker after a call the backend answered.
+    pub fn succeeded(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.consecutive_failures > 0 {
+            *health = Health::default();
+        }
+    }
+
+    fn failed(&self, error: String, breaker: Breaker) -> bool {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        health.last_error = Some(error);
+        // Once open, a single failure on the next try reopens it
+        let open = breaker.failures > 0 && health.consecutive_failures >= breaker.failures;
+        if open {
+            health.open_until = Some(Instant::now() + breaker.cooldown);
+        }
+        open
+    }
+
+    fn status(&self, now: Instant) -> BackendStatus {
+        let health = self.health.lock().unwrap();
+        BackendStatus {
+            url: self.url.clone(),
+            error: health.last_error.clone(),
+            retry_in_secs: health
+                .open_until
+                .filter(|&until| until > now)
+                .map(|until| until.duration_since(now).as_secs_f64().ceil() as u64),
+        }
+    }
+
+    pub fn info(&self) -> Option<TeiInfo> {
+        *self.info.read().unwrap()
+    }
+
+    pub fn set_info(&self, info: TeiInfo) {
+        *self.info.write().unwrap() = Some(info);
+    }
+
+    /// Count a request as in flight until the returned guard is dropped.
+    pub fn begin(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            backend: self.clone(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Fold a successful request's latency into the moving average.
+    pub fn observe(&self, latency: Duration) {
+        let sample = latency.as_micros().min(u64::MAX as u128) as u64;
+        let _ = self
+            .ewma_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(if current == 0 {
+                    sample.max(1)
+                } else {
+                    (EWMA_ALPHA * sample as f64 + (1.0 - EWMA_ALPHA) * current as f64) as u64
+                })
+            });
+    }
+
+    /// Expected cost of sending one more request here.
+    fn load_score(&self) -> u64 {
+        self.ewma_micros
+            .load(Ordering::Relaxed)
+            .saturating_mul(self.in_flight() as u64 + 1)
+    }
+}
+
+/// Keeps a backend's in-flight count raised while a request is outstanding.
+#[derive(Debug)]
+pub struct InFlight {
+    backend: Arc<Backend>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.backend.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The set of TEI backends currently in rotation. Static when only
+/// `tei_endpoint` is configured, otherwise maintained by service discovery.
+#[derive(Debug, Default)]
+pub struct BackendPool {
+    backends: RwLock<Arc<Vec<Arc<Backend>>>>,
+    next: AtomicUsize,
+    strategy: Strategy,
+    weights: BTreeMap<String, u32>,
+    breaker: Breaker,
+}
+
+impl BackendPool {
+    /// An empty pool; fill it with [`BackendPool::replace`].
+    pub fn new(strategy: Strategy, weights: BTreeMap<String, u32>, breaker: Breaker) -> Self {
+        Self {
+            strategy,
+            weights,
+            breaker,
+            ..Self::default()
+        }
+    }
+
+    /// Swap in a new set of backend URLs, keeping the existing entries for
+    /// URLs that are still present. Returns whether the set changed.
+    pub fn replace(&self, mut urls: Vec<String>) -> bool {
+        urls.sort();
+        urls.dedup();
+
+        let mut backends = self.backends.write().unwrap();
+        if backends.iter().map(|b| &b.url).eq(urls.iter()) {
+            return false;
+        }
+
+        let updated = urls
+            .into_iter()
+            .map(|url| {
+                backends
+                    .iter()
+                    .find(|b| b.url == url)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        let weight = self.weights.get(&url).copied().unwrap_or(1);
+                        Arc::new(Backend::new(url, weight))
+                    })
+            })
+            .collect();
+        *backends = Arc::new(updated);
+        true
+    }
+
+    pub fn snapshot(&self) -> Arc<Vec<Arc<Backend>>> {
+        self.backends.read().unwrap().clone()
+    }
+
+    /// The tightest limits reported by the pool's backends, so a request
+    /// fits whichever one it is sent to. `None` until one has reported.
+    pub fn info(&self) -> Option<TeiInfo> {
+        self.snapshot()
+            .iter()
+            .filter_map(|backend| backend.info())
+            .reduce(|a, b| TeiInfo {
+                max_client_batch_size: a.max_client_batch_size.min(b.max_client_batch_size),
+                max_input_length: a.max_input_length.min(b.max_input_length),
+            })
+    }
+
+    /// Count a failed call against `backend`, taking it out of rotation
+    /// once it has failed too often in a row.
+    pub fn record_failure(&self, backend: &Backend, error: String) {
+        if backend.failed(error, self.breaker) {
+            warn!(
+                "🔌 TEI backend {} failed {} times in a row, out of rotation for {:?}",
+                backend.url, self.breaker.failures, self.breaker.cooldown
+            );
+        }
+    }
+
+    /// Why each backend is out of rotation, for when [`BackendPool::pick`]
+    /// finds none.
+    pub fn statuses(&self) -> Vec<BackendStatus> {
+        let now = Instant::now();
+        self.snapshot().iter().map(|b| b.status(now)).collect()
+    }
+
+    /// Choose the backend for the next request using the configured strategy,
+    /// skipping backends whose breaker is open. `key` (the query) is only
+    /// used by `consistent-hash`.
+    pub fn pick(&self, key: &str) -> Option<Arc<Backend>> {
+        let now = Instant::now();
+        let backends: Vec<Arc<Backend>> = self
+            .snapshot()
+            .iter()
+            .filter(|b| b.is_available(now))
+            .cloned()
+            .collect();
+        if backends.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+
+        let chosen = match self.strategy {
+            Strategy::RoundRobin => &backends[next % backends.len()],
+            Strategy::LeastInFlight => {
+                // Rotate the starting point so ties don't all land on the first
+                min_by_key_from(&backends, next, |b| b.in_flight() as u64)
+            }
+            Strategy::Weighted => {
+                let total: u64 = backends.iter().map(|b| b.weight as u64).sum();
+                if total == 0 {
+                    &backends[next % backends.len()]
+                } else {
+                    let mut slot = next as u64 % total;
+                    backends
+                        .iter()
+                        .find(|b| {
+                            if slot < b.weight as u64 {
+                                return true;
+                            }
+                            slot -= b.weight as u64;
+                            false
+                        })
+                        .unwrap_or(&backends[0])
+                }
+            }
+            Strategy::Latency => min_by_key_from(&backends, next, Backend::load_score),
+            Strategy::ConsistentHash => rendezvous(key, &backends),
+        };
+        Some(chosen.clone())
+    }
+}
+
+/// Minimum by `key`, scanning from `start` so ties rotate between backends.
+fn min_by_key_from(
+    backends: &[Arc<Backend>],
+    start: usize,
+    key: impl Fn(&Backend) -> u64,
+) -> &Arc<Backend> {
+    let len = backends.len();
+    (0..len)
+        .map(|i| &backends[(start + i) % len])
+        .min_by_key(|b| key(b))
+        .unwrap_or(&backends[0])
+}
+
+/// Weighted rendezvous hashing: every backend scores the key, the highest
+/// score wins, and a backend's share of keys follows its weight. Adding or
+/// removing a backend only moves the keys that score highest on it.
+fn rendezvous<'a>(key: &str, backends: &'a [Arc<Backend>]) -> &'a Arc<Backend> {
+    let key = fnv1a(key.as_bytes(), FNV_OFFSET);
+    backends
+        .iter()
+        .max_by(|a, b| rendezvous_score(key, a).total_cmp(&rendezvous_score(key, b)))
+        .unwrap_or(&backends[0])
+}
+
+fn rendezvous_score(key: u64, backend: &Backend) -> f64 {
+    // FNV-1a alone barely spreads URLs that differ in one character, so the
+    // pair goes through a finalizer before it's used
+    let hash = splitmix64(key ^ splitmix64(fnv1a(backend.url.as_bytes(), FNV_OFFSET)));
+    // Uniform in (0, 1), so the logarithm is finite and negative
+    let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    backend.weight as f64 / -unit.ln()
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// FNV-1a, chosen over `DefaultHasher` because it is stable across Rust
+/// releases, so every proxy instance maps a query to the same backend.
+fn fnv1a(bytes: &[u8], seed: u64) -> u64 {
+    bytes.iter().fold(seed, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The splitmix64 finalizer: every input bit affects every output bit.
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(urls: &[&str]) -> Vec<Arc<Backend>> {
+        urls.iter()
+            .map(|url| Arc::new(Backend::new(url.to_string(), 1)))
+            .collect()
+    }
+
+    fn shares(backends: &[Arc<Backend>], keys: usize) -> Vec<f64> {
+        let mut counts = vec![0; backends.len()];
+        for i in 0..keys {
+            let chosen = rendezvous(&format!("query {}", i), backends);
+            counts[backends
+                .iter()
+                .position(|b| Arc::ptr_eq(b, chosen))
+                .unwrap()] += 1;
+        }
+        counts.iter().map(|&n| n as f64 / keys as f64).collect()
+    }
+
+    #[test]
+    fn consistent_hash_spreads_similar_urls_evenly() {
+        for n in [2, 3, 4, 5] {
+            let urls: Vec<String> = (1..=n).map(|i| format!("http://tei-{}:8080", i)).collect();
+            let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+            for share in shares(&pool(&urls), 100_000) {
+                let even = 1.0 / n as f64;
+                assert!((share - even).abs() < 0.01, "{} backends: {}", n, share);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hash_follows_weights() {
+        let backends = vec![
+            Arc::new(Backend::new("http://tei-1:8080".to_string(), 1)),
+            Arc::new(Backend::new("http://tei-2:8080".to_string(), 3)),
+        ];
+        let shares = shares(&backends, 100_000);
+        assert!((shares[1] - 0.75).abs() < 0.01, "{:?}", shares);
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_keys_of_a_removed_backend() {
+        let all = pool(&[
+            "http://tei-1:8080",
+            "http://tei-2:8080",
+            "http://tei-3:8080",
+        ]);
+        let remaining = &all[..2];
+        for i in 0..10_000 {
+            let key = format!("query {}", i);
+            let before = rendezvous(&key, &all);
+            if !Arc::ptr_eq(before, &all[2]) {
+                assert!(Arc::ptr_eq(before, rendezvous(&key, remaining)), "{}", key);
+            }
+        }
+    }
+}
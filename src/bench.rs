@@ -0,0 +1,190 @@
+use crate::context::RequestContext;
+use crate::rerank::{self, Document, OpenWebUIRequest, SortOrder};
+use crate::state::AppState;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Load generator settings for the `bench` command.
+#[derive(Debug)]
+pub struct BenchOptions {
+    /// Requests replayed round-robin until the run ends.
+    pub corpus: Vec<OpenWebUIRequest>,
+    /// Target request rate; `None` sends as fast as concurrency allows.
+    pub rps: Option<f64>,
+    pub concurrency: usize,
+    /// Stop after this many requests ...
+    pub requests: Option<usize>,
+    /// ... or after this much time, whichever is configured.
+    pub duration: Option<Duration>,
+}
+
+/// What a load run has seen so far.
+#[derive(Debug, Default)]
+pub struct Samples {
+    latencies: Vec<Duration>,
+    documents: usize,
+    errors: usize,
+    last_error: Option<String>,
+}
+
+impl Samples {
+    pub fn succeeded(&mut self, latency: Duration, documents: usize) {
+        self.latencies.push(latency);
+        self.documents += documents;
+    }
+
+    pub fn failed(&mut self, error: String) {
+        self.errors += 1;
+        self.last_error = Some(error);
+    }
+
+    pub fn report(mut self, requests: usize, elapsed: Duration) -> BenchReport {
+        self.latencies.sort();
+        BenchReport {
+            requests,
+            errors: self.errors,
+            documents: self.documents,
+            elapsed,
+            last_error: self.last_error,
+            latencies: self.latencies,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BenchReport {
+    pub requests: usize,
+    pub errors: usize,
+    pub documents: usize,
+    pub elapsed: Duration,
+    pub last_error: Option<String>,
+    /// Sorted latencies of successful requests.
+    latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32
+    }
+
+    pub fn print(&self) {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        println!("Requests:    {} ({} failed)", self.requests, self.errors);
+        if let Some(e) = &self.last_error {
+            println!("Last error:  {}", e);
+        }
+        println!("Duration:    {:.2}s", secs);
+        println!(
+            "Throughput:  {:.2} req/s, {:.1} docs/s",
+            (self.requests - self.errors) as f64 / secs,
+            self.documents as f64 / secs
+        );
+        println!("Latency:");
+        println!("  mean       {:>10.2?}", self.mean());
+        for p in [50.0, 90.0, 95.0, 99.0, 100.0] {
+            let label = if p == 100.0 {
+                "max".to_string()
+            } else {
+                format!("p{}", p)
+            };
+            println!("  {:<10} {:>10.2?}", label, self.percentile(p));
+        }
+    }
+}
+
+/// Replay the corpus against the backend through the normal rerank path and
+/// collect per-request latencies.
+pub async fn run(state: Arc<AppState>, options: BenchOptions) -> BenchReport {
+    let corpus = Arc::new(options.corpus);
+    let samples = Arc::new(Mutex::new(Samples::default()));
+    let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let mut ticker = options
+        .rps
+        .map(|rps| tokio::time::interval(Duration::from_secs_f64(1.0 / rps)));
+
+    let started = Instant::now();
+    let mut tasks = Vec::new();
+    let mut sent = 0;
+
+    loop {
+        if options.requests.is_some_and(|max| sent >= max) {
+            break;
+        }
+        if options.duration.is_some_and(|max| started.elapsed() >= max) {
+            break;
+        }
+
+        if let Some(ticker) = ticker.as_mut() {
+            ticker.tick().await;
+        }
+
+        let permit = match semaphore.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+
+        let corpus = corpus.clone();
+        let state = state.clone();
+        let samples = samples.clone();
+
+        tasks.push(tokio::spawn(async move {
+            let req = &corpus[sent % corpus.len()];
+            let documents = req.documents.len();
+            let start = Instant::now();
+            let result = rerank::rerank(&state, req, &RequestContext::default()).await;
+            let latency = start.elapsed();
+            drop(permit);
+
+            let mut samples = samples.lock().unwrap();
+            match result {
+                Ok(_) => samples.succeeded(latency, documents),
+                Err(e) => samples.failed(e.to_string()),
+            }
+        }));
+        sent += 1;
+    }
+
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    let samples = std::mem::take(&mut *samples.lock().unwrap());
+    samples.report(sent, started.elapsed())
+}
+
+/// Build a synthetic request when no corpus file is supplied.
+pub fn synthetic_request(batch_size: usize) -> OpenWebUIRequest {
+    OpenWebUIRequest {
+        query: "What is the capital of France?".to_string(),
+        documents: (0..batch_size)
+            .map(|i| {
+                Document::Text(format!(
+                    "Document {} about European geography, history and the cities of France.",
+                    i
+                ))
+            })
+            .collect(),
+        model: None,
+        top_n: None,
+        include_meta: false,
+        return_documents: false,
+        extra: Default::default(),
+        strip_markup: None,
+        order: SortOrder::default(),
+        tie_break: Vec::new(),
+        offset: 0,
+        limit: None,
+    }
+}
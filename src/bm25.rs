@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+/// How quickly repeats of a term stop adding to a document's score.
+const K1: f64 = 1.2;
+/// How much a document's length counts against it, from 0 (not at all) to
+/// 1 (fully).
+const B: f64 = 0.75;
+
+/// The lowercase words of `text`, split on anything that isn't a letter or
+/// a digit.
+fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Okapi BM25 scores of `documents` for `query`. Term statistics come from
+/// the documents themselves, as there's no corpus to draw them from, and
+/// the scores are divided by the best one so they fall in `[0, 1]`.
+pub fn scores(query: &str, documents: &[&str]) -> Vec<f64> {
+    let counted: Vec<(HashMap<String, usize>, usize)> = documents
+        .iter()
+        .map(|document| {
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            let mut length = 0;
+            for term in terms(document) {
+                *counts.entry(term).or_default() += 1;
+                length += 1;
+            }
+            (counts, length)
+        })
+        .collect();
+    let total_length: usize = counted.iter().map(|(_, length)| length).sum();
+    let average_length = (total_length as f64 / counted.len().max(1) as f64).max(1.0);
+
+    let mut query_terms: Vec<String> = terms(query).collect();
+    query_terms.sort();
+    query_terms.dedup();
+    let documents_count = counted.len() as f64;
+    let weights: Vec<(&str, f64)> = query_terms
+        .iter()
+        .map(|term| {
+            let containing = counted
+                .iter()
+                .filter(|(counts, _)| counts.contains_key(term))
+                .count() as f64;
+            let idf = ((documents_count - containing + 0.5) / (containing + 0.5) + 1.0).ln();
+            (term.as_str(), idf)
+        })
+        .collect();
+
+    let scores: Vec<f64> = counted
+        .iter()
+        .map(|(counts, length)| {
+            let norm = K1 * (1.0 - B + B * *length as f64 / average_length);
+            weights
+                .iter()
+                .map(|&(term, idf)| {
+                    let frequency = counts.get(term).copied().unwrap_or(0) as f64;
+                    idf * frequency * (K1 + 1.0) / (frequency + norm)
+                })
+                .sum()
+        })
+        .collect();
+
+    let best = scores.iter().copied().fold(0.0, f64::max);
+    if best <= 0.0 {
+        return scores;
+    }
+    scores.into_iter().map(|score| score / best).collect()
+}
@@ -0,0 +1,111 @@
+use super::CacheKey;
+use log::warn;
+use std::path::Path;
+
+/// Scores persisted with sled so they survive restarts.
+#[derive(Debug)]
+pub struct DiskCache {
+    db: sled::Db,
+    /// Cache key -> score (f64, little endian), sequence number and expiry
+    /// (unix seconds, 0 for never).
+    scores: sled::Tree,
+    /// Sequence number (big endian, so oldest sorts first) -> cache key.
+    order: sled::Tree,
+    /// Approximate size cap enforced by [`DiskCache::compact`]; 0 for none.
+    max_bytes: u64,
+}
+
+impl DiskCache {
+    pub fn open(path: &Path, max_bytes: u64) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            scores: db.open_tree("scores")?,
+            order: db.open_tree("order")?,
+            db,
+            max_bytes,
+        })
+    }
+
+    /// The stored score and its expiry time.
+    pub fn get(&self, key: &CacheKey) -> Option<(f64, u64)> {
+        match self.scores.get(key) {
+            Ok(value) => {
+                let value = value?;
+                let score = f64::from_le_bytes(value.get(..8)?.try_into().ok()?);
+                Some((score, expires_at(&value)))
+            }
+            Err(e) => {
+                warn!("Disk cache read failed: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &CacheKey, score: f64, expires_at: u64) {
+        if let Err(e) = self.try_insert(key, score, expires_at) {
+            warn!("Disk cache write failed: {}", e);
+        }
+    }
+
+    fn try_insert(&self, key: &CacheKey, score: f64, expires_at: u64) -> sled::Result<()> {
+        let seq = self.db.generate_id()?.to_be_bytes();
+        let mut value = [0u8; 24];
+        value[..8].copy_from_slice(&score.to_le_bytes());
+        value[8..16].copy_from_slice(&seq);
+        value[16..].copy_from_slice(&expires_at.to_le_bytes());
+
+        if let Some(previous) = self.scores.insert(key, &value)? {
+            if let Some(previous_seq) = previous.get(8..16) {
+                self.order.remove(previous_seq)?;
+            }
+        }
+        self.order.insert(seq, key)?;
+        Ok(())
+    }
+
+    /// Drop expired entries, then the oldest ones in proportion to how far
+    /// the database is over its size cap, and flush to disk. Returns the
+    /// number of entries expired and evicted.
+    pub fn compact(&self, now: u64) -> sled::Result<(usize, usize)> {
+        let mut expired = 0;
+        for item in self.scores.iter() {
+            let (key, value) = item?;
+            let expires_at = expires_at(&value);
+            if expires_at != 0 && expires_at <= now {
+                self.scores.remove(&key)?;
+                if let Some(seq) = value.get(8..16) {
+                    self.order.remove(seq)?;
+                }
+                expired += 1;
+            }
+        }
+
+        let size = self.db.size_on_disk()?;
+        let mut evicted = 0;
+
+        if self.max_bytes > 0 && size > self.max_bytes {
+            // sled reclaims space lazily, so evict a proportional share per
+            // pass rather than looping until the size drops
+            let excess = (size - self.max_bytes) as f64 / size as f64;
+            let target = ((self.order.len() as f64 * excess).ceil() as usize).max(1);
+            while evicted < target {
+                let Some((_, key)) = self.order.pop_min()? else {
+                    break;
+                };
+                self.scores.remove(key)?;
+                evicted += 1;
+            }
+        }
+
+        self.db.flush()?;
+        Ok((expired, evicted))
+    }
+}
+
+fn expires_at(value: &[u8]) -> u64 {
+    value
+        .get(16..24)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or(0)
+}
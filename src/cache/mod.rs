@@ -0,0 +1,484 @@
+#[cfg(feature = "disk-cache")]
+mod disk;
+
+use crate::config::Config;
+use crate::state::AppState;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::HeaderValue;
+
+/// How often the disk cache is trimmed to its size cap and flushed.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rough in-memory footprint of one entry: the key, the entry itself, its
+/// slot in the eviction index and hash table overhead.
+pub const ESTIMATED_ENTRY_BYTES: u64 = 160;
+
+/// Identifies one (model, query, document) pair without keeping the text.
+pub type CacheKey = [u8; 32];
+
+/// The key for a pair scored by the default backends, or by the backends
+/// for `language` when the request was routed to them.
+pub fn key(model: Option<&str>, language: Option<&str>, query: &str, document: &str) -> CacheKey {
+    let mut hasher = Sha256::new();
+    // The language goes last, so keys of unrouted requests stay as they were
+    for part in [model.unwrap_or(""), query, document]
+        .into_iter()
+        .chain(language)
+    {
+        // Length-prefixed so ("ab", "c") and ("a", "bc") differ
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// Which in-memory entry makes room when the cache is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    /// Least recently used.
+    #[default]
+    Lru,
+    /// Least frequently used, oldest first among equals. Use counts are
+    /// halved each time as many entries as the cache holds have been
+    /// added, so entries that were popular once don't stay forever.
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            other => Err(format!(
+                "unknown eviction policy '{}' (expected lru or lfu)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+        })
+    }
+}
+
+/// Counters and sizes reported on `/metrics`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Scores for individual (query, document) pairs, so that a repeated query
+/// with a partially overlapping candidate set only sends the new documents
+/// to TEI. The in-memory tier is capped by entry count and estimated bytes
+/// and evicts by `policy`; an optional disk tier survives restarts.
+#[derive(Debug, Default)]
+pub struct ScoreCache {
+    capacity: usize,
+    policy: EvictionPolicy,
+    ttl_secs: u64,
+    model_ttl_secs: BTreeMap<String, u64>,
+    inner: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    #[cfg(feature = "disk-cache")]
+    disk: Option<disk::DiskCache>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    score: f64,
+    hits: u64,
+    tick: u64,
+    /// Unix seconds; 0 never expires.
+    expires_at: u64,
+}
+
+enum Lookup {
+    Hit(f64),
+    Expired,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    scores: HashMap<CacheKey, Entry>,
+    /// Eviction order, first to go first: `(hits, tick)` for LFU, `(0, tick)`
+    /// for LRU.
+    order: BTreeMap<(u64, u64), CacheKey>,
+    tick: u64,
+    /// Entries added since use counts were last halved.
+    added: u64,
+}
+
+impl Entries {
+    fn rank(policy: EvictionPolicy, entry: &Entry) -> (u64, u64) {
+        match policy {
+            EvictionPolicy::Lru => (0, entry.tick),
+            EvictionPolicy::Lfu => (entry.hits, entry.tick),
+        }
+    }
+
+    /// Record a use of `key` and return its score, dropping it instead if it
+    /// has expired.
+    fn touch(&mut self, policy: EvictionPolicy, key: &CacheKey, now: u64) -> Option<Lookup> {
+        let entry = self.scores.get_mut(key)?;
+        self.order.remove(&Self::rank(policy, entry));
+
+        if entry.expires_at != 0 && entry.expires_at <= now {
+            self.scores.remove(key);
+            return Some(Lookup::Expired);
+        }
+
+        self.tick += 1;
+        entry.hits += 1;
+        entry.tick = self.tick;
+        self.order.insert(Self::rank(policy, entry), *key);
+        Some(Lookup::Hit(entry.score))
+    }
+
+    fn insert(&mut self, policy: EvictionPolicy, key: CacheKey, score: f64, expires_at: u64) {
+        self.tick += 1;
+        self.added += 1;
+        let entry = Entry {
+            score,
+            hits: 0,
+            tick: self.tick,
+            expires_at,
+        };
+        self.order.insert(Self::rank(policy, &entry), key);
+        if let Some(previous) = self.scores.insert(key, entry) {
+            self.order.remove(&Self::rank(policy, &previous));
+        }
+    }
+
+    /// Halve every entry's use count, keeping their order among equals.
+    fn decay(&mut self, policy: EvictionPolicy) {
+        self.added = 0;
+        for entry in self.scores.values_mut() {
+            entry.hits /= 2;
+        }
+        self.order = self
+            .scores
+            .iter()
+            .map(|(key, entry)| (Self::rank(policy, entry), *key))
+            .collect();
+    }
+
+    fn evict_one(&mut self) -> bool {
+        match self.order.pop_first() {
+            Some((_, key)) => self.scores.remove(&key).is_some(),
+            None => false,
+        }
+    }
+}
+
+impl ScoreCache {
+    /// Build the cache described by the configuration, opening the disk tier
+    /// when `cache_path` is set.
+    pub fn open(config: &Config) -> anyhow::Result<Self> {
+        // Both caps apply; 0 means "no limit" for either, unless both are 0
+        let by_bytes = (config.cache_max_bytes / ESTIMATED_ENTRY_BYTES) as usize;
+        let capacity = match (config.cache_max_entries, by_bytes) {
+            (0, bytes) => bytes,
+            (entries, 0) => entries,
+            (entries, bytes) => entries.min(bytes.max(1)),
+        };
+
+        #[cfg_attr(not(feature = "disk-cache"), allow(unused_mut))]
+        let mut cache = Self {
+            capacity,
+            policy: config.cache_eviction,
+            ttl_secs: config.cache_ttl_secs,
+            model_ttl_secs: config.cache_model_ttl_secs.clone(),
+            ..Self::default()
+        };
+
+        #[cfg(feature = "disk-cache")]
+        if let Some(path) = &config.cache_path {
+            cache.disk = Some(disk::DiskCache::open(path, config.cache_disk_max_bytes)?);
+        }
+
+        Ok(cache)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0 || self.has_disk()
+    }
+
+    /// Entries the in-memory tier can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[cfg(feature = "disk-cache")]
+    pub fn has_disk(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    #[cfg(not(feature = "disk-cache"))]
+    pub fn has_disk(&self) -> bool {
+        false
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.inner.lock().unwrap().scores.len() as u64;
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            entries,
+            bytes: entries * ESTIMATED_ENTRY_BYTES,
+        }
+    }
+
+    /// Look up several pairs, checking memory first and then disk.
+    pub fn get_many(&self, keys: &[CacheKey]) -> Vec<Option<f64>> {
+        let now = unix_now();
+        let mut expired = 0;
+
+        #[cfg_attr(not(feature = "disk-cache"), allow(unused_mut))]
+        let mut scores: Vec<Option<f64>> = {
+            let mut entries = self.inner.lock().unwrap();
+            keys.iter()
+                .map(|key| match entries.touch(self.policy, key, now)? {
+                    Lookup::Hit(score) => Some(score),
+                    Lookup::Expired => {
+                        expired += 1;
+                        None
+                    }
+                })
+                .collect()
+        };
+
+        #[cfg(feature = "disk-cache")]
+        if let Some(disk) = &self.disk {
+            let mut promoted = Vec::new();
+            for (key, score) in keys.iter().zip(scores.iter_mut()) {
+                if score.is_some() {
+                    continue;
+                }
+                match disk.get(key) {
+                    Some((_, expires_at)) if expires_at != 0 && expires_at <= now => expired += 1,
+                    Some((value, expires_at)) => {
+                        *score = Some(value);
+                        promoted.push((*key, value, expires_at));
+                    }
+                    None => {}
+                }
+            }
+            self.remember(promoted);
+        }
+
+        let hits = scores.iter().filter(|score| score.is_some()).count() as u64;
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses
+            .fetch_add(keys.len() as u64 - hits, Ordering::Relaxed);
+        self.expirations.fetch_add(expired, Ordering::Relaxed);
+
+        scores
+    }
+
+    /// How long scores computed for `model` stay valid; 0 for until evicted.
+    pub fn ttl_secs(&self, model: Option<&str>) -> u64 {
+        model
+            .and_then(|model| self.model_ttl_secs.get(model))
+            .copied()
+            .unwrap_or(self.ttl_secs)
+    }
+
+    /// Add `X-Cache`, `HIT` when every score came from the cache, and a
+    /// `Cache-Control` keeping a `cacheable` response as long as its scores
+    /// are, or not at all. Responses can depend on the API key, so only
+    /// clients may keep them.
+    pub fn add_headers(
+        &self,
+        model: Option<&str>,
+        hit: bool,
+        cacheable: bool,
+        response: &mut warp::reply::Response,
+    ) {
+        let cache_control = match self.ttl_secs(model) {
+            _ if !cacheable => "no-store".to_string(),
+            0 => "private".to_string(),
+            ttl => format!("private, max-age={}", ttl),
+        };
+        let headers = response.headers_mut();
+        headers.insert(
+            "x-cache",
+            HeaderValue::from_static(if hit { "HIT" } else { "MISS" }),
+        );
+        if let Ok(value) = HeaderValue::from_str(&cache_control) {
+            headers.insert("cache-control", value);
+        }
+    }
+
+    /// Store scores computed for `model`, which decides their TTL.
+    pub fn insert_many(
+        &self,
+        model: Option<&str>,
+        scores: impl IntoIterator<Item = (CacheKey, f64)>,
+    ) {
+        let ttl = self.ttl_secs(model);
+        let expires_at = if ttl == 0 { 0 } else { unix_now() + ttl };
+
+        let scores: Vec<(CacheKey, f64, u64)> = scores
+            .into_iter()
+            .map(|(key, score)| (key, score, expires_at))
+            .collect();
+
+        #[cfg(feature = "disk-cache")]
+        if let Some(disk) = &self.disk {
+            for (key, score, expires_at) in &scores {
+                disk.insert(key, *score, *expires_at);
+            }
+        }
+
+        self.remember(scores);
+    }
+
+    /// Add entries to the in-memory tier, evicting past capacity.
+    fn remember(&self, scores: Vec<(CacheKey, f64, u64)>) {
+        if self.capacity == 0 || scores.is_empty() {
+            return;
+        }
+
+        let mut entries = self.inner.lock().unwrap();
+        let mut evicted = 0;
+        for (key, score, expires_at) in scores {
+            // Room is made before adding, so a new entry isn't the first to
+            // go under LFU, having not been used yet
+            if !entries.scores.contains_key(&key) {
+                while entries.scores.len() >= self.capacity && entries.evict_one() {
+                    evicted += 1;
+                }
+            }
+            entries.insert(self.policy, key, score, expires_at);
+            if self.policy == EvictionPolicy::Lfu && entries.added >= self.capacity as u64 {
+                entries.decay(self.policy);
+            }
+        }
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    /// Drop expired disk entries, trim the disk tier to its size cap and
+    /// flush it. Blocking.
+    fn compact(&self) -> anyhow::Result<usize> {
+        #[cfg(feature = "disk-cache")]
+        if let Some(disk) = &self.disk {
+            let (expired, evicted) = disk.compact(unix_now())?;
+            self.expirations
+                .fetch_add(expired as u64, Ordering::Relaxed);
+            self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+            return Ok(expired + evicted);
+        }
+        Ok(0)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Start the background task that keeps the disk cache under its size cap.
+/// Does nothing without a disk cache.
+pub fn spawn_compaction(state: Arc<AppState>) {
+    if !state.cache.has_disk() {
+        return;
+    }
+
+    info!(
+        "Compacting disk cache every {}s",
+        COMPACTION_INTERVAL.as_secs()
+    );
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(COMPACTION_INTERVAL).await;
+
+            let state = state.clone();
+            match tokio::task::spawn_blocking(move || state.cache.compact()).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => debug!("Removed {} entries from the disk cache", removed),
+                Ok(Err(e)) => warn!("Disk cache compaction failed: {:#}", e),
+                Err(e) => warn!("Disk cache compaction panicked: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lfu(capacity: usize) -> ScoreCache {
+        ScoreCache::open(&Config {
+            cache_max_entries: capacity,
+            cache_eviction: EvictionPolicy::Lfu,
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    fn cached(cache: &ScoreCache, keys: &[CacheKey]) -> Vec<bool> {
+        let entries = cache.inner.lock().unwrap();
+        keys.iter()
+            .map(|key| entries.scores.contains_key(key))
+            .collect()
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_used_entry_first() {
+        let cache = lfu(3);
+        let [a, b, c, d, e] = [[1; 32], [2; 32], [3; 32], [4; 32], [5; 32]];
+        cache.insert_many(None, [(a, 0.1), (b, 0.2), (c, 0.3)]);
+        cache.get_many(&[a, a, a, c]);
+
+        // b was never used, and is older than d
+        cache.insert_many(None, [(d, 0.4)]);
+        assert_eq!(cached(&cache, &[a, b, c, d]), [true, false, true, true]);
+
+        // c, used once, goes before d, used twice
+        cache.get_many(&[d, d]);
+        cache.insert_many(None, [(e, 0.5)]);
+        assert_eq!(cached(&cache, &[a, c, d, e]), [true, false, true, true]);
+    }
+
+    #[test]
+    fn lfu_forgets_old_popularity() {
+        let cache = lfu(2);
+        let popular = [0; 32];
+        cache.insert_many(None, [(popular, 0.1)]);
+        cache.get_many(&[popular; 100]);
+
+        for i in 1..=20u8 {
+            cache.insert_many(None, [([i; 32], 0.2)]);
+        }
+        assert_eq!(cached(&cache, &[popular, [20; 32]]), [false, true]);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::config::Config;
+use anyhow::Context;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Keeps the logit of scores of exactly 0 or 1 finite.
+const EPSILON: f64 = 1e-12;
+
+/// Maps a model's scores onto a shared scale, so one threshold works for
+/// several models: temperature scaling, then `scale * score + offset`, then
+/// clipping to `[min, max]`. The default leaves scores as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct Calibration {
+    /// Divides the score's logit: above 1 pulls scores towards 0.5, below
+    /// 1 pushes them apart. Meant for TEI's default sigmoid scores, not
+    /// `raw_scores`.
+    pub temperature: f64,
+    pub scale: f64,
+    pub offset: f64,
+    /// Scores below this are raised to it.
+    pub min: Option<f64>,
+    /// Scores above this are lowered to it.
+    pub max: Option<f64>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            scale: 1.0,
+            offset: 0.0,
+            min: None,
+            max: None,
+        }
+    }
+}
+
+impl Calibration {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.temperature.is_finite() || self.temperature <= 0.0 {
+            return Err("temperature: must be greater than 0".to_string());
+        }
+        for (name, value) in [
+            ("scale", Some(self.scale)),
+            ("offset", Some(self.offset)),
+            ("min", self.min),
+            ("max", self.max),
+        ] {
+            if value.is_some_and(|value| !value.is_finite()) {
+                return Err(format!("{}: must be a finite number", name));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err("min: must not be greater than max".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, score: f64) -> f64 {
+        let mut score = score;
+        if self.temperature != 1.0 {
+            let p = score.clamp(EPSILON, 1.0 - EPSILON);
+            let logit = (p / (1.0 - p)).ln();
+            score = 1.0 / (1.0 + (-logit / self.temperature).exp());
+        }
+        score = self.scale * score + self.offset;
+        if let Some(min) = self.min {
+            score = score.max(min);
+        }
+        if let Some(max) = self.max {
+            score = score.min(max);
+        }
+        score
+    }
+}
+
+/// One labelled score from `calibration_labels_path`: what TEI scored a
+/// document for a query, and whether it turned out to be relevant.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Label {
+    #[serde(default)]
+    model: Option<String>,
+    score: f64,
+    relevant: bool,
+}
+
+/// A non-decreasing map from TEI's scores to the share of documents with
+/// that score that were relevant, fitted to labels by isotonic regression.
+#[derive(Debug)]
+pub struct Curve {
+    /// Scores and their fitted probabilities, in increasing order of
+    /// score; scores between two are interpolated linearly.
+    knots: Vec<(f64, f64)>,
+    labels: usize,
+    relevant: usize,
+}
+
+impl Curve {
+    /// Fit with pool-adjacent-violators: walk the labels in order of score,
+    /// merging each block into the one before while it would be less
+    /// likely to be relevant, or has the same score. `labels` must be
+    /// sorted by score.
+    fn fit(labels: &[(f64, bool)]) -> Self {
+        // Each block: lowest and highest score, labels, relevant ones
+        let mut blocks: Vec<(f64, f64, f64, f64)> = Vec::new();
+        for &(score, relevant) in labels {
+            let mut block = (score, score, 1.0, if relevant { 1.0 } else { 0.0 });
+            while let Some(&last) = blocks.last() {
+                if last.1 < block.0 && last.3 / last.2 < block.3 / block.2 {
+                    break;
+                }
+                blocks.pop();
+                block = (last.0, block.1, last.2 + block.2, last.3 + block.3);
+            }
+            blocks.push(block);
+        }
+
+        let mut knots: Vec<(f64, f64)> = Vec::with_capacity(blocks.len() * 2);
+        for (low, high, count, relevant) in blocks {
+            let probability = relevant / count;
+            knots.push((low, probability));
+            if high > low {
+                knots.push((high, probability));
+            }
+        }
+        Self {
+            knots,
+            labels: labels.len(),
+            relevant: labels.iter().filter(|(_, relevant)| *relevant).count(),
+        }
+    }
+
+    /// The probability that a document TEI gave `score` is relevant.
+    /// Scores outside the labelled range get the nearest end's.
+    pub fn apply(&self, score: f64) -> f64 {
+        let Some(&(first, low)) = self.knots.first() else {
+            return score;
+        };
+        if score <= first {
+            return low;
+        }
+        for pair in self.knots.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            if score <= x1 {
+                return y0 + (y1 - y0) * (score - x0) / (x1 - x0);
+            }
+        }
+        self.knots.last().map_or(score, |&(_, high)| high)
+    }
+}
+
+/// Isotonic calibration curves per model, fitted to the labels in
+/// `calibration_labels_path` at startup and again on each reload. A
+/// model's curve takes the place of its `score_calibration`.
+#[derive(Debug)]
+pub struct Curves {
+    path: Option<PathBuf>,
+    /// By the `model` requests name, `None` for requests naming none.
+    fitted: RwLock<BTreeMap<Option<String>, Arc<Curve>>>,
+}
+
+impl Curves {
+    pub fn load(config: &Config) -> anyhow::Result<Self> {
+        let curves = Self {
+            path: config.calibration_labels_path.clone(),
+            fitted: RwLock::default(),
+        };
+        curves.reload()?;
+        Ok(curves)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Read the labels file again and refit every curve. The old curves
+    /// stay in use if it can't be read.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read calibration labels {}", path.display()))?;
+
+        let mut labels: BTreeMap<Option<String>, Vec<(f64, bool)>> = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let label: Label = serde_json::from_str(line).with_context(|| {
+                format!("invalid label on line {} of {}", number + 1, path.display())
+            })?;
+            if !label.score.is_finite() {
+                anyhow::bail!(
+                    "invalid label on line {} of {}: score must be a finite number",
+                    number + 1,
+                    path.display()
+                );
+            }
+            labels
+                .entry(label.model)
+                .or_default()
+                .push((label.score, label.relevant));
+        }
+
+        let fitted: BTreeMap<Option<String>, Arc<Curve>> = labels
+            .into_iter()
+            .map(|(model, mut labels)| {
+                labels.sort_by(|a, b| a.0.total_cmp(&b.0));
+                (model, Arc::new(Curve::fit(&labels)))
+            })
+            .collect();
+        for (model, curve) in &fitted {
+            info!(
+                "📐 Fitted calibration for {} to {} labels ({} relevant)",
+                model.as_deref().unwrap_or("requests without a model"),
+                curve.labels,
+                curve.relevant
+            );
+        }
+        *self.fitted.write().unwrap() = fitted;
+        Ok(())
+    }
+
+    /// The curve for requests to `model`, if there were labels for it.
+    pub fn get(&self, model: Option<&str>) -> Option<Arc<Curve>> {
+        self.fitted
+            .read()
+            .unwrap()
+            .get(&model.map(str::to_string))
+            .cloned()
+    }
+
+    /// What was fitted, for `/admin/calibration`.
+    pub fn summary(&self) -> serde_json::Value {
+        let models: Vec<serde_json::Value> = self
+            .fitted
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(model, curve)| {
+                serde_json::json!({
+                    "model": model,
+                    "labels": curve.labels,
+                    "relevant": curve.relevant,
+                    "knots": curve.knots,
+                })
+            })
+            .collect();
+        serde_json::json!({ "path": self.path, "models": models })
+    }
+}
@@ -0,0 +1,949 @@
+use crate::bench::{self, BenchOptions};
+use crate::config::{self, Config, ConfigSource};
+use crate::context::RequestContext;
+use crate::discovery;
+use crate::metrics;
+use crate::replay::{self, ReplayOptions};
+use crate::rerank::{self, Document, OpenWebUIRequest, OpenWebUIResponse, SortOrder};
+#[cfg(all(windows, feature = "windows-service"))]
+use crate::service;
+use crate::soak::{self, SoakOptions};
+use crate::state::{self, AppState};
+use log::error;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: rerank-proxy [COMMAND] [OPTIONS]
+
+Commands:
+  serve         Run the proxy server (default)
+  check         Validate the configuration and exit
+  print-config  Print the effective configuration (secrets masked)
+  healthcheck   Probe the running proxy's /health endpoint, exit 0 if healthy
+  rerank        Rank documents against a query through TEI and print the results
+  bench         Load test the TEI backend and report latency percentiles
+  replay        Re-send a JSONL log of requests to a proxy or backend, on its schedule
+  soak          Rerank continuously for a long time and report memory and fd drift
+  service       Install, uninstall or run as a Windows service
+                (install|uninstall|run; requires the windows-service feature)
+  help          Show this message
+
+Options:
+  --config <FILE>                  JSON config file (env: TEI_PROXY_CONFIG)
+  --tei-endpoint <URL>             Base URL of the TEI service
+  --port <PORT>                    Port where this proxy will listen
+  --bind-address <IP>              Interface to listen on (default: 0.0.0.0)
+  --listeners <ADDR[=ROUTES],...>  Listen on several addresses instead; ROUTES is
+                                   all, rerank or admin (default: all)
+  --max-client-batch-size <N>      Maximum number of documents per request
+  --max-document-length <N>        Maximum document length in characters
+  --max-request-tokens <N>         Maximum estimated tokens per request
+  --tei-timeout-secs <SECS>        Overall timeout for calls to TEI (default: 30)
+  --tei-max-retries <N>            Retries on 429, 503 or dropped connections (default: 2)
+  --tei-max-retry-wait-secs <SECS> Longest Retry-After waited out before returning 429
+                                   (default: 5)
+  --tei-retry-budget <RATIO>       Extra share of TEI calls retries may add (default: 0.2)
+  --breaker-failures <N>           Failures in a row that take a backend out of rotation
+                                   (0 never; default: 5)
+  --breaker-cooldown-secs <SECS>   How long a failing backend stays out (default: 10)
+  --embed-fallback <BOOL>          Score by embedding similarity when TEI can't rerank
+  --bm25-fallback <BOOL>           Score with BM25 in the proxy when TEI is unreachable
+  --tei-info-refresh-secs <SECS>   Read limits from TEI's /info this often (default: 300)
+  --model-limits <JSON>            Per-model batch size, length, token and timeout limits
+  --route-limits <JSON>            Per-route-group timeout, body size and concurrency limits
+  --allowed-models <NAME,...>      Models requests may name (default: any)
+  --max-request-bytes <N>          Maximum request body size (0 disables)
+  --stream-body-threshold-bytes <N>
+                                   Parse larger request bodies as they stream in
+  --strict-content-type <BOOL>     Reject non-JSON content types with 415 (default: true)
+  --client-header-timeout-secs <SECS>
+                                   Close connections slower to send headers (default: 10)
+  --client-body-timeout-secs <SECS>
+                                   Answer 408 to slower request bodies (default: 30)
+  --memory-budget-bytes <N>        Shed requests with 503 above this much buffered data
+  --shed-latency-p99-ms <MS>       Shed requests while the upstream p99 exceeds MS
+  --shed-latency-window-secs <SECS>
+                                   Window the upstream p99 is computed over (default: 30)
+  --shed-latency-fraction <0-1>    Share of requests shed while degraded (default: 0.5)
+  --fusion-rerank-weight <W>       Weight of the TEI score when fusing (default: 1)
+  --fusion-retrieval-weight <W>    Weight of documents' retrieval_score (default: 0)
+  --fusion-bias <B>                Constant added to fused scores (default: 0)
+  --lexical-weight <W>             Share of BM25 keyword scores in hybrid scores (default: 0)
+  --score-calibration <JSON>       Temperature, affine and clipping applied to TEI scores
+  --model-score-calibration <JSON> Per-model calibration overrides
+  --calibration-labels-path <PATH> Labelled scores to fit isotonic calibration to
+  --query-strip-prefixes <P,...>   Prefixes removed from the start of queries
+  --query-replacements <JSON>      Regex replacements applied to queries
+  --query-lowercase <BOOL>         Lowercase queries before scoring
+  --strip-markup <BOOL>            Strip HTML/Markdown from documents before scoring
+  --normalization <MODE>           Text cleanup: off, whitespace, nfc or nfkc (default: off)
+  --model-normalization <MODEL=MODE,...>
+                                   Per-model normalization overrides
+  --truncation <MODE>              Fit documents to the model: off, head, tail or middle
+  --score-precision <N>            Round relevance scores to N decimal places
+  --score-field <FIELD>            relevance_score (default), score or both
+  --response-envelope <SHAPE>      object (default) or array of results
+  --slow-request-ms <MS>           Log a warning for requests slower than MS (0 disables)
+  --payload-log-sample-rate <0-1>  Log full payloads of this share of requests at info level
+  --payload-log-on-header <BOOL>   Also log payloads of requests sent with X-Debug-Payload
+  --max-concurrent-requests <N>    Queue requests to TEI beyond N, highest priority first
+  --max-requests-per-ip <N>        Rerank requests one client IP may have in flight
+  --tei-api-key <KEY>              Bearer token sent to TEI
+  --tei-google-auth <BOOL>         Send Google service account tokens upstream
+  --language-backends <LANG=URL,...>
+                                   Backends for requests in a given language
+  --cascade-endpoint <URL>         Fast first-stage backend that shortlists documents
+  --cascade-model <NAME>           Model the first stage asks for
+  --cascade-top-k <N>              Documents the first stage passes on (default: 50)
+  --tei-embed-endpoint <URL>       TEI embedding instance for /retrieve_rerank queries
+  --qdrant-url <URL>               Qdrant server that /retrieve_rerank searches
+  --qdrant-collection <NAME>       Collection to search
+  --qdrant-api-key <KEY>           API key sent to Qdrant
+  --qdrant-vector <NAME>           Named vector to search
+  --qdrant-text-field <FIELD>      Payload field holding the text (default: text)
+  --elasticsearch-url <URL>        Elasticsearch/OpenSearch server searched instead (BM25)
+  --elasticsearch-index <NAME>     Index to search
+  --elasticsearch-api-key <KEY>    API key sent to Elasticsearch
+  --elasticsearch-fields <F,...>   Fields the query is matched against
+  --elasticsearch-text-field <F>   Source field holding the text (default: text)
+  --postgres-url <URL>             Postgres database searched instead (pgvector feature)
+  --pgvector-table <NAME>          Table holding documents and embeddings
+  --pgvector-id-column <NAME>      Column holding each document's ID (default: id)
+  --pgvector-text-column <NAME>    Column holding the text (default: text)
+  --pgvector-embedding-column <NAME>
+                                   Column holding the embedding (default: embedding)
+  --pgvector-metadata-column <NAME>
+                                   JSON column returned as metadata and filtered on
+  --pgvector-distance <METRIC>     cosine, l2 or inner_product (default: cosine)
+  --retrieve-top-k <N>             Candidates fetched per query (default: 50)
+  --tei-forward-params <NAME,...>  Extra request fields passed through to TEI
+  --tei-adapter <JSON>             Request/response mapping for non-TEI upstreams
+  --api-keys <KEY,...>             API keys clients must present on /rerank
+  --top-window-secs <SECS>         Window /admin/top ranks API keys over (default: 3600)
+  --rate-limit <N>                 Rerank requests per client per window (default: 0, off)
+  --rate-limit-window-secs <SECS>  Rate limit window (default: 60)
+  --idempotency-ttl-secs <SECS>    Replay responses for a repeated Idempotency-Key (default: 600)
+  --idempotency-max-entries <N>    Stored idempotent responses kept at most (default: 10000)
+  --fault-injection <BOOL>         Allow injecting faults into TEI calls, for testing clients
+  --fault-error-rate <0-1>         Share of TEI calls that fail
+  --fault-latency-ms <MS>          Latency added to every TEI call
+  --fault-malformed-rate <0-1>     Share of TEI calls answered with malformed JSON
+  --response-delays <ROUTE=MS,...> Latency added to a rerank route's responses; MS may
+                                   be a range, e.g. /rerank=200-3000
+  --cache-max-entries <N>          Cache this many (query, document) scores in memory
+  --cache-max-bytes <N>            Estimated memory cap for cached scores
+  --cache-eviction <lru|lfu>       Which cached score to drop first (default: lru)
+  --cache-ttl-secs <SECS>          Expire cached scores after SECS (0 never)
+  --cache-model-ttl-secs <MODEL=SECS,...>
+                                   Per-model TTL overrides
+  --cache-path <DIR>               Persist cached scores in DIR (disk-cache feature)
+  --cache-disk-max-bytes <N>       Approximate size cap for the disk cache (0 disables)
+  --statsd-address <HOST:PORT>     Also push metrics to a StatsD/DogStatsD agent
+  --statsd-prefix <PREFIX>         Prefix for StatsD metric names (default: rerank_proxy)
+  --statsd-tags <TAG=VALUE,...>    DogStatsD tags added to every metric
+  --statsd-interval-secs <SECS>    How often metrics are pushed (default: 10)
+  --pushgateway-url <URL>          Push metrics of rerank, bench and soak runs to a Pushgateway
+  --pushgateway-job <NAME>         Job label for pushed metrics (default: rerank_proxy)
+  --audit-log-path <FILE>          Append a JSON audit record per request (query hashed)
+  --preprocess-webhook-url <URL>   Webhook that may rewrite requests before forwarding
+  --preprocess-webhook-timeout-ms <MS>
+                                   Webhook timeout (default: 1000)
+  --preprocess-webhook-failure <P> On webhook failure: open (forward) or closed (reject)
+  --scripts <FILE,...>             Rhai scripts run around each request
+  --script-max-operations <N>      Operation budget per script hook call (0 = none)
+  --plugins <FILE,...>             WebAssembly plugins run around each request
+  --plugin-fuel <N>                Instruction budget per plugin hook call
+  --dns-refresh-secs <SECS>        Re-resolve the TEI hostname this often (0 disables)
+  --discovery <SPEC>               Discover backends: kubernetes://ns/service:port,
+                                   srv://_tei._tcp.example.com or consul://host:8500/service
+  --discovery-refresh-secs <SECS>  Polling interval for srv and consul discovery
+  --load-balancing <STRATEGY>      round-robin, least-in-flight, weighted, latency
+                                   or consistent-hash
+  --backend-weights <URL=N,...>    Relative backend weights for the weighted strategy
+  --upstream-proxy <URL>           HTTP(S) proxy for TEI requests (default: HTTP(S)_PROXY)
+  --upstream-no-proxy <HOST,...>   Hosts that bypass --upstream-proxy
+  --backend-proxies <URL=PROXY,...>
+                                   Per-backend proxies, e.g. socks5h://127.0.0.1:1080
+  --upstream-pool-max-idle-per-host <N>
+                                   Idle TEI connections kept per host (default: unlimited)
+  --upstream-pool-idle-timeout-secs <SECS>
+                                   Close idle TEI connections after SECS (0 never; default: 90)
+  --upstream-connect-timeout-ms <MS>
+                                   Timeout for connecting to TEI (0 disables; default: 2000)
+  --upstream-read-timeout-secs <SECS>
+                                   Fail TEI calls silent for SECS (0 disables)
+  --upstream-tcp-nodelay <BOOL>    Disable Nagle's algorithm on TEI sockets (default: true)
+  --upstream-tcp-keepalive-secs <SECS>
+                                   TCP keep-alive interval for TEI sockets (0 disables)
+  --upstream-http2-prior-knowledge <BOOL>
+                                   Speak HTTP/2 to TEI without negotiating it first
+  --upstream-http2-keepalive-secs <SECS>
+                                   HTTP/2 PING interval to TEI (0 disables)
+  --upstream-http2-adaptive-window <BOOL>
+                                   Size HTTP/2 windows from measured bandwidth
+  --upstream-ca-file <PATH>        Extra root certificates (PEM) to trust for TEI
+  --upstream-tls-server-name <NAME>
+                                   Name to expect on TEI_ENDPOINT's certificate
+  --upstream-tls-skip-verify <BOOL>
+                                   Accept any TEI certificate (testing only)
+
+Check options:
+  --ping                           Also verify that the TEI backends are reachable
+
+Healthcheck options:
+  --url <URL>                      Health URL to probe (default: the first listener's /health)
+
+Rerank options:
+  --query <TEXT>                   Query to rank the documents against (required)
+  --file <FILE>                    Read documents from FILE, one per line ('-' for stdin)
+  --top-n <N>                      Only print the N best results
+  --model <NAME>                   Model name to pass along with the request
+  --format <table|json>            Output format (default: table)
+  [DOCUMENT]...                    Documents given as arguments; read from stdin if none
+
+Bench options:
+  --corpus <FILE>                  JSONL file of rerank requests to replay (default: synthetic)
+  --batch-size <N>                 Documents per synthetic request (default: 32)
+  --rps <N>                        Target requests per second (default: unthrottled)
+  --concurrency <N>                Maximum requests in flight (default: 8)
+  --requests <N>                   Stop after N requests (default: 100)
+  --duration <SECS>                Stop after SECS seconds instead
+
+Replay options:
+  --log <FILE>                     JSONL log of requests to replay (required)
+  --target <URL>                   Proxy or backend to send them to (default: the first
+                                   listener)
+  --speed <X>                      Replay X times faster than recorded; 0 for as fast as
+                                   possible (default: 1)
+  --concurrency <N>                Maximum requests in flight (default: 64)
+  --api-key <KEY>                  API key to send as a bearer token
+
+Soak options:
+  --corpus <FILE>                  JSONL file of rerank requests to send (default: synthetic,
+                                   100 distinct queries)
+  --batch-size <N>                 Documents per synthetic request (default: 32)
+  --rps <N>                        Target requests per second (default: unthrottled)
+  --concurrency <N>                Maximum requests in flight (default: 8)
+  --duration <SECS>                How long to run (default: 3600)
+  --interval <SECS>                Sample memory and file descriptors this often (default: 10)
+  --warmup <SECS>                  Growth before this doesn't count as drift (default: 60)
+  --max-memory-growth-bytes <N>    Fail if memory grows more after warmup (default: 67108864)
+  --max-fd-growth <N>              Fail if open fds grow more after warmup (default: 16)
+";
+
+/// Parsed command line: the subcommand, config layering options, and any
+/// command-specific switches.
+#[derive(Debug, Default)]
+struct Args {
+    command: String,
+    source: ConfigSource,
+    switches: Vec<String>,
+    options: Vec<(String, String)>,
+    positionals: Vec<String>,
+}
+
+impl Args {
+    fn has(&self, switch: &str) -> bool {
+        self.switches.iter().any(|s| s == switch)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn parsed<T: FromStr>(&self, name: &str) -> Result<Option<T>, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        self.option(name)
+            .map(|v| {
+                v.parse()
+                    .map_err(|e| format!("--{}: invalid value '{}' ({})", name, v, e))
+            })
+            .transpose()
+    }
+}
+
+/// Command-specific flags that take no value.
+const SWITCHES: &[&str] = &["ping", "help"];
+
+/// Command-specific flags that take a value.
+const OPTIONS: &[&str] = &[
+    "url",
+    "query",
+    "file",
+    "top-n",
+    "model",
+    "format",
+    "corpus",
+    "batch-size",
+    "rps",
+    "concurrency",
+    "requests",
+    "duration",
+    "log",
+    "target",
+    "speed",
+    "api-key",
+    "interval",
+    "warmup",
+    "max-memory-growth-bytes",
+    "max-fd-growth",
+];
+
+fn parse_args(raw: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut raw = raw.into_iter().peekable();
+    let mut args = Args {
+        command: "serve".to_string(),
+        ..Args::default()
+    };
+
+    if let Some(first) = raw.peek() {
+        if !first.starts_with('-') {
+            args.command = raw.next().unwrap_or_default();
+        }
+    }
+
+    while let Some(arg) = raw.next() {
+        let Some(name) = arg.strip_prefix("--") else {
+            args.positionals.push(arg);
+            continue;
+        };
+
+        let (name, inline_value) = match name.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (name.to_string(), None),
+        };
+
+        if SWITCHES.contains(&name.as_str()) {
+            if inline_value.is_some() {
+                return Err(format!("--{} does not take a value", name));
+            }
+            args.switches.push(name);
+            continue;
+        }
+
+        let value = match inline_value.or_else(|| raw.next()) {
+            Some(value) => value,
+            None => return Err(format!("--{} requires a value", name)),
+        };
+
+        if name == "config" {
+            args.source.file = Some(PathBuf::from(value));
+        } else if OPTIONS.contains(&name.as_str()) {
+            args.options.push((name, value));
+        } else if let Some(setting) = config::find_setting(&name) {
+            args.source.overrides.push((setting, value));
+        } else {
+            return Err(format!("unknown option '--{}'", name));
+        }
+    }
+
+    Ok(args)
+}
+
+/// Entry point for the binary: dispatch to the requested subcommand.
+pub async fn run() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    if args.has("help") {
+        print!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    init_logging(&args);
+
+    if args.command != "rerank" && args.command != "service" {
+        if let Some(arg) = args.positionals.first() {
+            eprintln!("error: unexpected argument '{}'\n\n{}", arg, USAGE);
+            return ExitCode::from(2);
+        }
+    }
+
+    match args.command.as_str() {
+        "serve" => match load(&args.source).and_then(build_state) {
+            Some(state) => crate::serve(state).await,
+            None => ExitCode::FAILURE,
+        },
+        "check" => check(&args).await,
+        "print-config" => match load(&args.source) {
+            Some(config) => {
+                let json =
+                    serde_json::to_string_pretty(&config.to_masked_json()).unwrap_or_default();
+                println!("{}", json);
+                ExitCode::SUCCESS
+            }
+            None => ExitCode::FAILURE,
+        },
+        "healthcheck" => healthcheck(&args).await,
+        "rerank" => rerank_command(&args).await,
+        "bench" => bench_command(&args).await,
+        "replay" => replay_command(&args).await,
+        "soak" => soak_command(&args).await,
+        "service" => service_command(&args).await,
+        "help" => {
+            print!("{}", USAGE);
+            ExitCode::SUCCESS
+        }
+        other => {
+            eprintln!("error: unknown command '{}'\n\n{}", other, USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(all(windows, feature = "windows-service"))]
+fn init_logging(args: &Args) {
+    if args.command == "service" && args.positionals.first().map(String::as_str) == Some("run") {
+        service::init_logging();
+    } else {
+        env_logger::init();
+    }
+}
+
+#[cfg(not(all(windows, feature = "windows-service")))]
+fn init_logging(_args: &Args) {
+    env_logger::init();
+}
+
+fn load(source: &ConfigSource) -> Option<Config> {
+    match Config::load(source) {
+        Ok(config) => Some(config),
+        Err(errors) => {
+            for e in &errors {
+                error!("Invalid configuration: {}", e);
+            }
+            None
+        }
+    }
+}
+
+fn build_state(config: Config) -> Option<AppState> {
+    match AppState::new(config) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            error!("Failed to initialize: {}", e);
+            None
+        }
+    }
+}
+
+async fn check(args: &Args) -> ExitCode {
+    let config = match Config::load(&args.source) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("Configuration is invalid ({} error(s)):", errors.len());
+            for e in &errors {
+                eprintln!("  - {}", e);
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("Configuration is valid");
+
+    if args.has("ping") {
+        let urls = match &config.discovery {
+            Some(discovery) => match discovery::discover_once(discovery).await {
+                Ok(urls) if urls.is_empty() => {
+                    eprintln!("Discovery {} found no ready backends", discovery);
+                    return ExitCode::FAILURE;
+                }
+                Ok(urls) => urls,
+                Err(e) => {
+                    eprintln!("Discovery {} failed: {:#}", discovery, e);
+                    return ExitCode::FAILURE;
+                }
+            },
+            None => vec![config.endpoint_url()],
+        };
+
+        let client = match state::build_client(&config, None) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Failed to build the upstream client: {:#}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut healthy = true;
+        for url in urls {
+            let health = format!("{}/health", url);
+            match ping(&client, &health, config.tei_api_key.as_deref()).await {
+                Ok(()) => println!("TEI endpoint {} is reachable", url),
+                Err(e) => {
+                    eprintln!("TEI endpoint {} is not healthy: {}", url, e);
+                    healthy = false;
+                }
+            }
+        }
+        if !healthy {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+async fn healthcheck(args: &Args) -> ExitCode {
+    let url = match args.option("url") {
+        Some(url) => url.to_string(),
+        None => match load(&args.source) {
+            // Every listener serves /health
+            Some(config) => format!("{}/health", local_url(&config)),
+            None => return ExitCode::FAILURE,
+        },
+    };
+
+    match ping(&reqwest::Client::new(), &url, None).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("unhealthy: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The proxy's first listener, over loopback when it is bound to all
+/// interfaces.
+fn local_url(config: &Config) -> String {
+    let mut address = config.listeners()[0].address;
+    if address.ip().is_unspecified() {
+        address.set_ip(match address.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    format!("http://{}", address)
+}
+
+async fn ping(client: &reqwest::Client, url: &str, token: Option<&str>) -> anyhow::Result<()> {
+    let mut request = client.get(url).timeout(Duration::from_secs(5));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        anyhow::bail!("GET {} returned {}", url, response.status());
+    }
+    Ok(())
+}
+
+async fn rerank_command(args: &Args) -> ExitCode {
+    let Some(query) = args.option("query") else {
+        eprintln!("error: rerank requires --query\n\n{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    let format = args.option("format").unwrap_or("table");
+    if format != "table" && format != "json" {
+        eprintln!("error: --format must be 'table' or 'json'");
+        return ExitCode::from(2);
+    }
+
+    let top_n = match args.parsed("top-n") {
+        Ok(top_n) => top_n,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let documents = match read_documents(args) {
+        Ok(documents) => documents,
+        Err(e) => {
+            eprintln!("error: failed to read documents: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let Some(state) = load(&args.source).and_then(build_state) else {
+        return ExitCode::FAILURE;
+    };
+
+    let req = OpenWebUIRequest {
+        query: query.to_string(),
+        documents: documents.into_iter().map(Document::from).collect(),
+        model: args.option("model").map(str::to_string),
+        top_n,
+        include_meta: false,
+        return_documents: false,
+        extra: Default::default(),
+        strip_markup: None,
+        order: SortOrder::default(),
+        tie_break: Vec::new(),
+        offset: 0,
+        limit: None,
+    };
+
+    let ctx = RequestContext::default();
+    let req = match rerank::prepare_request(&state, &ctx, req).await {
+        Ok(req) => req,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut response = match rerank::respond(&state, &req, &ctx).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    metrics::push(&state, "rerank", None).await;
+
+    if let Some(top_n) = top_n {
+        response.results.truncate(top_n);
+    }
+
+    if format == "json" {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response).unwrap_or_default()
+        );
+    } else {
+        print_table(&response, &req.documents);
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Documents come from positional arguments, `--file` (one per line), or
+/// stdin when neither is given.
+fn read_documents(args: &Args) -> std::io::Result<Vec<String>> {
+    if !args.positionals.is_empty() {
+        return Ok(args.positionals.clone());
+    }
+
+    let mut text = String::new();
+    match args.option("file") {
+        Some("-") | None => {
+            std::io::stdin().lock().read_to_string(&mut text)?;
+        }
+        Some(path) => {
+            text = std::fs::read_to_string(path)?;
+        }
+    }
+
+    Ok(text
+        .lines()
+        .map(str::to_string)
+        .filter(|line| !line.trim().is_empty())
+        .collect())
+}
+
+fn print_table(response: &OpenWebUIResponse, documents: &[Document]) {
+    const PREVIEW: usize = 60;
+
+    println!("{:>4}  {:>5}  {:>10}  DOCUMENT", "RANK", "INDEX", "SCORE");
+    for (rank, result) in response.results.iter().enumerate() {
+        let document = documents
+            .get(result.index)
+            .map(Document::text)
+            .unwrap_or("");
+        let mut preview: String = document.chars().take(PREVIEW).collect();
+        if document.chars().count() > PREVIEW {
+            preview.push('…');
+        }
+        println!(
+            "{:>4}  {:>5}  {:>10.6}  {}",
+            rank + 1,
+            result.index,
+            result.relevance_score,
+            preview.replace('\n', " ")
+        );
+    }
+}
+
+async fn bench_command(args: &Args) -> ExitCode {
+    let options = match bench_options(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let Some(state) = load(&args.source).and_then(build_state) else {
+        return ExitCode::FAILURE;
+    };
+
+    let target = match &state.config.discovery {
+        Some(discovery) => discovery.to_string(),
+        None => state.config.tei_endpoint.clone(),
+    };
+    println!(
+        "Benchmarking {} with {} request template(s), concurrency {}, rate {}",
+        target,
+        options.corpus.len(),
+        options.concurrency,
+        options
+            .rps
+            .map(|rps| format!("{} req/s", rps))
+            .unwrap_or_else(|| "unthrottled".to_string())
+    );
+
+    let state = Arc::new(state);
+    let report = bench::run(state.clone(), options).await;
+    report.print();
+    metrics::push(&state, "bench", Some(&report)).await;
+
+    if report.errors == report.requests {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn bench_options(args: &Args) -> Result<BenchOptions, String> {
+    let corpus = match args.option("corpus") {
+        Some(path) => read_corpus(path)?,
+        None => vec![bench::synthetic_request(
+            args.parsed("batch-size")?.unwrap_or(32),
+        )],
+    };
+
+    let rps: Option<f64> = args.parsed("rps")?;
+    if rps.is_some_and(|rps| rps <= 0.0 || !rps.is_finite()) {
+        return Err("--rps must be a positive number".to_string());
+    }
+
+    let duration = args
+        .parsed::<f64>("duration")?
+        .map(|secs| Duration::try_from_secs_f64(secs).map_err(|e| format!("--duration: {}", e)))
+        .transpose()?;
+    let requests = match (args.parsed("requests")?, duration) {
+        (None, None) => Some(100),
+        (requests, _) => requests,
+    };
+
+    Ok(BenchOptions {
+        corpus,
+        rps,
+        concurrency: args.parsed("concurrency")?.unwrap_or(8),
+        requests,
+        duration,
+    })
+}
+
+async fn replay_command(args: &Args) -> ExitCode {
+    let options = match replay_options(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    println!(
+        "Replaying {} request(s) against {} at {}, concurrency {}",
+        options.entries.len(),
+        options.target,
+        match options.speed {
+            0.0 => "full speed".to_string(),
+            speed => format!("{}x speed", speed),
+        },
+        options.concurrency
+    );
+
+    let (report, lag) = replay::run(options).await;
+    report.print();
+    println!("Max lag:     {:.2?} behind schedule", lag);
+
+    if report.errors == report.requests {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn replay_options(args: &Args) -> Result<ReplayOptions, String> {
+    let path = args
+        .option("log")
+        .ok_or_else(|| "replay requires --log".to_string())?;
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let entries = replay::parse_log(&text).map_err(|e| format!("{}: {}", path, e))?;
+    if entries.is_empty() {
+        return Err(format!("{}: log is empty", path));
+    }
+
+    let speed: f64 = args.parsed("speed")?.unwrap_or(1.0);
+    if !(speed.is_finite() && speed >= 0.0) {
+        return Err("--speed must be a non-negative number".to_string());
+    }
+    let target = match args.option("target") {
+        Some(target) => target.to_string(),
+        None => local_url(&load(&args.source).ok_or("invalid configuration")?),
+    };
+
+    Ok(ReplayOptions {
+        target,
+        entries,
+        speed,
+        concurrency: args.parsed("concurrency")?.unwrap_or(64),
+        api_key: args.option("api-key").map(str::to_string),
+    })
+}
+
+async fn soak_command(args: &Args) -> ExitCode {
+    let options = match soak_options(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let Some(state) = load(&args.source).and_then(build_state) else {
+        return ExitCode::FAILURE;
+    };
+
+    println!(
+        "Soaking {} for {:.0}s with {} request template(s), concurrency {}, rate {}",
+        match &state.config.discovery {
+            Some(discovery) => discovery.to_string(),
+            None => state.config.tei_endpoint.clone(),
+        },
+        options.duration.as_secs_f64(),
+        options.corpus.len(),
+        options.concurrency,
+        options
+            .rps
+            .map(|rps| format!("{} req/s", rps))
+            .unwrap_or_else(|| "unthrottled".to_string())
+    );
+
+    let state = Arc::new(state);
+    let report = soak::run(state.clone(), options).await;
+    report.print();
+    metrics::push(&state, "soak", None).await;
+
+    if report.leaked() {
+        println!("FAILED: grew past the limits, which suggests a leak");
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn soak_options(args: &Args) -> Result<SoakOptions, String> {
+    let corpus = match args.option("corpus") {
+        Some(path) => read_corpus(path)?,
+        // Distinct queries, so the cache both hits and fills up
+        None => {
+            let batch_size = args.parsed("batch-size")?.unwrap_or(32);
+            (0..100)
+                .map(|i| {
+                    let mut request = bench::synthetic_request(batch_size);
+                    request.query = format!("{} ({})", request.query, i);
+                    request
+                })
+                .collect()
+        }
+    };
+
+    let rps: Option<f64> = args.parsed("rps")?;
+    if rps.is_some_and(|rps| rps <= 0.0 || !rps.is_finite()) {
+        return Err("--rps must be a positive number".to_string());
+    }
+    let secs = |name: &str, default: f64| -> Result<Duration, String> {
+        let secs = args.parsed::<f64>(name)?.unwrap_or(default);
+        Duration::try_from_secs_f64(secs).map_err(|e| format!("--{}: {}", name, e))
+    };
+    let interval = secs("interval", 10.0)?;
+    if interval.is_zero() {
+        return Err("--interval must be positive".to_string());
+    }
+
+    Ok(SoakOptions {
+        corpus,
+        rps,
+        concurrency: args.parsed("concurrency")?.unwrap_or(8),
+        duration: secs("duration", 3600.0)?,
+        interval,
+        warmup: secs("warmup", 60.0)?,
+        max_memory_growth: args
+            .parsed("max-memory-growth-bytes")?
+            .unwrap_or(64 * 1024 * 1024),
+        max_fd_growth: args.parsed("max-fd-growth")?.unwrap_or(16),
+    })
+}
+
+fn read_corpus(path: &str) -> Result<Vec<OpenWebUIRequest>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let corpus = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            serde_json::from_str(line).map_err(|e| format!("{}:{}: {}", path, n + 1, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if corpus.is_empty() {
+        return Err(format!("{}: corpus is empty", path));
+    }
+    Ok(corpus)
+}
+
+#[cfg(all(windows, feature = "windows-service"))]
+async fn service_command(args: &Args) -> ExitCode {
+    let result = match args.positionals.first().map(String::as_str) {
+        Some("install") => service::install(args.source.file.as_deref()).map(|()| {
+            println!("Installed the {} service", service::SERVICE_NAME);
+        }),
+        Some("uninstall") => service::uninstall().map(|()| {
+            println!("Removed the {} service", service::SERVICE_NAME);
+        }),
+        Some("run") => {
+            let Some(state) = load(&args.source).and_then(build_state) else {
+                return ExitCode::FAILURE;
+            };
+            match tokio::task::spawn_blocking(move || service::dispatch(state)).await {
+                Ok(result) => result.map_err(anyhow::Error::from),
+                Err(e) => Err(anyhow::Error::from(e)),
+            }
+        }
+        _ => {
+            eprintln!(
+                "error: service requires install, uninstall or run\n\n{}",
+                USAGE
+            );
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Windows service command failed: {:#}", e);
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(not(all(windows, feature = "windows-service")))]
+async fn service_command(_args: &Args) -> ExitCode {
+    eprintln!(
+        "error: Windows service support requires a Windows build with --features windows-service"
+    );
+    ExitCode::FAILURE
+}
@@ -0,0 +1,106 @@
+use crate::error::ApiError;
+use crate::rerank::{OpenWebUIRequest, OpenWebUIResponse, SortOrder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A request to Mixedbread's reranking API.
+#[derive(Deserialize, Debug)]
+pub struct RerankRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub query: String,
+    /// Strings, or objects with the text to rank in `rank_fields`.
+    pub input: Vec<Value>,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub return_input: bool,
+    /// The fields holding each object's text, ranked together; `["text"]`
+    /// when not given.
+    #[serde(default)]
+    pub rank_fields: Option<Vec<String>>,
+}
+
+/// What the response needs from the request: the model and `top_k` asked
+/// for, and the input as sent when it's to be returned.
+#[derive(Debug)]
+pub struct Context {
+    model: Option<String>,
+    top_k: Option<usize>,
+    input: Option<Vec<Value>>,
+}
+
+#[derive(Serialize)]
+struct RankedInput<'a> {
+    index: usize,
+    score: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<&'a Value>,
+}
+
+/// Translate a Mixedbread request into the proxy's own.
+pub fn convert(request: RerankRequest) -> Result<(Context, OpenWebUIRequest), ApiError> {
+    let fields: Vec<&str> = match &request.rank_fields {
+        Some(fields) if !fields.is_empty() => fields.iter().map(String::as_str).collect(),
+        _ => vec!["text"],
+    };
+    let documents = request
+        .input
+        .iter()
+        .enumerate()
+        .map(|(index, input)| super::document(index, input, &fields))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let context = Context {
+        model: request.model.clone(),
+        top_k: request.top_k,
+        input: request.return_input.then_some(request.input),
+    };
+    let req = OpenWebUIRequest {
+        query: request.query,
+        documents,
+        model: request.model,
+        top_n: request.top_k,
+        include_meta: false,
+        return_documents: false,
+        extra: Default::default(),
+        strip_markup: None,
+        order: SortOrder::default(),
+        tie_break: Vec::new(),
+        offset: 0,
+        limit: None,
+    };
+    Ok((context, req))
+}
+
+impl Context {
+    /// The response in Mixedbread's shape. TEI doesn't say how many tokens
+    /// it scored, so usage is reported as zero.
+    pub fn render(&self, response: &OpenWebUIResponse) -> Value {
+        let data: Vec<RankedInput> = response
+            .results
+            .iter()
+            .take(self.top_k.unwrap_or(usize::MAX))
+            .map(|result| RankedInput {
+                index: result.index,
+                score: result.relevance_score,
+                input: self
+                    .input
+                    .as_ref()
+                    .and_then(|input| input.get(result.index)),
+            })
+            .collect();
+        let model = self
+            .model
+            .clone()
+            .or_else(|| response.meta.as_ref().and_then(|meta| meta.model.clone()));
+        serde_json::json!({
+            "object": "list",
+            "model": model,
+            "data": data,
+            "top_k": self.top_k.unwrap_or(data.len()),
+            "return_input": self.input.is_some(),
+            "usage": { "prompt_tokens": 0, "total_tokens": 0 },
+        })
+    }
+}
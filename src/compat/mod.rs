@@ -0,0 +1,76 @@
+pub mod mixedbread;
+pub mod pinecone;
+
+use crate::error::ApiError;
+use crate::rerank::{Document, Envelope, OpenWebUIResponse, ScoreField};
+use serde_json::Value;
+
+/// How a rerank response goes back to the client: in the proxy's own
+/// shape, or in that of the API the client was written against.
+#[derive(Debug)]
+pub enum Format {
+    Native {
+        score_field: ScoreField,
+        envelope: Envelope,
+    },
+    /// `/retrieve_rerank`: the proxy's own shape, cut off after `top_n`
+    /// results.
+    Retrieved { top_n: Option<usize> },
+    /// Pinecone's hosted rerank API, on `/pinecone/rerank`.
+    Pinecone(pinecone::Context),
+    /// Mixedbread's reranking API, on `/mixedbread/v1/reranking`.
+    Mixedbread(mixedbread::Context),
+}
+
+impl Format {
+    pub fn render(&self, response: &OpenWebUIResponse) -> Value {
+        match self {
+            Format::Native {
+                score_field,
+                envelope,
+            } => response.to_json(*score_field, *envelope),
+            Format::Retrieved { top_n } => {
+                let mut json = response.to_json(ScoreField::RelevanceScore, Envelope::Object);
+                if let (Some(top_n), Some(Value::Array(results))) = (top_n, json.get_mut("results"))
+                {
+                    results.truncate(*top_n);
+                }
+                json
+            }
+            Format::Pinecone(context) => context.render(response),
+            Format::Mixedbread(context) => context.render(response),
+        }
+    }
+}
+
+/// The document to rank from one sent as a string, or as an object with
+/// its text in `fields` (joined by newlines when there are several). An
+/// object's `id` is kept, so it shows up in logs and audit records.
+fn document(index: usize, document: &Value, fields: &[&str]) -> Result<Document, ApiError> {
+    match document {
+        Value::String(text) => Ok(Document::from(text.clone())),
+        Value::Object(object) => {
+            let text = fields
+                .iter()
+                .map(|field| match object.get(*field) {
+                    Some(Value::String(text)) => Ok(text.as_str()),
+                    _ => Err(ApiError::BadRequest(format!(
+                        "Document {} has no '{}' field of type string",
+                        index, field
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join("\n");
+            Ok(Document::Object {
+                text,
+                retrieval_score: None,
+                id: object.get("id").cloned(),
+                metadata: None,
+            })
+        }
+        _ => Err(ApiError::BadRequest(format!(
+            "Document {} is neither a string nor an object",
+            index
+        ))),
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::ApiError;
+use crate::rerank::{OpenWebUIRequest, OpenWebUIResponse, SortOrder};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A request to Pinecone's hosted rerank API.
+#[derive(Deserialize, Debug)]
+pub struct RerankRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub query: String,
+    /// Strings, or objects with the text to rank in one of `rank_fields`.
+    pub documents: Vec<Value>,
+    #[serde(default)]
+    pub top_n: Option<usize>,
+    #[serde(default)]
+    pub return_documents: Option<bool>,
+    /// The field holding each document's text; `["text"]` when not given.
+    #[serde(default)]
+    pub rank_fields: Option<Vec<String>>,
+    /// Model parameters, e.g. `{"truncate": "END"}`.
+    #[serde(default)]
+    pub parameters: Map<String, Value>,
+}
+
+/// What the response needs from the request: the model and `top_n` asked
+/// for, and the documents as sent when they're to be returned.
+#[derive(Debug)]
+pub struct Context {
+    model: Option<String>,
+    top_n: Option<usize>,
+    documents: Option<Vec<Value>>,
+}
+
+#[derive(Serialize)]
+struct RankedDocument<'a> {
+    index: usize,
+    score: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<&'a Value>,
+}
+
+/// Translate a Pinecone request into the proxy's own.
+pub fn convert(request: RerankRequest) -> Result<(Context, OpenWebUIRequest), ApiError> {
+    let field = match request.rank_fields.as_deref() {
+        None | Some([]) => "text",
+        Some([field]) => field.as_str(),
+        Some(_) => {
+            return Err(ApiError::BadRequest(
+                "Only one rank field is supported".to_string(),
+            ))
+        }
+    };
+
+    let documents = request
+        .documents
+        .iter()
+        .enumerate()
+        .map(|(index, document)| super::document(index, document, &[field]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Pinecone spells TEI's `truncate` flag as a direction
+    let extra = request
+        .parameters
+        .into_iter()
+        .map(|(name, value)| match (name.as_str(), value.as_str()) {
+            ("truncate", Some("END")) => (name, Value::Bool(true)),
+            ("truncate", Some("NONE")) => (name, Value::Bool(false)),
+            _ => (name, value),
+        })
+        .collect();
+
+    let context = Context {
+        model: request.model.clone(),
+        top_n: request.top_n,
+        // Returned as Pinecone does, with plain text as `{"text": ...}`
+        documents: request.return_documents.unwrap_or(true).then(|| {
+            request
+                .documents
+                .into_iter()
+                .map(|document| match document {
+                    Value::String(text) => serde_json::json!({ "text": text }),
+                    document => document,
+                })
+                .collect()
+        }),
+    };
+    let req = OpenWebUIRequest {
+        query: request.query,
+        documents,
+        model: request.model,
+        top_n: request.top_n,
+        include_meta: false,
+        return_documents: false,
+        extra,
+        strip_markup: None,
+        order: SortOrder::default(),
+        tie_break: Vec::new(),
+        offset: 0,
+        limit: None,
+    };
+    Ok((context, req))
+}
+
+impl Context {
+    /// The response in Pinecone's shape. Each reranked request counts as
+    /// one rerank unit, as Pinecone bills them.
+    pub fn render(&self, response: &OpenWebUIResponse) -> Value {
+        let data: Vec<RankedDocument> = response
+            .results
+            .iter()
+            .take(self.top_n.unwrap_or(usize::MAX))
+            .map(|result| RankedDocument {
+                index: result.index,
+                score: result.relevance_score,
+                document: self
+                    .documents
+                    .as_ref()
+                    .and_then(|documents| documents.get(result.index)),
+            })
+            .collect();
+        let model = self
+            .model
+            .clone()
+            .or_else(|| response.meta.as_ref().and_then(|meta| meta.model.clone()));
+        serde_json::json!({
+            "model": model,
+            "data": data,
+            "usage": { "rerank_units": 1 },
+        })
+    }
+}
@@ -0,0 +1,2172 @@
+use crate::adapter::Adapter;
+use crate::backends::{Breaker, Strategy};
+use crate::cache::EvictionPolicy;
+use crate::calibration::Calibration;
+use crate::discovery::Discovery;
+use crate::fault::{Delay, FaultSettings};
+use crate::language;
+use crate::preprocess::Normalization;
+use crate::queue::Priority;
+use crate::rerank::{Envelope, ScoreField};
+use crate::retrieve::Distance;
+use crate::rewrite::Replacement;
+use crate::truncation::Truncation;
+use crate::webhook::FailurePolicy;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Effective proxy configuration.
+///
+/// Values are layered in order: built-in defaults, then the optional JSON
+/// config file, then environment variables, then command-line flags.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub tei_endpoint: String,
+    pub port: u16,
+    /// Interface the default listener binds to, e.g. `::` or `127.0.0.1`.
+    pub bind_address: IpAddr,
+    /// Listeners to serve on instead of `bind_address:port`.
+    pub listeners: Vec<Listener>,
+    pub max_client_batch_size: usize,
+    /// Longest accepted document, in characters; unset for no limit.
+    pub max_document_length: Option<usize>,
+    /// Most tokens a request may add up to over all its (query, document)
+    /// pairs, as estimated from their length; unset for no limit.
+    pub max_request_tokens: Option<usize>,
+    /// How long a TEI call may take in total before it fails.
+    pub tei_timeout_secs: u64,
+    /// Retries of a TEI call answered with `429`, or `503` with
+    /// `Retry-After`, or whose connection was refused or reset.
+    pub tei_max_retries: u32,
+    /// Retries may add at most this share of extra calls to TEI, e.g. 0.2
+    /// for 20%, beyond a small reserve.
+    pub tei_retry_budget: f64,
+    /// Longest `Retry-After` the proxy waits out itself; longer waits are
+    /// passed on to the client as a `429`.
+    pub tei_max_retry_wait_secs: u64,
+    /// Consecutive failed calls after which a backend is taken out of
+    /// rotation; 0 never takes one out.
+    pub breaker_failures: u32,
+    /// How long a failing backend stays out of rotation before it gets
+    /// another try.
+    pub breaker_cooldown_secs: u64,
+    /// When TEI fails to rerank, score by the similarity of embeddings from
+    /// the backend's `/embed` instead, flagging the response as degraded.
+    pub embed_fallback: bool,
+    /// When no backend can be reached, score with BM25 in the proxy
+    /// instead, flagging the response as degraded.
+    pub bm25_fallback: bool,
+    /// How often backends' own batch size and input length limits are read
+    /// from their `/info`; 0 disables.
+    pub tei_info_refresh_secs: u64,
+    /// Overrides of the limits above for requests to a given `model`, e.g.
+    /// smaller batches for a CPU model.
+    pub model_limits: BTreeMap<String, ModelLimits>,
+    /// Overrides of the TEI timeout, `max_request_bytes` and concurrency
+    /// for a group of routes, e.g. a short timeout for `/tokenize`.
+    pub route_limits: BTreeMap<RouteGroup, RouteLimits>,
+    /// The `model` names requests may ask for; empty allows any.
+    /// Requests without a model are always let through.
+    pub allowed_models: Vec<String>,
+    /// Largest accepted request body in bytes; 0 disables the limit.
+    pub max_request_bytes: u64,
+    /// Request bodies larger than this, or without a `Content-Length`, are
+    /// parsed as they stream in instead of being buffered first.
+    pub stream_body_threshold_bytes: u64,
+    /// Answer `/rerank` requests that aren't sent as JSON with 415 instead
+    /// of trying to parse them.
+    pub strict_content_type: bool,
+    /// Time a client has to send a request's headers before the connection
+    /// is closed; 0 waits forever.
+    pub client_header_timeout_secs: u64,
+    /// Time a client has to send a request's body before it's answered
+    /// with 408; 0 waits forever.
+    pub client_body_timeout_secs: u64,
+    /// Shed new requests with 503 while buffered and in-flight requests
+    /// hold more than this many bytes; 0 disables.
+    pub memory_budget_bytes: u64,
+    /// Start shedding requests while the upstream p99 latency exceeds this
+    /// many milliseconds; 0 disables latency-based shedding.
+    pub shed_latency_p99_ms: u64,
+    /// Trailing window the upstream p99 is computed over.
+    pub shed_latency_window_secs: u64,
+    /// Share of requests rejected while shedding, between 0 and 1.
+    pub shed_latency_fraction: f64,
+    /// For documents sent with a `retrieval_score`, the relevance score is
+    /// `fusion_rerank_weight * score + fusion_retrieval_weight *
+    /// retrieval_score + fusion_bias`.
+    pub fusion_rerank_weight: f64,
+    pub fusion_retrieval_weight: f64,
+    pub fusion_bias: f64,
+    /// Share of the score that comes from BM25 keyword matching of the
+    /// query against the request's documents, the rest being the
+    /// (calibrated) cross-encoder score; 0 turns hybrid scoring off.
+    pub lexical_weight: f64,
+    /// Applied to TEI's scores before fusion, so scores from different
+    /// models can share a threshold.
+    pub score_calibration: Calibration,
+    /// Calibration for particular models, overriding `score_calibration`.
+    pub model_score_calibration: BTreeMap<String, Calibration>,
+    /// JSON Lines file of labelled scores that isotonic calibration curves
+    /// are fitted to, per model.
+    pub calibration_labels_path: Option<PathBuf>,
+    /// Prefixes removed from the start of queries, ignoring ASCII case,
+    /// e.g. `Answer the question:`.
+    pub query_strip_prefixes: Vec<String>,
+    /// Regex replacements applied to queries in order.
+    pub query_replacements: Vec<Replacement>,
+    /// Lowercase queries after the other rewrites.
+    pub query_lowercase: bool,
+    /// Strip HTML tags, Markdown syntax and extra whitespace from documents
+    /// before scoring; requests can override it with `strip_markup`.
+    pub strip_markup: bool,
+    /// Unicode normalization and whitespace cleanup applied to queries and
+    /// documents before scoring.
+    pub normalization: Normalization,
+    /// Normalization for particular models, overriding `normalization`.
+    pub model_normalization: BTreeMap<String, Normalization>,
+    /// Cut documents down to the tokens the model has left after the query,
+    /// instead of leaving it to TEI.
+    pub truncation: Truncation,
+    /// Round returned relevance scores to this many decimal places; unset
+    /// returns them at full precision.
+    pub score_precision: Option<u32>,
+    /// Field results carry their score in, unless a request asks otherwise.
+    pub score_field: ScoreField,
+    /// Whether results are wrapped in an object or sent as a bare array,
+    /// unless a request asks otherwise.
+    pub response_envelope: Envelope,
+    /// Log a warning for requests taking at least this many milliseconds;
+    /// 0 disables slow request logging.
+    pub slow_request_ms: u64,
+    /// Share of requests, between 0 and 1, whose full payloads are logged at
+    /// info level.
+    pub payload_log_sample_rate: f64,
+    /// Also log the payloads of requests sent with an `X-Debug-Payload`
+    /// header.
+    pub payload_log_on_header: bool,
+    /// Requests in flight to TEI at once; further requests wait, highest
+    /// priority first. 0 disables the cap.
+    pub max_concurrent_requests: usize,
+    /// Rerank requests one client IP address may have in flight at once;
+    /// 0 for no cap.
+    pub max_requests_per_ip: usize,
+    /// Bearer token sent to TEI (matches TEI's `--api-key`).
+    pub tei_api_key: Option<String>,
+    /// Authenticate to the upstream with the Google service account's
+    /// access tokens from the metadata server, e.g. for the Vertex AI
+    /// ranking API.
+    pub tei_google_auth: bool,
+    /// Request fields the proxy doesn't know itself but passes on to TEI,
+    /// e.g. `truncate` or `raw_scores`. Others are ignored.
+    pub tei_forward_params: Vec<String>,
+    /// Talk to an upstream with its own rerank API instead of TEI's.
+    pub tei_adapter: Option<Adapter>,
+    /// Backends for requests detected to be in a given language, keyed by
+    /// ISO 639-3 code (e.g. `eng`). Others go to the regular backends.
+    pub language_backends: BTreeMap<String, String>,
+    /// Backend with a fast, cheap model that scores every document first;
+    /// only the `cascade_top_k` it ranks highest go on to the regular
+    /// backends.
+    pub cascade_endpoint: Option<String>,
+    /// Model the cascade's first stage asks for.
+    pub cascade_model: Option<String>,
+    /// Documents the first stage passes on. Requests with no more than
+    /// this many skip it.
+    pub cascade_top_k: usize,
+    /// TEI instance serving an embedding model, for `/retrieve_rerank`
+    /// queries.
+    pub tei_embed_endpoint: Option<String>,
+    /// Qdrant server `/retrieve_rerank` fetches candidates from; the route
+    /// is only served when this, `elasticsearch_url` or `postgres_url` is
+    /// set.
+    pub qdrant_url: Option<String>,
+    pub qdrant_collection: Option<String>,
+    pub qdrant_api_key: Option<String>,
+    /// Named vector to search, for collections with several.
+    pub qdrant_vector: Option<String>,
+    /// Payload field holding each point's text.
+    pub qdrant_text_field: String,
+    /// Elasticsearch or OpenSearch server `/retrieve_rerank` fetches
+    /// candidates from with BM25, instead of Qdrant. Basic auth credentials
+    /// can be given in the URL.
+    pub elasticsearch_url: Option<String>,
+    pub elasticsearch_index: Option<String>,
+    /// Sent as `Authorization: ApiKey <key>`.
+    pub elasticsearch_api_key: Option<String>,
+    /// Fields the query is matched against; the text field when empty.
+    pub elasticsearch_fields: Vec<String>,
+    /// Source field holding each document's text.
+    pub elasticsearch_text_field: String,
+    /// Postgres database `/retrieve_rerank` searches with pgvector, instead
+    /// of Qdrant or Elasticsearch, as a connection string. Needs the
+    /// `pgvector` feature.
+    pub postgres_url: Option<String>,
+    /// Table holding the documents and their embeddings.
+    pub pgvector_table: Option<String>,
+    pub pgvector_id_column: String,
+    pub pgvector_text_column: String,
+    pub pgvector_embedding_column: String,
+    /// JSON column returned as each document's metadata, and matched
+    /// against requests' `filter`.
+    pub pgvector_metadata_column: Option<String>,
+    pub pgvector_distance: Distance,
+    /// Candidates `/retrieve_rerank` fetches, unless a request asks
+    /// otherwise.
+    pub retrieve_top_k: usize,
+    /// Keys clients must present as `Authorization: Bearer <key>`. Empty
+    /// disables authentication.
+    pub api_keys: Vec<ApiKey>,
+    /// Rolling window `/admin/top` ranks API keys' usage over.
+    pub top_window_secs: u64,
+    /// Rerank requests each client (API key, or IP address) may make per
+    /// `rate_limit_window_secs`; 0 disables rate limiting.
+    pub rate_limit: u32,
+    pub rate_limit_window_secs: u64,
+    /// How long responses to requests with an `Idempotency-Key` are kept
+    /// for replay; 0 ignores the header.
+    pub idempotency_ttl_secs: u64,
+    /// Stored idempotent responses kept at most; 0 for no cap.
+    pub idempotency_max_entries: usize,
+    /// Allow injecting faults into upstream calls, as set below or through
+    /// `/admin/faults`. For testing clients only.
+    pub fault_injection: bool,
+    /// Share of upstream calls, between 0 and 1, that fail.
+    pub fault_error_rate: f64,
+    /// Latency added to every upstream call.
+    pub fault_latency_ms: u64,
+    /// Share of upstream calls, between 0 and 1, answered with malformed
+    /// JSON.
+    pub fault_malformed_rate: f64,
+    /// Latency added to rerank responses, keyed by route (e.g. `/rerank`),
+    /// to see how clients cope with slow reranking in staging.
+    pub response_delays: BTreeMap<String, Delay>,
+    /// StatsD/DogStatsD agent (`host:port`) to push metrics to over UDP.
+    pub statsd_address: Option<String>,
+    /// Prefix for metric names pushed to StatsD.
+    pub statsd_prefix: String,
+    /// DogStatsD tags added to every metric; plain StatsD when empty.
+    pub statsd_tags: BTreeMap<String, String>,
+    /// How often metrics are pushed to StatsD.
+    pub statsd_interval_secs: u64,
+    /// Prometheus Pushgateway the `rerank`, `bench` and `soak` commands push
+    /// their metrics to when they finish.
+    pub pushgateway_url: Option<String>,
+    /// `job` label of the metrics pushed to the Pushgateway.
+    pub pushgateway_job: String,
+    /// Append a JSON line per rerank request to this file, with the query
+    /// hashed and no document text.
+    pub audit_log_path: Option<PathBuf>,
+    /// Called with each request before it's forwarded; may return a
+    /// rewritten request.
+    pub preprocess_webhook_url: Option<String>,
+    pub preprocess_webhook_timeout_ms: u64,
+    /// Whether requests go ahead unchanged or are rejected when the
+    /// webhook fails.
+    pub preprocess_webhook_failure: FailurePolicy,
+    /// Rhai scripts run around every rerank request, in order
+    /// (`rhai-scripts` feature).
+    pub scripts: Vec<PathBuf>,
+    /// Operations a script may perform per hook call; 0 for no limit.
+    pub script_max_operations: u64,
+    /// WebAssembly plugins run around every rerank request, in order
+    /// (`wasm-plugins` feature).
+    pub plugins: Vec<PathBuf>,
+    /// Instructions, roughly, a plugin may execute per hook call.
+    pub plugin_fuel: u64,
+    /// How often to re-resolve the TEI hostname; 0 disables re-resolution.
+    pub dns_refresh_secs: u64,
+    /// Scores cached in memory per (model, query, document); 0 for no
+    /// entry cap.
+    pub cache_max_entries: usize,
+    /// Estimated memory cap for cached scores; 0 for no byte cap. The
+    /// in-memory cache is disabled when both caps are 0.
+    pub cache_max_bytes: u64,
+    /// Which cached score is evicted first when the cache is full.
+    pub cache_eviction: EvictionPolicy,
+    /// How long cached scores stay valid; 0 keeps them until evicted.
+    pub cache_ttl_secs: u64,
+    /// `cache_ttl_secs` overrides keyed by the request's model name.
+    pub cache_model_ttl_secs: BTreeMap<String, u64>,
+    /// Directory for a persistent sled score cache (`disk-cache` feature).
+    pub cache_path: Option<PathBuf>,
+    /// Approximate size cap for the disk cache; 0 disables the cap.
+    pub cache_disk_max_bytes: u64,
+    /// Discover the backend pool dynamically instead of using `tei_endpoint`.
+    pub discovery: Option<Discovery>,
+    /// Polling interval for SRV and Consul discovery.
+    pub discovery_refresh_secs: u64,
+    /// How requests are spread across the backend pool.
+    pub load_balancing: Strategy,
+    /// Relative weights for the `weighted` strategy, keyed by backend URL.
+    /// Backends not listed get weight 1.
+    pub backend_weights: BTreeMap<String, u32>,
+    /// HTTP(S) proxy for upstream requests. When unset, `HTTP_PROXY`,
+    /// `HTTPS_PROXY` and `NO_PROXY` are honored.
+    pub upstream_proxy: Option<String>,
+    /// Comma-separated hosts that bypass `upstream_proxy`, in `NO_PROXY`
+    /// syntax.
+    pub upstream_no_proxy: Option<String>,
+    /// Per-backend proxies keyed by backend URL, e.g. a `socks5h://` SSH
+    /// tunnel to a GPU box. Takes precedence over `upstream_proxy`.
+    pub backend_proxies: BTreeMap<String, String>,
+    /// Idle upstream connections kept per host; unset keeps them all.
+    pub upstream_pool_max_idle_per_host: Option<usize>,
+    /// Close idle upstream connections after this long; 0 keeps them open.
+    pub upstream_pool_idle_timeout_secs: u64,
+    /// How long establishing an upstream connection may take; 0 leaves it
+    /// to the OS.
+    pub upstream_connect_timeout_ms: u64,
+    /// Fail a TEI call when no data arrives for this long, however long
+    /// the call has run overall; 0 disables.
+    pub upstream_read_timeout_secs: u64,
+    /// Disable Nagle's algorithm on upstream sockets.
+    pub upstream_tcp_nodelay: bool,
+    /// TCP keep-alive probe interval for upstream sockets; 0 disables.
+    pub upstream_tcp_keepalive_secs: u64,
+    /// Talk HTTP/2 to TEI from the first byte, even over plain `http://`,
+    /// so concurrent calls share one connection per backend.
+    pub upstream_http2_prior_knowledge: bool,
+    /// HTTP/2 PING interval on upstream connections; 0 disables.
+    pub upstream_http2_keepalive_secs: u64,
+    /// Let HTTP/2 flow-control windows grow with the measured bandwidth.
+    pub upstream_http2_adaptive_window: bool,
+    /// PEM file of root certificates to trust for upstreams on top of the
+    /// built-in ones, e.g. an internal CA's.
+    pub upstream_ca_file: Option<PathBuf>,
+    /// Name `tei_endpoint` is asked for with SNI and its certificate is
+    /// checked against, when that isn't the endpoint's own host. It's still
+    /// connected to at the endpoint's address.
+    pub upstream_tls_server_name: Option<String>,
+    /// Accept any certificate from upstreams. Only for testing.
+    pub upstream_tls_skip_verify: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tei_endpoint: "http://localhost:4000".to_string(),
+            port: 8000,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listeners: Vec::new(),
+            max_client_batch_size: 1000,
+            max_document_length: None,
+            max_request_tokens: None,
+            tei_timeout_secs: 30,
+            tei_max_retries: 2,
+            tei_max_retry_wait_secs: 5,
+            tei_retry_budget: 0.2,
+            breaker_failures: 5,
+            breaker_cooldown_secs: 10,
+            embed_fallback: false,
+            bm25_fallback: false,
+            tei_info_refresh_secs: 300,
+            model_limits: BTreeMap::new(),
+            route_limits: BTreeMap::new(),
+            allowed_models: Vec::new(),
+            max_request_bytes: 0,
+            stream_body_threshold_bytes: 1024 * 1024,
+            strict_content_type: true,
+            client_header_timeout_secs: 10,
+            client_body_timeout_secs: 30,
+            memory_budget_bytes: 0,
+            shed_latency_p99_ms: 0,
+            shed_latency_window_secs: 30,
+            shed_latency_fraction: 0.5,
+            fusion_rerank_weight: 1.0,
+            fusion_retrieval_weight: 0.0,
+            fusion_bias: 0.0,
+            lexical_weight: 0.0,
+            score_calibration: Calibration::default(),
+            model_score_calibration: BTreeMap::new(),
+            calibration_labels_path: None,
+            query_strip_prefixes: Vec::new(),
+            query_replacements: Vec::new(),
+            query_lowercase: false,
+            strip_markup: false,
+            normalization: Normalization::default(),
+            model_normalization: BTreeMap::new(),
+            truncation: Truncation::default(),
+            score_precision: None,
+            score_field: ScoreField::default(),
+            response_envelope: Envelope::default(),
+            slow_request_ms: 0,
+            payload_log_sample_rate: 0.0,
+            payload_log_on_header: false,
+            max_concurrent_requests: 0,
+            max_requests_per_ip: 0,
+            tei_api_key: None,
+            tei_google_auth: false,
+            tei_forward_params: Vec::new(),
+            tei_adapter: None,
+            language_backends: BTreeMap::new(),
+            cascade_endpoint: None,
+            cascade_model: None,
+            cascade_top_k: 50,
+            tei_embed_endpoint: None,
+            qdrant_url: None,
+            qdrant_collection: None,
+            qdrant_api_key: None,
+            qdrant_vector: None,
+            qdrant_text_field: "text".to_string(),
+            elasticsearch_url: None,
+            elasticsearch_index: None,
+            elasticsearch_api_key: None,
+            elasticsearch_fields: Vec::new(),
+            elasticsearch_text_field: "text".to_string(),
+            postgres_url: None,
+            pgvector_table: None,
+            pgvector_id_column: "id".to_string(),
+            pgvector_text_column: "text".to_string(),
+            pgvector_embedding_column: "embedding".to_string(),
+            pgvector_metadata_column: None,
+            pgvector_distance: Distance::default(),
+            retrieve_top_k: 50,
+            api_keys: Vec::new(),
+            top_window_secs: 3600,
+            rate_limit: 0,
+            rate_limit_window_secs: 60,
+            idempotency_ttl_secs: 600,
+            idempotency_max_entries: 10_000,
+            fault_injection: false,
+            fault_error_rate: 0.0,
+            fault_latency_ms: 0,
+            fault_malformed_rate: 0.0,
+            response_delays: BTreeMap::new(),
+            statsd_address: None,
+            statsd_prefix: "rerank_proxy".to_string(),
+            statsd_tags: BTreeMap::new(),
+            statsd_interval_secs: 10,
+            pushgateway_url: None,
+            pushgateway_job: "rerank_proxy".to_string(),
+            audit_log_path: None,
+            preprocess_webhook_url: None,
+            preprocess_webhook_timeout_ms: 1000,
+            preprocess_webhook_failure: FailurePolicy::default(),
+            scripts: Vec::new(),
+            script_max_operations: 1_000_000,
+            plugins: Vec::new(),
+            plugin_fuel: 1_000_000_000,
+            dns_refresh_secs: 30,
+            cache_max_entries: 0,
+            cache_max_bytes: 0,
+            cache_eviction: EvictionPolicy::default(),
+            cache_ttl_secs: 0,
+            cache_model_ttl_secs: BTreeMap::new(),
+            cache_path: None,
+            cache_disk_max_bytes: 1024 * 1024 * 1024,
+            discovery: None,
+            discovery_refresh_secs: 30,
+            load_balancing: Strategy::default(),
+            backend_weights: BTreeMap::new(),
+            upstream_proxy: None,
+            upstream_no_proxy: None,
+            backend_proxies: BTreeMap::new(),
+            upstream_pool_max_idle_per_host: None,
+            upstream_pool_idle_timeout_secs: 90,
+            upstream_connect_timeout_ms: 2000,
+            upstream_read_timeout_secs: 0,
+            upstream_tcp_nodelay: true,
+            upstream_tcp_keepalive_secs: 0,
+            upstream_http2_prior_knowledge: false,
+            upstream_http2_keepalive_secs: 0,
+            upstream_http2_adaptive_window: false,
+            upstream_ca_file: None,
+            upstream_tls_server_name: None,
+            upstream_tls_skip_verify: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub key: String,
+    /// Label used in logs instead of the key itself.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Priority of this key's requests, and the highest it may ask for
+    /// with `X-Priority`.
+    #[serde(default)]
+    pub priority: Option<Priority>,
+    /// May use the admin endpoints, such as `/admin/top`.
+    #[serde(default)]
+    pub admin: bool,
+    /// The `model` names this key's requests may ask for; unset for any.
+    #[serde(default)]
+    pub models: Option<Vec<String>>,
+}
+
+impl ApiKey {
+    /// Whether this key may ask for `model`. Requests without one are
+    /// always let through, as with `allowed_models`.
+    pub fn may_use(&self, model: Option<&str>) -> bool {
+        match (&self.models, model) {
+            (Some(models), Some(model)) => models.iter().any(|m| m == model),
+            _ => true,
+        }
+    }
+
+    /// Name to show in logs: the configured label or a short key prefix.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}…", self.key.chars().take(4).collect::<String>()),
+        }
+    }
+}
+
+/// A model's own request limits; unset ones fall back to the global
+/// setting.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ModelLimits {
+    #[serde(default)]
+    pub max_batch_size: Option<usize>,
+    #[serde(default)]
+    pub max_document_length: Option<usize>,
+    #[serde(default)]
+    pub max_request_tokens: Option<usize>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// A route group's own limits; unset ones fall back to the global setting.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimits {
+    /// Overall timeout for the TEI calls the group's requests make; a
+    /// model's own `timeout_secs` still takes precedence.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Largest accepted request body in bytes; 0 disables the limit.
+    #[serde(default)]
+    pub max_request_bytes: Option<u64>,
+    /// Requests on the group's routes in flight at once; further ones are
+    /// refused with 503.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+}
+
+/// The limits that apply to one request.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_batch_size: usize,
+    pub max_document_length: Option<usize>,
+    pub max_request_tokens: Option<usize>,
+    pub timeout: Duration,
+}
+
+/// An address the proxy accepts connections on, and what it serves there.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub routes: Routes,
+}
+
+/// Which endpoints a listener serves. `/health` is served everywhere.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Routes {
+    #[default]
+    All,
+    /// The rerank routes only, e.g. on a public interface.
+    Rerank,
+    /// `/metrics`, `/selftest` and `/admin/*` only, e.g. on localhost.
+    Admin,
+}
+
+impl Routes {
+    pub fn rerank(self) -> bool {
+        self != Routes::Admin
+    }
+
+    pub fn admin(self) -> bool {
+        self != Routes::Rerank
+    }
+}
+
+/// Routes that share limits, as they make similar calls: rerank requests
+/// score whole batches, tokenizer requests are cheap lookups, and admin
+/// requests mostly don't reach TEI at all.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteGroup {
+    /// `/rerank` and the routes that rerank in other shapes; also the
+    /// `rerank`, `bench` and `soak` commands.
+    #[default]
+    Rerank,
+    /// `/tokenize` and `/decode`, forwarded to TEI as they are.
+    Tokenize,
+    /// `/metrics`, `/selftest`, `/admin/*` and `/debug/*`.
+    Admin,
+}
+
+impl RouteGroup {
+    /// The group serving `path`, if any; `/health` is in none.
+    pub fn of(path: &str) -> Option<RouteGroup> {
+        let first = path.trim_start_matches('/').split('/').next()?;
+        match first {
+            "rerank" | "retrieve_rerank" | "pinecone" | "mixedbread" => Some(RouteGroup::Rerank),
+            "tokenize" | "decode" => Some(RouteGroup::Tokenize),
+            "metrics" | "selftest" | "admin" | "debug" => Some(RouteGroup::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RouteGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RouteGroup::Rerank => "rerank",
+            RouteGroup::Tokenize => "tokenize",
+            RouteGroup::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Routes {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "all" => Ok(Routes::All),
+            "rerank" => Ok(Routes::Rerank),
+            "admin" => Ok(Routes::Admin),
+            other => Err(format!(
+                "unknown routes '{}' (expected all, rerank or admin)",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Routes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Routes::All => "all",
+            Routes::Rerank => "rerank",
+            Routes::Admin => "admin",
+        })
+    }
+}
+
+/// A single scalar setting that can be overridden from the environment or
+/// the command line.
+pub struct Setting {
+    pub env: &'static str,
+    pub flag: &'static str,
+    apply: fn(&mut Config, &str) -> Result<(), String>,
+}
+
+impl std::fmt::Debug for Setting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.env)
+    }
+}
+
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        env: "TEI_ENDPOINT",
+        flag: "tei-endpoint",
+        apply: |c, v| set(&mut c.tei_endpoint, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PORT",
+        flag: "port",
+        apply: |c, v| set(&mut c.port, v),
+    },
+    Setting {
+        env: "TEI_PROXY_BIND_ADDRESS",
+        flag: "bind-address",
+        apply: |c, v| set(&mut c.bind_address, v),
+    },
+    Setting {
+        env: "TEI_PROXY_LISTENERS",
+        flag: "listeners",
+        apply: |c, v| {
+            c.listeners = split_list(v)
+                .map(|entry| {
+                    let (address, routes) = match entry.split_once('=') {
+                        Some((address, routes)) => (address.trim(), routes.trim().parse()?),
+                        None => (entry.as_str(), Routes::default()),
+                    };
+                    let address = address
+                        .parse()
+                        .map_err(|e| format!("invalid listen address '{}': {}", address, e))?;
+                    Ok(Listener { address, routes })
+                })
+                .collect::<Result<_, String>>()?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "MAX_CLIENT_BATCH_SIZE",
+        flag: "max-client-batch-size",
+        apply: |c, v| set(&mut c.max_client_batch_size, v),
+    },
+    Setting {
+        env: "TEI_PROXY_MAX_DOCUMENT_LENGTH",
+        flag: "max-document-length",
+        apply: |c, v| set_optional(&mut c.max_document_length, v),
+    },
+    Setting {
+        env: "TEI_PROXY_MAX_REQUEST_TOKENS",
+        flag: "max-request-tokens",
+        apply: |c, v| set_optional(&mut c.max_request_tokens, v),
+    },
+    Setting {
+        env: "TEI_TIMEOUT_SECS",
+        flag: "tei-timeout-secs",
+        apply: |c, v| set(&mut c.tei_timeout_secs, v),
+    },
+    Setting {
+        env: "TEI_MAX_RETRIES",
+        flag: "tei-max-retries",
+        apply: |c, v| set(&mut c.tei_max_retries, v),
+    },
+    Setting {
+        env: "TEI_MAX_RETRY_WAIT_SECS",
+        flag: "tei-max-retry-wait-secs",
+        apply: |c, v| set(&mut c.tei_max_retry_wait_secs, v),
+    },
+    Setting {
+        env: "TEI_RETRY_BUDGET",
+        flag: "tei-retry-budget",
+        apply: |c, v| set(&mut c.tei_retry_budget, v),
+    },
+    Setting {
+        env: "TEI_BREAKER_FAILURES",
+        flag: "breaker-failures",
+        apply: |c, v| set(&mut c.breaker_failures, v),
+    },
+    Setting {
+        env: "TEI_BREAKER_COOLDOWN_SECS",
+        flag: "breaker-cooldown-secs",
+        apply: |c, v| set(&mut c.breaker_cooldown_secs, v),
+    },
+    Setting {
+        env: "TEI_EMBED_FALLBACK",
+        flag: "embed-fallback",
+        apply: |c, v| set(&mut c.embed_fallback, v),
+    },
+    Setting {
+        env: "TEI_BM25_FALLBACK",
+        flag: "bm25-fallback",
+        apply: |c, v| set(&mut c.bm25_fallback, v),
+    },
+    Setting {
+        env: "TEI_INFO_REFRESH_SECS",
+        flag: "tei-info-refresh-secs",
+        apply: |c, v| set(&mut c.tei_info_refresh_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_MODEL_LIMITS",
+        flag: "model-limits",
+        apply: |c, v| {
+            c.model_limits = match v.trim() {
+                "" => BTreeMap::new(),
+                json => serde_json::from_str(json)
+                    .map_err(|e| format!("invalid model limits ({})", e))?,
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_ROUTE_LIMITS",
+        flag: "route-limits",
+        apply: |c, v| {
+            c.route_limits = match v.trim() {
+                "" => BTreeMap::new(),
+                json => serde_json::from_str(json)
+                    .map_err(|e| format!("invalid route limits ({})", e))?,
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_ALLOWED_MODELS",
+        flag: "allowed-models",
+        apply: |c, v| {
+            c.allowed_models = split_list(v).collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_MAX_REQUEST_BYTES",
+        flag: "max-request-bytes",
+        apply: |c, v| set(&mut c.max_request_bytes, v),
+    },
+    Setting {
+        env: "TEI_PROXY_STREAM_BODY_THRESHOLD_BYTES",
+        flag: "stream-body-threshold-bytes",
+        apply: |c, v| set(&mut c.stream_body_threshold_bytes, v),
+    },
+    Setting {
+        env: "TEI_PROXY_STRICT_CONTENT_TYPE",
+        flag: "strict-content-type",
+        apply: |c, v| set(&mut c.strict_content_type, v),
+    },
+    Setting {
+        env: "TEI_PROXY_CLIENT_HEADER_TIMEOUT_SECS",
+        flag: "client-header-timeout-secs",
+        apply: |c, v| set(&mut c.client_header_timeout_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_CLIENT_BODY_TIMEOUT_SECS",
+        flag: "client-body-timeout-secs",
+        apply: |c, v| set(&mut c.client_body_timeout_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_MEMORY_BUDGET_BYTES",
+        flag: "memory-budget-bytes",
+        apply: |c, v| set(&mut c.memory_budget_bytes, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SHED_LATENCY_P99_MS",
+        flag: "shed-latency-p99-ms",
+        apply: |c, v| set(&mut c.shed_latency_p99_ms, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SHED_LATENCY_WINDOW_SECS",
+        flag: "shed-latency-window-secs",
+        apply: |c, v| set(&mut c.shed_latency_window_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SHED_LATENCY_FRACTION",
+        flag: "shed-latency-fraction",
+        apply: |c, v| set(&mut c.shed_latency_fraction, v),
+    },
+    Setting {
+        env: "TEI_PROXY_FUSION_RERANK_WEIGHT",
+        flag: "fusion-rerank-weight",
+        apply: |c, v| set(&mut c.fusion_rerank_weight, v),
+    },
+    Setting {
+        env: "TEI_PROXY_FUSION_RETRIEVAL_WEIGHT",
+        flag: "fusion-retrieval-weight",
+        apply: |c, v| set(&mut c.fusion_retrieval_weight, v),
+    },
+    Setting {
+        env: "TEI_PROXY_FUSION_BIAS",
+        flag: "fusion-bias",
+        apply: |c, v| set(&mut c.fusion_bias, v),
+    },
+    Setting {
+        env: "TEI_PROXY_LEXICAL_WEIGHT",
+        flag: "lexical-weight",
+        apply: |c, v| set(&mut c.lexical_weight, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SCORE_CALIBRATION",
+        flag: "score-calibration",
+        apply: |c, v| {
+            c.score_calibration = match v.trim() {
+                "" => Calibration::default(),
+                json => serde_json::from_str(json)
+                    .map_err(|e| format!("invalid score calibration ({})", e))?,
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_MODEL_SCORE_CALIBRATION",
+        flag: "model-score-calibration",
+        apply: |c, v| {
+            c.model_score_calibration = match v.trim() {
+                "" => BTreeMap::new(),
+                json => serde_json::from_str(json)
+                    .map_err(|e| format!("invalid model score calibration ({})", e))?,
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_CALIBRATION_LABELS_PATH",
+        flag: "calibration-labels-path",
+        apply: |c, v| {
+            c.calibration_labels_path = Some(v.trim()).filter(|v| !v.is_empty()).map(PathBuf::from);
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_QUERY_STRIP_PREFIXES",
+        flag: "query-strip-prefixes",
+        apply: |c, v| {
+            c.query_strip_prefixes = split_list(v).collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_QUERY_REPLACEMENTS",
+        flag: "query-replacements",
+        apply: |c, v| {
+            c.query_replacements = match v.trim() {
+                "" => Vec::new(),
+                json => serde_json::from_str(json)
+                    .map_err(|e| format!("invalid replacements ({})", e))?,
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_QUERY_LOWERCASE",
+        flag: "query-lowercase",
+        apply: |c, v| set(&mut c.query_lowercase, v),
+    },
+    Setting {
+        env: "TEI_PROXY_STRIP_MARKUP",
+        flag: "strip-markup",
+        apply: |c, v| set(&mut c.strip_markup, v),
+    },
+    Setting {
+        env: "TEI_PROXY_NORMALIZATION",
+        flag: "normalization",
+        apply: |c, v| set(&mut c.normalization, v),
+    },
+    Setting {
+        env: "TEI_PROXY_MODEL_NORMALIZATION",
+        flag: "model-normalization",
+        apply: |c, v| {
+            c.model_normalization = split_pairs(v, "model", "normalization")?
+                .into_iter()
+                .map(|(model, normalization)| Ok((model, normalization.parse()?)))
+                .collect::<Result<_, String>>()?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_TRUNCATION",
+        flag: "truncation",
+        apply: |c, v| set(&mut c.truncation, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SCORE_PRECISION",
+        flag: "score-precision",
+        apply: |c, v| set_optional(&mut c.score_precision, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SCORE_FIELD",
+        flag: "score-field",
+        apply: |c, v| set(&mut c.score_field, v),
+    },
+    Setting {
+        env: "TEI_PROXY_RESPONSE_ENVELOPE",
+        flag: "response-envelope",
+        apply: |c, v| set(&mut c.response_envelope, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SLOW_REQUEST_MS",
+        flag: "slow-request-ms",
+        apply: |c, v| set(&mut c.slow_request_ms, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PAYLOAD_LOG_SAMPLE_RATE",
+        flag: "payload-log-sample-rate",
+        apply: |c, v| set(&mut c.payload_log_sample_rate, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PAYLOAD_LOG_ON_HEADER",
+        flag: "payload-log-on-header",
+        apply: |c, v| set(&mut c.payload_log_on_header, v),
+    },
+    Setting {
+        env: "TEI_PROXY_MAX_CONCURRENT_REQUESTS",
+        flag: "max-concurrent-requests",
+        apply: |c, v| set(&mut c.max_concurrent_requests, v),
+    },
+    Setting {
+        env: "TEI_PROXY_MAX_REQUESTS_PER_IP",
+        flag: "max-requests-per-ip",
+        apply: |c, v| set(&mut c.max_requests_per_ip, v),
+    },
+    Setting {
+        env: "TEI_API_KEY",
+        flag: "tei-api-key",
+        apply: |c, v| {
+            c.tei_api_key = Some(v.trim().to_string()).filter(|v| !v.is_empty());
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_GOOGLE_AUTH",
+        flag: "tei-google-auth",
+        apply: |c, v| set(&mut c.tei_google_auth, v),
+    },
+    Setting {
+        env: "TEI_LANGUAGE_BACKENDS",
+        flag: "language-backends",
+        apply: |c, v| {
+            c.language_backends = split_pairs(v, "language", "url")?.into_iter().collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_CASCADE_ENDPOINT",
+        flag: "cascade-endpoint",
+        apply: |c, v| set_optional(&mut c.cascade_endpoint, v),
+    },
+    Setting {
+        env: "TEI_CASCADE_MODEL",
+        flag: "cascade-model",
+        apply: |c, v| set_optional(&mut c.cascade_model, v),
+    },
+    Setting {
+        env: "TEI_CASCADE_TOP_K",
+        flag: "cascade-top-k",
+        apply: |c, v| set(&mut c.cascade_top_k, v),
+    },
+    Setting {
+        env: "TEI_EMBED_ENDPOINT",
+        flag: "tei-embed-endpoint",
+        apply: |c, v| set_optional(&mut c.tei_embed_endpoint, v),
+    },
+    Setting {
+        env: "TEI_PROXY_QDRANT_URL",
+        flag: "qdrant-url",
+        apply: |c, v| set_optional(&mut c.qdrant_url, v),
+    },
+    Setting {
+        env: "TEI_PROXY_QDRANT_COLLECTION",
+        flag: "qdrant-collection",
+        apply: |c, v| set_optional(&mut c.qdrant_collection, v),
+    },
+    Setting {
+        env: "TEI_PROXY_QDRANT_API_KEY",
+        flag: "qdrant-api-key",
+        apply: |c, v| set_optional(&mut c.qdrant_api_key, v),
+    },
+    Setting {
+        env: "TEI_PROXY_QDRANT_VECTOR",
+        flag: "qdrant-vector",
+        apply: |c, v| set_optional(&mut c.qdrant_vector, v),
+    },
+    Setting {
+        env: "TEI_PROXY_QDRANT_TEXT_FIELD",
+        flag: "qdrant-text-field",
+        apply: |c, v| set(&mut c.qdrant_text_field, v),
+    },
+    Setting {
+        env: "TEI_PROXY_ELASTICSEARCH_URL",
+        flag: "elasticsearch-url",
+        apply: |c, v| set_optional(&mut c.elasticsearch_url, v),
+    },
+    Setting {
+        env: "TEI_PROXY_ELASTICSEARCH_INDEX",
+        flag: "elasticsearch-index",
+        apply: |c, v| set_optional(&mut c.elasticsearch_index, v),
+    },
+    Setting {
+        env: "TEI_PROXY_ELASTICSEARCH_API_KEY",
+        flag: "elasticsearch-api-key",
+        apply: |c, v| set_optional(&mut c.elasticsearch_api_key, v),
+    },
+    Setting {
+        env: "TEI_PROXY_ELASTICSEARCH_FIELDS",
+        flag: "elasticsearch-fields",
+        apply: |c, v| {
+            c.elasticsearch_fields = split_list(v).collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_ELASTICSEARCH_TEXT_FIELD",
+        flag: "elasticsearch-text-field",
+        apply: |c, v| set(&mut c.elasticsearch_text_field, v),
+    },
+    Setting {
+        env: "TEI_PROXY_POSTGRES_URL",
+        flag: "postgres-url",
+        apply: |c, v| set_optional(&mut c.postgres_url, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PGVECTOR_TABLE",
+        flag: "pgvector-table",
+        apply: |c, v| set_optional(&mut c.pgvector_table, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PGVECTOR_ID_COLUMN",
+        flag: "pgvector-id-column",
+        apply: |c, v| set(&mut c.pgvector_id_column, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PGVECTOR_TEXT_COLUMN",
+        flag: "pgvector-text-column",
+        apply: |c, v| set(&mut c.pgvector_text_column, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PGVECTOR_EMBEDDING_COLUMN",
+        flag: "pgvector-embedding-column",
+        apply: |c, v| set(&mut c.pgvector_embedding_column, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PGVECTOR_METADATA_COLUMN",
+        flag: "pgvector-metadata-column",
+        apply: |c, v| set_optional(&mut c.pgvector_metadata_column, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PGVECTOR_DISTANCE",
+        flag: "pgvector-distance",
+        apply: |c, v| set(&mut c.pgvector_distance, v),
+    },
+    Setting {
+        env: "TEI_PROXY_RETRIEVE_TOP_K",
+        flag: "retrieve-top-k",
+        apply: |c, v| set(&mut c.retrieve_top_k, v),
+    },
+    Setting {
+        env: "TEI_FORWARD_PARAMS",
+        flag: "tei-forward-params",
+        apply: |c, v| {
+            c.tei_forward_params = split_list(v).collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_ADAPTER",
+        flag: "tei-adapter",
+        apply: |c, v| {
+            c.tei_adapter = match v.trim() {
+                "" => None,
+                spec => Some(spec.parse()?),
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_API_KEYS",
+        flag: "api-keys",
+        apply: |c, v| {
+            c.api_keys = split_list(v)
+                .map(|key| ApiKey {
+                    key,
+                    name: None,
+                    priority: None,
+                    admin: false,
+                    models: None,
+                })
+                .collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_TOP_WINDOW_SECS",
+        flag: "top-window-secs",
+        apply: |c, v| set(&mut c.top_window_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_RATE_LIMIT",
+        flag: "rate-limit",
+        apply: |c, v| set(&mut c.rate_limit, v),
+    },
+    Setting {
+        env: "TEI_PROXY_RATE_LIMIT_WINDOW_SECS",
+        flag: "rate-limit-window-secs",
+        apply: |c, v| set(&mut c.rate_limit_window_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_IDEMPOTENCY_TTL_SECS",
+        flag: "idempotency-ttl-secs",
+        apply: |c, v| set(&mut c.idempotency_ttl_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_IDEMPOTENCY_MAX_ENTRIES",
+        flag: "idempotency-max-entries",
+        apply: |c, v| set(&mut c.idempotency_max_entries, v),
+    },
+    Setting {
+        env: "TEI_PROXY_FAULT_INJECTION",
+        flag: "fault-injection",
+        apply: |c, v| set(&mut c.fault_injection, v),
+    },
+    Setting {
+        env: "TEI_PROXY_FAULT_ERROR_RATE",
+        flag: "fault-error-rate",
+        apply: |c, v| set(&mut c.fault_error_rate, v),
+    },
+    Setting {
+        env: "TEI_PROXY_FAULT_LATENCY_MS",
+        flag: "fault-latency-ms",
+        apply: |c, v| set(&mut c.fault_latency_ms, v),
+    },
+    Setting {
+        env: "TEI_PROXY_FAULT_MALFORMED_RATE",
+        flag: "fault-malformed-rate",
+        apply: |c, v| set(&mut c.fault_malformed_rate, v),
+    },
+    Setting {
+        env: "TEI_PROXY_RESPONSE_DELAYS",
+        flag: "response-delays",
+        apply: |c, v| {
+            c.response_delays = split_pairs(v, "route", "ms")?
+                .into_iter()
+                .map(|(route, delay)| Ok((route, delay.parse::<Delay>()?)))
+                .collect::<Result<_, String>>()?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_STATSD_ADDRESS",
+        flag: "statsd-address",
+        apply: |c, v| {
+            c.statsd_address = Some(v.trim().to_string()).filter(|v| !v.is_empty());
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_STATSD_PREFIX",
+        flag: "statsd-prefix",
+        apply: |c, v| {
+            c.statsd_prefix = v.trim().to_string();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_STATSD_TAGS",
+        flag: "statsd-tags",
+        apply: |c, v| {
+            c.statsd_tags = split_pairs(v, "tag", "value")?.into_iter().collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_STATSD_INTERVAL_SECS",
+        flag: "statsd-interval-secs",
+        apply: |c, v| set(&mut c.statsd_interval_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PUSHGATEWAY_URL",
+        flag: "pushgateway-url",
+        apply: |c, v| set_optional(&mut c.pushgateway_url, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PUSHGATEWAY_JOB",
+        flag: "pushgateway-job",
+        apply: |c, v| {
+            c.pushgateway_job = v.trim().to_string();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_AUDIT_LOG_PATH",
+        flag: "audit-log-path",
+        apply: |c, v| {
+            c.audit_log_path = Some(v.trim()).filter(|v| !v.is_empty()).map(PathBuf::from);
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_PREPROCESS_WEBHOOK_URL",
+        flag: "preprocess-webhook-url",
+        apply: |c, v| {
+            c.preprocess_webhook_url = Some(v.trim().to_string()).filter(|v| !v.is_empty());
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_PREPROCESS_WEBHOOK_TIMEOUT_MS",
+        flag: "preprocess-webhook-timeout-ms",
+        apply: |c, v| set(&mut c.preprocess_webhook_timeout_ms, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PREPROCESS_WEBHOOK_FAILURE",
+        flag: "preprocess-webhook-failure",
+        apply: |c, v| set(&mut c.preprocess_webhook_failure, v),
+    },
+    Setting {
+        env: "TEI_PROXY_SCRIPTS",
+        flag: "scripts",
+        apply: |c, v| {
+            c.scripts = split_list(v).map(PathBuf::from).collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_SCRIPT_MAX_OPERATIONS",
+        flag: "script-max-operations",
+        apply: |c, v| set(&mut c.script_max_operations, v),
+    },
+    Setting {
+        env: "TEI_PROXY_PLUGINS",
+        flag: "plugins",
+        apply: |c, v| {
+            c.plugins = split_list(v).map(PathBuf::from).collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_PLUGIN_FUEL",
+        flag: "plugin-fuel",
+        apply: |c, v| set(&mut c.plugin_fuel, v),
+    },
+    Setting {
+        env: "TEI_DNS_REFRESH_SECS",
+        flag: "dns-refresh-secs",
+        apply: |c, v| set(&mut c.dns_refresh_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_CACHE_MAX_ENTRIES",
+        flag: "cache-max-entries",
+        apply: |c, v| set(&mut c.cache_max_entries, v),
+    },
+    Setting {
+        env: "TEI_PROXY_CACHE_MAX_BYTES",
+        flag: "cache-max-bytes",
+        apply: |c, v| set(&mut c.cache_max_bytes, v),
+    },
+    Setting {
+        env: "TEI_PROXY_CACHE_EVICTION",
+        flag: "cache-eviction",
+        apply: |c, v| set(&mut c.cache_eviction, v),
+    },
+    Setting {
+        env: "TEI_PROXY_CACHE_TTL_SECS",
+        flag: "cache-ttl-secs",
+        apply: |c, v| set(&mut c.cache_ttl_secs, v),
+    },
+    Setting {
+        env: "TEI_PROXY_CACHE_MODEL_TTL_SECS",
+        flag: "cache-model-ttl-secs",
+        apply: |c, v| {
+            c.cache_model_ttl_secs = split_pairs(v, "model", "secs")?
+                .into_iter()
+                .map(|(model, secs)| {
+                    let secs = secs
+                        .parse()
+                        .map_err(|e| format!("invalid TTL for '{}' ({})", model, e))?;
+                    Ok((model, secs))
+                })
+                .collect::<Result<_, String>>()?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_CACHE_PATH",
+        flag: "cache-path",
+        apply: |c, v| {
+            c.cache_path = Some(v.trim()).filter(|v| !v.is_empty()).map(PathBuf::from);
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_PROXY_CACHE_DISK_MAX_BYTES",
+        flag: "cache-disk-max-bytes",
+        apply: |c, v| set(&mut c.cache_disk_max_bytes, v),
+    },
+    Setting {
+        env: "TEI_DISCOVERY",
+        flag: "discovery",
+        apply: |c, v| {
+            c.discovery = match v.trim() {
+                "" => None,
+                spec => Some(spec.parse()?),
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_DISCOVERY_REFRESH_SECS",
+        flag: "discovery-refresh-secs",
+        apply: |c, v| set(&mut c.discovery_refresh_secs, v),
+    },
+    Setting {
+        env: "TEI_LOAD_BALANCING",
+        flag: "load-balancing",
+        apply: |c, v| set(&mut c.load_balancing, v),
+    },
+    Setting {
+        env: "TEI_BACKEND_WEIGHTS",
+        flag: "backend-weights",
+        apply: |c, v| {
+            c.backend_weights = split_pairs(v, "url", "weight")?
+                .into_iter()
+                .map(|(url, weight)| {
+                    let weight = weight
+                        .parse()
+                        .map_err(|e| format!("invalid weight for '{}' ({})", url, e))?;
+                    Ok((url, weight))
+                })
+                .collect::<Result<_, String>>()?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_BACKEND_PROXIES",
+        flag: "backend-proxies",
+        apply: |c, v| {
+            c.backend_proxies = split_pairs(v, "url", "proxy")?.into_iter().collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_UPSTREAM_PROXY",
+        flag: "upstream-proxy",
+        apply: |c, v| {
+            c.upstream_proxy = Some(v.trim().to_string()).filter(|v| !v.is_empty());
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_UPSTREAM_NO_PROXY",
+        flag: "upstream-no-proxy",
+        apply: |c, v| {
+            c.upstream_no_proxy = Some(v.trim().to_string()).filter(|v| !v.is_empty());
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_UPSTREAM_POOL_MAX_IDLE_PER_HOST",
+        flag: "upstream-pool-max-idle-per-host",
+        apply: |c, v| set_optional(&mut c.upstream_pool_max_idle_per_host, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_POOL_IDLE_TIMEOUT_SECS",
+        flag: "upstream-pool-idle-timeout-secs",
+        apply: |c, v| set(&mut c.upstream_pool_idle_timeout_secs, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_CONNECT_TIMEOUT_MS",
+        flag: "upstream-connect-timeout-ms",
+        apply: |c, v| set(&mut c.upstream_connect_timeout_ms, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_READ_TIMEOUT_SECS",
+        flag: "upstream-read-timeout-secs",
+        apply: |c, v| set(&mut c.upstream_read_timeout_secs, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_TCP_NODELAY",
+        flag: "upstream-tcp-nodelay",
+        apply: |c, v| set(&mut c.upstream_tcp_nodelay, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_TCP_KEEPALIVE_SECS",
+        flag: "upstream-tcp-keepalive-secs",
+        apply: |c, v| set(&mut c.upstream_tcp_keepalive_secs, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_HTTP2_PRIOR_KNOWLEDGE",
+        flag: "upstream-http2-prior-knowledge",
+        apply: |c, v| set(&mut c.upstream_http2_prior_knowledge, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_HTTP2_KEEPALIVE_SECS",
+        flag: "upstream-http2-keepalive-secs",
+        apply: |c, v| set(&mut c.upstream_http2_keepalive_secs, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_HTTP2_ADAPTIVE_WINDOW",
+        flag: "upstream-http2-adaptive-window",
+        apply: |c, v| set(&mut c.upstream_http2_adaptive_window, v),
+    },
+    Setting {
+        env: "TEI_UPSTREAM_CA_FILE",
+        flag: "upstream-ca-file",
+        apply: |c, v| {
+            c.upstream_ca_file = Some(v.trim()).filter(|v| !v.is_empty()).map(PathBuf::from);
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_UPSTREAM_TLS_SERVER_NAME",
+        flag: "upstream-tls-server-name",
+        apply: |c, v| {
+            c.upstream_tls_server_name = Some(v.trim().to_string()).filter(|v| !v.is_empty());
+            Ok(())
+        },
+    },
+    Setting {
+        env: "TEI_UPSTREAM_TLS_SKIP_VERIFY",
+        flag: "upstream-tls-skip-verify",
+        apply: |c, v| set(&mut c.upstream_tls_skip_verify, v),
+    },
+];
+
+/// Look up the setting bound to a command-line flag (without the `--`).
+pub fn find_setting(flag: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|s| s.flag == flag)
+}
+
+fn set<T: FromStr>(target: &mut T, value: &str) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    *target = value
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid value '{}' ({})", value, e))?;
+    Ok(())
+}
+
+/// Like [`set`], but an empty value clears the setting.
+fn set_optional<T: FromStr>(target: &mut Option<T>, value: &str) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    *target = match value.trim() {
+        "" => None,
+        trimmed => Some(
+            trimmed
+                .parse()
+                .map_err(|e| format!("invalid value '{}' ({})", value, e))?,
+        ),
+    };
+    Ok(())
+}
+
+/// Split a comma-separated environment value, dropping empty entries.
+fn split_list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+/// Split a comma-separated list of `<name>=<value>` pairs.
+fn split_pairs(value: &str, name: &str, what: &str) -> Result<Vec<(String, String)>, String> {
+    split_list(value)
+        .map(|entry| match entry.split_once('=') {
+            Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string())),
+            None => Err(format!("'{}' must look like <{}>=<{}>", entry, name, what)),
+        })
+        .collect()
+}
+
+/// Read a setting from `NAME`, or from the file named by `NAME_FILE` so
+/// that mounted Docker/Kubernetes secrets stay out of the environment.
+fn read_env(name: &str) -> Result<Option<String>, String> {
+    let file_var = format!("{}_FILE", name);
+    match (env::var(name).ok(), env::var_os(&file_var)) {
+        (Some(_), Some(_)) => Err(format!("set either {} or {}, not both", name, file_var)),
+        (Some(value), None) => Ok(Some(value)),
+        (None, Some(path)) => std::fs::read_to_string(&path)
+            .map(|text| Some(text.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|e| format!("{}: {}: {}", file_var, PathBuf::from(path).display(), e)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Where to load configuration from, as collected from the command line.
+#[derive(Debug, Default)]
+pub struct ConfigSource {
+    pub file: Option<PathBuf>,
+    pub overrides: Vec<(&'static Setting, String)>,
+}
+
+impl Config {
+    /// Load and validate the configuration, returning every problem found
+    /// rather than stopping at the first one.
+    pub fn load(source: &ConfigSource) -> Result<Config, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let file = source
+            .file
+            .clone()
+            .or_else(|| env::var_os("TEI_PROXY_CONFIG").map(PathBuf::from));
+
+        let mut config = match file {
+            Some(path) => match std::fs::read_to_string(&path) {
+                Ok(text) => match serde_json::from_str(&text) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        errors.push(format!("{}: {}", path.display(), e));
+                        Config::default()
+                    }
+                },
+                Err(e) => {
+                    errors.push(format!("{}: {}", path.display(), e));
+                    Config::default()
+                }
+            },
+            None => Config::default(),
+        };
+
+        for setting in SETTINGS {
+            match read_env(setting.env) {
+                Ok(Some(value)) => {
+                    if let Err(e) = (setting.apply)(&mut config, &value) {
+                        errors.push(format!("{}: {}", setting.env, e));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+
+        for (setting, value) in &source.overrides {
+            if let Err(e) = (setting.apply)(&mut config, value) {
+                errors.push(format!("--{}: {}", setting.flag, e));
+            }
+        }
+
+        errors.extend(config.validate());
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The limits for requests to `model` on `route`'s routes.
+    pub fn limits_for(&self, route: RouteGroup, model: Option<&str>) -> Limits {
+        let overrides = model
+            .and_then(|model| self.model_limits.get(model))
+            .cloned()
+            .unwrap_or_default();
+        let timeout_secs = overrides
+            .timeout_secs
+            .or_else(|| self.route_limits.get(&route)?.timeout_secs)
+            .unwrap_or(self.tei_timeout_secs);
+        Limits {
+            max_batch_size: overrides
+                .max_batch_size
+                .unwrap_or(self.max_client_batch_size),
+            max_document_length: overrides.max_document_length.or(self.max_document_length),
+            max_request_tokens: overrides.max_request_tokens.or(self.max_request_tokens),
+            timeout: Duration::from_secs(timeout_secs),
+        }
+    }
+
+    /// The largest request body accepted on `route`'s routes; 0 for no
+    /// limit.
+    pub fn max_request_bytes_for(&self, route: RouteGroup) -> u64 {
+        self.route_limits
+            .get(&route)
+            .and_then(|limits| limits.max_request_bytes)
+            .unwrap_or(self.max_request_bytes)
+    }
+
+    pub fn breaker(&self) -> Breaker {
+        Breaker {
+            failures: self.breaker_failures,
+            cooldown: Duration::from_secs(self.breaker_cooldown_secs),
+        }
+    }
+
+    /// The score calibration for requests to `model`.
+    pub fn calibration_for(&self, model: Option<&str>) -> Calibration {
+        model
+            .and_then(|model| self.model_score_calibration.get(model))
+            .copied()
+            .unwrap_or(self.score_calibration)
+    }
+
+    /// The text normalization for requests to `model`.
+    pub fn normalization_for(&self, model: Option<&str>) -> Normalization {
+        model
+            .and_then(|model| self.model_normalization.get(model))
+            .copied()
+            .unwrap_or(self.normalization)
+    }
+
+    /// `tei_endpoint` as it's called: under `upstream_tls_server_name`,
+    /// when set, which the client resolves to the endpoint's addresses.
+    pub fn endpoint_url(&self) -> String {
+        let Some(name) = &self.upstream_tls_server_name else {
+            return self.tei_endpoint.clone();
+        };
+        let Ok(mut url) = Url::parse(&self.tei_endpoint) else {
+            return self.tei_endpoint.clone();
+        };
+        match url.set_host(Some(name)) {
+            Ok(()) => url.as_str().trim_end_matches('/').to_string(),
+            Err(_) => self.tei_endpoint.clone(),
+        }
+    }
+
+    /// Where to accept connections: the configured listeners, or everything
+    /// on `bind_address:port` when none are configured.
+    pub fn listeners(&self) -> Vec<Listener> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![Listener {
+            address: SocketAddr::new(self.bind_address, self.port),
+            routes: Routes::All,
+        }]
+    }
+
+    /// The faults to inject at startup.
+    pub fn fault_settings(&self) -> FaultSettings {
+        FaultSettings {
+            error_rate: self.fault_error_rate,
+            latency_ms: self.fault_latency_ms,
+            malformed_rate: self.fault_malformed_rate,
+        }
+    }
+
+    /// Render the configuration as JSON with secret values replaced, suitable
+    /// for printing or logging.
+    pub fn to_masked_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        mask_secrets(&mut value);
+        value
+    }
+
+    /// Semantic checks on an already-parsed configuration.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Err(e) = validate_url(&self.tei_endpoint) {
+            errors.push(format!("tei_endpoint: {}", e));
+        }
+
+        if self.port == 0 {
+            errors.push("port: must be between 1 and 65535".to_string());
+        }
+
+        for listener in &self.listeners {
+            if listener.address.port() == 0 {
+                errors.push(format!(
+                    "listeners: {} must have a port between 1 and 65535",
+                    listener.address
+                ));
+            }
+        }
+        if !self.listeners.is_empty() && !self.listeners.iter().any(|l| l.routes.rerank()) {
+            errors.push("listeners: at least one listener must serve rerank".to_string());
+        }
+
+        if self.statsd_address.is_some() && self.statsd_interval_secs == 0 {
+            errors.push("statsd_interval_secs: must be greater than 0".to_string());
+        }
+        if let Some(Err(e)) = self.pushgateway_url.as_deref().map(validate_url) {
+            errors.push(format!("pushgateway_url: {}", e));
+        }
+        let valid_job = !self.pushgateway_job.is_empty()
+            && self
+                .pushgateway_job
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid_job {
+            errors.push("pushgateway_job: must be letters, digits, '_', '-' or '.'".to_string());
+        }
+
+        if self.max_client_batch_size == 0 {
+            errors.push("max_client_batch_size: must be greater than 0".to_string());
+        }
+        if self.max_document_length == Some(0) {
+            errors.push("max_document_length: must be greater than 0".to_string());
+        }
+        if self.max_request_tokens == Some(0) {
+            errors.push("max_request_tokens: must be greater than 0".to_string());
+        }
+        if self.top_window_secs == 0 {
+            errors.push("top_window_secs: must be greater than 0".to_string());
+        }
+        if self.rate_limit_window_secs == 0 {
+            errors.push("rate_limit_window_secs: must be greater than 0".to_string());
+        }
+        if self.tei_timeout_secs == 0 {
+            errors.push("tei_timeout_secs: must be greater than 0".to_string());
+        }
+        for (model, limits) in &self.model_limits {
+            for (name, value) in [
+                ("max_batch_size", limits.max_batch_size.map(|n| n as u64)),
+                (
+                    "max_document_length",
+                    limits.max_document_length.map(|n| n as u64),
+                ),
+                (
+                    "max_request_tokens",
+                    limits.max_request_tokens.map(|n| n as u64),
+                ),
+                ("timeout_secs", limits.timeout_secs),
+            ] {
+                if value == Some(0) {
+                    errors.push(format!(
+                        "model_limits: {} for '{}' must be greater than 0",
+                        name, model
+                    ));
+                }
+            }
+        }
+        for (route, limits) in &self.route_limits {
+            for (name, value) in [
+                ("timeout_secs", limits.timeout_secs),
+                (
+                    "max_concurrent_requests",
+                    limits.max_concurrent_requests.map(|n| n as u64),
+                ),
+            ] {
+                if value == Some(0) {
+                    errors.push(format!(
+                        "route_limits: {} for '{}' must be greater than 0",
+                        name, route
+                    ));
+                }
+            }
+        }
+
+        if self.cache_path.is_some() && !cfg!(feature = "disk-cache") {
+            errors.push(
+                "cache_path: this build has no disk cache support (rebuild with --features disk-cache)"
+                    .to_string(),
+            );
+        }
+
+        if let Some(url) = &self.preprocess_webhook_url {
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("preprocess_webhook_url: {}", e));
+            }
+            if self.preprocess_webhook_timeout_ms == 0 {
+                errors.push("preprocess_webhook_timeout_ms: must be greater than 0".to_string());
+            }
+        }
+
+        if !self.scripts.is_empty() && !cfg!(feature = "rhai-scripts") {
+            errors.push(
+                "scripts: this build has no scripting support (rebuild with --features rhai-scripts)"
+                    .to_string(),
+            );
+        }
+
+        if !self.plugins.is_empty() {
+            if !cfg!(feature = "wasm-plugins") {
+                errors.push(
+                    "plugins: this build has no plugin support (rebuild with --features wasm-plugins)"
+                        .to_string(),
+                );
+            }
+            if self.plugin_fuel == 0 {
+                errors.push("plugin_fuel: must be greater than 0".to_string());
+            }
+        }
+
+        if !(self.tei_retry_budget.is_finite() && self.tei_retry_budget >= 0.0) {
+            errors.push("tei_retry_budget: must be a non-negative number".to_string());
+        }
+
+        if !(0.0..=1.0).contains(&self.shed_latency_fraction) {
+            errors.push("shed_latency_fraction: must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.lexical_weight) {
+            errors.push("lexical_weight: must be between 0 and 1".to_string());
+        }
+
+        for (name, value) in [
+            ("fusion_rerank_weight", self.fusion_rerank_weight),
+            ("fusion_retrieval_weight", self.fusion_retrieval_weight),
+            ("fusion_bias", self.fusion_bias),
+        ] {
+            if !value.is_finite() {
+                errors.push(format!("{}: must be a finite number", name));
+            }
+        }
+
+        if let Err(e) = self.score_calibration.validate() {
+            errors.push(format!("score_calibration: {}", e));
+        }
+        for (model, calibration) in &self.model_score_calibration {
+            if let Err(e) = calibration.validate() {
+                errors.push(format!("model_score_calibration: '{}': {}", model, e));
+            }
+        }
+
+        for replacement in &self.query_replacements {
+            if let Err(e) = regex::Regex::new(&replacement.pattern) {
+                errors.push(format!("query_replacements: {}", e));
+            }
+        }
+
+        if let Err(e) = self.fault_settings().validate() {
+            errors.push(format!("fault_{}", e));
+        }
+        if !self.fault_injection && self.fault_settings() != FaultSettings::default() {
+            errors.push("fault_injection: must be enabled to inject faults".to_string());
+        }
+        for route in self.response_delays.keys() {
+            if !RERANK_ROUTES.contains(&route.as_str()) {
+                errors.push(format!(
+                    "response_delays: unknown route '{}' (expected {})",
+                    route,
+                    RERANK_ROUTES.join(", ")
+                ));
+            }
+        }
+        if self.tei_google_auth && self.tei_api_key.is_some() {
+            errors.push("tei_google_auth: can't be combined with tei_api_key".to_string());
+        }
+        if let Some(Err(e)) = self.tei_adapter.as_ref().map(Adapter::check) {
+            errors.push(format!("tei_adapter: {}", e));
+        }
+        if self.embed_fallback && self.tei_adapter.is_some() {
+            errors.push("embed_fallback: needs TEI backends, not tei_adapter".to_string());
+        }
+
+        for name in &self.tei_forward_params {
+            if matches!(name.as_str(), "query" | "texts") {
+                errors.push(format!(
+                    "tei_forward_params: '{}' is set by the proxy and can't be forwarded",
+                    name
+                ));
+            }
+        }
+
+        if self
+            .score_precision
+            .is_some_and(|digits| digits > MAX_SCORE_PRECISION)
+        {
+            errors.push(format!(
+                "score_precision: must be at most {} decimal places",
+                MAX_SCORE_PRECISION
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.payload_log_sample_rate) {
+            errors.push("payload_log_sample_rate: must be between 0 and 1".to_string());
+        }
+
+        if self.shed_latency_p99_ms > 0 && self.shed_latency_window_secs == 0 {
+            errors.push("shed_latency_window_secs: must be greater than 0".to_string());
+        }
+
+        if self.discovery_refresh_secs == 0 {
+            errors.push("discovery_refresh_secs: must be greater than 0".to_string());
+        }
+
+        if let Some(proxy) = &self.upstream_proxy {
+            if let Err(e) = validate_proxy_url(proxy) {
+                errors.push(format!("upstream_proxy: {}", e));
+            }
+        }
+
+        if let Some(name) = &self.upstream_tls_server_name {
+            let valid = Url::parse(&format!("https://{}", name))
+                .is_ok_and(|url| url.host_str() == Some(name.as_str()));
+            if !valid {
+                errors.push(format!(
+                    "upstream_tls_server_name: '{}' is not a host name",
+                    name
+                ));
+            }
+            if self.discovery.is_some() {
+                errors.push(
+                    "upstream_tls_server_name: only applies to tei_endpoint, not discovery"
+                        .to_string(),
+                );
+            } else if !self.tei_endpoint.starts_with("https://") {
+                errors.push("upstream_tls_server_name: tei_endpoint must use https".to_string());
+            }
+        }
+
+        for (url, proxy) in &self.backend_proxies {
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("backend_proxies: {}", e));
+            }
+            if let Err(e) = validate_proxy_url(proxy) {
+                errors.push(format!("backend_proxies[{}]: {}", url, e));
+            }
+        }
+
+        for (language, url) in &self.language_backends {
+            if !language::is_known(language) {
+                errors.push(format!(
+                    "language_backends: unknown ISO 639-3 language code '{}'",
+                    language
+                ));
+            }
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("language_backends[{}]: {}", language, e));
+            }
+        }
+
+        if let Some(url) = &self.cascade_endpoint {
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("cascade_endpoint: {}", e));
+            }
+            if self.cascade_top_k == 0 {
+                errors.push("cascade_top_k: must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(url) = &self.tei_embed_endpoint {
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("tei_embed_endpoint: {}", e));
+            }
+        }
+        if let Some(url) = &self.qdrant_url {
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("qdrant_url: {}", e));
+            }
+            if self.qdrant_collection.is_none() {
+                errors.push("qdrant_collection: required with qdrant_url".to_string());
+            }
+            if self.tei_embed_endpoint.is_none() {
+                errors.push("tei_embed_endpoint: required with qdrant_url".to_string());
+            }
+        }
+        if let Some(url) = &self.elasticsearch_url {
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("elasticsearch_url: {}", e));
+            }
+            if self.elasticsearch_index.is_none() {
+                errors.push("elasticsearch_index: required with elasticsearch_url".to_string());
+            }
+            if self.qdrant_url.is_some() {
+                errors.push("elasticsearch_url: can't be combined with qdrant_url".to_string());
+            }
+        }
+        if self.postgres_url.is_some() {
+            if !cfg!(feature = "pgvector") {
+                errors.push(
+                    "postgres_url: this build has no pgvector support (rebuild with --features pgvector)"
+                        .to_string(),
+                );
+            }
+            if self.pgvector_table.is_none() {
+                errors.push("pgvector_table: required with postgres_url".to_string());
+            }
+            if self.tei_embed_endpoint.is_none() {
+                errors.push("tei_embed_endpoint: required with postgres_url".to_string());
+            }
+            if self.qdrant_url.is_some() || self.elasticsearch_url.is_some() {
+                errors.push(
+                    "postgres_url: can't be combined with qdrant_url or elasticsearch_url"
+                        .to_string(),
+                );
+            }
+        }
+        if self.retrieve_top_k == 0 {
+            errors.push("retrieve_top_k: must be greater than 0".to_string());
+        }
+
+        for (url, weight) in &self.backend_weights {
+            if let Err(e) = validate_url(url) {
+                errors.push(format!("backend_weights: {}", e));
+            }
+            if *weight == 0 {
+                errors.push(format!("backend_weights[{}]: must be greater than 0", url));
+            }
+        }
+
+        for (i, key) in self.api_keys.iter().enumerate() {
+            if key.key.trim().is_empty() {
+                errors.push(format!("api_keys[{}]: key cannot be empty", i));
+            }
+            if self.allowed_models.is_empty() {
+                continue;
+            }
+            for model in key.models.iter().flatten() {
+                if !self.allowed_models.contains(model) {
+                    errors.push(format!(
+                        "api_keys[{}]: model '{}' is not in allowed_models",
+                        i, model
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+fn validate_url(value: &str) -> Result<Url, String> {
+    let url = Url::parse(value).map_err(|e| format!("'{}' is not a valid URL ({})", value, e))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!(
+            "'{}' must use http or https, got '{}'",
+            value,
+            url.scheme()
+        ));
+    }
+
+    if url.host_str().is_none() {
+        return Err(format!("'{}' has no host", value));
+    }
+
+    Ok(url)
+}
+
+fn validate_proxy_url(value: &str) -> Result<Url, String> {
+    let url = Url::parse(value).map_err(|e| format!("'{}' is not a valid URL ({})", value, e))?;
+
+    if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(format!(
+            "'{}' must use http, https, socks5 or socks5h, got '{}'",
+            value,
+            url.scheme()
+        ));
+    }
+
+    Ok(url)
+}
+
+/// Routes that serve rerank requests, in any format.
+pub const RERANK_ROUTES: &[&str] = &[
+    "/rerank",
+    "/retrieve_rerank",
+    "/pinecone/rerank",
+    "/mixedbread/v1/reranking",
+];
+
+/// An f64 has about 15 significant decimal digits; rounding further is
+/// meaningless.
+const MAX_SCORE_PRECISION: u32 = 15;
+
+const SECRET_SUFFIXES: &[&str] = &["key", "token", "secret", "password"];
+
+fn mask_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (name, field) in map.iter_mut() {
+                let secret = SECRET_SUFFIXES.iter().any(|k| name.ends_with(k));
+                if secret && field.is_string() {
+                    *field = serde_json::Value::String("********".to_string());
+                } else if let Some(url) = field.as_str().and_then(masked_password) {
+                    *field = serde_json::Value::String(url);
+                } else {
+                    mask_secrets(field);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(mask_secrets),
+        _ => {}
+    }
+}
+
+/// A connection string with credentials in it, such as `elasticsearch_url`
+/// or `postgres_url`, with the password replaced: a URL's, including a
+/// `password` query parameter, or a libpq `key=value` string's.
+fn masked_password(value: &str) -> Option<String> {
+    let Ok(mut url) = Url::parse(value) else {
+        // `password=secret` or `password = 'se cret'`, quotes escaped with `\`
+        let pattern = regex::Regex::new(r"(^|\s)password\s*=\s*('(\\.|[^'\\])*'|\S+)").ok()?;
+        return pattern.is_match(value).then(|| {
+            pattern
+                .replace_all(value, "${1}password=********")
+                .into_owned()
+        });
+    };
+    let in_query = url.query_pairs().any(|(name, _)| name == "password");
+    if url.password().is_none() && !in_query {
+        return None;
+    }
+    if url.password().is_some() {
+        url.set_password(Some("********")).ok()?;
+    }
+    if in_query {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| match &*name {
+                "password" => (name.into_owned(), "********".to_string()),
+                _ => (name.into_owned(), value.into_owned()),
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    Some(url.to_string())
+}
@@ -0,0 +1,165 @@
+use crate::auth;
+use crate::config::{ApiKey, RouteGroup};
+use crate::error::ApiError;
+use crate::queue::Priority;
+use crate::state::AppState;
+use crate::trace::{self, TraceContext};
+use log::{log_enabled, Level};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use warp::Filter;
+
+/// Longest client-supplied `X-Request-Id` kept; longer ones are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Who sent a request and how it should be treated, carried from the HTTP
+/// filters into the rerank path.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    /// The client's API key, when authentication is enabled.
+    pub api_key: Option<ApiKey>,
+    pub priority: Priority,
+    /// The caller's `X-Request-Id`, or a generated one.
+    pub request_id: String,
+    /// The caller's W3C trace, continued on the request to TEI.
+    pub trace: Option<TraceContext>,
+    /// Log this request's payloads at info level, whatever `RUST_LOG` says.
+    pub log_payloads: bool,
+    /// Score every document afresh instead of reading cached scores, as
+    /// asked with `Cache-Control: no-cache`; the new scores are still cached.
+    pub no_cache: bool,
+    /// The routes the request came in on, whose limits apply to it.
+    pub route: RouteGroup,
+}
+
+impl RequestContext {
+    /// The level to log full payloads at, if they should be logged at all:
+    /// info for sampled requests, debug otherwise.
+    pub fn payload_log_level(&self) -> Option<Level> {
+        let level = if self.log_payloads {
+            Level::Info
+        } else {
+            Level::Debug
+        };
+        log_enabled!(level).then_some(level)
+    }
+}
+
+/// Picks the requests whose full payloads are logged in production: an
+/// evenly spread share of all requests, plus any carrying `X-Debug-Payload`
+/// when that's allowed.
+#[derive(Debug)]
+pub struct PayloadSampler {
+    rate: f64,
+    on_header: bool,
+    seen: AtomicU64,
+}
+
+impl PayloadSampler {
+    pub fn new(rate: f64, on_header: bool) -> Self {
+        Self {
+            rate,
+            on_header,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    fn sample(&self, header: bool) -> bool {
+        if header && self.on_header {
+            return true;
+        }
+        if self.rate <= 0.0 {
+            return false;
+        }
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+}
+
+/// Authenticate the request, work out its priority from the `X-Priority`
+/// header and the API key, pick up any W3C trace context, see whether
+/// cached scores may be used and decide whether to log its payloads. A key's
+/// configured priority is also the highest its clients may ask for.
+pub fn extract(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (RequestContext,), Error = warp::Rejection> + Clone {
+    let sampler_state = state.clone();
+    auth::authorize(state)
+        .and(warp::header::optional::<String>("x-priority"))
+        .and(warp::header::optional::<String>("traceparent"))
+        .and(warp::header::optional::<String>("tracestate"))
+        .and(warp::header::optional::<String>("x-debug-payload"))
+        .and(warp::header::optional::<String>("x-request-id"))
+        .and(warp::any().map(move || sampler_state.clone()))
+        .and_then(resolve)
+        .and(warp::header::optional::<String>("cache-control"))
+        .and(warp::header::optional::<String>("pragma"))
+        .and(warp::path::full())
+        .map(
+            |mut ctx: RequestContext,
+             cache_control: Option<String>,
+             pragma: Option<String>,
+             path: warp::path::FullPath| {
+                ctx.no_cache = no_cache(cache_control.as_deref(), pragma.as_deref());
+                ctx.route = RouteGroup::of(path.as_str()).unwrap_or_default();
+                ctx
+            },
+        )
+}
+
+async fn resolve(
+    api_key: Option<ApiKey>,
+    header: Option<String>,
+    traceparent: Option<String>,
+    tracestate: Option<String>,
+    debug_payload: Option<String>,
+    request_id: Option<String>,
+    state: Arc<AppState>,
+) -> Result<RequestContext, warp::Rejection> {
+    let requested = header
+        .map(|value| value.parse::<Priority>())
+        .transpose()
+        .map_err(|e| warp::reject::custom(ApiError::BadRequest(format!("X-Priority: {}", e))))?;
+    let allowed = api_key.as_ref().and_then(|key| key.priority);
+
+    let priority = match (requested, allowed) {
+        (Some(requested), Some(allowed)) => requested.min(allowed),
+        (requested, allowed) => requested.or(allowed).unwrap_or_default(),
+    };
+
+    let trace = traceparent.and_then(|value| TraceContext::continue_from(&value, tracestate));
+
+    let log_payloads = state.payload_sampler.sample(debug_payload.is_some());
+    let request_id = request_id
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .unwrap_or_else(trace::new_request_id);
+
+    Ok(RequestContext {
+        api_key,
+        priority,
+        request_id,
+        trace,
+        log_payloads,
+        no_cache: false,
+        route: RouteGroup::default(),
+    })
+}
+
+/// Whether the client asked for a fresh answer: `no-cache` (or `no-store`,
+/// or `max-age=0`) in `Cache-Control`, or HTTP/1.0's `Pragma: no-cache`.
+fn no_cache(cache_control: Option<&str>, pragma: Option<&str>) -> bool {
+    let directives = |value: Option<&str>| {
+        value
+            .unwrap_or_default()
+            .split(',')
+            .map(|directive| directive.trim().to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+    directives(cache_control)
+        .iter()
+        .any(|directive| matches!(directive.as_str(), "no-cache" | "no-store" | "max-age=0"))
+        || directives(pragma)
+            .iter()
+            .any(|directive| directive == "no-cache")
+}
@@ -0,0 +1,130 @@
+use reqwest::Url;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A service in the Consul catalog, e.g. `consul://consul:8500/tei?tag=gpu`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsulTarget {
+    /// Consul HTTP API address, `host:port`.
+    pub address: String,
+    pub service: String,
+    pub tag: Option<String>,
+    pub datacenter: Option<String>,
+}
+
+impl FromStr for ConsulTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "'{}' must look like consul://host:port/service[?tag=..&dc=..]",
+                value
+            )
+        };
+
+        let url = Url::parse(&format!("http://{}", value)).map_err(|_| invalid())?;
+        let service = url.path().trim_matches('/');
+        if service.is_empty() || service.contains('/') {
+            return Err(invalid());
+        }
+
+        let mut target = Self {
+            address: format!(
+                "{}:{}",
+                url.host_str().ok_or_else(invalid)?,
+                url.port().unwrap_or(8500)
+            ),
+            service: service.to_string(),
+            tag: None,
+            datacenter: None,
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "tag" => target.tag = Some(value.into_owned()),
+                "dc" => target.datacenter = Some(value.into_owned()),
+                other => return Err(format!("unknown consul option '{}'", other)),
+            }
+        }
+        Ok(target)
+    }
+}
+
+impl fmt::Display for ConsulTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.service)?;
+        let mut separator = '?';
+        if let Some(tag) = &self.tag {
+            write!(f, "{}tag={}", separator, tag)?;
+            separator = '&';
+        }
+        if let Some(dc) = &self.datacenter {
+            write!(f, "{}dc={}", separator, dc)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: Service,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(default)]
+    address: String,
+    port: u16,
+}
+
+/// Query the catalog for instances passing their health checks.
+pub async fn list(target: &ConsulTarget) -> anyhow::Result<Vec<String>> {
+    let mut url = Url::parse(&format!(
+        "http://{}/v1/health/service/{}",
+        target.address, target.service
+    ))?;
+    url.query_pairs_mut().append_pair("passing", "true");
+    if let Some(tag) = &target.tag {
+        url.query_pairs_mut().append_pair("tag", tag);
+    }
+    if let Some(dc) = &target.datacenter {
+        url.query_pairs_mut().append_pair("dc", dc);
+    }
+
+    let mut request = reqwest::Client::new()
+        .get(url)
+        .timeout(Duration::from_secs(10));
+    if let Ok(token) = std::env::var("CONSUL_HTTP_TOKEN") {
+        request = request.header("X-Consul-Token", token);
+    }
+
+    let entries: Vec<ServiceEntry> = request.send().await?.error_for_status()?.json().await?;
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            // Service address falls back to the node address when unset
+            let address = if entry.service.address.is_empty() {
+                entry.node.address
+            } else {
+                entry.service.address
+            };
+            if address.contains(':') {
+                format!("http://[{}]:{}", address, entry.service.port)
+            } else {
+                format!("http://{}:{}", address, entry.service.port)
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,284 @@
+use super::{update_pool, Discovery, RETRY_DELAY};
+use crate::state::AppState;
+use anyhow::Context;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// How long a single watch request stays open before it is re-established.
+const WATCH_TIMEOUT_SECS: u64 = 300;
+
+/// A Kubernetes service whose EndpointSlices make up the backend pool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KubernetesTarget {
+    /// Defaults to the namespace the proxy itself runs in.
+    pub namespace: Option<String>,
+    pub service: String,
+    /// Port name or number; the service's first port when unset.
+    pub port: Option<String>,
+}
+
+impl FromStr for KubernetesTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (namespace, rest) = match value.split_once('/') {
+            Some((namespace, rest)) => (Some(namespace.to_string()), rest),
+            None => (None, value),
+        };
+        let (service, port) = match rest.split_once(':') {
+            Some((service, port)) => (service, Some(port.to_string())),
+            None => (rest, None),
+        };
+
+        if service.is_empty() || namespace.as_deref() == Some("") || port.as_deref() == Some("") {
+            return Err(format!(
+                "'{}' must look like kubernetes://[namespace/]service[:port]",
+                value
+            ));
+        }
+
+        Ok(Self {
+            namespace,
+            service: service.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for KubernetesTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(namespace) = &self.namespace {
+            write!(f, "{}/", namespace)?;
+        }
+        f.write_str(&self.service)?;
+        if let Some(port) = &self.port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EndpointSliceList {
+    metadata: ListMeta,
+    #[serde(default)]
+    items: Vec<EndpointSlice>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ListMeta {
+    #[serde(default)]
+    resource_version: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EndpointSlice {
+    #[serde(default)]
+    ports: Option<Vec<EndpointPort>>,
+    #[serde(default)]
+    endpoints: Vec<Endpoint>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EndpointPort {
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Endpoint {
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: EndpointConditions,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct EndpointConditions {
+    ready: Option<bool>,
+}
+
+/// In-cluster API access using the pod's service account.
+struct ApiClient {
+    client: reqwest::Client,
+    base: String,
+    token: String,
+    namespace: String,
+}
+
+impl ApiClient {
+    fn in_cluster() -> anyhow::Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .context("KUBERNETES_SERVICE_HOST is not set; not running in a cluster?")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let host = if host.contains(':') {
+            format!("[{}]", host)
+        } else {
+            host
+        };
+
+        let read = |name: &str| {
+            let path = format!("{}/{}", SERVICE_ACCOUNT_DIR, name);
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path))
+        };
+
+        let ca = reqwest::Certificate::from_pem(&read("ca.crt")?)?;
+        let token = String::from_utf8(read("token")?)?.trim().to_string();
+        let namespace = String::from_utf8(read("namespace")?)?.trim().to_string();
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+
+        Ok(Self {
+            client,
+            base: format!("https://{}:{}", host, port),
+            token,
+            namespace,
+        })
+    }
+
+    fn slices_url(&self, target: &KubernetesTarget) -> String {
+        format!(
+            "{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}",
+            self.base,
+            target.namespace.as_deref().unwrap_or(&self.namespace),
+            target.service
+        )
+    }
+
+    async fn list(&self, target: &KubernetesTarget) -> anyhow::Result<EndpointSliceList> {
+        let response = self
+            .client
+            .get(self.slices_url(target))
+            .bearer_auth(&self.token)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    /// Block until the first change event after `resource_version`, or until
+    /// the watch times out. Returns whether a change was seen.
+    async fn wait_for_change(
+        &self,
+        target: &KubernetesTarget,
+        resource_version: &str,
+    ) -> anyhow::Result<bool> {
+        let url = format!(
+            "{}&watch=true&resourceVersion={}&timeoutSeconds={}",
+            self.slices_url(target),
+            resource_version,
+            WATCH_TIMEOUT_SECS
+        );
+        let mut response = self
+            .client
+            .get(url)
+            .bearer_auth(&self.token)
+            .timeout(Duration::from_secs(WATCH_TIMEOUT_SECS + 30))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // Events are newline-delimited JSON objects; any complete line means
+        // something changed and the pool should be re-listed.
+        while let Some(chunk) = response.chunk().await? {
+            if chunk.contains(&b'\n') {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn backend_urls(target: &KubernetesTarget, slices: &[EndpointSlice]) -> Vec<String> {
+    let mut urls = Vec::new();
+
+    for slice in slices {
+        let ports = slice.ports.as_deref().unwrap_or_default();
+        let port = match &target.port {
+            Some(wanted) => match wanted.parse::<u16>() {
+                Ok(number) => Some(number),
+                Err(_) => ports
+                    .iter()
+                    .find(|p| p.name.as_deref() == Some(wanted.as_str()))
+                    .and_then(|p| p.port),
+            },
+            None => ports.first().and_then(|p| p.port),
+        };
+        let Some(port) = port else {
+            continue;
+        };
+
+        for endpoint in &slice.endpoints {
+            if endpoint.conditions.ready == Some(false) {
+                continue;
+            }
+            for address in &endpoint.addresses {
+                if address.contains(':') {
+                    urls.push(format!("http://[{}]:{}", address, port));
+                } else {
+                    urls.push(format!("http://{}:{}", address, port));
+                }
+            }
+        }
+    }
+
+    urls
+}
+
+/// List the service's ready endpoints once.
+pub async fn list(target: &KubernetesTarget) -> anyhow::Result<Vec<String>> {
+    let api = ApiClient::in_cluster()?;
+    let slices = api.list(target).await?;
+    Ok(backend_urls(target, &slices.items))
+}
+
+/// Keep the pool in sync: list, then watch for changes and re-list on each.
+pub async fn watch(state: &AppState, target: &KubernetesTarget) {
+    let source = Discovery::Kubernetes(target.clone());
+
+    let api = loop {
+        match ApiClient::in_cluster() {
+            Ok(api) => break api,
+            Err(e) => {
+                warn!("Kubernetes discovery unavailable: {:#}", e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    };
+
+    loop {
+        let slices = match api.list(target).await {
+            Ok(slices) => slices,
+            Err(e) => {
+                warn!("Failed to list EndpointSlices for {}: {:#}", target, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        update_pool(state, &source, backend_urls(target, &slices.items));
+
+        match api
+            .wait_for_change(target, &slices.metadata.resource_version)
+            .await
+        {
+            Ok(true) => debug!("EndpointSlices for {} changed", target),
+            Ok(false) => debug!("Watch on {} timed out, re-listing", target),
+            Err(e) => {
+                warn!("Watch on EndpointSlices for {} failed: {:#}", target, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+mod consul;
+mod kubernetes;
+mod srv;
+
+use crate::state::AppState;
+use consul::ConsulTarget;
+use kubernetes::KubernetesTarget;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use srv::SrvTarget;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where to discover the TEI backend pool from, written as a URL-like spec,
+/// e.g. `kubernetes://namespace/service:port`, `srv://_tei._tcp.example.com`
+/// or `consul://consul:8500/tei`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Discovery {
+    Kubernetes(KubernetesTarget),
+    Srv(SrvTarget),
+    Consul(ConsulTarget),
+}
+
+impl FromStr for Discovery {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, rest)) = spec.split_once("://") else {
+            return Err(format!("'{}' must look like <provider>://<target>", spec));
+        };
+
+        match scheme {
+            "kubernetes" | "k8s" => rest.parse().map(Discovery::Kubernetes),
+            "srv" => rest.parse().map(Discovery::Srv),
+            "consul" => rest.parse().map(Discovery::Consul),
+            other => Err(format!(
+                "unknown discovery provider '{}' (expected kubernetes, srv or consul)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discovery::Kubernetes(target) => write!(f, "kubernetes://{}", target),
+            Discovery::Srv(target) => write!(f, "srv://{}", target),
+            Discovery::Consul(target) => write!(f, "consul://{}", target),
+        }
+    }
+}
+
+impl TryFrom<String> for Discovery {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Discovery> for String {
+    fn from(value: Discovery) -> Self {
+        value.to_string()
+    }
+}
+
+/// Run discovery once and return the backend URLs found.
+pub async fn discover_once(discovery: &Discovery) -> anyhow::Result<Vec<String>> {
+    match discovery {
+        Discovery::Kubernetes(target) => kubernetes::list(target).await,
+        Discovery::Srv(target) => srv::list(target).await,
+        Discovery::Consul(target) => consul::list(target).await,
+    }
+}
+
+/// Start keeping the backend pool in sync with the discovery source.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(discovery) = state.config.discovery.clone() else {
+        return;
+    };
+
+    info!("Discovering TEI backends from {}", discovery);
+
+    tokio::spawn(async move {
+        match &discovery {
+            Discovery::Kubernetes(target) => kubernetes::watch(&state, target).await,
+            Discovery::Srv(_) | Discovery::Consul(_) => poll(&state, &discovery).await,
+        }
+    });
+}
+
+/// Refresh the pool on a fixed interval for sources that can't be watched.
+async fn poll(state: &AppState, discovery: &Discovery) {
+    let interval = Duration::from_secs(state.config.discovery_refresh_secs.max(1));
+
+    loop {
+        match discover_once(discovery).await {
+            Ok(urls) => update_pool(state, discovery, urls),
+            // Keep serving from the last known pool until discovery recovers
+            Err(e) => warn!("Discovery {} failed: {:#}", discovery, e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Replace the pool contents and log what changed.
+fn update_pool(state: &AppState, source: &Discovery, urls: Vec<String>) {
+    if urls.is_empty() {
+        warn!("Discovery {} returned no ready backends", source);
+    }
+    if state.backends.replace(urls) {
+        let urls: Vec<String> = state
+            .backends
+            .snapshot()
+            .iter()
+            .map(|b| b.url.clone())
+            .collect();
+        info!("🔁 Backend pool from {} is now {:?}", source, urls);
+    }
+}
+
+/// Delay before retrying after a discovery failure.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
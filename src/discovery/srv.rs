@@ -0,0 +1,51 @@
+use hickory_resolver::TokioAsyncResolver;
+use std::fmt;
+use std::str::FromStr;
+
+/// A DNS SRV name such as `_tei._tcp.example.com`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrvTarget {
+    pub name: String,
+}
+
+impl FromStr for SrvTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.is_empty() || value.contains('/') {
+            return Err(format!(
+                "'{}' must look like srv://_service._tcp.example.com",
+                value
+            ));
+        }
+        Ok(Self {
+            name: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for SrvTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// Resolve the SRV record and return one backend URL per target in the
+/// lowest (most preferred) priority group.
+pub async fn list(target: &SrvTarget) -> anyhow::Result<Vec<String>> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    let lookup = resolver.srv_lookup(target.name.as_str()).await?;
+
+    let Some(priority) = lookup.iter().map(|srv| srv.priority()).min() else {
+        return Ok(Vec::new());
+    };
+
+    Ok(lookup
+        .iter()
+        .filter(|srv| srv.priority() == priority)
+        .map(|srv| {
+            let host = srv.target().to_utf8();
+            format!("http://{}:{}", host.trim_end_matches('.'), srv.port())
+        })
+        .collect())
+}
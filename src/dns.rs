@@ -0,0 +1,125 @@
+use crate::state::{self, AppState};
+use log::{debug, info, warn};
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Minimum gap between two resolutions, so a burst of connection failures
+/// doesn't turn into a burst of DNS queries.
+const MIN_REFRESH_GAP: Duration = Duration::from_secs(1);
+
+/// Last addresses resolved for the TEI host, and a wakeup used to force a
+/// refresh after a connection failure.
+#[derive(Default)]
+pub struct DnsCache {
+    addrs: Mutex<Vec<SocketAddr>>,
+    wakeup: Notify,
+    /// When the client was last rebuilt by [`reconnect`].
+    reconnected: Mutex<Option<Instant>>,
+}
+
+impl DnsCache {
+    /// Ask the refresh task to re-resolve as soon as possible.
+    pub fn invalidate(&self) {
+        self.wakeup.notify_one();
+    }
+}
+
+/// Swap in a fresh upstream client, so connections pooled to a backend
+/// that has restarted are dropped instead of each failing in turn. At most
+/// once every [`MIN_REFRESH_GAP`], however many calls fail together.
+pub fn reconnect(state: &AppState) {
+    {
+        let mut reconnected = state.dns.reconnected.lock().unwrap();
+        if reconnected.is_some_and(|at| at.elapsed() < MIN_REFRESH_GAP) {
+            return;
+        }
+        *reconnected = Some(Instant::now());
+    }
+
+    let addrs = state.dns.addrs.lock().unwrap().clone();
+    let host = endpoint_host(&state.config.tei_endpoint).map(|(host, _)| host);
+    let resolved = match &host {
+        Some(host) if !addrs.is_empty() => Some((host.as_str(), addrs.as_slice())),
+        _ => None,
+    };
+    match state::build_client(&state.config, resolved) {
+        Ok(client) => {
+            debug!("🔌 Dropped pooled connections to TEI");
+            state.replace_client(client);
+        }
+        Err(e) => warn!("Failed to rebuild HTTP client: {}", e),
+    }
+}
+
+/// Start the background re-resolution task. Does nothing when refreshing is
+/// disabled, backends come from discovery, or the endpoint is an IP literal.
+pub fn spawn_refresh(state: Arc<AppState>) {
+    let interval = state.config.dns_refresh_secs;
+    if interval == 0 || state.config.discovery.is_some() {
+        return;
+    }
+
+    let Some((host, port)) = endpoint_host(&state.config.tei_endpoint) else {
+        return;
+    };
+    if host.parse::<IpAddr>().is_ok() {
+        return;
+    }
+
+    info!(
+        "Re-resolving TEI host {} every {}s and on connection failures",
+        host, interval
+    );
+
+    tokio::spawn(async move {
+        loop {
+            refresh(&state, &host, port).await;
+
+            tokio::time::sleep(MIN_REFRESH_GAP).await;
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(interval)) => {}
+                _ = state.dns.wakeup.notified() => {
+                    debug!("Connection failure, re-resolving {}", host);
+                }
+            }
+        }
+    });
+}
+
+async fn refresh(state: &AppState, host: &str, port: u16) {
+    let mut addrs: Vec<SocketAddr> = match tokio::net::lookup_host((host, port)).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            warn!("Failed to resolve TEI host {}: {}", host, e);
+            return;
+        }
+    };
+    if addrs.is_empty() {
+        warn!("TEI host {} resolved to no addresses", host);
+        return;
+    }
+    addrs.sort();
+    addrs.dedup();
+
+    if *state.dns.addrs.lock().unwrap() == addrs {
+        return;
+    }
+
+    match state::build_client(&state.config, Some((host, &addrs))) {
+        Ok(client) => {
+            info!("🔁 TEI host {} now resolves to {:?}", host, addrs);
+            state.replace_client(client);
+            *state.dns.addrs.lock().unwrap() = addrs;
+        }
+        Err(e) => warn!("Failed to rebuild HTTP client: {}", e),
+    }
+}
+
+fn endpoint_host(endpoint: &str) -> Option<(String, u16)> {
+    let url = Url::parse(endpoint).ok()?;
+    let host = url.host_str()?.trim_matches(['[', ']']).to_string();
+    Some((host, url.port_or_known_default()?))
+}
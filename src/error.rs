@@ -0,0 +1,209 @@
+use crate::backends::BackendStatus;
+use crate::metrics::ErrorStats;
+use log::error;
+use serde::Serialize;
+use std::time::Duration;
+use warp::{reject, Reply};
+
+#[derive(Serialize, Debug)]
+struct ErrorResponse<'a> {
+    error: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backends: Option<&'a [BackendStatus]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+}
+
+// Custom error types
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    InvalidJson(String),
+    PayloadTooLarge(String),
+    /// The request's estimated tokens, over the most allowed.
+    TooManyTokens(usize, usize),
+    UnsupportedMediaType(String),
+    /// The client took too long to send its request.
+    RequestTimeout(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    /// TEI is overloaded; the client may retry after the given wait.
+    TooManyRequests(String, Option<Duration>),
+    /// TEI answered, but rejected the call or with something unusable.
+    TEIError(String),
+    /// TEI couldn't be reached, or failed with a server error.
+    TEIUnavailable(String),
+    /// Fetching candidates for `/retrieve_rerank` failed.
+    Retrieval(String),
+    /// TEI didn't answer in time.
+    Timeout(String),
+    Unavailable(String),
+    /// Every backend is failing, or there are none.
+    NoBackends(Vec<BackendStatus>),
+    Internal(String),
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::BadRequest(msg)
+            | ApiError::InvalidJson(msg)
+            | ApiError::PayloadTooLarge(msg)
+            | ApiError::UnsupportedMediaType(msg)
+            | ApiError::RequestTimeout(msg)
+            | ApiError::Unauthorized(msg)
+            | ApiError::Forbidden(msg)
+            | ApiError::Conflict(msg)
+            | ApiError::TooManyRequests(msg, _)
+            | ApiError::TEIError(msg)
+            | ApiError::TEIUnavailable(msg)
+            | ApiError::Retrieval(msg)
+            | ApiError::Timeout(msg)
+            | ApiError::Unavailable(msg)
+            | ApiError::Internal(msg) => f.write_str(msg),
+            ApiError::NoBackends(_) => f.write_str("No TEI backends available"),
+            ApiError::TooManyTokens(estimated, max) => write!(
+                f,
+                "Request is too large: about {} tokens, max: {}",
+                estimated, max
+            ),
+        }
+    }
+}
+
+impl ApiError {
+    /// HTTP status and machine-readable error code sent to the client.
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            ApiError::BadRequest(_) => (400, "bad_request"),
+            ApiError::InvalidJson(_) => (400, "invalid_json"),
+            ApiError::PayloadTooLarge(_) => (413, "payload_too_large"),
+            ApiError::TooManyTokens(..) => (400, "too_many_tokens"),
+            ApiError::UnsupportedMediaType(_) => (415, "unsupported_media_type"),
+            ApiError::RequestTimeout(_) => (408, "request_timeout"),
+            ApiError::Unauthorized(_) => (401, "unauthorized"),
+            ApiError::Forbidden(_) => (403, "forbidden"),
+            ApiError::Conflict(_) => (409, "conflict"),
+            ApiError::TooManyRequests(..) => (429, "too_many_requests"),
+            ApiError::TEIError(_) | ApiError::TEIUnavailable(_) => (502, "tei_error"),
+            ApiError::Retrieval(_) => (502, "retrieval_error"),
+            ApiError::Timeout(_) => (504, "timeout"),
+            ApiError::Unavailable(_) => (503, "service_unavailable"),
+            ApiError::NoBackends(_) => (503, "no_backends_available"),
+            ApiError::Internal(_) => (500, "internal_error"),
+        }
+    }
+
+    /// How long the client should wait before retrying, sent as
+    /// `Retry-After`.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::TooManyRequests(_, retry_after) => *retry_after,
+            // The earliest any backend comes back
+            ApiError::NoBackends(backends) => backends
+                .iter()
+                .filter_map(|b| b.retry_in_secs)
+                .min()
+                .map(Duration::from_secs),
+            _ => None,
+        }
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+// Error handling
+pub async fn handle_rejection(
+    err: warp::Rejection,
+    errors: &ErrorStats,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let mut retry_after = None;
+    let mut backends = None;
+    let mut tokens = None;
+    let (code, message, error_type) = if err.is_not_found() {
+        (404, "Not Found".to_string(), "not_found")
+    } else if let Some(api_error) = err.find::<ApiError>() {
+        let (code, error_type) = api_error.status();
+        retry_after = api_error.retry_after();
+        match api_error {
+            ApiError::NoBackends(statuses) => backends = Some(statuses.as_slice()),
+            ApiError::TooManyTokens(estimated, max) => tokens = Some((*estimated, *max)),
+            _ => {}
+        }
+        (code, api_error.to_string(), error_type)
+    } else if err
+        .find::<warp::filters::body::BodyDeserializeError>()
+        .is_some()
+    {
+        (
+            400,
+            "Invalid JSON in request body".to_string(),
+            "invalid_json",
+        )
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
+        (
+            405,
+            "Method not allowed for this endpoint".to_string(),
+            "method_not_allowed",
+        )
+    } else if err.find::<reject::UnsupportedMediaType>().is_some() {
+        (
+            415,
+            "Unsupported content type, expected application/json".to_string(),
+            "unsupported_media_type",
+        )
+    } else if err.find::<reject::PayloadTooLarge>().is_some() {
+        (
+            413,
+            "Request body too large".to_string(),
+            "payload_too_large",
+        )
+    } else if err.find::<reject::LengthRequired>().is_some() {
+        (
+            411,
+            "A Content-Length header is required".to_string(),
+            "length_required",
+        )
+    } else if let Some(e) = err.find::<reject::MissingHeader>() {
+        (400, e.to_string(), "bad_request")
+    } else if let Some(e) = err.find::<reject::InvalidHeader>() {
+        (400, e.to_string(), "bad_request")
+    } else if let Some(e) = err.find::<reject::InvalidQuery>() {
+        (400, e.to_string(), "bad_request")
+    } else {
+        error!("Unhandled rejection: {:?}", err);
+        (500, "Internal Server Error".to_string(), "internal_error")
+    };
+
+    errors.record(error_type);
+
+    // Whole seconds, rounded up so clients don't come back early
+    let retry_after_secs =
+        retry_after.map(|wait| wait.as_secs() + u64::from(wait.subsec_nanos() > 0));
+    let error_response = ErrorResponse {
+        error: error_type.to_string(),
+        message,
+        retry_after_secs,
+        backends,
+        estimated_tokens: tokens.map(|(estimated, _)| estimated),
+        max_tokens: tokens.map(|(_, max)| max),
+    };
+
+    let mut response = warp::reply::with_status(
+        warp::reply::json(&error_response),
+        warp::http::StatusCode::from_u16(code).unwrap(),
+    )
+    .into_response();
+    if let Some(secs) = retry_after_secs {
+        response
+            .headers_mut()
+            .insert(warp::http::header::RETRY_AFTER, secs.into());
+    }
+    Ok(response)
+}
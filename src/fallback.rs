@@ -0,0 +1,126 @@
+use crate::backends::BackendPool;
+use crate::bm25;
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank;
+use crate::state::AppState;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How a response was scored when TEI couldn't rerank it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Degraded {
+    /// Cosine similarity of the query's and documents' embeddings.
+    EmbeddingSimilarity,
+    /// BM25 keyword matching in the proxy itself.
+    Bm25,
+}
+
+impl Degraded {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Degraded::EmbeddingSimilarity => "embedding_similarity",
+            Degraded::Bm25 => "bm25",
+        }
+    }
+}
+
+impl fmt::Display for Degraded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Score `texts` some other way after TEI failed to rerank them with
+/// `error`, as configured: by embedding similarity when TEI answered but
+/// couldn't rerank, or with BM25 when it couldn't be reached, timed out or
+/// failed with a server error, or the embeddings failed too. Otherwise,
+/// like when TEI rejected the request, `error` is passed on.
+pub async fn score(
+    state: &AppState,
+    ctx: &RequestContext,
+    pool: &BackendPool,
+    query: &str,
+    texts: &[&str],
+    model: Option<&str>,
+    error: ApiError,
+) -> Result<(Degraded, Vec<f64>), ApiError> {
+    let config = &state.config;
+    if config.embed_fallback && matches!(error, ApiError::TEIError(_) | ApiError::TEIUnavailable(_))
+    {
+        warn!(
+            "⚠️ TEI failed to rerank, falling back to embedding similarity: {}",
+            error
+        );
+        match embedding_similarity(state, ctx, pool, query, texts, model).await {
+            Ok(scores) => return Ok((Degraded::EmbeddingSimilarity, scores)),
+            Err(e) => error!("Embedding fallback failed too: {}", e),
+        }
+    }
+    let unavailable = matches!(
+        error,
+        ApiError::TEIUnavailable(_) | ApiError::Timeout(_) | ApiError::NoBackends(_)
+    );
+    if config.bm25_fallback && unavailable {
+        warn!("⚠️ TEI is unavailable, falling back to BM25: {}", error);
+        return Ok((Degraded::Bm25, bm25::scores(query, texts)));
+    }
+    Err(error)
+}
+
+/// Score `texts` by the cosine similarity of their embeddings to the
+/// query's, from `/embed` on one of `pool`'s backends, e.g. when it turns
+/// out to serve an embedding model rather than a reranker.
+async fn embedding_similarity(
+    state: &AppState,
+    ctx: &RequestContext,
+    pool: &BackendPool,
+    query: &str,
+    texts: &[&str],
+    model: Option<&str>,
+) -> Result<Vec<f64>, String> {
+    let backend = pool
+        .pick(query)
+        .ok_or_else(|| "no TEI backends available".to_string())?;
+    let inputs: Vec<&str> = std::iter::once(query)
+        .chain(texts.iter().copied())
+        .collect();
+    let request = state
+        .client()
+        .post(format!("{}/embed", backend.url))
+        .timeout(state.config.limits_for(ctx.route, model).timeout)
+        .json(&serde_json::json!({ "inputs": inputs, "truncate": true }));
+    let request = rerank::authenticate(state, request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("TEI returned {}", response.status()));
+    }
+
+    // One embedding per input, the query's first
+    let embeddings: Vec<Vec<f32>> = response.json().await.map_err(|e| e.to_string())?;
+    match embeddings.split_first() {
+        Some((query, documents)) if documents.len() == texts.len() => Ok(documents
+            .iter()
+            .map(|document| cosine(query, document))
+            .collect()),
+        _ => Err("TEI returned the wrong number of embeddings".to_string()),
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f64 {
+    let (mut dot, mut a_norm, mut b_norm) = (0.0, 0.0, 0.0);
+    for (&x, &y) in a.iter().zip(b) {
+        let (x, y) = (f64::from(x), f64::from(y));
+        dot += x * y;
+        a_norm += x * x;
+        b_norm += y * y;
+    }
+    if a_norm == 0.0 || b_norm == 0.0 {
+        return 0.0;
+    }
+    dot / (a_norm.sqrt() * b_norm.sqrt())
+}
@@ -0,0 +1,247 @@
+use crate::config::Config;
+use crate::error::ApiError;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use warp::Filter;
+
+/// What answers TEI's in place of a malformed upstream response.
+const MALFORMED_RESPONSE: &str = "[{\"index\": 0, \"score\": ";
+
+/// Faults to inject into upstream calls.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FaultSettings {
+    /// Share of upstream calls, between 0 and 1, that fail as if TEI had
+    /// answered `500`.
+    #[serde(default)]
+    pub error_rate: f64,
+    /// Added to every upstream call.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Share of upstream calls, between 0 and 1, whose response is
+    /// replaced by truncated JSON.
+    #[serde(default)]
+    pub malformed_rate: f64,
+}
+
+impl FaultSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.error_rate) {
+            return Err("error_rate: must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.malformed_rate) {
+            return Err("malformed_rate: must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        self.error_rate > 0.0 || self.latency_ms > 0 || self.malformed_rate > 0.0
+    }
+}
+
+/// Counts faults of one kind, and picks the calls that get one: an evenly
+/// spread share of them, so a test run sees the configured rate.
+#[derive(Debug, Default)]
+struct Spread {
+    seen: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl Spread {
+    fn pick(&self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        let picked = ((n + 1.0) * rate).floor() > (n * rate).floor();
+        if picked {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        picked
+    }
+}
+
+/// Failures, latency and malformed responses injected into upstream calls,
+/// so client-side retries and timeouts can be tested without breaking a
+/// real TEI. Only when `fault_injection` is on; the settings can then be
+/// changed at runtime through `/admin/faults`. Injected faults don't count
+/// against backend health.
+#[derive(Debug)]
+pub struct Faults {
+    enabled: bool,
+    settings: RwLock<FaultSettings>,
+    errors: Spread,
+    delayed: AtomicU64,
+    malformed: Spread,
+}
+
+impl Faults {
+    pub fn new(enabled: bool, settings: FaultSettings) -> Self {
+        if enabled && settings.is_active() {
+            warn!("Injecting faults into upstream calls: {:?}", settings);
+        }
+        Self {
+            enabled,
+            settings: RwLock::new(settings),
+            errors: Spread::default(),
+            delayed: AtomicU64::new(0),
+            malformed: Spread::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn settings(&self) -> FaultSettings {
+        *self.settings.read().unwrap()
+    }
+
+    pub fn set(&self, settings: FaultSettings) -> Result<(), String> {
+        settings.validate()?;
+        if settings.is_active() {
+            warn!("Injecting faults into upstream calls: {:?}", settings);
+        } else {
+            warn!("No longer injecting faults");
+        }
+        *self.settings.write().unwrap() = settings;
+        Ok(())
+    }
+
+    /// Before an upstream call: wait out the injected latency, then fail
+    /// the call if it's one of those picked to.
+    pub async fn before_call(&self) -> Result<(), ApiError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let settings = self.settings();
+        if settings.latency_ms > 0 {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(settings.latency_ms)).await;
+        }
+        if self.errors.pick(settings.error_rate) {
+            warn!("Injecting an upstream error");
+            return Err(ApiError::TEIUnavailable(
+                "TEI service error 500 Internal Server Error: injected fault".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The upstream's response, or malformed JSON in its place if this call
+    /// is one of those picked to get it.
+    pub fn response(&self, text: String) -> String {
+        if self.enabled && self.malformed.pick(self.settings().malformed_rate) {
+            warn!("Injecting a malformed upstream response");
+            return MALFORMED_RESPONSE.to_string();
+        }
+        text
+    }
+
+    /// Faults injected so far, by kind.
+    pub fn injected(&self) -> Vec<(String, u64)> {
+        vec![
+            (
+                "error".to_string(),
+                self.errors.injected.load(Ordering::Relaxed),
+            ),
+            ("latency".to_string(), self.delayed.load(Ordering::Relaxed)),
+            (
+                "malformed".to_string(),
+                self.malformed.injected.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+/// Latency added to a route's responses: a fixed number of milliseconds
+/// (`500`), or a random one in a range (`200-3000`).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Delay {
+    min_ms: u64,
+    max_ms: u64,
+}
+
+impl Delay {
+    /// How long to wait this time.
+    pub fn pick(&self) -> Duration {
+        let spread = self.max_ms - self.min_ms;
+        // RandomState is randomly keyed, which is plenty for jitter
+        let offset = match spread {
+            0 => 0,
+            _ => RandomState::new().build_hasher().finish() % (spread + 1),
+        };
+        Duration::from_millis(self.min_ms + offset)
+    }
+}
+
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parse = |ms: &str| {
+            ms.trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid delay '{}' ({})", spec, e))
+        };
+        let (min_ms, max_ms) = match spec.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(spec)?, parse(spec)?),
+        };
+        if min_ms > max_ms {
+            return Err(format!("delay '{}' ends before it starts", spec));
+        }
+        Ok(Self { min_ms, max_ms })
+    }
+}
+
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min_ms == self.max_ms {
+            write!(f, "{}", self.min_ms)
+        } else {
+            write!(f, "{}-{}", self.min_ms, self.max_ms)
+        }
+    }
+}
+
+impl TryFrom<String> for Delay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Delay> for String {
+    fn from(value: Delay) -> Self {
+        value.to_string()
+    }
+}
+
+/// Wait out the delay configured for `route` in `response_delays`, if
+/// any, before the request is handled. Passes requests straight through
+/// otherwise.
+pub fn delay(
+    config: &Config,
+    route: &str,
+) -> impl Filter<Extract = (), Error = Infallible> + Clone {
+    let delay = config.response_delays.get(route).copied();
+    warp::any()
+        .then(move || async move {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay.pick()).await;
+            }
+        })
+        .untuple_one()
+}
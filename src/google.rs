@@ -0,0 +1,74 @@
+use log::debug;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Where GCE, GKE (with Workload Identity) and Cloud Run hand out access
+/// tokens for the attached service account.
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// Tokens are replaced this long before they expire, so none runs out
+/// while a request is on its way.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// How long the metadata server gets to answer.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// OAuth access tokens for Google APIs, such as the Vertex AI ranking API,
+/// from the metadata server. Cached, and refreshed shortly before they
+/// expire.
+#[derive(Debug)]
+pub struct GoogleAuth {
+    /// The metadata server is link-local and never behind the outbound
+    /// proxy, so it gets a client of its own.
+    client: reqwest::Client,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GoogleAuth {
+    pub fn new() -> reqwest::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .no_proxy()
+                .timeout(TOKEN_TIMEOUT)
+                .build()?,
+            token: Mutex::default(),
+        })
+    }
+
+    /// A valid access token. Concurrent callers wait for a single refresh.
+    pub async fn token(&self) -> Result<String, String> {
+        let mut token = self.token.lock().await;
+        if let Some((value, expires)) = token.as_ref() {
+            if Instant::now() + REFRESH_MARGIN < *expires {
+                return Ok(value.clone());
+            }
+        }
+
+        let response = self
+            .client
+            .get(METADATA_TOKEN_URL)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("metadata server returned {}", response.status()));
+        }
+        let fresh: Token = response.json().await.map_err(|e| e.to_string())?;
+        debug!(
+            "🔑 Refreshed Google access token, valid for {}s",
+            fresh.expires_in
+        );
+        let expires = Instant::now() + Duration::from_secs(fresh.expires_in);
+        *token = Some((fresh.access_token.clone(), expires));
+        Ok(fresh.access_token)
+    }
+}
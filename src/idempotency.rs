@@ -0,0 +1,282 @@
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank::{OpenWebUIRequest, OpenWebUIResponse};
+use crate::retrieve::RetrieveRequest;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest `Idempotency-Key` accepted.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Identifies an `Idempotency-Key` within the API key that sent it.
+type StoreKey = [u8; 32];
+
+/// Responses to requests that carried an `Idempotency-Key`, kept for a TTL
+/// so a retried submission gets the stored response instead of being
+/// scored again.
+#[derive(Debug)]
+pub struct IdempotencyStore {
+    /// Zero disables the store.
+    ttl: Duration,
+    /// Stored responses kept at most; 0 for no cap.
+    max_entries: usize,
+    state: Mutex<Store>,
+    replays: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    entries: HashMap<StoreKey, Entry>,
+    /// Stored responses, oldest first.
+    expiry: VecDeque<(Instant, StoreKey)>,
+}
+
+#[derive(Debug)]
+enum Entry {
+    InFlight,
+    Done {
+        fingerprint: StoreKey,
+        response: Arc<OpenWebUIResponse>,
+        expires_at: Instant,
+    },
+}
+
+/// Requests that can carry an `Idempotency-Key`.
+pub trait Idempotent {
+    /// Identifies what the response depends on, to tell a retry from a
+    /// different request sent under the same key.
+    fn fingerprint(&self) -> StoreKey;
+}
+
+/// What to do with a request carrying an `Idempotency-Key`.
+pub enum Claim<'a> {
+    /// The request was already answered; send this again.
+    Replay(Arc<OpenWebUIResponse>),
+    /// First time this key is seen; process the request and complete it.
+    Fresh(Pending<'a>),
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            state: Mutex::new(Store::default()),
+            replays: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Responses served again instead of being recomputed.
+    pub fn replays(&self) -> u64 {
+        self.replays.load(Ordering::Relaxed)
+    }
+
+    /// Look up `key` for the client in `ctx`. Reusing a key for a different
+    /// request, or while the first one is still being processed, is an
+    /// error.
+    pub fn begin(
+        &self,
+        ctx: &RequestContext,
+        key: &str,
+        req: &impl Idempotent,
+    ) -> Result<Claim<'_>, ApiError> {
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return Err(ApiError::BadRequest(format!(
+                "Idempotency-Key must be 1 to {} characters",
+                MAX_KEY_LENGTH
+            )));
+        }
+
+        let store_key = store_key(ctx, key);
+        let request = req.fingerprint();
+
+        let mut state = self.state.lock().unwrap();
+        state.purge(Instant::now());
+
+        match state.entries.get(&store_key) {
+            Some(Entry::InFlight) => Err(ApiError::Conflict(
+                "A request with this Idempotency-Key is still being processed".to_string(),
+            )),
+            Some(Entry::Done { fingerprint, .. }) if *fingerprint != request => {
+                Err(ApiError::BadRequest(
+                    "Idempotency-Key was already used for a different request".to_string(),
+                ))
+            }
+            Some(Entry::Done { response, .. }) => {
+                self.replays.fetch_add(1, Ordering::Relaxed);
+                Ok(Claim::Replay(response.clone()))
+            }
+            None => {
+                state.entries.insert(store_key, Entry::InFlight);
+                Ok(Claim::Fresh(Pending {
+                    store: self,
+                    key: store_key,
+                    fingerprint: request,
+                    done: false,
+                }))
+            }
+        }
+    }
+}
+
+impl Store {
+    fn purge(&mut self, now: Instant) {
+        while let Some(&(expires_at, key)) = self.expiry.front() {
+            if expires_at > now {
+                break;
+            }
+            self.expiry.pop_front();
+            self.remove_done(key, expires_at);
+        }
+    }
+
+    /// Drop a stored response unless the key has been reused since.
+    fn remove_done(&mut self, key: StoreKey, expires_at: Instant) {
+        if matches!(
+            self.entries.get(&key),
+            Some(Entry::Done { expires_at: at, .. }) if *at == expires_at
+        ) {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// A request being processed under an `Idempotency-Key`. Dropping it
+/// without completing (e.g. on error) frees the key for a retry.
+pub struct Pending<'a> {
+    store: &'a IdempotencyStore,
+    key: StoreKey,
+    fingerprint: StoreKey,
+    done: bool,
+}
+
+impl Pending<'_> {
+    /// Keep `response` for replays until the TTL runs out.
+    pub fn complete(mut self, response: Arc<OpenWebUIResponse>) {
+        self.done = true;
+        let expires_at = Instant::now() + self.store.ttl;
+
+        let mut state = self.store.state.lock().unwrap();
+        if self.store.max_entries > 0 {
+            while state.expiry.len() >= self.store.max_entries {
+                let Some((at, key)) = state.expiry.pop_front() else {
+                    break;
+                };
+                state.remove_done(key, at);
+            }
+        }
+        state.entries.insert(
+            self.key,
+            Entry::Done {
+                fingerprint: self.fingerprint,
+                response,
+                expires_at,
+            },
+        );
+        state.expiry.push_back((expires_at, self.key));
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.store.state.lock().unwrap().entries.remove(&self.key);
+        }
+    }
+}
+
+/// Scope the key to the API key that sent it so clients can't read each
+/// other's responses.
+fn store_key(ctx: &RequestContext, key: &str) -> StoreKey {
+    let scope = ctx
+        .api_key
+        .as_ref()
+        .map_or("", |api_key| api_key.key.as_str());
+    hash([scope, key])
+}
+
+impl Idempotent for OpenWebUIRequest {
+    fn fingerprint(&self) -> StoreKey {
+        fingerprint(self)
+    }
+}
+
+/// A `/retrieve_rerank` response is claimed before the search runs, so it
+/// is told apart by the request rather than the candidates found.
+impl Idempotent for RetrieveRequest {
+    fn fingerprint(&self) -> StoreKey {
+        let top_k = self.top_k.map(|k| k.to_string()).unwrap_or_default();
+        let top_n = self.top_n.map(|n| n.to_string()).unwrap_or_default();
+        let filter = serde_json::to_string(&self.filter).unwrap_or_default();
+        let include_meta = self.include_meta.to_string();
+        hash([
+            // Not to be mistaken for a `/rerank` request under the same key
+            "retrieve",
+            self.model.as_deref().unwrap_or(""),
+            self.query.as_str(),
+            top_k.as_str(),
+            top_n.as_str(),
+            filter.as_str(),
+            include_meta.as_str(),
+        ])
+    }
+}
+
+fn fingerprint(req: &OpenWebUIRequest) -> StoreKey {
+    let top_n = req.top_n.map(|n| n.to_string()).unwrap_or_default();
+    let include_meta = req.include_meta.to_string();
+    let return_documents = req.return_documents.to_string();
+    let extra = serde_json::to_string(&req.extra).unwrap_or_default();
+    let strip_markup = format!("{:?}", req.strip_markup);
+    let order = format!("{:?} {:?}", req.order, req.tie_break);
+    let page = format!("{} {:?}", req.offset, req.limit);
+    let retrieval_scores: Vec<String> = req
+        .documents
+        .iter()
+        .map(|d| {
+            d.retrieval_score()
+                .map(|s| s.to_string())
+                .unwrap_or_default()
+        })
+        .collect();
+    // Echoed back on results, so part of what the response depends on
+    let annotations: Vec<String> = req
+        .documents
+        .iter()
+        .map(|d| serde_json::to_string(&(d.id(), d.metadata())).unwrap_or_default())
+        .collect();
+    hash(
+        [
+            req.model.as_deref().unwrap_or(""),
+            req.query.as_str(),
+            top_n.as_str(),
+            include_meta.as_str(),
+            return_documents.as_str(),
+            extra.as_str(),
+            strip_markup.as_str(),
+            order.as_str(),
+            page.as_str(),
+        ]
+        .into_iter()
+        .chain(req.documents.iter().map(|d| d.text()))
+        .chain(retrieval_scores.iter().map(String::as_str))
+        .chain(annotations.iter().map(String::as_str)),
+    )
+}
+
+fn hash<'a>(parts: impl IntoIterator<Item = &'a str>) -> StoreKey {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // Length-prefixed so ("ab", "c") and ("a", "bc") differ
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize().into()
+}
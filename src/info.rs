@@ -0,0 +1,78 @@
+use crate::backends::Backend;
+use crate::state::AppState;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a backend gets to answer `/info`.
+const INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The limits a TEI instance reports on `/info`, matching its
+/// `--max-client-batch-size` and the model's maximum input length.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TeiInfo {
+    pub max_client_batch_size: usize,
+    /// In tokens, for the query and document together.
+    pub max_input_length: usize,
+}
+
+/// Start reading every backend's limits from its `/info`, at startup and
+/// every `tei_info_refresh_secs` after.
+pub fn spawn(state: Arc<AppState>) {
+    let interval = state.config.tei_info_refresh_secs;
+    if interval == 0 {
+        return;
+    }
+    // Upstreams behind an adapter aren't TEI and have no `/info`
+    if state.config.tei_adapter.is_some() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let pools = std::iter::once(&state.backends)
+                .chain(state.language_backends.values())
+                .chain(&state.cascade);
+            for pool in pools {
+                for backend in pool.snapshot().iter() {
+                    refresh(&state, backend).await;
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        }
+    });
+}
+
+async fn refresh(state: &AppState, backend: &Backend) {
+    match fetch(state, &backend.url).await {
+        Ok(info) => {
+            if backend.info() != Some(info) {
+                info!(
+                    "📏 TEI backend {} accepts {} documents per request, {} tokens per input",
+                    backend.url, info.max_client_batch_size, info.max_input_length
+                );
+            } else {
+                debug!("TEI backend {} limits unchanged", backend.url);
+            }
+            backend.set_info(info);
+        }
+        // Keep enforcing the last known limits until the backend answers
+        Err(e) => warn!("Failed to read {}/info: {}", backend.url, e),
+    }
+}
+
+async fn fetch(state: &AppState, url: &str) -> Result<TeiInfo, String> {
+    let mut request = state
+        .client()
+        .get(format!("{}/info", url))
+        .timeout(INFO_TIMEOUT);
+    if let Some(token) = &state.config.tei_api_key {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("TEI returned {}", response.status()));
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,209 @@
+use crate::error::ApiError;
+use futures_util::StreamExt;
+use log::debug;
+use serde::de::DeserializeOwned;
+use std::io::{BufReader, Read};
+use std::pin::pin;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use warp::hyper::body::{Buf, Bytes};
+use warp::Filter;
+
+/// Chunks buffered between the connection and the streaming parser; keeps
+/// memory bounded when the client sends faster than we parse.
+const STREAM_CHANNEL_CHUNKS: usize = 8;
+
+/// Parse a JSON request body, rejecting bodies over `max_bytes` (0 for no
+/// limit) with 413 and ones that take longer than `timeout` to arrive with
+/// 408.
+///
+/// Bodies up to `stream_threshold` bytes are read whole and go through
+/// [`decode`], so the `simd-json` feature applies. Larger bodies, or ones
+/// without a `Content-Length`, are parsed incrementally with serde_json as
+/// they arrive so the raw body is never held in memory alongside the parsed
+/// request. Either way, the filter only yields once the whole body is parsed.
+pub fn body<T: DeserializeOwned + Send + 'static>(
+    stream_threshold: u64,
+    max_bytes: u64,
+    timeout: Option<Duration>,
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::body::stream())
+        .and_then(move |length: Option<u64>, stream| async move {
+            if max_bytes > 0 && length.is_some_and(|length| length > max_bytes) {
+                return Err(too_large(max_bytes));
+            }
+
+            let read = async {
+                if length.is_some_and(|length| length <= stream_threshold) {
+                    let bytes = read_all(stream, max_bytes).await?;
+                    Ok(decode(&bytes))
+                } else {
+                    decode_streaming(stream, max_bytes).await
+                }
+            };
+            let result = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, read)
+                    .await
+                    .map_err(|_| timed_out(timeout))??,
+                None => read.await?,
+            };
+
+            result.map_err(|e| {
+                debug!("Invalid JSON in request body: {}", e);
+                warp::reject::custom(ApiError::InvalidJson(
+                    "Invalid JSON in request body".to_string(),
+                ))
+            })
+        })
+}
+
+/// Reject requests whose `Content-Type` isn't JSON with 415. With `strict`
+/// off anything goes, for clients that send JSON under the wrong type.
+pub fn content_type(strict: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and_then(move |content_type: Option<String>| async move {
+            if !strict || content_type.as_deref().is_some_and(is_json) {
+                return Ok(());
+            }
+            let found = match content_type {
+                Some(content_type) => format!("'{}'", content_type),
+                None => "none".to_string(),
+            };
+            Err(warp::reject::custom(ApiError::UnsupportedMediaType(
+                format!(
+                    "Unsupported content type {}, expected application/json or application/*+json",
+                    found
+                ),
+            )))
+        })
+        .untuple_one()
+}
+
+/// `application/json` or a `+json` type, with any parameters.
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || essence
+            .strip_prefix("application/")
+            .is_some_and(|subtype| subtype.ends_with("+json"))
+}
+
+/// Deserialize JSON with serde_json.
+#[cfg(not(feature = "simd-json"))]
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(bytes).map_err(|e| e.to_string())
+}
+
+/// Deserialize JSON with simd-json, which parses in place and so works on a
+/// private copy of the input.
+#[cfg(feature = "simd-json")]
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    let mut owned = bytes.to_vec();
+    simd_json::serde::from_slice(&mut owned).map_err(|e| e.to_string())
+}
+
+fn too_large(max_bytes: u64) -> warp::Rejection {
+    warp::reject::custom(ApiError::PayloadTooLarge(format!(
+        "Request body too large, max: {} bytes",
+        max_bytes
+    )))
+}
+
+fn timed_out(timeout: Duration) -> warp::Rejection {
+    warp::reject::custom(ApiError::RequestTimeout(format!(
+        "Request body not received within {}s",
+        timeout.as_secs()
+    )))
+}
+
+fn read_failed(e: impl std::fmt::Display) -> warp::Rejection {
+    warp::reject::custom(ApiError::BadRequest(format!(
+        "Failed to read request body: {}",
+        e
+    )))
+}
+
+async fn read_all<B: Buf>(
+    stream: impl futures_util::Stream<Item = Result<B, warp::Error>>,
+    max_bytes: u64,
+) -> Result<Vec<u8>, warp::Rejection> {
+    let mut stream = pin!(stream);
+    let mut body = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk.map_err(read_failed)?;
+        if max_bytes > 0 && (body.len() + chunk.remaining()) as u64 > max_bytes {
+            return Err(too_large(max_bytes));
+        }
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            body.extend_from_slice(part);
+            let read = part.len();
+            chunk.advance(read);
+        }
+    }
+
+    Ok(body)
+}
+
+/// Feed the body to serde_json on a blocking thread chunk by chunk, as it
+/// arrives from the client.
+async fn decode_streaming<T: DeserializeOwned + Send + 'static, B: Buf>(
+    stream: impl futures_util::Stream<Item = Result<B, warp::Error>>,
+    max_bytes: u64,
+) -> Result<Result<T, String>, warp::Rejection> {
+    let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    let parser = tokio::task::spawn_blocking(move || {
+        let reader = BufReader::new(ChannelReader {
+            rx,
+            current: Bytes::new(),
+        });
+        serde_json::from_reader(reader).map_err(|e| e.to_string())
+    });
+
+    let mut stream = pin!(stream);
+    let mut received = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk.map_err(read_failed)?;
+        received += chunk.remaining() as u64;
+        if max_bytes > 0 && received > max_bytes {
+            return Err(too_large(max_bytes));
+        }
+        let bytes = chunk.copy_to_bytes(chunk.remaining());
+        // The parser hung up early, e.g. on a syntax error
+        if tx.send(bytes).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+
+    parser.await.map_err(read_failed)
+}
+
+/// Blocking `Read` over body chunks handed over from the async side.
+struct ChannelReader {
+    rx: mpsc::Receiver<Bytes>,
+    current: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.current = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        Ok(n)
+    }
+}
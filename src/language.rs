@@ -0,0 +1,32 @@
+use std::borrow::Cow;
+
+/// How much text language detection looks at; more adds cost, not
+/// accuracy.
+const SAMPLE_BYTES: usize = 2048;
+
+/// The ISO 639-3 code (e.g. `eng`) of the language the query and leading
+/// documents are written in, if it can be told reliably.
+pub fn detect(query: &str, documents: &[Cow<str>]) -> Option<&'static str> {
+    // Queries alone are often too short to tell
+    let mut sample = String::from(query);
+    for document in documents {
+        let room = SAMPLE_BYTES.saturating_sub(sample.len());
+        if room == 0 {
+            break;
+        }
+        let mut end = room.min(document.len());
+        while !document.is_char_boundary(end) {
+            end -= 1;
+        }
+        sample.push('\n');
+        sample.push_str(&document[..end]);
+    }
+
+    let info = whatlang::detect(&sample)?;
+    info.is_reliable().then(|| info.lang().code())
+}
+
+/// Whether `code` is a language [`detect`] can report.
+pub fn is_known(code: &str) -> bool {
+    whatlang::Lang::from_code(code).is_some()
+}
@@ -1,67 +1,113 @@
-use log::{debug, error, info, warn};
-use serde::{Deserialize, Serialize};
-use std::env;
-use warp::Filter;
-
-#[derive(Serialize, Deserialize, Debug)]
-struct OpenWebUIRequest {
-    query: String,
-    documents: Vec<String>,
-    #[serde(default)]
-    model: Option<String>,
-    #[serde(default)]
-    top_n: Option<usize>,
-}
-
-#[derive(Serialize, Debug)]
-struct TEIRequest {
-    query: String,
-    texts: Vec<String>,
-}
-
-#[derive(Deserialize, Debug)]
-struct TEIResponse(Vec<TEIRankResult>);
-
-#[derive(Deserialize, Debug)]
-struct TEIRankResult {
-    index: usize,
-    score: f64,
-}
-
-#[derive(Serialize, Debug)]
-struct OpenWebUIResponse {
-    results: Vec<RankResult>,
-}
-
-#[derive(Serialize, Debug)]
-struct RankResult {
-    index: usize,
-    relevance_score: f64,
-}
-
-#[derive(Serialize, Debug)]
-struct ErrorResponse {
-    error: String,
-    message: String,
-}
+mod adapter;
+mod admission;
+mod audit;
+mod auth;
+mod backends;
+mod bench;
+mod bm25;
+mod cache;
+mod calibration;
+mod cli;
+mod compat;
+mod config;
+mod context;
+mod discovery;
+mod dns;
+mod error;
+mod fallback;
+mod fault;
+mod google;
+mod idempotency;
+mod info;
+mod json;
+mod language;
+mod metrics;
+mod plugins;
+mod preprocess;
+mod queue;
+mod ratelimit;
+mod replay;
+mod rerank;
+mod retrieve;
+mod retry;
+mod rewrite;
+mod selftest;
+mod server;
+#[cfg(all(windows, feature = "windows-service"))]
+mod service;
+mod soak;
+mod state;
+mod systemd;
+mod tokenizer;
+mod trace;
+mod truncation;
+mod usage;
+mod webhook;
+
+use admission::{ClientSlot, Reservation};
+use compat::Format;
+use config::{RouteGroup, Routes};
+use context::RequestContext;
+use error::handle_rejection;
+use futures_util::future::BoxFuture;
+use idempotency::{Claim, Pending};
+use log::{error, info, warn};
+use rerank::{OpenWebUIRequest, OpenWebUIResponse};
+use state::AppState;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::{Filter, Reply};
 
 #[tokio::main]
-async fn main() {
-    // Initialize logger
-    env_logger::init();
-
-    // Get configuration from environment
-    let tei_endpoint =
-        env::var("TEI_ENDPOINT").unwrap_or_else(|_| "http://localhost:4000".to_string());
+async fn main() -> ExitCode {
+    cli::run().await
+}
 
-    let port: u16 = env::var("TEI_PROXY_PORT")
-        .unwrap_or_else(|_| "8000".to_string())
-        .parse()
-        .unwrap_or(8000);
+async fn serve(state: AppState) -> ExitCode {
+    let state = Arc::new(state);
 
     info!("Starting rerank proxy server");
-    info!("TEI endpoint: {}", tei_endpoint);
-    info!("Listening on port: {}", port);
+    match &state.config.discovery {
+        Some(discovery) => info!("TEI backends: discovered from {}", discovery),
+        None => info!("TEI endpoint: {}", state.config.tei_endpoint),
+    }
+    if let Some(name) = &state.config.upstream_tls_server_name {
+        info!("TEI endpoint's TLS server name: {}", name);
+    }
+    if state.config.upstream_tls_skip_verify {
+        warn!("⚠️ TEI certificates are not verified (TEI_UPSTREAM_TLS_SKIP_VERIFY)");
+    }
+    if !state.config.api_keys.is_empty() {
+        info!("API key authentication enabled");
+    }
+    if state.cache.is_enabled() {
+        info!(
+            "Score cache enabled ({} entries in memory, {} eviction)",
+            state.cache.capacity(),
+            state.config.cache_eviction
+        );
+    }
+    if state.config.memory_budget_bytes > 0 {
+        info!(
+            "Shedding requests above a memory budget of {} bytes",
+            state.config.memory_budget_bytes
+        );
+    }
+    if let Some(path) = &state.config.cache_path {
+        info!("Persisting cached scores to {}", path.display());
+    }
+    for (route, delay) in &state.config.response_delays {
+        warn!("Delaying {} responses by {}ms", route, delay);
+    }
+
+    dns::spawn_refresh(state.clone());
+    cache::spawn_compaction(state.clone());
+    admission::spawn_latency_monitor(state.clone());
+    discovery::spawn(state.clone());
+    info::spawn(state.clone());
+    metrics::spawn_statsd(state.clone());
 
     // Health check endpoint
     let health = warp::path("health").and(warp::get()).map(|| {
@@ -71,248 +117,591 @@ async fn main() {
         }))
     });
 
-    // Rerank endpoint with error handling
+    // Prometheus metrics
+    let metrics_state = state.clone();
+    let metrics = warp::path("metrics").and(warp::get()).map(move || {
+        warp::reply::with_header(
+            metrics::render(&metrics_state),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+    });
+
+    // Canned request through the whole rerank path, for after deploys
+    let selftest = warp::path("selftest")
+        .and(warp::get())
+        .and(auth::require_admin(state.clone()))
+        .and(context::extract(state.clone()))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .then(|ctx: RequestContext, state: Arc<AppState>| async move {
+            let report = selftest::run(&state, &ctx).await;
+            let status = if report.passed {
+                warp::http::StatusCode::OK
+            } else {
+                warp::http::StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(warp::reply::json(&report), status)
+        });
+
+    // Heaviest API keys over the rolling window
+    let top = warp::path!("admin" / "top")
+        .and(warp::get())
+        .and(auth::require_admin(state.clone()))
+        .and(warp::query::<TopQuery>())
+        .map({
+            let state = state.clone();
+            move |query: TopQuery| {
+                let order = query.by.unwrap_or_default();
+                warp::reply::json(&serde_json::json!({
+                    "window_secs": state.usage.window().as_secs(),
+                    "by": order,
+                    "keys": state.usage.top(order, query.limit.unwrap_or(10)),
+                }))
+            }
+        });
+
+    // Rerank endpoint
+    let listeners = state.config.listeners();
+    let ready_state = state.clone();
+    let routes_state = state.clone();
+    let stream_threshold = state.config.stream_body_threshold_bytes;
+    let rerank_body = state.config.max_request_bytes_for(RouteGroup::Rerank);
+    let tokenize_body = state.config.max_request_bytes_for(RouteGroup::Tokenize);
+    let admin_body = state.config.max_request_bytes_for(RouteGroup::Admin);
+    let body_timeout = timeout_secs(state.config.client_body_timeout_secs);
+    let header_timeout = timeout_secs(state.config.client_header_timeout_secs);
+    // What every rerank route starts with, whatever the request format
+    let rerank_request = warp::post()
+        .and(json::content_type(state.config.strict_content_type))
+        .and(admission::admit(state.clone()))
+        .and(warp::header::optional::<String>("idempotency-key"));
     let rerank = warp::path("rerank")
+        .and(warp::path::end())
+        .and(rerank_request.clone())
+        .and(warp::query::<ShapeQuery>().map({
+            let state = state.clone();
+            move |shape: ShapeQuery| Format::Native {
+                score_field: shape.score_field.unwrap_or(state.config.score_field),
+                envelope: shape.envelope.unwrap_or(state.config.response_envelope),
+            }
+        }))
+        .and(json::body(stream_threshold, rerank_body, body_timeout))
+        .and(fault::delay(&state.config, "/rerank"))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(handle_rerank);
+
+    // What a rerank request would send TEI, without sending it
+    let validate = warp::path!("rerank" / "validate")
+        .and(warp::post())
+        .and(json::content_type(state.config.strict_content_type))
+        .and(admission::admit(state.clone()))
+        .and(json::body(stream_threshold, rerank_body, body_timeout))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(handle_validate);
+
+    // The same, with the exact upstream requests, for admins debugging
+    // preprocessing
+    let transform = warp::path!("debug" / "transform")
+        .and(warp::post())
+        .and(json::content_type(state.config.strict_content_type))
+        .and(auth::require_admin(state.clone()))
+        .and(context::extract(state.clone()))
+        .and(json::body(stream_threshold, admin_body, body_timeout))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(handle_transform);
+
+    // Candidates from Qdrant, Elasticsearch or Postgres, reranked
+    let retrieves = state.config.qdrant_url.is_some()
+        || state.config.elasticsearch_url.is_some()
+        || state.config.postgres_url.is_some();
+    let retrieve = warp::path!("retrieve_rerank")
+        .and(serves(retrieves))
+        .and(rerank_request.clone())
+        .and(json::body(stream_threshold, rerank_body, body_timeout))
+        .and(fault::delay(&state.config, "/retrieve_rerank"))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(handle_retrieve);
+
+    // The same, in the shape of Pinecone's hosted rerank API
+    let pinecone = warp::path!("pinecone" / "rerank")
+        .and(rerank_request.clone())
+        .and(
+            json::body(stream_threshold, rerank_body, body_timeout)
+                .and_then(|request| async move {
+                    compat::pinecone::convert(request)
+                        .map(|(context, req)| (Format::Pinecone(context), req))
+                        .map_err(warp::reject::custom)
+                })
+                .untuple_one(),
+        )
+        .and(fault::delay(&state.config, "/pinecone/rerank"))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(handle_rerank);
+
+    // And in that of Mixedbread's
+    let mixedbread = warp::path!("mixedbread" / "v1" / "reranking")
+        .and(rerank_request)
+        .and(
+            json::body(stream_threshold, rerank_body, body_timeout)
+                .and_then(|request| async move {
+                    compat::mixedbread::convert(request)
+                        .map(|(context, req)| (Format::Mixedbread(context), req))
+                        .map_err(warp::reject::custom)
+                })
+                .untuple_one(),
+        )
+        .and(fault::delay(&state.config, "/mixedbread/v1/reranking"))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(handle_rerank);
+
+    // Faults injected into TEI calls, when enabled: shown, or replaced
+    let faults = warp::path!("admin" / "faults")
+        .and(serves(state.config.fault_injection))
+        .and(auth::require_admin(state.clone()))
+        .and(
+            warp::put()
+                .and(json::body(stream_threshold, admin_body, body_timeout))
+                .and_then({
+                    let state = state.clone();
+                    move |settings: fault::FaultSettings| {
+                        let state = state.clone();
+                        async move {
+                            state
+                                .faults
+                                .set(settings)
+                                .map_err(|e| warp::reject::custom(error::ApiError::BadRequest(e)))
+                        }
+                    }
+                })
+                .untuple_one()
+                .or(warp::get())
+                .unify(),
+        )
+        .map({
+            let state = state.clone();
+            move || {
+                let injected: BTreeMap<String, u64> = state.faults.injected().into_iter().collect();
+                warp::reply::json(&serde_json::json!({
+                    "settings": state.faults.settings(),
+                    "injected": injected,
+                }))
+            }
+        });
+
+    // TEI's tokenizer, for clients sizing chunks; upstreams behind an
+    // adapter have none
+    let tokenize = warp::path!("tokenize")
+        .map(|| "/tokenize")
+        .or(warp::path!("decode").map(|| "/decode"))
+        .unify()
+        .and(serves(state.config.tei_adapter.is_none()))
         .and(warp::post())
-        .and(warp::body::json())
-        .and(warp::any().map(move || tei_endpoint.clone()))
-        .and_then(handle_rerank)
-        .recover(handle_rejection);
+        .and(json::content_type(state.config.strict_content_type))
+        .and(admission::admit(state.clone()))
+        .and(json::body(stream_threshold, tokenize_body, body_timeout))
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(handle_tokenize);
+
+    // Calibration curves fitted to labelled scores: shown, or refitted
+    let calibration = warp::path("admin")
+        .and(warp::path("calibration"))
+        .and(serves(state.calibration.is_enabled()))
+        .and(auth::require_admin(state.clone()))
+        .and(
+            warp::post()
+                .and(warp::path!("reload"))
+                .and_then({
+                    let state = state.clone();
+                    move || {
+                        let state = state.clone();
+                        async move {
+                            state.calibration.reload().map_err(|e| {
+                                warp::reject::custom(error::ApiError::Internal(format!("{:#}", e)))
+                            })
+                        }
+                    }
+                })
+                .untuple_one()
+                .or(warp::get().and(warp::path::end()))
+                .unify(),
+        )
+        .map({
+            let state = state.clone();
+            move || warp::reply::json(&state.calibration.summary())
+        });
 
     // CORS support
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["content-type", "authorization"])
-        .allow_methods(vec!["GET", "POST", "OPTIONS"]);
+        .allow_headers(vec![
+            "content-type",
+            "authorization",
+            "api-key",
+            "x-priority",
+            "idempotency-key",
+            "traceparent",
+            "tracestate",
+            "x-debug-payload",
+            "x-request-id",
+            "cache-control",
+            "pragma",
+        ])
+        .allow_methods(vec!["GET", "POST", "PUT", "OPTIONS"])
+        .expose_headers(vec![
+            "retry-after",
+            "x-ratelimit-limit",
+            "x-ratelimit-remaining",
+            "x-ratelimit-reset",
+            "ratelimit-limit",
+            "ratelimit-remaining",
+            "ratelimit-reset",
+            "ratelimit-policy",
+            "x-cache",
+            "x-degraded",
+        ]);
+
+    let routes_for = |routes: Routes| {
+        ratelimit::client(routes_state.clone())
+            .and(
+                admission::route_slot(routes_state.clone())
+                    .and(
+                        health
+                            .or(serves(routes.admin()).and(metrics.clone()))
+                            .or(serves(routes.admin()).and(selftest.clone()))
+                            .or(serves(routes.admin()).and(top.clone()))
+                            .or(serves(routes.admin()).and(faults.clone()))
+                            .or(serves(routes.admin()).and(calibration.clone()))
+                            .or(serves(routes.admin()).and(transform.clone()))
+                            .or(serves(routes.rerank()).and(validate.clone()))
+                            .or(serves(routes.rerank()).and(rerank.clone()))
+                            .or(serves(routes.rerank()).and(retrieve.clone()))
+                            .or(serves(routes.rerank()).and(pinecone.clone()))
+                            .or(serves(routes.rerank()).and(mixedbread.clone()))
+                            .or(serves(routes.rerank()).and(tokenize.clone())),
+                    )
+                    // The group's slot is held until the reply is ready
+                    .map(|_slot, reply| reply)
+                    // Errors from any route, counted by type
+                    .recover({
+                        let state = routes_state.clone();
+                        move |err| {
+                            let state = state.clone();
+                            async move { handle_rejection(err, &state.errors).await }
+                        }
+                    }),
+            )
+            // Every response tells the client how much of its rate limit is left
+            .map({
+                let state = routes_state.clone();
+                move |client: String, reply| {
+                    let mut response = Reply::into_response(reply);
+                    if state.rate_limiter.is_enabled() {
+                        state.rate_limiter.add_headers(&client, &mut response);
+                    }
+                    response
+                }
+            })
+            .with(cors.clone())
+            .with(warp::log("rerank_proxy"))
+    };
 
-    let routes = health.or(rerank).with(cors).with(warp::log("rerank_proxy"));
+    let mut servers: Vec<BoxFuture<'static, ()>> = Vec::new();
+    let inherited = systemd::listeners();
+    if inherited.is_empty() {
+        for listener in listeners {
+            let service = warp::service(routes_for(listener.routes));
+            match server::bind(listener.address, service, header_timeout) {
+                Ok((address, server)) => {
+                    info!("Listening on {} ({} routes)", address, listener.routes);
+                    servers.push(server);
+                }
+                Err(e) => {
+                    error!("Failed to listen on {}: {}", listener.address, e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    } else {
+        // Socket-activated: systemd owns the sockets and keeps accepting
+        // connections across restarts
+        for (listener, routes) in inherited {
+            let listener = match listener
+                .set_nonblocking(true)
+                .and_then(|()| tokio::net::TcpListener::from_std(listener))
+            {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to use socket from systemd: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            if let Ok(address) = listener.local_addr() {
+                info!("Listening on {} ({} routes, from systemd)", address, routes);
+            }
+            let service = warp::service(routes_for(routes));
+            servers.push(server::run_incoming(listener, service, header_timeout));
+        }
+    }
 
     info!("Server started successfully");
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
-}
-
-async fn handle_rerank(
-    req: OpenWebUIRequest,
-    tei_endpoint: String,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    info!("🔄 Processing rerank request for query: '{}'", req.query);
-    info!(
-        "📊 Number of documents: {}, top_n: {:?}",
-        req.documents.len(),
-        req.top_n
-    );
-
-    // Debug: Log the complete incoming request from WebUI
-    match serde_json::to_string_pretty(&req) {
-        Ok(json_str) => debug!("📥 Complete WebUI Request:\n{}", json_str),
-        Err(e) => warn!("❌ Failed to serialize WebUI request for debug: {}", e),
+    if systemd::is_notify_enabled() {
+        tokio::spawn(async move {
+            systemd::wait_for_backends(&ready_state).await;
+            systemd::notify("READY=1\nSTATUS=Serving rerank requests");
+        });
     }
+    futures_util::future::join_all(servers).await;
+    ExitCode::SUCCESS
+}
 
-    // Validate input
-    if req.query.trim().is_empty() {
-        warn!("Empty query received");
-        return Err(warp::reject::custom(ApiError::BadRequest(
-            "Query cannot be empty".to_string(),
-        )));
-    }
+/// Per-request overrides of the response shape, e.g. `?score_field=score`.
+#[derive(serde::Deserialize)]
+struct ShapeQuery {
+    score_field: Option<rerank::ScoreField>,
+    envelope: Option<rerank::Envelope>,
+}
 
-    if req.documents.is_empty() {
-        warn!("No documents provided");
-        return Err(warp::reject::custom(ApiError::BadRequest(
-            "Documents list cannot be empty".to_string(),
-        )));
-    }
+#[derive(serde::Deserialize)]
+struct TopQuery {
+    limit: Option<usize>,
+    by: Option<usage::TopOrder>,
+}
 
-    let max_batch_size = env::var("MAX_CLIENT_BATCH_SIZE")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or_else(|| 1000);
+/// A timeout in seconds from the config, where 0 means none.
+fn timeout_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
 
-    if req.documents.len() > max_batch_size {
-        warn!("Too many documents: {}", req.documents.len());
-        return Err(warp::reject::custom(ApiError::BadRequest(
-            format!("Too many documents, max: {}", max_batch_size).to_string(),
-        )));
-    }
+/// Pass requests through on listeners that serve a route; reject them as
+/// not found elsewhere.
+fn serves(enabled: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
 
-    // Transform to TEI format
-    let tei_req = TEIRequest {
-        query: req.query.clone(),
-        texts: req.documents.clone(),
+async fn handle_rerank(
+    ctx: RequestContext,
+    mut reservation: Reservation,
+    _slot: Option<ClientSlot>,
+    idempotency_key: Option<String>,
+    format: Format,
+    req: OpenWebUIRequest,
+    state: Arc<AppState>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let started = Instant::now();
+
+    // The raw body is gone; what stays in memory is the parsed request
+    reservation.resize(req.estimated_bytes());
+
+    // Answer retried submissions from the stored response, keyed by the
+    // request as sent, before webhooks and plugins see it again
+    let pending = match idempotency_key.filter(|_| state.idempotency.is_enabled()) {
+        Some(key) => match state
+            .idempotency
+            .begin(&ctx, &key, &req)
+            .map_err(warp::reject::custom)?
+        {
+            Claim::Replay(response) => {
+                state
+                    .audit
+                    .record(&ctx, &req, Ok(&*response), started.elapsed());
+                return Ok(replay(&key, &format, &response));
+            }
+            Claim::Fresh(pending) => Some(pending),
+        },
+        None => None,
     };
 
-    // Debug: Log the request being sent to TEI
-    match serde_json::to_string_pretty(&tei_req) {
-        Ok(json_str) => debug!("📤 TEI Request:\n{}", json_str),
-        Err(e) => warn!("❌ Failed to serialize TEI request for debug: {}", e),
-    }
+    rerank_claimed(&state, ctx, pending, format, req, started).await
+}
 
-    info!("🚀 Forwarding request to TEI endpoint: {}", tei_endpoint);
-
-    // Call TEI endpoint with timeout and retries
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()
-        .map_err(|e| {
-            error!("Failed to create HTTP client: {}", e);
-            warp::reject::custom(ApiError::InternalError(
-                "HTTP client creation failed".to_string(),
-            ))
-        })?;
-
-    let tei_url = format!("{}/rerank", tei_endpoint);
-    let response = client
-        .post(&tei_url)
-        .json(&tei_req)
-        .send()
+/// Rerank a request whose `Idempotency-Key`, if any, has been claimed,
+/// keeping the response for replays.
+async fn rerank_claimed(
+    state: &AppState,
+    ctx: RequestContext,
+    pending: Option<Pending<'_>>,
+    format: Format,
+    req: OpenWebUIRequest,
+    started: Instant,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let req = rerank::prepare_request(state, &ctx, req)
         .await
-        .map_err(|e| {
-            error!("TEI request failed: {}", e);
-            warp::reject::custom(ApiError::TEIError(format!(
-                "Failed to connect to TEI service: {}",
-                e
-            )))
-        })?;
-
-    // Check response status
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        error!("TEI returned error {}: {}", status, error_text);
-        return Err(warp::reject::custom(ApiError::TEIError(format!(
-            "TEI service error {}: {}",
-            status, error_text
-        ))));
-    }
-
-    // Get response text first for debugging
-    let response_text = response.text().await.map_err(|e| {
-        error!("Failed to read TEI response body: {}", e);
-        warp::reject::custom(ApiError::TEIError(
-            "Failed to read response from TEI service".to_string(),
-        ))
-    })?;
-
-    // Debug: Log the complete TEI response with pretty formatting
-    match serde_json::from_str::<serde_json::Value>(&response_text) {
-        Ok(json_value) => {
-            let pretty_json =
-                serde_json::to_string_pretty(&json_value).unwrap_or_else(|_| response_text.clone());
-            debug!("📨 TEI Response:\n{}", pretty_json);
-        }
-        Err(_) => {
-            debug!("📨 TEI Response (raw text):\n{}", response_text);
-        }
-    }
-
-    // Parse TEI response
-    let tei_response: TEIResponse = serde_json::from_str(&response_text).map_err(|e| {
-        error!(
-            "Failed to parse TEI response: {}. Raw response: {}",
-            e, response_text
+        .map_err(warp::reject::custom)?;
+
+    let result = rerank::respond(state, &req, &ctx).await;
+    state
+        .audit
+        .record(&ctx, &req, result.as_ref(), started.elapsed());
+    state.usage.record(
+        &ctx,
+        req.documents.len(),
+        result.is_err(),
+        started.elapsed(),
+    );
+    let mut response = result.map_err(warp::reject::custom)?;
+    let rendering = Instant::now();
+    let reply = warp::reply::json(&format.render(&response));
+    response.timing.postprocess += rendering.elapsed();
+    let reply = warp::reply::with_header(
+        reply,
+        "server-timing",
+        response.timing.header(started.elapsed()),
+    );
+    // Browsers only show other origins' timings when allowed to
+    let mut reply = warp::reply::with_header(reply, "timing-allow-origin", "*").into_response();
+    if state.cache.is_enabled() {
+        state.cache.add_headers(
+            req.model.as_deref(),
+            response.cache_hit,
+            response.cacheable,
+            &mut reply,
         );
-        warp::reject::custom(ApiError::TEIError(format!(
-            "Invalid response format from TEI service. Expected array of scores, got: {}",
-            response_text
-        )))
-    })?;
-
-    // Validate TEI response
-    if tei_response.0.len() != req.documents.len() {
-        error!(
-            "TEI response length mismatch: expected {}, got {}",
-            req.documents.len(),
-            tei_response.0.len()
+    }
+    if let Some(degraded) = response.meta.as_ref().and_then(|meta| meta.degraded) {
+        reply.headers_mut().insert(
+            "x-degraded",
+            warp::http::HeaderValue::from_static(degraded.as_str()),
         );
-        return Err(warp::reject::custom(ApiError::TEIError(
-            "TEI response length doesn't match input documents".to_string(),
-        )));
     }
+    if let Some(pending) = pending {
+        pending.complete(Arc::new(response));
+    }
+    Ok(reply)
+}
 
-    info!(
-        "✅ TEI request successful, processing {} scores",
-        tei_response.0.len()
-    );
-
-    // Transform back to OpenWebUI format with ranking
-    // TEI returns results with indices, but we need to sort by score
-    let mut indexed_scores: Vec<(usize, f64)> = tei_response
-        .0
-        .into_iter()
-        .map(|result| (result.index, result.score))
-        .collect();
-
-    // Sort by relevance score descending
-    indexed_scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-    let results: Vec<RankResult> = indexed_scores
-        .into_iter()
-        .map(|(index, score)| RankResult {
-            index,
-            relevance_score: score,
-        })
-        .collect();
+/// Send a stored response again. Usage was counted when it was first
+/// answered; replays only count in `rerank_proxy_idempotent_replays_total`.
+fn replay(key: &str, format: &Format, response: &OpenWebUIResponse) -> warp::reply::Response {
+    info!("♻️ Replaying stored response for Idempotency-Key '{}'", key);
+    let reply = warp::reply::json(&format.render(response));
+    warp::reply::with_header(reply, "idempotent-replayed", "true").into_response()
+}
 
-    let response = OpenWebUIResponse { results };
+/// Validate and preprocess a rerank request, and answer with what would
+/// be sent to TEI for it.
+async fn handle_validate(
+    ctx: RequestContext,
+    _reservation: Reservation,
+    _slot: Option<ClientSlot>,
+    req: OpenWebUIRequest,
+    state: Arc<AppState>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let plan = plan(&ctx, req, &state).await?;
+    Ok(warp::reply::json(&plan).into_response())
+}
 
-    // Debug: Log the final response being sent back to WebUI
-    match serde_json::to_string_pretty(&response) {
-        Ok(json_str) => debug!("📤 Final WebUI Response:\n{}", json_str),
-        Err(e) => warn!("❌ Failed to serialize WebUI response for debug: {}", e),
-    }
+/// Answer with the exact requests that would be sent upstream for a rerank
+/// request.
+async fn handle_transform(
+    ctx: RequestContext,
+    req: OpenWebUIRequest,
+    state: Arc<AppState>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let plan = plan(&ctx, req, &state).await?;
+    Ok(warp::reply::json(&plan.requests(&state.config)).into_response())
+}
 
-    info!(
-        "✅ Successfully processed rerank request, returning {} results",
-        response.results.len()
-    );
-    Ok(warp::reply::json(&response))
+/// Preprocess a rerank request as `handle_rerank` does, then plan it.
+async fn plan(
+    ctx: &RequestContext,
+    req: OpenWebUIRequest,
+    state: &AppState,
+) -> Result<rerank::Plan, warp::Rejection> {
+    let req = rerank::prepare_request(state, ctx, req)
+        .await
+        .map_err(warp::reject::custom)?;
+    rerank::plan(state, ctx, &req)
+        .await
+        .map_err(warp::reject::custom)
 }
 
-// Custom error types
-#[derive(Debug)]
-enum ApiError {
-    BadRequest(String),
-    TEIError(String),
-    InternalError(String),
+/// Forward a request to TEI's tokenizer.
+async fn handle_tokenize(
+    path: &'static str,
+    ctx: RequestContext,
+    _reservation: Reservation,
+    _slot: Option<ClientSlot>,
+    body: serde_json::Value,
+    state: Arc<AppState>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let response = tokenizer::forward(&state, &ctx, path, &body)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&response).into_response())
 }
 
-impl warp::reject::Reject for ApiError {}
-
-// Error handling
-async fn handle_rejection(
-    err: warp::Rejection,
-) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let (code, message, error_type) = if err.is_not_found() {
-        (404, "Not Found".to_string(), "not_found")
-    } else if let Some(api_error) = err.find::<ApiError>() {
-        match api_error {
-            ApiError::BadRequest(msg) => (400, msg.clone(), "bad_request"),
-            ApiError::TEIError(msg) => (502, msg.clone(), "tei_error"),
-            ApiError::InternalError(msg) => (500, msg.clone(), "internal_error"),
-        }
-    } else if err
-        .find::<warp::filters::body::BodyDeserializeError>()
-        .is_some()
-    {
-        (
-            400,
-            "Invalid JSON in request body".to_string(),
-            "invalid_json",
-        )
-    } else {
-        error!("Unhandled rejection: {:?}", err);
-        (500, "Internal Server Error".to_string(), "internal_error")
+/// Fetch candidates for the query, then rerank them like any other request.
+async fn handle_retrieve(
+    ctx: RequestContext,
+    mut reservation: Reservation,
+    _slot: Option<ClientSlot>,
+    idempotency_key: Option<String>,
+    request: retrieve::RetrieveRequest,
+    state: Arc<AppState>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let started = Instant::now();
+    let format = Format::Retrieved {
+        top_n: request.top_n,
     };
 
-    let error_response = ErrorResponse {
-        error: error_type.to_string(),
-        message,
+    // Replays skip the search as well as TEI
+    let pending = match idempotency_key.filter(|_| state.idempotency.is_enabled()) {
+        Some(key) => match state
+            .idempotency
+            .begin(&ctx, &key, &request)
+            .map_err(warp::reject::custom)?
+        {
+            Claim::Replay(response) => {
+                let req = retrieve::rerank_request(&request, Vec::new());
+                state
+                    .audit
+                    .record(&ctx, &req, Ok(&*response), started.elapsed());
+                return Ok(replay(&key, &format, &response));
+            }
+            Claim::Fresh(pending) => Some(pending),
+        },
+        None => None,
     };
 
-    Ok(warp::reply::with_status(
-        warp::reply::json(&error_response),
-        warp::http::StatusCode::from_u16(code).unwrap(),
-    ))
+    let req = retrieve::candidates(&state, &request)
+        .await
+        .map_err(warp::reject::custom)?;
+    // Nothing to rerank isn't an error here
+    if req.documents.is_empty() {
+        return Ok(warp::reply::json(&serde_json::json!({ "results": [] })).into_response());
+    }
+    reservation.resize(req.estimated_bytes());
+    rerank_claimed(&state, ctx, pending, format, req, started).await
 }
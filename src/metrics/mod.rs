@@ -0,0 +1,300 @@
+mod pushgateway;
+mod statsd;
+
+use crate::fallback::Degraded;
+use crate::state::AppState;
+use crate::usage::Counts;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub use pushgateway::push;
+pub use statsd::spawn_statsd;
+
+/// Totals over every call made to TEI. One client request can turn into
+/// several calls, each recorded on its own.
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    requests: AtomicU64,
+    documents: AtomicU64,
+    micros: AtomicU64,
+    /// Responses by HTTP status code.
+    statuses: Mutex<BTreeMap<u16, u64>>,
+    /// Requests scored some other way after TEI failed, by how.
+    fallbacks: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl UpstreamStats {
+    pub fn record_status(&self, status: u16) {
+        *self.statuses.lock().unwrap().entry(status).or_default() += 1;
+    }
+
+    pub fn record_fallback(&self, degraded: Degraded) {
+        *self
+            .fallbacks
+            .lock()
+            .unwrap()
+            .entry(degraded.as_str())
+            .or_default() += 1;
+    }
+
+    pub fn record(&self, documents: usize, latency: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.documents
+            .fetch_add(documents as u64, Ordering::Relaxed);
+        self.micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Error responses sent to clients, by error type (`bad_request`,
+/// `tei_error`, `timeout`, ...).
+#[derive(Debug, Default)]
+pub struct ErrorStats {
+    by_type: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl ErrorStats {
+    pub fn record(&self, error_type: &'static str) {
+        *self.by_type.lock().unwrap().entry(error_type).or_default() += 1;
+    }
+}
+
+/// Receives the proxy's metrics one at a time, as `collect` reads them.
+trait Sink {
+    fn counter(&mut self, name: &str, help: &str, value: u64);
+    fn gauge(&mut self, name: &str, help: &str, value: impl Display);
+    /// A counter split by the values of one label.
+    fn labeled_counter(&mut self, name: &str, help: &str, label: &str, values: &[(String, u64)]);
+}
+
+/// Render the proxy's metrics in the Prometheus text exposition format.
+pub fn render(state: &AppState) -> String {
+    let mut out = Prometheus(String::new());
+    collect(state, &mut out);
+    out.0
+}
+
+fn collect(state: &AppState, sink: &mut impl Sink) {
+    sink.counter(
+        "rerank_proxy_memory_shed_total",
+        "Requests rejected because the memory budget was exhausted",
+        state.memory.shed(),
+    );
+    sink.gauge(
+        "rerank_proxy_memory_in_use_bytes",
+        "Estimated memory held by buffered and in-flight requests",
+        state.memory.used(),
+    );
+    sink.counter(
+        "rerank_proxy_client_limited_total",
+        "Requests rejected because their IP address had too many in flight",
+        state.client_limit.limited(),
+    );
+    let route_limited: Vec<(String, u64)> = state
+        .route_slots
+        .limited()
+        .into_iter()
+        .map(|(route, count)| (route.to_string(), count))
+        .collect();
+    sink.labeled_counter(
+        "rerank_proxy_route_limited_total",
+        "Requests rejected because their route group had too many in flight, by group",
+        "route",
+        &route_limited,
+    );
+
+    sink.counter(
+        "rerank_proxy_upstream_requests_total",
+        "Calls made to TEI",
+        state.upstream.requests.load(Ordering::Relaxed),
+    );
+    sink.counter(
+        "rerank_proxy_upstream_documents_total",
+        "Documents sent to TEI for scoring",
+        state.upstream.documents.load(Ordering::Relaxed),
+    );
+    sink.counter(
+        "rerank_proxy_upstream_request_microseconds_total",
+        "Time spent waiting on TEI calls",
+        state.upstream.micros.load(Ordering::Relaxed),
+    );
+
+    let statuses: Vec<(String, u64)> = state
+        .upstream
+        .statuses
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(status, count)| (status.to_string(), *count))
+        .collect();
+    sink.labeled_counter(
+        "rerank_proxy_upstream_responses_total",
+        "TEI responses by HTTP status code",
+        "status",
+        &statuses,
+    );
+    let fallbacks: Vec<(String, u64)> = state
+        .upstream
+        .fallbacks
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(mode, count)| (mode.to_string(), *count))
+        .collect();
+    sink.labeled_counter(
+        "rerank_proxy_fallbacks_total",
+        "Requests scored without the reranker after TEI failed, by mode",
+        "mode",
+        &fallbacks,
+    );
+    let errors: Vec<(String, u64)> = state
+        .errors
+        .by_type
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(error_type, count)| (error_type.to_string(), *count))
+        .collect();
+    sink.labeled_counter(
+        "rerank_proxy_errors_total",
+        "Error responses sent to clients by error type",
+        "error",
+        &errors,
+    );
+
+    if state.faults.is_enabled() {
+        sink.labeled_counter(
+            "rerank_proxy_faults_injected_total",
+            "Faults injected into TEI calls by kind",
+            "kind",
+            &state.faults.injected(),
+        );
+    }
+
+    sink.counter(
+        "rerank_proxy_upstream_retries_total",
+        "TEI calls retried after a 429 or 503",
+        state.retry_budget.retries(),
+    );
+    sink.counter(
+        "rerank_proxy_retry_budget_exhausted_total",
+        "Retries skipped because the retry budget was spent",
+        state.retry_budget.exhausted(),
+    );
+
+    let usage = state.usage.totals();
+    let per_key = |count: fn(&Counts) -> u64| -> Vec<(String, u64)> {
+        usage
+            .iter()
+            .map(|(key, counts)| (key.clone(), count(counts)))
+            .collect()
+    };
+    sink.labeled_counter(
+        "rerank_proxy_key_requests_total",
+        "Rerank requests by API key",
+        "key",
+        &per_key(|c| c.requests),
+    );
+    sink.labeled_counter(
+        "rerank_proxy_key_errors_total",
+        "Failed rerank requests by API key",
+        "key",
+        &per_key(|c| c.errors),
+    );
+    sink.labeled_counter(
+        "rerank_proxy_key_documents_total",
+        "Documents reranked by API key",
+        "key",
+        &per_key(|c| c.documents),
+    );
+    sink.labeled_counter(
+        "rerank_proxy_key_request_microseconds_total",
+        "Time spent serving rerank requests by API key",
+        "key",
+        &per_key(|c| c.latency_micros),
+    );
+
+    sink.counter(
+        "rerank_proxy_latency_shed_total",
+        "Requests rejected because upstream latency was degraded",
+        state.latency.shed(),
+    );
+    sink.gauge(
+        "rerank_proxy_upstream_p99_seconds",
+        "Upstream p99 latency over the shedding window",
+        state.latency.p99().as_secs_f64(),
+    );
+    sink.counter(
+        "rerank_proxy_idempotent_replays_total",
+        "Stored responses replayed for a repeated Idempotency-Key",
+        state.idempotency.replays(),
+    );
+    sink.gauge(
+        "rerank_proxy_queue_waiting",
+        "Requests waiting for a slot to TEI",
+        state.queue.waiting(),
+    );
+
+    let cache = state.cache.stats();
+    sink.counter(
+        "rerank_proxy_cache_hits_total",
+        "Scores served from the cache",
+        cache.hits,
+    );
+    sink.counter(
+        "rerank_proxy_cache_misses_total",
+        "Scores not found in the cache",
+        cache.misses,
+    );
+    sink.counter(
+        "rerank_proxy_cache_evictions_total",
+        "Cached scores dropped to stay within the cache limits",
+        cache.evictions,
+    );
+    sink.counter(
+        "rerank_proxy_cache_expirations_total",
+        "Cached scores dropped after their TTL",
+        cache.expirations,
+    );
+    sink.gauge(
+        "rerank_proxy_cache_entries",
+        "Scores held in the in-memory cache",
+        cache.entries,
+    );
+    sink.gauge(
+        "rerank_proxy_cache_bytes",
+        "Estimated memory used by the in-memory cache",
+        cache.bytes,
+    );
+}
+
+struct Prometheus(String);
+
+impl Sink for Prometheus {
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.metric("counter", name, help, value);
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.metric("gauge", name, help, value);
+    }
+
+    fn labeled_counter(&mut self, name: &str, help: &str, label: &str, values: &[(String, u64)]) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} counter", name);
+        for (value, count) in values {
+            let _ = writeln!(self.0, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+        }
+    }
+}
+
+impl Prometheus {
+    fn metric(&mut self, kind: &str, name: &str, help: &str, value: impl Display) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.0, "{} {}", name, value);
+    }
+}
@@ -0,0 +1,87 @@
+use super::Prometheus;
+use crate::bench::BenchReport;
+use crate::state::AppState;
+use log::{info, warn};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// How long the Pushgateway gets to accept the metrics.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Push the metrics of a short-lived `command` run, such as `bench`, to the
+/// Prometheus Pushgateway at `pushgateway_url`, replacing the ones its last
+/// run pushed. A bench run's results go along as `rerank_proxy_bench_*`
+/// gauges. Failing to push is logged, not fatal.
+pub async fn push(state: &AppState, command: &str, bench: Option<&BenchReport>) {
+    let Some(url) = &state.config.pushgateway_url else {
+        return;
+    };
+    let mut out = Prometheus(String::new());
+    super::collect(state, &mut out);
+    if let Some(report) = bench {
+        add_bench(&mut out, report);
+    }
+
+    let url = format!(
+        "{}/metrics/job/{}/command/{}",
+        url.trim_end_matches('/'),
+        state.config.pushgateway_job,
+        command
+    );
+    let pushed = reqwest::Client::new()
+        .put(&url)
+        .timeout(PUSH_TIMEOUT)
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(out.0)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    match pushed {
+        Ok(_) => info!("📈 Pushed metrics to {}", url),
+        Err(e) => warn!("Failed to push metrics to {}: {}", url, e),
+    }
+}
+
+fn add_bench(out: &mut Prometheus, report: &BenchReport) {
+    out.metric(
+        "gauge",
+        "rerank_proxy_bench_requests",
+        "Requests the benchmark sent",
+        report.requests,
+    );
+    out.metric(
+        "gauge",
+        "rerank_proxy_bench_errors",
+        "Benchmark requests that failed",
+        report.errors,
+    );
+    out.metric(
+        "gauge",
+        "rerank_proxy_bench_documents",
+        "Documents the benchmark had reranked",
+        report.documents,
+    );
+    out.metric(
+        "gauge",
+        "rerank_proxy_bench_duration_seconds",
+        "How long the benchmark ran",
+        report.elapsed.as_secs_f64(),
+    );
+
+    let name = "rerank_proxy_bench_latency_seconds";
+    let _ = writeln!(
+        out.0,
+        "# HELP {} Latency of successful benchmark requests",
+        name
+    );
+    let _ = writeln!(out.0, "# TYPE {} gauge", name);
+    for p in [50.0, 90.0, 95.0, 99.0, 100.0] {
+        let _ = writeln!(
+            out.0,
+            "{}{{quantile=\"{}\"}} {}",
+            name,
+            p / 100.0,
+            report.percentile(p).as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use super::Sink;
+use crate::state::AppState;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Keep datagrams within a typical Ethernet MTU so they aren't fragmented.
+const MAX_DATAGRAM_BYTES: usize = 1432;
+
+/// Prometheus-style prefix swapped for `statsd_prefix`.
+const NAME_PREFIX: &str = "rerank_proxy_";
+
+/// Push the proxy's metrics to a StatsD or DogStatsD agent over UDP every
+/// `statsd_interval_secs`, alongside the Prometheus endpoint.
+pub fn spawn_statsd(state: Arc<AppState>) {
+    let Some(address) = state.config.statsd_address.clone() else {
+        return;
+    };
+    let interval = Duration::from_secs(state.config.statsd_interval_secs);
+
+    let tags = state
+        .config
+        .statsd_tags
+        .iter()
+        .map(|(key, value)| format!("{}:{}", key, value))
+        .collect::<Vec<_>>();
+    let mut exporter = StatsD {
+        prefix: state.config.statsd_prefix.clone(),
+        // DogStatsD tag extension; plain StatsD when no tags are configured
+        tags: if tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", tags.join(","))
+        },
+        lines: Vec::new(),
+        previous: HashMap::new(),
+    };
+
+    info!(
+        "📈 Pushing metrics to StatsD at {} every {}s",
+        address,
+        interval.as_secs()
+    );
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut socket = None;
+        loop {
+            ticker.tick().await;
+
+            super::collect(&state, &mut exporter);
+            let datagrams = exporter.datagrams();
+
+            // Reconnect, and so re-resolve the agent, after any failure
+            if socket.is_none() {
+                match connect(&address).await {
+                    Ok(connected) => socket = Some(connected),
+                    Err(e) => {
+                        warn!("Failed to reach StatsD agent {}: {}", address, e);
+                        continue;
+                    }
+                }
+            }
+            let Some(connected) = &socket else {
+                continue;
+            };
+            let mut failed = false;
+            for datagram in datagrams {
+                if let Err(e) = connected.send(datagram.as_bytes()).await {
+                    warn!("Failed to send metrics to StatsD agent {}: {}", address, e);
+                    failed = true;
+                    break;
+                }
+            }
+            if failed {
+                socket = None;
+            }
+        }
+    });
+}
+
+async fn connect(address: &str) -> std::io::Result<UdpSocket> {
+    let target = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other("no addresses found"))?;
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// Collects metrics as StatsD lines. Counters are sent as the increase
+/// since the previous push.
+struct StatsD {
+    prefix: String,
+    tags: String,
+    lines: Vec<String>,
+    previous: HashMap<String, u64>,
+}
+
+impl Sink for StatsD {
+    fn counter(&mut self, name: &str, _help: &str, value: u64) {
+        let last = self.previous.insert(name.to_string(), value).unwrap_or(0);
+        let name = self.name(name);
+        self.lines.push(format!(
+            "{}:{}|c{}",
+            name,
+            value.saturating_sub(last),
+            self.tags
+        ));
+    }
+
+    fn gauge(&mut self, name: &str, _help: &str, value: impl Display) {
+        let name = self.name(name);
+        self.lines
+            .push(format!("{}:{}|g{}", name, value, self.tags));
+    }
+
+    /// DogStatsD gets the label as a tag; plain StatsD, which has no tags,
+    /// as a name suffix (`errors.timeout`).
+    fn labeled_counter(&mut self, name: &str, _help: &str, label: &str, values: &[(String, u64)]) {
+        for (value, count) in values {
+            let key = format!("{}{{{}={}}}", name, label, value);
+            let last = self.previous.insert(key, *count).unwrap_or(0);
+            let increase = count.saturating_sub(last);
+            let name = self.name(name);
+            self.lines.push(if self.tags.is_empty() {
+                format!("{}.{}:{}|c", name, value, increase)
+            } else {
+                format!("{}:{}|c{},{}:{}", name, increase, self.tags, label, value)
+            });
+        }
+    }
+}
+
+impl StatsD {
+    /// `rerank_proxy_cache_hits_total` becomes `<prefix>.cache_hits`.
+    fn name(&self, name: &str) -> String {
+        let name = name.strip_prefix(NAME_PREFIX).unwrap_or(name);
+        let name = name.strip_suffix("_total").unwrap_or(name);
+        if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.prefix, name)
+        }
+    }
+
+    /// Pack the collected lines into as few datagrams as fit.
+    fn datagrams(&mut self) -> Vec<String> {
+        let mut datagrams: Vec<String> = Vec::new();
+        for line in self.lines.drain(..) {
+            match datagrams.last_mut() {
+                Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM_BYTES => {
+                    datagram.push('\n');
+                    datagram.push_str(&line);
+                }
+                _ => datagrams.push(line),
+            }
+        }
+        datagrams
+    }
+}
@@ -0,0 +1,103 @@
+#[cfg(feature = "rhai-scripts")]
+mod script;
+#[cfg(feature = "wasm-plugins")]
+mod wasm;
+
+use crate::config::Config;
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank::{OpenWebUIRequest, OpenWebUIResponse};
+
+/// Operator-supplied request and response filters: Rhai scripts
+/// (`rhai-scripts` feature), then WebAssembly plugins (`wasm-plugins`
+/// feature), each in configured order. Every filter sees the request or
+/// response as left by the one before it.
+pub struct Plugins {
+    #[cfg(feature = "rhai-scripts")]
+    scripts: script::Scripts,
+    #[cfg(feature = "wasm-plugins")]
+    loaded: Vec<wasm::Plugin>,
+}
+
+impl Plugins {
+    /// Config validation rejects filters the build has no support for.
+    #[cfg_attr(
+        not(any(feature = "rhai-scripts", feature = "wasm-plugins")),
+        allow(unused_variables)
+    )]
+    pub fn load(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            #[cfg(feature = "rhai-scripts")]
+            scripts: script::Scripts::load(&config.scripts, config.script_max_operations)?,
+            #[cfg(feature = "wasm-plugins")]
+            loaded: wasm::load_all(&config.plugins, config.plugin_fuel)?,
+        })
+    }
+
+    /// Run the request hooks, which may rewrite or reject the request.
+    #[cfg_attr(not(feature = "wasm-plugins"), allow(unused_variables))]
+    #[cfg_attr(
+        not(any(feature = "rhai-scripts", feature = "wasm-plugins")),
+        allow(unused_mut)
+    )]
+    pub fn on_request(
+        &self,
+        ctx: &RequestContext,
+        mut req: OpenWebUIRequest,
+    ) -> Result<OpenWebUIRequest, ApiError> {
+        #[cfg(feature = "rhai-scripts")]
+        {
+            req = self.scripts.on_request(req)?;
+        }
+
+        #[cfg(feature = "wasm-plugins")]
+        for plugin in &self.loaded {
+            if let Some(output) = plugin.hook(wasm::ON_REQUEST, ctx, &req, None)? {
+                if let Some(message) = output.reject {
+                    return Err(ApiError::Forbidden(message));
+                }
+                if let Some(request) = output.request {
+                    req = request;
+                }
+            }
+        }
+
+        Ok(req)
+    }
+
+    /// Run the response hooks, which may rewrite the response or withhold
+    /// it.
+    #[cfg_attr(not(feature = "wasm-plugins"), allow(unused_variables))]
+    #[cfg_attr(
+        not(any(feature = "rhai-scripts", feature = "wasm-plugins")),
+        allow(unused_mut)
+    )]
+    pub fn on_response(
+        &self,
+        ctx: &RequestContext,
+        req: &OpenWebUIRequest,
+        mut response: OpenWebUIResponse,
+    ) -> Result<OpenWebUIResponse, ApiError> {
+        #[cfg(feature = "rhai-scripts")]
+        {
+            response.results = self.scripts.on_response(req, response.results)?;
+        }
+
+        #[cfg(feature = "wasm-plugins")]
+        for plugin in &self.loaded {
+            if let Some(output) = plugin.hook(wasm::ON_RESPONSE, ctx, req, Some(&response))? {
+                if let Some(message) = output.reject {
+                    return Err(ApiError::Forbidden(message));
+                }
+                if let Some(mut rewritten) = output.response {
+                    rewritten.timing = response.timing;
+                    rewritten.cache_hit = response.cache_hit;
+                    rewritten.cacheable = response.cacheable;
+                    response = rewritten;
+                }
+            }
+        }
+
+        Ok(response)
+    }
+}
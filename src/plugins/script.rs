@@ -0,0 +1,143 @@
+use crate::error::ApiError;
+use crate::rerank::{OpenWebUIRequest, RankResult};
+use anyhow::Context;
+use log::{error, info};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Hook that may reject a request by throwing.
+const VALIDATE: &str = "validate";
+/// Hook that may rewrite a request before it's forwarded.
+const PRE_FORWARD: &str = "pre_forward";
+/// Hook that may rewrite the scored results.
+const POST_SCORE: &str = "post_score";
+
+/// Rhai scripts defining any of `validate(request)`,
+/// `pre_forward(request)` and `post_score(request, results)`.
+pub struct Scripts {
+    engine: Engine,
+    loaded: Vec<Script>,
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+}
+
+impl Scripts {
+    pub fn load(paths: &[PathBuf], max_operations: u64) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        // Scripts can't import modules from disk, and get a bounded amount
+        // of work per call
+        engine.set_module_resolver(DummyModuleResolver::new());
+        engine.set_max_operations(max_operations);
+
+        let loaded = paths
+            .iter()
+            .map(|path| {
+                Script::load(&engine, path)
+                    .with_context(|| format!("failed to load script {}", path.display()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { engine, loaded })
+    }
+
+    pub fn on_request(&self, mut req: OpenWebUIRequest) -> Result<OpenWebUIRequest, ApiError> {
+        for script in &self.loaded {
+            if script.defines(VALIDATE) {
+                // Only a throw rejects; what it returns is ignored
+                let _: Dynamic = self.call(script, VALIDATE, (encode(&req)?,))?;
+            }
+            if script.defines(PRE_FORWARD) {
+                let output = self.call(script, PRE_FORWARD, (encode(&req)?,))?;
+                if !output.is_unit() {
+                    req = decode(script, PRE_FORWARD, output)?;
+                }
+            }
+        }
+        Ok(req)
+    }
+
+    pub fn on_response(
+        &self,
+        req: &OpenWebUIRequest,
+        mut results: Vec<RankResult>,
+    ) -> Result<Vec<RankResult>, ApiError> {
+        for script in self.loaded.iter().filter(|s| s.defines(POST_SCORE)) {
+            let args = (encode(req)?, encode(&results)?);
+            let output = self.call(script, POST_SCORE, args)?;
+            if !output.is_unit() {
+                results = decode(script, POST_SCORE, output)?;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Call `hook`. A value thrown by the script rejects the request with
+    /// that message; any other failure is the script's fault.
+    fn call(
+        &self,
+        script: &Script,
+        hook: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Result<Dynamic, ApiError> {
+        let mut scope = Scope::new();
+        self.engine
+            .call_fn::<Dynamic>(&mut scope, &script.ast, hook, args)
+            .map_err(|e| {
+                let mut e = *e;
+                while let EvalAltResult::ErrorInFunctionCall(_, _, inner, _) = e {
+                    e = *inner;
+                }
+                match e {
+                    EvalAltResult::ErrorRuntime(value, _) => ApiError::Forbidden(value.to_string()),
+                    other => {
+                        error!("Script {} failed in {}: {}", script.name, hook, other);
+                        ApiError::Internal(format!("Script {} failed", script.name))
+                    }
+                }
+            })
+    }
+}
+
+impl Script {
+    fn load(engine: &Engine, path: &Path) -> anyhow::Result<Self> {
+        let ast = engine.compile_file(path.to_path_buf())?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let script = Self { name, ast };
+
+        let hooks: Vec<&str> = [VALIDATE, PRE_FORWARD, POST_SCORE]
+            .into_iter()
+            .filter(|hook| script.defines(hook))
+            .collect();
+        info!("📜 Loaded script {} with hooks: {:?}", script.name, hooks);
+        Ok(script)
+    }
+
+    fn defines(&self, hook: &str) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|function| function.name == hook)
+    }
+}
+
+fn encode(value: &impl Serialize) -> Result<Dynamic, ApiError> {
+    rhai::serde::to_dynamic(value)
+        .map_err(|e| ApiError::Internal(format!("Failed to encode script input: {}", e)))
+}
+
+fn decode<T: DeserializeOwned>(script: &Script, hook: &str, value: Dynamic) -> Result<T, ApiError> {
+    rhai::serde::from_dynamic(&value).map_err(|e| {
+        error!(
+            "Script {} returned invalid {} output: {}",
+            script.name, hook, e
+        );
+        ApiError::Internal(format!("Script {} returned invalid output", script.name))
+    })
+}
@@ -0,0 +1,176 @@
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank::{OpenWebUIRequest, OpenWebUIResponse};
+use anyhow::Context;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use wasmtime::{Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Hook called with each request before it's ranked.
+pub const ON_REQUEST: &str = "on_request";
+/// Hook called with each response before it's returned.
+pub const ON_RESPONSE: &str = "on_response";
+
+/// Linear memory a plugin instance may grow to.
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+/// One loaded plugin module.
+///
+/// Plugins talk JSON through their own memory: the proxy calls the
+/// exported `alloc(len) -> ptr`, writes the input there and calls the hook
+/// as `hook(ptr, len) -> u64`. The result packs the output's pointer into
+/// the high 32 bits and its length into the low ones; 0 leaves everything
+/// unchanged.
+pub struct Plugin {
+    pub name: String,
+    module: Module,
+    instance: InstancePre<StoreLimits>,
+    fuel: u64,
+}
+
+/// What a hook is handed.
+#[derive(Serialize)]
+struct HookInput<'a> {
+    request_id: &'a str,
+    api_key: Option<String>,
+    priority: String,
+    request: &'a OpenWebUIRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<&'a OpenWebUIResponse>,
+}
+
+/// What a hook hands back; fields left out keep their current value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookOutput {
+    /// Reject the request with `403` and this message.
+    #[serde(default)]
+    pub reject: Option<String>,
+    #[serde(default)]
+    pub request: Option<OpenWebUIRequest>,
+    #[serde(default)]
+    pub response: Option<OpenWebUIResponse>,
+}
+
+pub fn load_all(paths: &[PathBuf], fuel: u64) -> anyhow::Result<Vec<Plugin>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+    paths
+        .iter()
+        .map(|path| {
+            Plugin::load(&engine, path, fuel)
+                .with_context(|| format!("failed to load plugin {}", path.display()))
+        })
+        .collect()
+}
+
+impl Plugin {
+    fn load(engine: &Engine, path: &Path, fuel: u64) -> anyhow::Result<Self> {
+        let module = Module::from_file(engine, path)?;
+        // Nothing is linked in, so plugins can't reach the filesystem,
+        // network or clock; they only see what they're handed
+        let instance = Linker::new(engine).instantiate_pre(&module)?;
+        for export in ["memory", "alloc"] {
+            if module.get_export(export).is_none() {
+                anyhow::bail!("module doesn't export '{}'", export);
+            }
+        }
+
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let hooks: Vec<&str> = [ON_REQUEST, ON_RESPONSE]
+            .into_iter()
+            .filter(|hook| module.get_export(hook).is_some())
+            .collect();
+        info!("🧩 Loaded plugin {} with hooks: {:?}", name, hooks);
+
+        Ok(Self {
+            name,
+            module,
+            instance,
+            fuel,
+        })
+    }
+
+    /// Run `hook` if this plugin exports it.
+    pub fn hook(
+        &self,
+        hook: &str,
+        ctx: &RequestContext,
+        request: &OpenWebUIRequest,
+        response: Option<&OpenWebUIResponse>,
+    ) -> Result<Option<HookOutput>, ApiError> {
+        if self.module.get_export(hook).is_none() {
+            return Ok(None);
+        }
+
+        let input = HookInput {
+            request_id: &ctx.request_id,
+            api_key: ctx.api_key.as_ref().map(|key| key.label()),
+            priority: ctx.priority.to_string(),
+            request,
+            response,
+        };
+        let output = serde_json::to_vec(&input)
+            .map_err(anyhow::Error::from)
+            .and_then(|input| self.call(hook, &input))
+            .map_err(|e| {
+                error!("Plugin {} failed in {}: {:#}", self.name, hook, e);
+                ApiError::Internal(format!("Plugin {} failed", self.name))
+            })?;
+        let Some(output) = output else {
+            return Ok(None);
+        };
+
+        serde_json::from_slice(&output).map(Some).map_err(|e| {
+            error!(
+                "Plugin {} returned invalid {} output: {}",
+                self.name, hook, e
+            );
+            ApiError::Internal(format!("Plugin {} returned invalid output", self.name))
+        })
+    }
+
+    /// Call `hook` in a fresh instance, so no state leaks between requests,
+    /// and return its raw output.
+    fn call(&self, hook: &str, input: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_BYTES)
+            .build();
+        let mut store = Store::new(self.module.engine(), limits);
+        store.limiter(|limits| limits);
+        // Bounds the work a plugin does per call, so a runaway loop
+        // traps instead of stalling the request
+        store.set_fuel(self.fuel)?;
+
+        let instance = self.instance.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("'memory' is not a memory")?;
+        let alloc = instance.get_typed_func::<u32, u32>(&mut store, "alloc")?;
+        let run = instance.get_typed_func::<(u32, u32), u64>(&mut store, hook)?;
+
+        let len = u32::try_from(input.len()).context("input too large")?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as usize, input)?;
+        let packed = run.call(&mut store, (ptr, len))?;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let start = (packed >> 32) as usize;
+        let end = start + (packed & 0xffff_ffff) as usize;
+        let output = memory
+            .data(&store)
+            .get(start..end)
+            .context("output is outside the plugin's memory")?;
+        Ok(Some(output.to_vec()))
+    }
+}
@@ -0,0 +1,314 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// Tags whose contents are never visible text.
+const HIDDEN_TAGS: &[&str] = &["script", "style", "noscript", "template"];
+
+/// Tags that don't break the flow of text; every other tag is replaced by
+/// a line break so words on either side don't run together.
+const INLINE_TAGS: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "cite", "code", "data", "dfn", "em", "font", "i", "kbd",
+    "mark", "q", "s", "samp", "small", "span", "strong", "sub", "sup", "time", "u", "var", "wbr",
+];
+
+/// Cleanup applied to the query and documents so that clients encoding
+/// the same text differently get the same scores.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// Text is scored exactly as sent.
+    #[default]
+    Off,
+    /// Remove control characters and collapse whitespace.
+    Whitespace,
+    /// As `whitespace`, after Unicode canonical composition (NFC).
+    Nfc,
+    /// As `whitespace`, after Unicode compatibility composition (NFKC),
+    /// which also folds e.g. full-width letters and ligatures.
+    Nfkc,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Normalization::Off),
+            "whitespace" => Ok(Normalization::Whitespace),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfkc" => Ok(Normalization::Nfkc),
+            other => Err(format!(
+                "unknown normalization '{}' (expected off, whitespace, nfc or nfkc)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Normalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Normalization::Off => "off",
+            Normalization::Whitespace => "whitespace",
+            Normalization::Nfc => "nfc",
+            Normalization::Nfkc => "nfkc",
+        })
+    }
+}
+
+pub fn normalize(text: Cow<'_, str>, normalization: Normalization) -> Cow<'_, str> {
+    let composed: Cow<str> = match normalization {
+        Normalization::Off => return text,
+        Normalization::Whitespace => text,
+        Normalization::Nfc => Cow::Owned(text.nfc().collect()),
+        Normalization::Nfkc => Cow::Owned(text.nfkc().collect()),
+    };
+
+    // Drop control characters (and byte order marks), and turn every run
+    // of whitespace into a single space
+    let mut out = String::with_capacity(composed.len());
+    let mut space = false;
+    for c in composed.chars() {
+        if c.is_whitespace() {
+            space = !out.is_empty();
+        } else if !c.is_control() && c != '\u{feff}' {
+            if space {
+                out.push(' ');
+                space = false;
+            }
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Strip HTML tags, Markdown syntax and redundant whitespace, keeping only
+/// the readable text. Web-scraped chunks full of markup otherwise skew
+/// cross-encoder scores.
+pub fn strip_markup(text: &str) -> String {
+    collapse_whitespace(&strip_markdown(&strip_html(text)))
+}
+
+fn strip_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let tag = &rest[start..];
+
+        // Only `<` followed by a name, `/` or `!` opens a tag; "a < b" is text
+        let opens_tag = tag[1..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!');
+        let Some(end) = tag.find('>').filter(|_| opens_tag) else {
+            out.push('<');
+            rest = &tag[1..];
+            continue;
+        };
+
+        if let Some(comment) = tag.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+
+        let name = tag_name(tag);
+        rest = &tag[end + 1..];
+        if HIDDEN_TAGS.contains(&name.as_str()) && !tag.starts_with("</") {
+            let closing = format!("</{}", name);
+            rest = rest
+                .to_ascii_lowercase()
+                .find(&closing)
+                .and_then(|close| {
+                    let after = &rest[close..];
+                    after.find('>').map(|end| &after[end + 1..])
+                })
+                .unwrap_or("");
+        }
+        if !INLINE_TAGS.contains(&name.as_str()) {
+            out.push('\n');
+        }
+    }
+    out.push_str(rest);
+    decode_entities(&out)
+}
+
+/// The lowercase element name of a tag like `</Div class="x">`.
+fn tag_name(tag: &str) -> String {
+    tag.trim_start_matches(['<', '/', '!'])
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let candidate = &rest[start + 1..];
+        let decoded = candidate
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((entity(&candidate[..end])?, end + 1)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &candidate[len..];
+            }
+            None => {
+                out.push('&');
+                rest = candidate;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        "ndash" => Some('–'),
+        "mdash" => Some('—'),
+        "hellip" => Some('…'),
+        _ => {
+            let number = name.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+fn strip_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for line in text.lines() {
+        let mut line = line.trim_start();
+
+        // Fences, horizontal rules and table separators carry no text
+        if line.starts_with("```") || line.starts_with("~~~") || is_rule(line) {
+            out.push('\n');
+            continue;
+        }
+
+        while let Some(quoted) = line.strip_prefix('>') {
+            line = quoted.trim_start();
+        }
+        let heading = line.trim_start_matches('#');
+        if heading.len() < line.len() && (heading.is_empty() || heading.starts_with(' ')) {
+            line = heading.trim_start();
+        }
+        line = strip_list_marker(line);
+
+        out.push_str(&strip_inline(line));
+        out.push('\n');
+    }
+    out
+}
+
+/// `---`, `***`, `___` and table separators like `|---|:--:|`.
+fn is_rule(line: &str) -> bool {
+    let marks = line.chars().filter(|c| !c.is_whitespace()).count();
+    marks >= 3
+        && line
+            .chars()
+            .all(|c| matches!(c, '-' | '*' | '_' | '|' | ':' | '=') || c.is_whitespace())
+}
+
+fn strip_list_marker(line: &str) -> &str {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = line.strip_prefix(bullet) {
+            return item.trim_start();
+        }
+    }
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits > 0 {
+        let rest = &line[digits..];
+        if let Some(item) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return item.trim_start();
+        }
+    }
+    line
+}
+
+/// Unwrap links and images to their text, and drop emphasis, code and
+/// table markers.
+fn strip_inline(line: &str) -> String {
+    let mut unlinked = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(open) = rest.find('[') {
+        let tail = &rest[open..];
+        let link = tail.find("](").and_then(|close| {
+            let label = &tail[1..close];
+            let end = tail[close..].find(')')?;
+            (!label.contains(['[', ']'])).then_some((label, close + end + 1))
+        });
+        match link {
+            Some((label, len)) => {
+                let before = &rest[..open];
+                unlinked.push_str(before.strip_suffix('!').unwrap_or(before));
+                unlinked.push_str(label);
+                rest = &tail[len..];
+            }
+            None => {
+                unlinked.push_str(&rest[..open + 1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    unlinked.push_str(rest);
+
+    let chars: Vec<char> = unlinked.chars().collect();
+    let mut out = String::with_capacity(unlinked.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let before = i.checked_sub(1).and_then(|p| chars.get(p)).copied();
+        let after = chars.get(i + 1).copied();
+        let word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+        let space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+        match c {
+            '`' => {}
+            '|' => out.push(' '),
+            // `2 * 3` and `~5` are text; `**bold**` and `~~struck~~` aren't
+            '*' if !(space(before) && space(after)) => {}
+            '~' if before == Some('~') || after == Some('~') => {}
+            // `snake_case` keeps its underscores; `_emphasis_` loses them
+            '_' if !(word(before) && word(after)) => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Collapse runs of spaces within lines and of blank lines between them.
+fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&words.join(" "));
+    }
+    out
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// How urgently a request should be served when the backend is saturated.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Batch and evaluation traffic; shed first, served last.
+    #[serde(alias = "batch")]
+    Low,
+    #[default]
+    Normal,
+    /// Interactive traffic such as chat; never shed for latency.
+    #[serde(alias = "interactive")]
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "low" | "batch" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" | "interactive" => Ok(Priority::High),
+            other => Err(format!(
+                "unknown priority '{}' (expected low, normal or high)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
+
+/// Caps the number of requests in flight to TEI. Once the cap is reached,
+/// waiting requests are admitted highest priority first, then in arrival
+/// order.
+#[derive(Debug, Default)]
+pub struct PriorityQueue {
+    /// 0 disables the cap.
+    limit: usize,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    in_flight: usize,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    priority: Priority,
+    seq: u64,
+    wake: oneshot::Sender<()>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Max-heap: higher priority first, then the earlier arrival
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PriorityQueue {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Wait for a slot. Dropping the future before it resolves gives up the
+    /// place in the queue.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> Slot {
+        if self.limit == 0 {
+            return Slot { queue: None };
+        }
+
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight < self.limit {
+                state.in_flight += 1;
+                None
+            } else {
+                let (wake, woken) = oneshot::channel();
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.waiting.push(Waiter {
+                    priority,
+                    seq,
+                    wake,
+                });
+                Some(woken)
+            }
+        };
+
+        if let Some(woken) = wait {
+            let mut pending = Pending {
+                queue: self,
+                woken,
+                done: false,
+            };
+            // The slot is handed over by `release`, which never drops a
+            // sender without sending while we're still listening
+            let _ = (&mut pending.woken).await;
+            pending.done = true;
+        }
+        Slot {
+            queue: Some(self.clone()),
+        }
+    }
+
+    /// Requests currently waiting for a slot.
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().waiting.len()
+    }
+
+    /// Pass a finished request's slot to the best waiter still listening.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiting.pop() {
+            if waiter.wake.send(()).is_ok() {
+                return;
+            }
+        }
+        state.in_flight -= 1;
+    }
+}
+
+/// A queued `acquire` that may be cancelled while waiting.
+struct Pending<'a> {
+    queue: &'a PriorityQueue,
+    woken: oneshot::Receiver<()>,
+    done: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        // Cancelled; if a slot was handed over in the meantime, pass it on
+        self.woken.close();
+        if self.woken.try_recv().is_ok() {
+            self.queue.release();
+        }
+    }
+}
+
+/// A place among the requests in flight to TEI; freed on drop.
+#[derive(Debug)]
+pub struct Slot {
+    queue: Option<Arc<PriorityQueue>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(queue) = &self.queue {
+            queue.release();
+        }
+    }
+}
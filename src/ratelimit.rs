@@ -0,0 +1,147 @@
+use crate::auth;
+use crate::server;
+use crate::state::AppState;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::http::{HeaderMap, HeaderValue};
+use warp::Filter;
+
+/// How many requests a client has left in the current window, and when
+/// the window resets.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,
+}
+
+impl Quota {
+    /// Seconds until the window resets, rounded up so clients never retry
+    /// a moment too early.
+    pub fn reset_secs(&self) -> u64 {
+        self.reset.as_secs() + u64::from(self.reset.subsec_nanos() > 0)
+    }
+}
+
+/// Requests per client (API key, or IP address without one) in fixed
+/// windows of `window`. A limit of 0 disables rate limiting.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    started: Instant,
+    /// Requests per client, with the window they were counted in.
+    clients: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            started: Instant::now(),
+            clients: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// The current window's number, and the time until the next one.
+    fn current_window(&self) -> (u64, Duration) {
+        let elapsed = self.started.elapsed();
+        let number = (elapsed.as_nanos() / self.window.as_nanos()) as u64;
+        let next = Duration::from_nanos(((number + 1) as u128 * self.window.as_nanos()) as u64);
+        (number, next.saturating_sub(elapsed))
+    }
+
+    /// Count a request against `client`, or refuse it with the quota that
+    /// ran out.
+    pub fn acquire(&self, client: &str) -> Result<Quota, Quota> {
+        let (window, reset) = self.current_window();
+        let mut clients = self.clients.lock().unwrap();
+        // Forget clients that haven't been seen this window, so one-off IP
+        // addresses don't pile up
+        if clients.len() > 1024 {
+            clients.retain(|_, (seen, _)| *seen == window);
+        }
+
+        let (seen, used) = clients.entry(client.to_string()).or_insert((window, 0));
+        if *seen != window {
+            *seen = window;
+            *used = 0;
+        }
+        let quota = |used: u32| Quota {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(used),
+            reset,
+        };
+        if *used >= self.limit {
+            return Err(quota(*used));
+        }
+        *used += 1;
+        Ok(quota(*used))
+    }
+
+    /// What `client` has left, without counting a request.
+    pub fn quota(&self, client: &str) -> Quota {
+        let (window, reset) = self.current_window();
+        let used = match self.clients.lock().unwrap().get(client) {
+            Some((seen, used)) if *seen == window => *used,
+            _ => 0,
+        };
+        Quota {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(used),
+            reset,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Add the `X-RateLimit-*` headers and their IETF `RateLimit-*`
+    /// equivalents, so clients can slow down before they get a `429`.
+    pub fn add_headers(&self, client: &str, response: &mut warp::reply::Response) {
+        let quota = self.quota(client);
+        let reset = quota.reset_secs();
+        let headers = response.headers_mut();
+        for (name, value) in [
+            ("x-ratelimit-limit", quota.limit.to_string()),
+            ("x-ratelimit-remaining", quota.remaining.to_string()),
+            ("x-ratelimit-reset", reset.to_string()),
+            ("ratelimit-limit", quota.limit.to_string()),
+            ("ratelimit-remaining", quota.remaining.to_string()),
+            ("ratelimit-reset", reset.to_string()),
+            (
+                "ratelimit-policy",
+                format!("{};w={}", quota.limit, self.window.as_secs()),
+            ),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+/// Who a request counts against: its API key's label when it carries a
+/// valid one, its IP address otherwise.
+pub fn client(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+    warp::header::headers_cloned().and(server::remote()).map(
+        move |headers: HeaderMap, remote: Option<SocketAddr>| {
+            let key = auth::find_key(&state.config.api_keys, &headers);
+            match (key, remote) {
+                (Some(key), _) => key.label(),
+                (None, Some(remote)) => remote.ip().to_string(),
+                (None, None) => "unknown".to_string(),
+            }
+        },
+    )
+}
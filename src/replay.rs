@@ -0,0 +1,150 @@
+use crate::bench::{BenchReport, Samples};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// How long the target gets to answer one replayed request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// One request read from a replay log: when it was first sent, relative to
+/// the log's first request, where to, and its body.
+#[derive(Debug)]
+pub struct Entry {
+    pub offset: Duration,
+    pub path: String,
+    pub body: Value,
+}
+
+/// Settings for the `replay` command.
+#[derive(Debug)]
+pub struct ReplayOptions {
+    /// Base URL of the proxy or backend the log is replayed against.
+    pub target: String,
+    pub entries: Vec<Entry>,
+    /// How much faster than recorded to send requests, e.g. 10 for ten
+    /// times; 0 sends them as fast as `concurrency` allows.
+    pub speed: f64,
+    pub concurrency: usize,
+    /// Sent as a bearer token, for targets that require an API key.
+    pub api_key: Option<String>,
+}
+
+/// Read a replay log: one JSON object per line, either a recorded request
+/// (`{"timestamp_ms": ..., "path": "/rerank", "body": {...}}`, where only
+/// `body` is required) or a bare rerank request as in a bench corpus.
+/// Lines without a timestamp follow the previous one immediately.
+pub fn parse_log(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut first: Option<f64> = None;
+    let mut offset = Duration::ZERO;
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |e: &dyn std::fmt::Display| format!("line {}: {}", number + 1, e);
+        let mut value: Value = serde_json::from_str(line).map_err(|e| invalid(&e))?;
+        let Value::Object(object) = &mut value else {
+            return Err(invalid(&"not a JSON object"));
+        };
+
+        let Some(body) = object.remove("body") else {
+            entries.push(Entry {
+                offset,
+                path: "/rerank".to_string(),
+                body: value,
+            });
+            continue;
+        };
+        if let Some(timestamp) = object.get("timestamp_ms") {
+            let timestamp = timestamp
+                .as_f64()
+                .ok_or_else(|| invalid(&"timestamp_ms must be a number"))?;
+            let first = *first.get_or_insert(timestamp);
+            // Out-of-order lines go out as soon as they're reached
+            offset = offset.max(Duration::from_secs_f64(
+                ((timestamp - first) / 1000.0).max(0.0),
+            ));
+        }
+        let path = match object.get("path") {
+            None => "/rerank".to_string(),
+            Some(Value::String(path)) if path.starts_with('/') => path.clone(),
+            Some(_) => return Err(invalid(&"path must be a string starting with '/'")),
+        };
+        entries.push(Entry { offset, path, body });
+    }
+    Ok(entries)
+}
+
+/// Send every entry to the target on the log's schedule, sped up by
+/// `speed`, and collect the latencies of those answered with a `2xx`.
+/// Also returns how far sending fell behind schedule at worst, which
+/// means the target, or `concurrency`, couldn't keep up.
+pub async fn run(options: ReplayOptions) -> (BenchReport, Duration) {
+    let client = reqwest::Client::new();
+    let target = options.target.trim_end_matches('/').to_string();
+    let samples = Arc::new(Mutex::new(Samples::default()));
+    let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+
+    let started = Instant::now();
+    let mut lag = Duration::ZERO;
+    let mut tasks = Vec::new();
+    let sent = options.entries.len();
+
+    for entry in options.entries {
+        if options.speed > 0.0 {
+            let due = started + entry.offset.div_f64(options.speed);
+            tokio::time::sleep_until(due.into()).await;
+        }
+        let permit = match semaphore.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        if options.speed > 0.0 {
+            let due = entry.offset.div_f64(options.speed);
+            lag = lag.max(started.elapsed().saturating_sub(due));
+        }
+
+        let documents = ["documents", "texts", "passages"]
+            .iter()
+            .find_map(|field| entry.body.get(field)?.as_array())
+            .map_or(0, Vec::len);
+        let mut request = client
+            .post(format!("{}{}", target, entry.path))
+            .timeout(REQUEST_TIMEOUT)
+            .json(&entry.body);
+        if let Some(key) = &options.api_key {
+            request = request.bearer_auth(key);
+        }
+        let samples = samples.clone();
+
+        tasks.push(tokio::spawn(async move {
+            let start = Instant::now();
+            let result = request.send().await;
+            let latency = start.elapsed();
+            drop(permit);
+
+            let error = match result {
+                Ok(response) if response.status().is_success() => None,
+                Ok(response) => {
+                    let status = response.status();
+                    let text = response.text().await.unwrap_or_default();
+                    Some(format!("{}: {}", status, text))
+                }
+                Err(e) => Some(e.to_string()),
+            };
+            let mut samples = samples.lock().unwrap();
+            match error {
+                None => samples.succeeded(latency, documents),
+                Some(e) => samples.failed(e),
+            }
+        }));
+    }
+
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    let samples = std::mem::take(&mut *samples.lock().unwrap());
+    (samples.report(sent, started.elapsed()), lag)
+}
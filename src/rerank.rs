@@ -0,0 +1,1491 @@
+use crate::backends::BackendPool;
+use crate::bm25;
+use crate::cache::{self, CacheKey};
+use crate::config::Config;
+use crate::context::RequestContext;
+use crate::dns;
+use crate::error::ApiError;
+use crate::fallback::{self, Degraded};
+use crate::json;
+use crate::language;
+use crate::preprocess;
+use crate::retry;
+use crate::state::AppState;
+use crate::truncation;
+use crate::webhook;
+use log::{debug, error, info, log, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Characters per token in English text with a typical subword tokenizer,
+/// for estimates where the real tokenizer isn't asked.
+const CHARS_PER_TOKEN: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpenWebUIRequest {
+    pub query: String,
+    // Field names used by other rerank APIs (TEI, Cohere-style clients) are
+    // accepted too, so those clients work without an adapter
+    #[serde(alias = "texts", alias = "passages", deserialize_with = "one_or_many")]
+    pub documents: Vec<Document>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default, alias = "top_k")]
+    pub top_n: Option<usize>,
+    /// Add a `meta` object describing how the request was served.
+    #[serde(default, alias = "verbose")]
+    pub include_meta: bool,
+    /// Add each result's document, as sent, like Cohere's
+    /// `return_documents`.
+    #[serde(default)]
+    pub return_documents: bool,
+    /// Overrides `strip_markup` for this request.
+    #[serde(default)]
+    pub strip_markup: Option<bool>,
+    /// Whether results come best or worst first.
+    #[serde(default)]
+    pub order: SortOrder,
+    /// What orders results with equal scores, in turn. Ties otherwise keep
+    /// the order the documents were sent in.
+    #[serde(default)]
+    pub tie_break: Vec<SortKey>,
+    /// Results to skip, for browsing large candidate sets a page at a
+    /// time.
+    #[serde(default)]
+    pub offset: usize,
+    /// Results to return at most, after `offset`.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Fields the proxy doesn't know; those listed in `tei_forward_params`
+    /// are passed on to TEI.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl OpenWebUIRequest {
+    /// Rough heap footprint, used for memory budgeting.
+    pub fn estimated_bytes(&self) -> u64 {
+        let slot = std::mem::size_of::<Document>();
+        let documents: usize = self.documents.iter().map(|d| d.text().len() + slot).sum();
+        (self.query.len() + documents + std::mem::size_of::<Self>()) as u64
+    }
+}
+
+/// A document to rank: plain text, or an object that can also carry the
+/// retriever's score for fusion and the client's own ID and metadata,
+/// which are echoed back on its result.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Document {
+    Text(String),
+    Object {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retrieval_score: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Value>,
+    },
+}
+
+impl Document {
+    pub fn text(&self) -> &str {
+        match self {
+            Document::Text(text) | Document::Object { text, .. } => text,
+        }
+    }
+
+    pub fn retrieval_score(&self) -> Option<f64> {
+        match self {
+            Document::Text(_) => None,
+            Document::Object {
+                retrieval_score, ..
+            } => *retrieval_score,
+        }
+    }
+
+    pub fn id(&self) -> Option<&Value> {
+        match self {
+            Document::Text(_) => None,
+            Document::Object { id, .. } => id.as_ref(),
+        }
+    }
+
+    pub fn metadata(&self) -> Option<&Value> {
+        match self {
+            Document::Text(_) => None,
+            Document::Object { metadata, .. } => metadata.as_ref(),
+        }
+    }
+}
+
+/// A single document, as thin clients that rerank one candidate at a time
+/// send it, or a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Document>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        Many(Vec<Document>),
+        One(Document),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::Many(documents) => documents,
+        OneOrMany::One(document) => vec![document],
+    })
+}
+
+impl Document {
+    /// The document as returned on its result: the object as sent, or
+    /// `{"text": ...}` for plain text, as Cohere does.
+    pub fn to_value(&self) -> Value {
+        match self {
+            Document::Text(text) => serde_json::json!({ "text": text }),
+            document => serde_json::to_value(document).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<String> for Document {
+    fn from(text: String) -> Self {
+        Document::Text(text)
+    }
+}
+
+/// Borrows from the client request so large document sets aren't copied.
+#[derive(Serialize, Debug)]
+pub struct TEIRequest<'a> {
+    pub query: &'a str,
+    pub texts: &'a [&'a str],
+    /// Client parameters forwarded as-is, e.g. `truncate`.
+    #[serde(flatten)]
+    pub params: &'a Map<String, Value>,
+}
+
+/// Body of an upstream rerank call: TEI's format, or one built by the
+/// configured adapter.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum UpstreamRequest<'a> {
+    Tei(TEIRequest<'a>),
+    Mapped(Value),
+}
+
+/// TEI's rerank results. Accepts the shapes TEI versions and compatible
+/// servers answer with: a bare array or one wrapped in an object, with or
+/// without each document's `text` and under either score name.
+#[derive(Deserialize, Debug)]
+#[serde(from = "TEIResponseShape")]
+pub struct TEIResponse(pub Vec<TEIRankResult>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TEIResponseShape {
+    Bare(Vec<TEIRankResult>),
+    Wrapped {
+        #[serde(alias = "data")]
+        results: Vec<TEIRankResult>,
+    },
+}
+
+impl From<TEIResponseShape> for TEIResponse {
+    fn from(shape: TEIResponseShape) -> Self {
+        match shape {
+            TEIResponseShape::Bare(results) | TEIResponseShape::Wrapped { results } => {
+                TEIResponse(results)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TEIRankResult {
+    pub index: usize,
+    #[serde(alias = "relevance_score")]
+    pub score: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenWebUIResponse {
+    pub results: Vec<RankResult>,
+    /// How many results there are in all, when the request asked for a
+    /// page of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ResponseMeta>,
+    #[serde(skip)]
+    pub timing: Timing,
+    /// Every score came from the cache, so TEI wasn't called at all.
+    #[serde(skip)]
+    pub cache_hit: bool,
+    /// The scores are ones the cache keeps, so clients may keep the
+    /// response as long; not when it bypassed the cache or was degraded.
+    #[serde(skip)]
+    pub cacheable: bool,
+}
+
+/// Where a request's time went, sent back in the `Server-Timing` header.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timing {
+    /// Waiting for a slot while TEI was saturated.
+    pub queue: Duration,
+    /// Waiting on TEI's answers, cascade stage included.
+    pub upstream: Duration,
+    /// Calibrating, sorting and rendering the scores.
+    pub postprocess: Duration,
+}
+
+impl Timing {
+    /// The `Server-Timing` header value, with `total` for the whole request.
+    pub fn header(&self, total: Duration) -> String {
+        [
+            ("queue", self.queue),
+            ("upstream", self.upstream),
+            ("postprocess", self.postprocess),
+            ("total", total),
+        ]
+        .iter()
+        .map(|(name, duration)| format!("{};dur={:.1}", name, duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// How a request was served, returned when the client asks for it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseMeta {
+    pub model: Option<String>,
+    /// The TEI backend that scored the uncached documents, if any.
+    pub backend: Option<String>,
+    pub upstream_latency_ms: f64,
+    pub total_latency_ms: f64,
+    /// Every score came from the cache; TEI wasn't called.
+    pub cached: bool,
+    pub cached_documents: usize,
+    /// ISO 639-3 code of the detected language, when language routing is
+    /// configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// How the documents were scored instead, when TEI couldn't rerank them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub degraded: Option<Degraded>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RankResult {
+    pub index: usize,
+    /// The result's position in the ranking, from 1, counting any results
+    /// skipped by `offset`.
+    #[serde(default)]
+    pub rank: usize,
+    pub relevance_score: f64,
+    /// The document's `id` and `metadata`, if it was sent with them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    /// The document itself, when the client asked for `return_documents`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document: Option<Value>,
+}
+
+/// Which field results carry their score in: Cohere's and OpenWebUI's
+/// `relevance_score`, TEI's `score`, or both.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreField {
+    #[default]
+    RelevanceScore,
+    Score,
+    Both,
+}
+
+impl FromStr for ScoreField {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "relevance_score" => Ok(ScoreField::RelevanceScore),
+            "score" => Ok(ScoreField::Score),
+            "both" => Ok(ScoreField::Both),
+            other => Err(format!(
+                "unknown score field '{}' (expected relevance_score, score or both)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ScoreField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ScoreField::RelevanceScore => "relevance_score",
+            ScoreField::Score => "score",
+            ScoreField::Both => "both",
+        })
+    }
+}
+
+/// Which way results are sorted by score.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Highest score first.
+    #[default]
+    Desc,
+    /// Lowest score first.
+    Asc,
+}
+
+/// A secondary sort key, for documents with equal scores. Both sort
+/// ascending whatever the `order`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    /// The document's position in the request.
+    Index,
+    /// The document's length in characters, shortest first.
+    Length,
+}
+
+/// What a rerank request would send TEI, from `/rerank/validate`.
+#[derive(Serialize, Debug)]
+pub struct Plan {
+    /// The query as TEI would see it, rewritten and normalized.
+    pub query: String,
+    pub documents: usize,
+    /// The language whose backends would score it, when routed by language.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<&'static str>,
+    /// The calls to TEI in order: a cascade's first stage, if any, then the
+    /// final model's.
+    pub calls: Vec<PlannedCall>,
+}
+
+/// One call to TEI in a [`Plan`]. What's unknown for a cascade's final
+/// model, which scores whichever documents the first stage ranks highest,
+/// is left out.
+#[derive(Serialize, Debug)]
+pub struct PlannedCall {
+    pub model: Option<String>,
+    pub documents: usize,
+    /// Documents cut down to fit the model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<usize>,
+    /// Size of the request body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_bytes: Option<usize>,
+    /// A rough count of the tokens in every (query, document) pair.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_tokens: Option<usize>,
+    /// Parameters sent along, including any `truncate` the proxy adds.
+    pub params: Map<String, Value>,
+    /// The request body itself, for `/debug/transform`.
+    #[serde(skip)]
+    pub body: Option<Value>,
+}
+
+impl Plan {
+    /// The requests the plan's calls would send upstream, for
+    /// `/debug/transform`. A body that isn't known yet is `null`.
+    pub fn requests(&self, config: &Config) -> Value {
+        let path = config
+            .tei_adapter
+            .as_ref()
+            .map_or("/rerank", |adapter| adapter.path.as_str());
+        let requests: Vec<Value> = self
+            .calls
+            .iter()
+            .map(|call| {
+                serde_json::json!({
+                    "model": call.model,
+                    "path": path,
+                    "body": call.body,
+                })
+            })
+            .collect();
+        serde_json::json!({ "requests": requests })
+    }
+}
+
+impl PlannedCall {
+    fn new(
+        config: &Config,
+        query: &str,
+        texts: &[&str],
+        model: Option<&str>,
+        params: Map<String, Value>,
+        truncated: usize,
+    ) -> Self {
+        let body = upstream_request(config, query, texts, model, &params);
+        let body = serde_json::to_value(&body).ok();
+        let request_bytes = body.as_ref().map_or(0, |body| body.to_string().len());
+        Self {
+            model: model.map(str::to_string),
+            documents: texts.len(),
+            truncated: Some(truncated),
+            request_bytes: Some(request_bytes),
+            estimated_tokens: Some(estimate_tokens(query, texts.iter().copied())),
+            params,
+            body,
+        }
+    }
+}
+
+/// Whether results are wrapped in `{"results": [...]}` or sent as a bare
+/// array, as TEI does. A bare array has no room for `meta`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Envelope {
+    #[default]
+    Object,
+    Array,
+}
+
+impl FromStr for Envelope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "object" => Ok(Envelope::Object),
+            "array" => Ok(Envelope::Array),
+            other => Err(format!(
+                "unknown envelope '{}' (expected object or array)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Envelope::Object => "object",
+            Envelope::Array => "array",
+        })
+    }
+}
+
+impl OpenWebUIResponse {
+    /// The response as sent to the client, in the shape its library
+    /// expects.
+    pub fn to_json(&self, score_field: ScoreField, envelope: Envelope) -> Value {
+        let results: Vec<Value> = self
+            .results
+            .iter()
+            .map(|result| {
+                let Ok(Value::Object(fields)) = serde_json::to_value(result) else {
+                    return Value::Null;
+                };
+                // Renamed in place, so the score stays where clients expect it
+                let fields = fields
+                    .into_iter()
+                    .flat_map(|(key, value)| match (key.as_str(), score_field) {
+                        ("relevance_score", ScoreField::Score) => {
+                            vec![("score".to_string(), value)]
+                        }
+                        ("relevance_score", ScoreField::Both) => {
+                            vec![(key, value.clone()), ("score".to_string(), value)]
+                        }
+                        _ => vec![(key, value)],
+                    })
+                    .collect();
+                Value::Object(fields)
+            })
+            .collect();
+
+        match envelope {
+            Envelope::Array => Value::Array(results),
+            Envelope::Object => {
+                let mut response = Map::new();
+                response.insert("results".to_string(), Value::Array(results));
+                if let Some(total) = self.total {
+                    response.insert("total".to_string(), total.into());
+                }
+                if let Some(meta) = &self.meta {
+                    response.insert(
+                        "meta".to_string(),
+                        serde_json::to_value(meta).unwrap_or_default(),
+                    );
+                }
+                Value::Object(response)
+            }
+        }
+    }
+}
+
+/// Run the request hooks over a request before it's reranked. Every way in,
+/// the HTTP handler and the `rerank` CLI command alike, goes through here
+/// and then `respond`.
+pub async fn prepare_request(
+    state: &AppState,
+    ctx: &RequestContext,
+    req: OpenWebUIRequest,
+) -> Result<OpenWebUIRequest, ApiError> {
+    let req = webhook::preprocess(state, ctx, req).await?;
+    state.plugins.on_request(ctx, req)
+}
+
+/// Rerank a prepared request, then run the response hooks over the result.
+pub async fn respond(
+    state: &AppState,
+    req: &OpenWebUIRequest,
+    ctx: &RequestContext,
+) -> Result<OpenWebUIResponse, ApiError> {
+    let response = rerank(state, req, ctx).await?;
+    state.plugins.on_response(ctx, req, response)
+}
+
+/// Validate a rerank request, forward it to TEI and return the ranked
+/// results, without running any hooks.
+pub async fn rerank(
+    state: &AppState,
+    req: &OpenWebUIRequest,
+    ctx: &RequestContext,
+) -> Result<OpenWebUIResponse, ApiError> {
+    let config = &state.config;
+    let started = Instant::now();
+    let mut timing = Timing::default();
+    let mut backend = None;
+    let mut degraded = None;
+
+    info!("🔄 Processing rerank request for query: '{}'", req.query);
+    if let Some(trace) = &ctx.trace {
+        info!(
+            "🧵 Trace {}: span {:016x}, child of {:016x}",
+            trace.trace_id(),
+            trace.span_id,
+            trace.parent_id
+        );
+    }
+    info!(
+        "📊 Number of documents: {}, top_n: {:?}",
+        req.documents.len(),
+        req.top_n
+    );
+
+    // Log the complete incoming request from WebUI
+    if let Some(level) = ctx.payload_log_level() {
+        match serde_json::to_string_pretty(req) {
+            Ok(json_str) => log!(level, "📥 Complete WebUI Request:\n{}", json_str),
+            Err(e) => warn!("❌ Failed to serialize WebUI request for logging: {}", e),
+        }
+    }
+
+    let Prepared {
+        mut params,
+        query,
+        documents,
+        language,
+        pool,
+    } = prepare(state, ctx, req)?;
+
+    // Reuse cached scores and only send TEI the documents it hasn't scored.
+    // Forwarded parameters such as `raw_scores` can change the scores, so
+    // requests carrying any bypass the cache. Language backends run their
+    // own models, so what they score is cached apart.
+    let routed = language.filter(|&code| state.language_backends.contains_key(code));
+    let keys: Vec<CacheKey> = if state.cache.is_enabled() && params.is_empty() {
+        documents
+            .iter()
+            .map(|document| cache::key(req.model.as_deref(), routed, &query, document))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // With a cascade, a cheaper model scores every document first and only
+    // those it ranks highest go on to the regular backends
+    let shortlisted = match &state.cascade {
+        Some(cascade) if req.documents.len() > config.cascade_top_k => {
+            let (shortlisted, call) =
+                shortlist(state, ctx, cascade, &query, &documents, &params).await?;
+            timing.queue += call.queued;
+            timing.upstream += call.latency;
+            Some(shortlisted)
+        }
+        _ => None,
+    };
+    let is_candidate = |index: usize| shortlisted.as_ref().is_none_or(|s| s[index]);
+    let candidates = (0..documents.len()).filter(|&i| is_candidate(i)).count();
+
+    // The limits the backends report on `/info`, beyond the configured ones
+    fit_to_info(pool, &query, candidates, &documents, &mut params)?;
+
+    let mut scores = if keys.is_empty() || ctx.no_cache {
+        vec![None; req.documents.len()]
+    } else {
+        state.cache.get_many(&keys)
+    };
+    let missing: Vec<usize> = (0..scores.len())
+        .filter(|&i| is_candidate(i) && scores[i].is_none())
+        .collect();
+
+    if missing.is_empty() {
+        info!("✅ All {} scores served from cache", candidates);
+    } else {
+        if missing.len() < candidates {
+            info!(
+                "♻️ {} of {} scores served from cache",
+                candidates - missing.len(),
+                candidates
+            );
+        }
+
+        let texts: Vec<&str> = missing.iter().map(|&i| &*documents[i]).collect();
+        let texts = truncation::fit(state, ctx, pool, &query, texts).await?;
+        let texts: Vec<&str> = texts.iter().map(|text| &**text).collect();
+        let call = call_tei(
+            state,
+            ctx,
+            pool,
+            &query,
+            &texts,
+            req.model.as_deref(),
+            &params,
+        )
+        .await;
+        match call {
+            Ok(call) => {
+                timing.queue += call.queued;
+                timing.upstream += call.latency;
+                backend = Some(call.backend);
+                for result in call.results {
+                    scores[missing[result.index]] = Some(result.score);
+                }
+
+                if !keys.is_empty() {
+                    state.cache.insert_many(
+                        req.model.as_deref(),
+                        missing.iter().filter_map(|&i| Some((keys[i], scores[i]?))),
+                    );
+                }
+            }
+            // Every candidate is scored again, as cached scores are on
+            // another scale; these ones aren't cached
+            Err(e) => {
+                let falling_back = Instant::now();
+                let indices: Vec<usize> =
+                    (0..documents.len()).filter(|&i| is_candidate(i)).collect();
+                let texts: Vec<&str> = indices.iter().map(|&i| &*documents[i]).collect();
+                let (mode, fallback_scores) =
+                    fallback::score(state, ctx, pool, &query, &texts, req.model.as_deref(), e)
+                        .await?;
+                if mode == Degraded::EmbeddingSimilarity {
+                    timing.upstream += falling_back.elapsed();
+                }
+                for (index, score) in indices.into_iter().zip(fallback_scores) {
+                    scores[index] = Some(score);
+                }
+                degraded = Some(mode);
+                state.upstream.record_fallback(mode);
+            }
+        }
+    }
+
+    // Transform back to OpenWebUI format with ranking, calibrating TEI's
+    // scores, blending in keyword matches and fusing in any client-supplied
+    // retrieval scores
+    let postprocessing = Instant::now();
+    let curve = state.calibration.get(req.model.as_deref());
+    let calibration = config.calibration_for(req.model.as_deref());
+    let lexical: Vec<f64> = if config.lexical_weight > 0.0 && degraded.is_none() {
+        let texts: Vec<&str> = documents.iter().map(|document| &**document).collect();
+        bm25::scores(&query, &texts)
+    } else {
+        Vec::new()
+    };
+    let mut indexed_scores: Vec<(usize, f64)> = scores
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, _)| is_candidate(index))
+        .map(|(index, score)| {
+            let retrieval_score = req.documents[index].retrieval_score();
+            score
+                .map(|score| {
+                    // Calibration was fitted to the reranker's scores
+                    let score = match &curve {
+                        _ if degraded.is_some() => score,
+                        Some(curve) => curve.apply(score),
+                        None => calibration.apply(score),
+                    };
+                    let score = match lexical.get(index) {
+                        Some(lexical) => {
+                            (1.0 - config.lexical_weight) * score + config.lexical_weight * lexical
+                        }
+                        None => score,
+                    };
+                    (index, fuse(config, score, retrieval_score))
+                })
+                .ok_or_else(|| {
+                    error!("TEI response has no score for document {}", index);
+                    ApiError::TEIError("TEI response is missing document scores".to_string())
+                })
+        })
+        .collect::<Result<_, _>>()?;
+
+    // Sort by relevance score, before any rounding so that scores rounded
+    // to the same value keep their true order
+    let lengths: Vec<usize> = if req.tie_break.contains(&SortKey::Length) {
+        req.documents
+            .iter()
+            .map(|d| d.text().chars().count())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    indexed_scores.sort_by(|a, b| {
+        let by_score = a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal);
+        let by_score = match req.order {
+            SortOrder::Desc => by_score.reverse(),
+            SortOrder::Asc => by_score,
+        };
+        req.tie_break.iter().fold(by_score, |ordering, key| {
+            ordering.then_with(|| match key {
+                SortKey::Index => a.0.cmp(&b.0),
+                SortKey::Length => lengths[a.0].cmp(&lengths[b.0]),
+            })
+        })
+    });
+    let total = (req.offset > 0 || req.limit.is_some()).then_some(indexed_scores.len());
+    let results: Vec<RankResult> = indexed_scores
+        .into_iter()
+        .enumerate()
+        .skip(req.offset)
+        .take(req.limit.unwrap_or(usize::MAX))
+        .map(|(position, (index, score))| RankResult {
+            index,
+            rank: position + 1,
+            relevance_score: match config.score_precision {
+                Some(digits) => round(score, digits),
+                None => score,
+            },
+            id: req.documents[index].id().cloned(),
+            metadata: req.documents[index].metadata().cloned(),
+            document: req
+                .return_documents
+                .then(|| req.documents[index].to_value()),
+        })
+        .collect();
+
+    // A degraded response always says so
+    let meta = (req.include_meta || degraded.is_some()).then(|| ResponseMeta {
+        model: req.model.clone(),
+        backend,
+        upstream_latency_ms: timing.upstream.as_secs_f64() * 1000.0,
+        total_latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        cached: missing.is_empty(),
+        cached_documents: match degraded {
+            Some(_) => 0,
+            None => candidates - missing.len(),
+        },
+        language: language.map(str::to_string),
+        degraded,
+    });
+    timing.postprocess = postprocessing.elapsed();
+    let response = OpenWebUIResponse {
+        results,
+        total,
+        meta,
+        timing,
+        cache_hit: !keys.is_empty() && missing.is_empty() && shortlisted.is_none(),
+        cacheable: !keys.is_empty() && degraded.is_none(),
+    };
+
+    // Log the final response being sent back to WebUI
+    if let Some(level) = ctx.payload_log_level() {
+        match serde_json::to_string_pretty(&response) {
+            Ok(json_str) => log!(level, "📤 Final WebUI Response:\n{}", json_str),
+            Err(e) => warn!("❌ Failed to serialize WebUI response for logging: {}", e),
+        }
+    }
+
+    info!(
+        "✅ Successfully processed rerank request, returning {} results",
+        response.results.len()
+    );
+
+    let elapsed = started.elapsed();
+    if config.slow_request_ms > 0 && elapsed >= Duration::from_millis(config.slow_request_ms) {
+        warn!(
+            "🐢 Slow rerank request: query_hash={} documents={} cached={} tei_ms={} total_ms={}",
+            query_hash(&req.query),
+            req.documents.len(),
+            candidates - missing.len(),
+            timing.upstream.as_millis(),
+            elapsed.as_millis()
+        );
+    }
+
+    Ok(response)
+}
+
+/// A request checked against the limits and turned into what TEI scores.
+struct Prepared<'a> {
+    /// Client parameters forwarded to TEI.
+    params: Map<String, Value>,
+    query: Cow<'a, str>,
+    documents: Vec<Cow<'a, str>>,
+    language: Option<&'static str>,
+    /// The backends that score it.
+    pool: &'a BackendPool,
+}
+
+/// Validate `req` and preprocess its query and documents, as `rerank` and
+/// `plan` both start by doing.
+fn prepare<'a>(
+    state: &'a AppState,
+    ctx: &RequestContext,
+    req: &'a OpenWebUIRequest,
+) -> Result<Prepared<'a>, ApiError> {
+    let config = &state.config;
+
+    // Validate input
+    if req.query.trim().is_empty() {
+        warn!("Empty query received");
+        return Err(ApiError::BadRequest("Query cannot be empty".to_string()));
+    }
+
+    if req.documents.is_empty() {
+        warn!("No documents provided");
+        return Err(ApiError::BadRequest(
+            "Documents list cannot be empty".to_string(),
+        ));
+    }
+
+    if let Some(model) = req.model.as_deref() {
+        if !config.allowed_models.is_empty() && !config.allowed_models.iter().any(|m| m == model) {
+            warn!("Model '{}' is not allowed", model);
+            return Err(ApiError::BadRequest(format!(
+                "Unknown model '{}', allowed: {}",
+                model,
+                config.allowed_models.join(", ")
+            )));
+        }
+        if let Some(key) = ctx.api_key.as_ref().filter(|key| !key.may_use(Some(model))) {
+            warn!("{} may not use model '{}'", key.label(), model);
+            return Err(ApiError::Forbidden(format!(
+                "This API key may not use model '{}'",
+                model
+            )));
+        }
+    }
+
+    let limits = config.limits_for(ctx.route, req.model.as_deref());
+
+    if req.documents.len() > limits.max_batch_size {
+        warn!("Too many documents: {}", req.documents.len());
+        return Err(ApiError::BadRequest(format!(
+            "Too many documents, max: {}",
+            limits.max_batch_size
+        )));
+    }
+
+    if let Some(max_length) = limits.max_document_length {
+        if let Some(index) = req
+            .documents
+            .iter()
+            .position(|document| document.text().chars().count() > max_length)
+        {
+            warn!("Document {} is too long", index);
+            return Err(ApiError::BadRequest(format!(
+                "Document {} is too long, max: {} characters",
+                index, max_length
+            )));
+        }
+    }
+
+    let params: Map<String, Value> = req
+        .extra
+        .iter()
+        .filter(|(name, _)| config.tei_forward_params.contains(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
+    // What TEI scores, and what the cache is keyed on
+    let normalization = config.normalization_for(req.model.as_deref());
+    let strip_markup = req.strip_markup.unwrap_or(config.strip_markup);
+    let query = preprocess::normalize(state.query_rewriter.rewrite(&req.query), normalization);
+    if query.is_empty() {
+        warn!("Query is empty after rewriting");
+        return Err(ApiError::BadRequest(
+            "Query is empty after rewriting".to_string(),
+        ));
+    }
+    let documents: Vec<Cow<str>> = req
+        .documents
+        .iter()
+        .map(|document| {
+            let text = if strip_markup {
+                Cow::Owned(preprocess::strip_markup(document.text()))
+            } else {
+                Cow::Borrowed(document.text())
+            };
+            preprocess::normalize(text, normalization)
+        })
+        .collect();
+
+    if let Some(max_tokens) = limits.max_request_tokens {
+        let estimated = estimate_tokens(&query, documents.iter().map(|document| &**document));
+        if estimated > max_tokens {
+            warn!("Too many tokens: about {}", estimated);
+            return Err(ApiError::TooManyTokens(estimated, max_tokens));
+        }
+    }
+
+    // Documents in a language with a dedicated backend are scored there
+    let language = if config.language_backends.is_empty() {
+        None
+    } else {
+        language::detect(&query, &documents)
+    };
+    let pool = match language.and_then(|code| Some((code, state.language_backends.get(code)?))) {
+        Some((code, pool)) => {
+            debug!("🌐 Routing {} request to its language backend", code);
+            pool
+        }
+        None => &state.backends,
+    };
+
+    Ok(Prepared {
+        params,
+        query,
+        documents,
+        language,
+        pool,
+    })
+}
+
+/// A rough count of the tokens in each (query, text) pair, added up.
+fn estimate_tokens<'a>(query: &str, texts: impl Iterator<Item = &'a str>) -> usize {
+    let query_length = query.chars().count();
+    texts
+        .map(|text| (query_length + text.chars().count()).div_ceil(CHARS_PER_TOKEN))
+        .sum()
+}
+
+/// The body of a rerank call: TEI's format, or the upstream's own.
+fn upstream_request<'a>(
+    config: &Config,
+    query: &'a str,
+    texts: &'a [&'a str],
+    model: Option<&str>,
+    params: &'a Map<String, Value>,
+) -> UpstreamRequest<'a> {
+    match &config.tei_adapter {
+        Some(adapter) => UpstreamRequest::Mapped(adapter.request(query, texts, model, params)),
+        None => UpstreamRequest::Tei(TEIRequest {
+            query,
+            texts,
+            params,
+        }),
+    }
+}
+
+/// What `rerank` would send TEI for a request, without sending it: the
+/// same validation and preprocessing, then the calls it would make. Cached
+/// scores aren't looked up, so every document is planned as scored.
+pub async fn plan(
+    state: &AppState,
+    ctx: &RequestContext,
+    req: &OpenWebUIRequest,
+) -> Result<Plan, ApiError> {
+    let config = &state.config;
+    let Prepared {
+        mut params,
+        query,
+        documents,
+        language,
+        pool,
+    } = prepare(state, ctx, req)?;
+    let texts: Vec<&str> = documents.iter().map(|document| &**document).collect();
+
+    let mut calls = Vec::new();
+    let candidates = match &state.cascade {
+        Some(cascade) if req.documents.len() > config.cascade_top_k => {
+            let mut params = params.clone();
+            fit_to_info(cascade, &query, documents.len(), &documents, &mut params)?;
+            let model = config.cascade_model.as_deref();
+            calls.push(PlannedCall::new(config, &query, &texts, model, params, 0));
+            config.cascade_top_k
+        }
+        _ => documents.len(),
+    };
+    fit_to_info(pool, &query, candidates, &documents, &mut params)?;
+
+    // Which documents make a cascade's shortlist isn't known until its
+    // first stage has scored them
+    if calls.is_empty() {
+        let fitted = truncation::fit(state, ctx, pool, &query, texts).await?;
+        let truncated = fitted
+            .iter()
+            .filter(|text| matches!(text, Cow::Owned(_)))
+            .count();
+        let texts: Vec<&str> = fitted.iter().map(|text| &**text).collect();
+        let model = req.model.as_deref();
+        calls.push(PlannedCall::new(
+            config, &query, &texts, model, params, truncated,
+        ));
+    } else {
+        calls.push(PlannedCall {
+            model: req.model.clone(),
+            documents: candidates,
+            truncated: None,
+            request_bytes: None,
+            estimated_tokens: None,
+            params,
+            body: None,
+        });
+    }
+
+    info!(
+        "🧪 Planned rerank request: {} documents in {} TEI calls",
+        req.documents.len(),
+        calls.len()
+    );
+    Ok(Plan {
+        query: query.into_owned(),
+        documents: req.documents.len(),
+        language,
+        calls,
+    })
+}
+
+/// Check a request against the limits `pool`'s backends report on
+/// `/info`, asking TEI to truncate when a (query, document) pair may be
+/// too long. `count` of the documents are to be scored.
+fn fit_to_info(
+    pool: &BackendPool,
+    query: &str,
+    count: usize,
+    documents: &[Cow<str>],
+    params: &mut Map<String, Value>,
+) -> Result<(), ApiError> {
+    let Some(info) = pool.info() else {
+        return Ok(());
+    };
+    if count > info.max_client_batch_size {
+        warn!("Too many documents for TEI: {}", count);
+        return Err(ApiError::BadRequest(format!(
+            "Too many documents, max: {}",
+            info.max_client_batch_size
+        )));
+    }
+    // Pairs with more characters than TEI takes tokens may be too long,
+    // which TEI rejects unless asked to truncate
+    let query_length = query.chars().count();
+    let may_overflow = documents
+        .iter()
+        .any(|document| query_length + document.chars().count() > info.max_input_length);
+    if may_overflow && !params.contains_key("truncate") {
+        debug!(
+            "✂️ Asking TEI to truncate inputs over {} tokens",
+            info.max_input_length
+        );
+        params.insert("truncate".to_string(), Value::Bool(true));
+    }
+    Ok(())
+}
+
+/// A cascade's first stage: score every document with `cascade_model` on
+/// the cascade backend, and mark the `cascade_top_k` best. Also returns
+/// the call, for how long it took.
+async fn shortlist(
+    state: &AppState,
+    ctx: &RequestContext,
+    pool: &BackendPool,
+    query: &str,
+    documents: &[Cow<'_, str>],
+    params: &Map<String, Value>,
+) -> Result<(Vec<bool>, TEICall), ApiError> {
+    let config = &state.config;
+    let mut params = params.clone();
+    fit_to_info(pool, query, documents.len(), documents, &mut params)?;
+
+    let texts: Vec<&str> = documents.iter().map(|document| &**document).collect();
+    let call = call_tei(
+        state,
+        ctx,
+        pool,
+        query,
+        &texts,
+        config.cascade_model.as_deref(),
+        &params,
+    )
+    .await?;
+
+    let mut ranked: Vec<(usize, f64)> = call
+        .results
+        .iter()
+        .map(|result| (result.index, result.score))
+        .collect();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    let mut shortlisted = vec![false; documents.len()];
+    for (index, _) in ranked.into_iter().take(config.cascade_top_k) {
+        shortlisted[index] = true;
+    }
+    info!(
+        "🪜 Cascade kept {} of {} documents for the final model",
+        config.cascade_top_k.min(documents.len()),
+        documents.len()
+    );
+    Ok((shortlisted, call))
+}
+
+/// Blend TEI's cross-encoder score with the client's retrieval score, when
+/// the document has one.
+fn fuse(config: &Config, score: f64, retrieval_score: Option<f64>) -> f64 {
+    match retrieval_score {
+        Some(retrieval_score) => {
+            config.fusion_rerank_weight * score
+                + config.fusion_retrieval_weight * retrieval_score
+                + config.fusion_bias
+        }
+        None => score,
+    }
+}
+
+/// Round `score` to `digits` decimal places.
+fn round(score: f64, digits: u32) -> f64 {
+    let scale = 10f64.powi(digits as i32);
+    (score * scale).round() / scale
+}
+
+/// Short stable fingerprint of a query, for logs that mustn't contain it.
+pub fn query_hash(query: &str) -> String {
+    Sha256::digest(query.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// One completed call to TEI.
+struct TEICall {
+    /// Validated results, with indices into the texts sent.
+    results: Vec<TEIRankResult>,
+    /// How long it waited for a slot before being sent.
+    queued: Duration,
+    latency: Duration,
+    backend: String,
+}
+
+/// Add the upstream's credentials to `request`: a Google access token with
+/// `tei_google_auth`, `tei_api_key` otherwise.
+pub async fn authenticate(
+    state: &AppState,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::RequestBuilder, ApiError> {
+    if let Some(google) = &state.google_auth {
+        let token = google.token().await.map_err(|e| {
+            error!("Failed to get a Google access token: {}", e);
+            ApiError::TEIError("Failed to authenticate to the upstream".to_string())
+        })?;
+        return Ok(request.bearer_auth(token));
+    }
+    Ok(match &state.config.tei_api_key {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    })
+}
+
+/// Send one rerank call to a TEI backend and return its validated results.
+async fn call_tei(
+    state: &AppState,
+    ctx: &RequestContext,
+    pool: &BackendPool,
+    query: &str,
+    texts: &[&str],
+    model: Option<&str>,
+    params: &Map<String, Value>,
+) -> Result<TEICall, ApiError> {
+    let config = &state.config;
+    let adapter = config.tei_adapter.as_ref();
+    let tei_req = upstream_request(config, query, texts, model, params);
+
+    // Log the request being sent to TEI
+    if let Some(level) = ctx.payload_log_level() {
+        match serde_json::to_string_pretty(&tei_req) {
+            Ok(json_str) => log!(level, "📤 TEI Request:\n{}", json_str),
+            Err(e) => warn!("❌ Failed to serialize TEI request for logging: {}", e),
+        }
+    }
+
+    // Wait for a slot when TEI is saturated; higher priorities go first
+    if state.queue.waiting() > 0 {
+        debug!("⏳ Queued {} priority request", ctx.priority);
+    }
+    let queueing = Instant::now();
+    let _slot = state.queue.acquire(ctx.priority).await;
+    let queued = queueing.elapsed();
+    state.faults.before_call().await?;
+
+    state.retry_budget.deposit();
+    let mut retries = 0;
+    let (backend, span, response_text, latency) = loop {
+        let backend = pool.pick(query).ok_or_else(|| {
+            error!("No TEI backends available");
+            ApiError::NoBackends(pool.statuses())
+        })?;
+
+        info!("🚀 Forwarding request to TEI endpoint: {}", backend.url);
+
+        // Call TEI endpoint
+        let tei_url = format!(
+            "{}{}",
+            backend.url,
+            adapter.map_or("/rerank", |adapter| adapter.path.as_str())
+        );
+        let mut request = state
+            .client()
+            .post(&tei_url)
+            .timeout(config.limits_for(ctx.route, model).timeout)
+            .json(&tei_req);
+        request = authenticate(state, request).await?;
+        // Each TEI call is its own child span so a straggler stands out
+        let span = ctx.trace.as_ref().map(|trace| trace.child());
+        if let Some(trace) = &span {
+            request = request.header("traceparent", trace.traceparent());
+            if let Some(tracestate) = trace.tracestate() {
+                request = request.header("tracestate", tracestate);
+            }
+        }
+        let in_flight = backend.begin();
+        let started = Instant::now();
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("TEI request failed: {}", e);
+                if e.is_connect() {
+                    state.dns.invalidate();
+                }
+                pool.record_failure(&backend, e.to_string());
+                // A restarting backend resets its connections and refuses
+                // new ones for a moment; another, or the same one back up,
+                // can take the call
+                if retry::is_dropped(&e)
+                    && retries < config.tei_max_retries
+                    && state.retry_budget.withdraw()
+                {
+                    retries += 1;
+                    warn!(
+                        "Connection to TEI backend {} lost, retrying ({}/{})",
+                        backend.url, retries, config.tei_max_retries
+                    );
+                    dns::reconnect(state);
+                    drop(in_flight);
+                    tokio::time::sleep(retry::RECONNECT_BACKOFF).await;
+                    continue;
+                }
+                return Err(if e.is_timeout() {
+                    ApiError::Timeout("TEI service did not answer in time".to_string())
+                } else {
+                    ApiError::TEIUnavailable(format!("Failed to connect to TEI service: {}", e))
+                });
+            }
+        };
+
+        // Back off when TEI is overloaded, for as long as it asks
+        let status = response.status();
+        state.upstream.record_status(status.as_u16());
+        let retry_after = retry::retry_after(response.headers());
+        if status == StatusCode::TOO_MANY_REQUESTS
+            || (status == StatusCode::SERVICE_UNAVAILABLE && retry_after.is_some())
+        {
+            let wait = retry_after.unwrap_or(retry::DEFAULT_BACKOFF);
+            if retries < config.tei_max_retries
+                && wait <= Duration::from_secs(config.tei_max_retry_wait_secs)
+                && state.retry_budget.withdraw()
+            {
+                retries += 1;
+                warn!(
+                    "TEI backend {} returned {}, retrying in {:?} ({}/{})",
+                    backend.url, status, wait, retries, config.tei_max_retries
+                );
+                drop(in_flight);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+            warn!("TEI backend {} returned {}, giving up", backend.url, status);
+            // Clients back off too, even when TEI didn't say for how long
+            return Err(ApiError::TooManyRequests(
+                "TEI is overloaded, retry later".to_string(),
+                Some(wait),
+            ));
+        }
+
+        // Check response status
+        if !status.is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            error!("TEI returned error {}: {}", status, error_text);
+            let message = format!("TEI service error {}: {}", status, error_text);
+            // Client errors are the request's fault, not the backend's
+            return Err(if status.is_server_error() {
+                pool.record_failure(&backend, format!("TEI returned {}", status));
+                ApiError::TEIUnavailable(message)
+            } else {
+                backend.succeeded();
+                ApiError::TEIError(message)
+            });
+        }
+
+        // Get response text first for debugging
+        let response_text = response.text().await.map_err(|e| {
+            error!("Failed to read TEI response body: {}", e);
+            pool.record_failure(&backend, e.to_string());
+            if e.is_timeout() {
+                ApiError::Timeout("TEI service did not answer in time".to_string())
+            } else {
+                ApiError::TEIUnavailable("Failed to read response from TEI service".to_string())
+            }
+        })?;
+        backend.succeeded();
+        let response_text = state.faults.response(response_text);
+        break (backend, span, response_text, started.elapsed());
+    };
+    backend.observe(latency);
+    state.latency.record(latency);
+    state.upstream.record(texts.len(), latency);
+    match &span {
+        Some(span) => info!(
+            "⏱️ TEI call with {} documents to {} took {:?} (span {:016x})",
+            texts.len(),
+            backend.url,
+            latency,
+            span.span_id
+        ),
+        None => debug!(
+            "⏱️ TEI call with {} documents to {} took {:?}",
+            texts.len(),
+            backend.url,
+            latency
+        ),
+    }
+
+    // Log the complete TEI response with pretty formatting
+    if let Some(level) = ctx.payload_log_level() {
+        match serde_json::from_str::<serde_json::Value>(&response_text) {
+            Ok(json_value) => {
+                let pretty_json = serde_json::to_string_pretty(&json_value)
+                    .unwrap_or_else(|_| response_text.clone());
+                log!(level, "📨 TEI Response:\n{}", pretty_json);
+            }
+            Err(_) => {
+                log!(level, "📨 TEI Response (raw text):\n{}", response_text);
+            }
+        }
+    }
+
+    // Parse TEI response
+    let tei_response = match adapter {
+        Some(adapter) => json::decode::<Value>(response_text.as_bytes())
+            .and_then(|value| adapter.results(&value))
+            .map(TEIResponse),
+        None => json::decode::<TEIResponse>(response_text.as_bytes()),
+    }
+    .map_err(|e| {
+        error!(
+            "Failed to parse TEI response: {}. Raw response: {}",
+            e, response_text
+        );
+        ApiError::TEIError(format!(
+            "Invalid response format from TEI service. Expected an array of results, bare or as `results`, got: {}",
+            response_text
+        ))
+    })?;
+
+    // Validate TEI response
+    if tei_response.0.len() != texts.len() {
+        error!(
+            "TEI response length mismatch: expected {}, got {}",
+            texts.len(),
+            tei_response.0.len()
+        );
+        return Err(ApiError::TEIError(
+            "TEI response length doesn't match input documents".to_string(),
+        ));
+    }
+    if let Some(result) = tei_response.0.iter().find(|r| r.index >= texts.len()) {
+        error!("TEI returned out-of-range index {}", result.index);
+        return Err(ApiError::TEIError(
+            "TEI response references an unknown document".to_string(),
+        ));
+    }
+
+    info!(
+        "✅ TEI request successful, processing {} scores",
+        tei_response.0.len()
+    );
+
+    Ok(TEICall {
+        results: tei_response.0,
+        queued,
+        latency,
+        backend: backend.url.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hint::black_box;
+
+    /// Average time per call of `f` over `iterations` calls, after as many
+    /// untimed calls to warm up.
+    fn time(iterations: u32, mut f: impl FnMut()) -> Duration {
+        for _ in 0..iterations {
+            f();
+        }
+        let started = Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        started.elapsed() / iterations
+    }
+
+    /// What building the upstream body for a 1000-document batch costs,
+    /// borrowed from the client request as `upstream_request` does, against
+    /// copying the query and documents first as the proxy used to. Run with
+    /// `cargo test --release bench_upstream_request -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_upstream_request() {
+        let config = Config::default();
+        let req: OpenWebUIRequest = serde_json::from_value(serde_json::json!({
+            "query": "What is the capital of France?",
+            "documents": (0..1000)
+                .map(|i| format!("{} ", i).repeat(512))
+                .collect::<Vec<_>>(),
+        }))
+        .unwrap();
+        let params = Map::new();
+
+        let borrowed = time(200, || {
+            let texts: Vec<&str> = req.documents.iter().map(Document::text).collect();
+            let body = upstream_request(&config, &req.query, &texts, None, &params);
+            black_box(serde_json::to_vec(&body).unwrap());
+        });
+        let copied = time(200, || {
+            let query = req.query.clone();
+            let documents: Vec<String> =
+                req.documents.iter().map(|d| d.text().to_string()).collect();
+            let texts: Vec<&str> = documents.iter().map(String::as_str).collect();
+            let body = upstream_request(&config, &query, &texts, None, &params);
+            black_box(serde_json::to_vec(&body).unwrap());
+        });
+
+        println!("upstream body for 1000 documents of 2 KiB:");
+        println!("  borrowed {:>10.1?}", borrowed);
+        println!("  copied   {:>10.1?}", copied);
+    }
+}
@@ -0,0 +1,106 @@
+use super::Candidate;
+use crate::error::ApiError;
+use crate::state::AppState;
+use log::debug;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    hits: Hits,
+}
+
+#[derive(Deserialize)]
+struct Hits {
+    hits: Vec<Hit>,
+}
+
+#[derive(Deserialize)]
+struct Hit {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(rename = "_score", default)]
+    score: Option<f64>,
+    #[serde(rename = "_source", default)]
+    source: Option<Map<String, Value>>,
+}
+
+/// The `size` documents of `elasticsearch_index` that best match the
+/// query under BM25, across `elasticsearch_fields`. Works with OpenSearch
+/// too.
+pub async fn search(
+    state: &AppState,
+    query: &str,
+    size: usize,
+    filter: Option<&Value>,
+) -> Result<Vec<Candidate>, ApiError> {
+    let config = &state.config;
+    let (Some(url), Some(index)) = (&config.elasticsearch_url, &config.elasticsearch_index) else {
+        return Err(ApiError::Internal(
+            "Elasticsearch is not configured".to_string(),
+        ));
+    };
+
+    let fields = if config.elasticsearch_fields.is_empty() {
+        vec![config.elasticsearch_text_field.clone()]
+    } else {
+        config.elasticsearch_fields.clone()
+    };
+    let matching = serde_json::json!({
+        "multi_match": { "query": query, "fields": fields }
+    });
+    let query = match filter {
+        Some(filter) => serde_json::json!({
+            "bool": { "must": matching, "filter": filter }
+        }),
+        None => matching,
+    };
+    let body = serde_json::json!({ "query": query, "size": size });
+
+    debug!(
+        "Searching Elasticsearch index {} for {} documents",
+        index, size
+    );
+    let mut request = state
+        .service_client()
+        .post(format!("{}/{}/_search", url.trim_end_matches('/'), index))
+        .json(&body);
+    if let Some(key) = &config.elasticsearch_api_key {
+        request = request.header("authorization", format!("ApiKey {}", key));
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| ApiError::Retrieval(format!("Failed to search Elasticsearch: {}", e)))?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        // Most likely the client's filter
+        if status == StatusCode::BAD_REQUEST {
+            return Err(ApiError::BadRequest(format!(
+                "Elasticsearch rejected the search: {}",
+                message
+            )));
+        }
+        return Err(ApiError::Retrieval(format!(
+            "Elasticsearch returned {}: {}",
+            status, message
+        )));
+    }
+    let found: SearchResponse = response
+        .json()
+        .await
+        .map_err(|e| ApiError::Retrieval(format!("Invalid response from Elasticsearch: {}", e)))?;
+
+    Ok(found
+        .hits
+        .hits
+        .into_iter()
+        .map(|hit| Candidate {
+            id: Value::String(hit.id),
+            score: hit.score.unwrap_or_default(),
+            payload: hit.source.unwrap_or_default(),
+        })
+        .collect())
+}
@@ -0,0 +1,202 @@
+pub mod elasticsearch;
+#[cfg(feature = "pgvector")]
+pub mod pgvector;
+pub mod qdrant;
+
+use crate::error::ApiError;
+use crate::rerank::{Document, OpenWebUIRequest, SortOrder};
+use crate::state::AppState;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::str::FromStr;
+
+/// How pgvector compares embeddings. It has to match the operator class
+/// of the table's index for the index to be used.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Distance {
+    #[default]
+    Cosine,
+    L2,
+    InnerProduct,
+}
+
+impl FromStr for Distance {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "cosine" => Ok(Distance::Cosine),
+            "l2" => Ok(Distance::L2),
+            "inner_product" => Ok(Distance::InnerProduct),
+            other => Err(format!(
+                "unknown distance '{}' (expected cosine, l2 or inner_product)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Distance::Cosine => "cosine",
+            Distance::L2 => "l2",
+            Distance::InnerProduct => "inner_product",
+        })
+    }
+}
+
+/// A `/retrieve_rerank` request: a query to find candidates for, then
+/// rerank.
+#[derive(Deserialize, Debug)]
+pub struct RetrieveRequest {
+    pub query: String,
+    /// Candidates to fetch; `retrieve_top_k` when not given.
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    /// Results to return, best first; all candidates when not given.
+    #[serde(default)]
+    pub top_n: Option<usize>,
+    /// Passed on to the search as-is: a Qdrant filter, an Elasticsearch
+    /// query clause, or an object Postgres rows' metadata has to contain.
+    #[serde(default)]
+    pub filter: Option<Value>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default, alias = "verbose")]
+    pub include_meta: bool,
+}
+
+/// A candidate found by the search.
+pub struct Candidate {
+    pub id: Value,
+    pub score: f64,
+    pub payload: Map<String, Value>,
+}
+
+/// Fetch candidates for the request from Elasticsearch (BM25), Postgres or
+/// Qdrant (nearest neighbors), whichever is configured, and turn them into a
+/// rerank request, with the search's score as each document's
+/// `retrieval_score` and the rest of its payload as metadata. Candidates
+/// without text are skipped.
+pub async fn candidates(
+    state: &AppState,
+    request: &RetrieveRequest,
+) -> Result<OpenWebUIRequest, ApiError> {
+    let config = &state.config;
+    let top_k = request.top_k.unwrap_or(config.retrieve_top_k);
+    let filter = request.filter.as_ref();
+    let (found, text_field) = if config.elasticsearch_url.is_some() {
+        let found = elasticsearch::search(state, &request.query, top_k, filter).await?;
+        info!("🔎 Retrieved {} candidates from Elasticsearch", found.len());
+        (found, config.elasticsearch_text_field.as_str())
+    } else if config.postgres_url.is_some() {
+        let found = postgres(state, &request.query, top_k, filter).await?;
+        info!("🔎 Retrieved {} candidates from Postgres", found.len());
+        (found, config.pgvector_text_column.as_str())
+    } else {
+        let found = qdrant::search(state, &request.query, top_k, filter).await?;
+        info!("🔎 Retrieved {} candidates from Qdrant", found.len());
+        (found, config.qdrant_text_field.as_str())
+    };
+
+    let documents = found
+        .into_iter()
+        .filter_map(|mut candidate| match candidate.payload.remove(text_field) {
+            Some(Value::String(text)) => Some(Document::Object {
+                text,
+                retrieval_score: Some(candidate.score),
+                id: Some(candidate.id),
+                metadata: Some(Value::Object(candidate.payload)),
+            }),
+            _ => {
+                warn!(
+                    "Skipping candidate {} without a '{}' text field",
+                    candidate.id, text_field
+                );
+                None
+            }
+        })
+        .collect();
+
+    Ok(rerank_request(request, documents))
+}
+
+/// The rerank request for `request`'s candidates.
+pub fn rerank_request(request: &RetrieveRequest, documents: Vec<Document>) -> OpenWebUIRequest {
+    OpenWebUIRequest {
+        query: request.query.clone(),
+        documents,
+        model: request.model.clone(),
+        top_n: request.top_n,
+        include_meta: request.include_meta,
+        return_documents: true,
+        extra: Default::default(),
+        strip_markup: None,
+        order: SortOrder::default(),
+        tie_break: Vec::new(),
+        offset: 0,
+        limit: None,
+    }
+}
+
+#[cfg(feature = "pgvector")]
+async fn postgres(
+    state: &AppState,
+    query: &str,
+    limit: usize,
+    filter: Option<&Value>,
+) -> Result<Vec<Candidate>, ApiError> {
+    pgvector::search(state, query, limit, filter).await
+}
+
+/// Config validation rejects `postgres_url` in builds without pgvector
+/// support.
+#[cfg(not(feature = "pgvector"))]
+async fn postgres(
+    _state: &AppState,
+    _query: &str,
+    _limit: usize,
+    _filter: Option<&Value>,
+) -> Result<Vec<Candidate>, ApiError> {
+    Err(ApiError::Internal(
+        "This build has no pgvector support".to_string(),
+    ))
+}
+
+/// Embed `text` with the TEI instance at `tei_embed_endpoint`.
+pub async fn embed(state: &AppState, text: &str) -> Result<Vec<f32>, ApiError> {
+    let Some(endpoint) = &state.config.tei_embed_endpoint else {
+        return Err(ApiError::Internal(
+            "No embedding endpoint configured".to_string(),
+        ));
+    };
+    let mut request = state
+        .service_client()
+        .post(format!("{}/embed", endpoint))
+        .json(&serde_json::json!({ "inputs": text }));
+    if let Some(token) = &state.config.tei_api_key {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| ApiError::Retrieval(format!("Failed to embed the query: {}", e)))?;
+    if !response.status().is_success() {
+        return Err(ApiError::Retrieval(format!(
+            "Failed to embed the query: TEI returned {}",
+            response.status()
+        )));
+    }
+    // One embedding per input
+    let mut embeddings: Vec<Vec<f32>> = response
+        .json()
+        .await
+        .map_err(|e| ApiError::Retrieval(format!("Invalid embedding from TEI: {}", e)))?;
+    embeddings
+        .pop()
+        .ok_or_else(|| ApiError::Retrieval("TEI returned no embedding".to_string()))
+}
@@ -0,0 +1,155 @@
+use super::{Candidate, Distance};
+use crate::error::ApiError;
+use crate::state::AppState;
+use log::{debug, info, warn};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls};
+
+/// The connection to `postgres_url`, opened on first use and reopened
+/// after it drops. Queries on it are pipelined, so one is enough.
+#[derive(Default)]
+pub struct Postgres {
+    client: Mutex<Option<Arc<Client>>>,
+}
+
+impl Postgres {
+    async fn client(&self, url: &str) -> Result<Arc<Client>, ApiError> {
+        let mut client = self.client.lock().await;
+        if let Some(open) = client.as_ref().filter(|open| !open.is_closed()) {
+            return Ok(open.clone());
+        }
+
+        let (opened, connection) = tokio_postgres::connect(url, NoTls)
+            .await
+            .map_err(|e| ApiError::Retrieval(format!("Failed to connect to Postgres: {}", e)))?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("Postgres connection closed: {}", e);
+            }
+        });
+        info!("Connected to Postgres");
+        let opened = Arc::new(opened);
+        *client = Some(opened.clone());
+        Ok(opened)
+    }
+}
+
+/// A table or column name from the configuration, quoted. Dots separate a
+/// schema from a table.
+fn identifier(name: &str) -> String {
+    name.split('.')
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// The `limit` rows of `pgvector_table` nearest to the query's embedding.
+/// A filter is matched against the metadata column with `@>`, so only
+/// rows containing it are searched.
+pub async fn search(
+    state: &AppState,
+    query: &str,
+    limit: usize,
+    filter: Option<&Value>,
+) -> Result<Vec<Candidate>, ApiError> {
+    let config = &state.config;
+    let (Some(url), Some(table)) = (&config.postgres_url, &config.pgvector_table) else {
+        return Err(ApiError::Internal("Postgres is not configured".to_string()));
+    };
+    let metadata = config.pgvector_metadata_column.as_deref().map(identifier);
+    let filter = match (filter, &metadata) {
+        (None, _) => None,
+        (Some(filter @ Value::Object(_)), Some(_)) => Some(filter.to_string()),
+        (Some(_), Some(_)) => {
+            return Err(ApiError::BadRequest(
+                "filter: must be an object the metadata has to contain".to_string(),
+            ))
+        }
+        (Some(_), None) => {
+            return Err(ApiError::BadRequest(
+                "filter: no metadata column is configured to filter on".to_string(),
+            ))
+        }
+    };
+
+    let vector = super::embed(state, query).await?;
+    let vector = format!(
+        "[{}]",
+        vector
+            .iter()
+            .map(f32::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    let operator = match config.pgvector_distance {
+        Distance::Cosine => "<=>",
+        Distance::L2 => "<->",
+        Distance::InnerProduct => "<#>",
+    };
+    let embedding = identifier(&config.pgvector_embedding_column);
+    let distance = format!("{} {} $1::text::vector", embedding, operator);
+    let limit = limit as i64;
+
+    // Ordered by the distance expression itself, so an index on it is used
+    let mut sql = format!(
+        "SELECT {}::text, {}::text, ({})::float8, {} FROM {}",
+        identifier(&config.pgvector_id_column),
+        identifier(&config.pgvector_text_column),
+        distance,
+        metadata
+            .as_deref()
+            .map_or("NULL::text".to_string(), |m| format!("{}::text", m)),
+        identifier(table),
+    );
+    // Rows without an embedding have no distance to rank them by
+    sql.push_str(&format!(" WHERE {} IS NOT NULL", embedding));
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&vector, &limit];
+    if let (Some(filter), Some(metadata)) = (&filter, &metadata) {
+        sql.push_str(&format!(" AND {}::jsonb @> $3::text::jsonb", metadata));
+        params.push(filter);
+    }
+    sql.push_str(&format!(" ORDER BY {} LIMIT $2", distance));
+
+    debug!("Searching Postgres table {} for {} rows", table, limit);
+    let client = state.postgres.client(url).await?;
+    let rows = client
+        .query(sql.as_str(), &params)
+        .await
+        .map_err(|e| ApiError::Retrieval(format!("Failed to search Postgres: {}", e)))?;
+
+    // The text goes in the payload, where candidates() looks for it
+    rows.iter()
+        .map(|row| {
+            let id: Option<String> = row.get(0);
+            let text: Option<String> = row.get(1);
+            let distance: f64 = row.get(2);
+            let metadata: Option<String> = row.get(3);
+
+            let mut payload = match metadata.as_deref().map(serde_json::from_str) {
+                Some(Ok(Value::Object(metadata))) => metadata,
+                Some(Err(e)) => {
+                    return Err(ApiError::Retrieval(format!(
+                        "Invalid metadata in Postgres: {}",
+                        e
+                    )))
+                }
+                _ => Map::new(),
+            };
+            if let Some(text) = text {
+                payload.insert(config.pgvector_text_column.clone(), Value::String(text));
+            }
+            Ok(Candidate {
+                id: id.map_or(Value::Null, Value::String),
+                score: match config.pgvector_distance {
+                    Distance::Cosine => 1.0 - distance,
+                    // <#> is the negated inner product
+                    Distance::L2 | Distance::InnerProduct => -distance,
+                },
+                payload,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,97 @@
+use super::Candidate;
+use crate::error::ApiError;
+use crate::state::AppState;
+use log::debug;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    result: Vec<Point>,
+}
+
+#[derive(Deserialize)]
+struct Point {
+    id: Value,
+    score: f64,
+    #[serde(default)]
+    payload: Option<Map<String, Value>>,
+}
+
+/// The `limit` points of `qdrant_collection` nearest to the query's
+/// embedding.
+pub async fn search(
+    state: &AppState,
+    query: &str,
+    limit: usize,
+    filter: Option<&Value>,
+) -> Result<Vec<Candidate>, ApiError> {
+    let config = &state.config;
+    let (Some(url), Some(collection)) = (&config.qdrant_url, &config.qdrant_collection) else {
+        return Err(ApiError::Internal("Qdrant is not configured".to_string()));
+    };
+
+    let vector = super::embed(state, query).await?;
+    let vector = match &config.qdrant_vector {
+        Some(name) => serde_json::json!({ "name": name, "vector": vector }),
+        None => serde_json::json!(vector),
+    };
+    let mut body = serde_json::json!({
+        "vector": vector,
+        "limit": limit,
+        "with_payload": true,
+    });
+    if let Some(filter) = filter {
+        body["filter"] = filter.clone();
+    }
+
+    debug!(
+        "Searching Qdrant collection {} for {} points",
+        collection, limit
+    );
+    let mut request = state
+        .service_client()
+        .post(format!(
+            "{}/collections/{}/points/search",
+            url.trim_end_matches('/'),
+            collection
+        ))
+        .json(&body);
+    if let Some(key) = &config.qdrant_api_key {
+        request = request.header("api-key", key);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| ApiError::Retrieval(format!("Failed to search Qdrant: {}", e)))?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        // Most likely the client's filter
+        if status == StatusCode::BAD_REQUEST {
+            return Err(ApiError::BadRequest(format!(
+                "Qdrant rejected the search: {}",
+                message
+            )));
+        }
+        return Err(ApiError::Retrieval(format!(
+            "Qdrant returned {}: {}",
+            status, message
+        )));
+    }
+    let found: SearchResponse = response
+        .json()
+        .await
+        .map_err(|e| ApiError::Retrieval(format!("Invalid response from Qdrant: {}", e)))?;
+
+    Ok(found
+        .result
+        .into_iter()
+        .map(|point| Candidate {
+            id: point.id,
+            score: point.score,
+            payload: point.payload.unwrap_or_default(),
+        })
+        .collect())
+}
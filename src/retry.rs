@@ -0,0 +1,110 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::error::Error;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Backoff after a `429` that doesn't say how long to wait.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Backoff after a connection was refused or dropped, long enough for a
+/// load balancer to stop sending new connections to a restarting backend.
+pub const RECONNECT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// The wait a `Retry-After` header asks for. Only the delay-seconds form
+/// is understood; an HTTP date counts as no header.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Whether a request failed because its connection was refused or reset,
+/// as when a backend restarts, rather than timing out. Rerank calls have
+/// no side effects, so these are safe to retry.
+pub fn is_dropped(error: &reqwest::Error) -> bool {
+    if error.is_timeout() {
+        return false;
+    }
+    if error.is_connect() {
+        return true;
+    }
+    let mut source = error.source();
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+            );
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// Retries the budget holds when full, so a quiet proxy can still retry.
+const RESERVE: u64 = 10;
+/// Budget units per retry, so fractional deposits add up exactly.
+const UNITS: u64 = 1000;
+
+/// Caps retries at a share of upstream calls, so that during a brownout
+/// retrying doesn't multiply the load on TEI and deepen the outage.
+///
+/// Every call deposits `ratio` of a retry, up to a reserve of
+/// [`RESERVE`] retries, and every retry withdraws a whole one.
+#[derive(Debug)]
+pub struct RetryBudget {
+    deposit: u64,
+    balance: AtomicU64,
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> Self {
+        Self {
+            deposit: (ratio * UNITS as f64).round() as u64,
+            balance: AtomicU64::new(RESERVE * UNITS),
+            retries: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Credit the budget for a first attempt.
+    pub fn deposit(&self) {
+        let _ = self
+            .balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                Some((balance + self.deposit).min(RESERVE * UNITS))
+            });
+    }
+
+    /// Take one retry from the budget; `false` if none is left.
+    pub fn withdraw(&self) -> bool {
+        let withdrawn = self
+            .balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                balance.checked_sub(UNITS)
+            })
+            .is_ok();
+        if withdrawn {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.exhausted.fetch_add(1, Ordering::Relaxed);
+        }
+        withdrawn
+    }
+
+    /// Retries made so far.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Retries skipped because the budget was empty.
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
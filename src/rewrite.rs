@@ -0,0 +1,78 @@
+use crate::config::Config;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// A regex replacement applied to queries, e.g. to unwrap prompts.
+/// `replacement` may refer to capture groups as `$1` or `$name`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Replacement {
+    pub pattern: String,
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// Config-defined cleanup of queries, for clients (OpenWebUI among them)
+/// that sometimes send whole prompts instead of clean search queries.
+#[derive(Debug, Default)]
+pub struct QueryRewriter {
+    strip_prefixes: Vec<String>,
+    replacements: Vec<(Regex, String)>,
+    lowercase: bool,
+}
+
+impl QueryRewriter {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let replacements = config
+            .query_replacements
+            .iter()
+            .map(|r| Ok((Regex::new(&r.pattern)?, r.replacement.clone())))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            strip_prefixes: config
+                .query_strip_prefixes
+                .iter()
+                .filter(|prefix| !prefix.is_empty())
+                .cloned()
+                .collect(),
+            replacements,
+            lowercase: config.query_lowercase,
+        })
+    }
+
+    /// Strip known prefixes (ignoring ASCII case), then apply the regex
+    /// replacements in order, then lowercase.
+    pub fn rewrite<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        let mut stripped = query.trim_start();
+        while let Some(rest) = self.strip_prefixes.iter().find_map(|prefix| {
+            stripped
+                .get(..prefix.len())
+                .filter(|head| head.eq_ignore_ascii_case(prefix))
+                .map(|_| stripped[prefix.len()..].trim_start())
+        }) {
+            stripped = rest;
+        }
+        let mut query = if stripped.len() < query.trim_start().len() {
+            Cow::Borrowed(stripped)
+        } else {
+            Cow::Borrowed(query)
+        };
+
+        for (pattern, replacement) in &self.replacements {
+            let replaced = match pattern.replace_all(&query, replacement.as_str()) {
+                Cow::Owned(replaced) => Some(replaced),
+                // No match
+                Cow::Borrowed(_) => None,
+            };
+            if let Some(replaced) = replaced {
+                query = Cow::Owned(replaced);
+            }
+        }
+
+        if self.lowercase {
+            query = Cow::Owned(query.to_lowercase());
+        }
+        query
+    }
+}
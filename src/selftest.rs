@@ -0,0 +1,149 @@
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank::{self, Document, OpenWebUIRequest, OpenWebUIResponse, SortOrder};
+use crate::state::AppState;
+use crate::webhook;
+use log::{info, warn};
+use serde::Serialize;
+use std::time::Instant;
+
+const QUERY: &str = "What is the capital of France?";
+
+/// The first document is the only relevant one; any working reranker
+/// scores it highest.
+const DOCUMENTS: &[&str] = &[
+    "Paris is the capital and most populous city of France.",
+    "The Great Barrier Reef lies off the coast of Queensland, Australia.",
+    "Photosynthesis turns light energy into chemical energy in plants.",
+];
+
+#[derive(Serialize, Debug)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub total_ms: f64,
+    /// The backend that scored the documents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// Of the `rerank` stage, the time spent waiting on the backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_ms: Option<f64>,
+    pub stages: Vec<Stage>,
+}
+
+/// One step of the rerank path and how it went.
+#[derive(Serialize, Debug)]
+pub struct Stage {
+    pub name: &'static str,
+    pub passed: bool,
+    pub ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Stage {
+    fn timed<T>(name: &'static str, started: Instant, result: &Result<T, ApiError>) -> Self {
+        Self {
+            name,
+            passed: result.is_ok(),
+            ms: started.elapsed().as_secs_f64() * 1000.0,
+            error: result.as_ref().err().map(ToString::to_string),
+        }
+    }
+}
+
+/// Send a canned request through the same steps as a client's, bypassing
+/// the score cache so the backend is really called, and check the answer.
+pub async fn run(state: &AppState, ctx: &RequestContext) -> SelfTestReport {
+    let started = Instant::now();
+    let ctx = RequestContext {
+        no_cache: true,
+        ..ctx.clone()
+    };
+    let mut stages = Vec::new();
+    let mut backend = None;
+    let mut upstream_ms = None;
+
+    let req = OpenWebUIRequest {
+        query: QUERY.to_string(),
+        documents: DOCUMENTS
+            .iter()
+            .map(|text| Document::from(text.to_string()))
+            .collect(),
+        model: None,
+        top_n: None,
+        include_meta: true,
+        return_documents: false,
+        extra: Default::default(),
+        strip_markup: None,
+        order: SortOrder::default(),
+        tie_break: Vec::new(),
+        offset: 0,
+        limit: None,
+    };
+
+    let stage_started = Instant::now();
+    let preprocessed = webhook::preprocess(state, &ctx, req)
+        .await
+        .and_then(|req| state.plugins.on_request(&ctx, req));
+    stages.push(Stage::timed("preprocess", stage_started, &preprocessed));
+
+    if let Ok(req) = preprocessed {
+        let stage_started = Instant::now();
+        let reranked = rerank::rerank(state, &req, &ctx).await;
+        stages.push(Stage::timed("rerank", stage_started, &reranked));
+        if let Some(meta) = reranked.as_ref().ok().and_then(|r| r.meta.as_ref()) {
+            backend = meta.backend.clone();
+            upstream_ms = Some(meta.upstream_latency_ms);
+        }
+
+        if let Ok(response) = reranked {
+            let stage_started = Instant::now();
+            let postprocessed = state.plugins.on_response(&ctx, &req, response);
+            stages.push(Stage::timed("postprocess", stage_started, &postprocessed));
+
+            if let Ok(response) = postprocessed {
+                let stage_started = Instant::now();
+                stages.push(Stage::timed(
+                    "check",
+                    stage_started,
+                    &check(&req, &response),
+                ));
+            }
+        }
+    }
+
+    // A stage that failed skips the ones after it
+    let passed = stages.len() == 4 && stages.iter().all(|stage| stage.passed);
+    let total_ms = started.elapsed().as_secs_f64() * 1000.0;
+    if passed {
+        info!("✅ Self-test passed in {:.1}ms", total_ms);
+    } else {
+        warn!("Self-test failed after {:.1}ms", total_ms);
+    }
+    SelfTestReport {
+        passed,
+        total_ms,
+        backend,
+        upstream_ms,
+        stages,
+    }
+}
+
+/// Every document scored, and the relevant one ranked first.
+fn check(req: &OpenWebUIRequest, response: &OpenWebUIResponse) -> Result<(), ApiError> {
+    if response.results.len() != req.documents.len() {
+        return Err(ApiError::Internal(format!(
+            "expected {} results, got {}",
+            req.documents.len(),
+            response.results.len()
+        )));
+    }
+    match response.results.first() {
+        Some(top) if top.index == 0 => Ok(()),
+        Some(top) => Err(ApiError::Internal(format!(
+            "expected document 0 to rank first, got document {}",
+            top.index
+        ))),
+        None => Err(ApiError::Internal("no results".to_string())),
+    }
+}
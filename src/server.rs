@@ -0,0 +1,123 @@
+use futures_util::future::{BoxFuture, FutureExt};
+use log::error;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use warp::hyper::server::accept::{self, Accept};
+use warp::hyper::server::conn::{AddrIncoming, AddrStream};
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Request, Response, Server};
+use warp::Filter;
+
+/// The client's address, attached to every request it sends.
+#[derive(Debug, Clone, Copy)]
+struct ClientAddr(SocketAddr);
+
+/// The address a request came from, if known. Stands in for
+/// `warp::addr::remote`, which only works under `warp::serve`.
+pub fn remote() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::ext::optional::<ClientAddr>()
+        .map(|addr: Option<ClientAddr>| addr.map(|ClientAddr(addr)| addr))
+}
+
+/// Connections the proxy knows the peer address of.
+pub trait Connection {
+    fn peer(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for AddrStream {
+    fn peer(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+impl Connection for TcpStream {
+    fn peer(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+/// Bind `address`, returning the bound address and the server to run.
+/// `routes` is a listener's filter made into a service by `warp::service`.
+pub fn bind<S>(
+    address: SocketAddr,
+    routes: S,
+    header_timeout: Option<Duration>,
+) -> Result<(SocketAddr, BoxFuture<'static, ()>), warp::hyper::Error>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S: Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let mut incoming = AddrIncoming::bind(&address)?;
+    incoming.set_nodelay(true);
+    let address = incoming.local_addr();
+    Ok((address, serve(incoming, routes, header_timeout)))
+}
+
+/// Serve connections on an inherited listener.
+pub fn run_incoming<S>(
+    listener: tokio::net::TcpListener,
+    routes: S,
+    header_timeout: Option<Duration>,
+) -> BoxFuture<'static, ()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S: Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    // hyper stops serving at the first accept error, so errors that pass,
+    // like running out of file descriptors, are logged and retried instead
+    let connections = futures_util::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok::<_, std::io::Error>(stream), listener)),
+                Err(e) => {
+                    error!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+    serve(accept::from_stream(connections), routes, header_timeout)
+}
+
+/// Like `warp::serve`, but with a deadline for request headers, so clients
+/// that trickle them in (slowloris) can't hold connections open forever.
+fn serve<I, S>(incoming: I, routes: S, header_timeout: Option<Duration>) -> BoxFuture<'static, ()>
+where
+    I: Accept + Send + 'static,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S: Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let make_service = make_service_fn(move |connection: &I::Conn| {
+        let peer = connection.peer();
+        let routes = routes.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                if let Some(peer) = peer {
+                    request.extensions_mut().insert(ClientAddr(peer));
+                }
+                routes.clone().call(request)
+            }))
+        }
+    });
+
+    let mut builder = Server::builder(incoming);
+    if let Some(timeout) = header_timeout {
+        builder = builder.http1_header_read_timeout(timeout);
+    }
+    builder
+        .serve(make_service)
+        .map(|result| {
+            if let Err(e) = result {
+                error!("Server error: {}", e);
+            }
+        })
+        .boxed()
+}
@@ -0,0 +1,142 @@
+use crate::state::AppState;
+use log::{error, info};
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// Name of the service and of its event log source.
+pub const SERVICE_NAME: &str = "rerank-proxy";
+
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// What `service_main` runs: the runtime to block on and the state to
+/// serve. The service control manager calls `service_main` on its own
+/// thread, so they are handed over through here.
+static STARTUP: Mutex<Option<(Handle, AppState)>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Send log output to the Windows event log; a service has no console.
+pub fn init_logging() {
+    if let Err(e) = eventlog::init(SERVICE_NAME, log::Level::Info) {
+        eprintln!("error: failed to log to the event log: {}", e);
+    }
+}
+
+/// Register the service to start automatically with `service run`,
+/// passing along the config file if one is given.
+pub fn install(config: Option<&Path>) -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let mut launch_arguments = vec![OsString::from("service"), OsString::from("run")];
+    if let Some(path) = config {
+        launch_arguments.push(OsString::from("--config"));
+        launch_arguments.push(std::path::absolute(path)?.into_os_string());
+    }
+
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from("TEI Rerank Proxy"),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Translates OpenWebUI rerank requests to Hugging Face TEI")?;
+    eventlog::register(SERVICE_NAME)?;
+    Ok(())
+}
+
+pub fn uninstall() -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    manager
+        .open_service(SERVICE_NAME, ServiceAccess::DELETE)?
+        .delete()?;
+    eventlog::deregister(SERVICE_NAME)?;
+    Ok(())
+}
+
+/// Hand this thread to the service control manager and serve until the
+/// service is stopped. Must be called from a blocking task.
+pub fn dispatch(state: AppState) -> windows_service::Result<()> {
+    *STARTUP.lock().unwrap() = Some((Handle::current(), state));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run() {
+        error!("Windows service failed: {}", e);
+    }
+}
+
+fn run() -> windows_service::Result<()> {
+    let stop = Arc::new(Notify::new());
+    let handler_stop = stop.clone();
+    let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            handler_stop.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+
+    let report = |state: ServiceState, controls: ServiceControlAccept, exit_code: u32| {
+        status.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted: controls,
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+    };
+
+    let Some((handle, state)) = STARTUP.lock().unwrap().take() else {
+        return report(ServiceState::Stopped, ServiceControlAccept::empty(), 1);
+    };
+
+    report(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        0,
+    )?;
+
+    let exit_code = handle.block_on(async {
+        tokio::select! {
+            _ = crate::serve(state) => {
+                error!("Server stopped unexpectedly");
+                1
+            }
+            _ = stop.notified() => {
+                info!("🛑 Windows service stop requested");
+                0
+            }
+        }
+    });
+
+    report(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    )
+}
@@ -0,0 +1,292 @@
+use crate::context::RequestContext;
+use crate::rerank::{self, OpenWebUIRequest};
+use crate::state::AppState;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Settings for the `soak` command.
+#[derive(Debug)]
+pub struct SoakOptions {
+    /// Requests sent round-robin for the whole run.
+    pub corpus: Vec<OpenWebUIRequest>,
+    /// Target request rate; `None` sends as fast as concurrency allows.
+    pub rps: Option<f64>,
+    pub concurrency: usize,
+    pub duration: Duration,
+    /// How often memory and file descriptors are sampled.
+    pub interval: Duration,
+    /// Growth before this point, while pools and caches fill up, doesn't
+    /// count as drift.
+    pub warmup: Duration,
+    /// Resident memory the run may gain after warmup before it fails.
+    pub max_memory_growth: u64,
+    /// Open file descriptors the run may gain after warmup before it fails.
+    pub max_fd_growth: usize,
+}
+
+/// The process's resident memory and open file descriptors, where the
+/// platform exposes them (Linux's `/proc`).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub rss_bytes: Option<u64>,
+    pub open_fds: Option<usize>,
+}
+
+impl Usage {
+    pub fn current() -> Usage {
+        let rss_bytes = std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+                let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+                Some(kb * 1024)
+            });
+        let open_fds = std::fs::read_dir("/proc/self/fd")
+            .ok()
+            .map(|entries| entries.count());
+        Usage {
+            rss_bytes,
+            open_fds,
+        }
+    }
+}
+
+/// One reading taken during the run.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub elapsed: Duration,
+    pub requests: usize,
+    pub errors: usize,
+    pub usage: Usage,
+}
+
+#[derive(Debug)]
+pub struct SoakReport {
+    pub samples: Vec<Sample>,
+    pub warmup: Duration,
+    pub max_memory_growth: u64,
+    pub max_fd_growth: usize,
+    pub last_error: Option<String>,
+}
+
+impl SoakReport {
+    /// Readings taken once warmup was over; the whole run if it never was.
+    fn steady(&self) -> &[Sample] {
+        let start = self
+            .samples
+            .iter()
+            .position(|sample| sample.elapsed >= self.warmup)
+            .filter(|&start| start + 1 < self.samples.len())
+            .unwrap_or(0);
+        &self.samples[start..]
+    }
+
+    /// Resident memory gained since warmup, in bytes.
+    pub fn memory_growth(&self) -> Option<i64> {
+        growth(self.steady(), |usage| usage.rss_bytes.map(|rss| rss as f64)).map(|g| g as i64)
+    }
+
+    /// Open file descriptors gained since warmup.
+    pub fn fd_growth(&self) -> Option<i64> {
+        growth(self.steady(), |usage| usage.open_fds.map(|fds| fds as f64)).map(|g| g as i64)
+    }
+
+    /// Whether memory or file descriptors grew past their limits.
+    pub fn leaked(&self) -> bool {
+        self.memory_growth()
+            .is_some_and(|g| g > self.max_memory_growth as i64)
+            || self
+                .fd_growth()
+                .is_some_and(|g| g > self.max_fd_growth as i64)
+    }
+
+    pub fn print(&self) {
+        let Some(last) = self.samples.last() else {
+            return;
+        };
+        let secs = last.elapsed.as_secs_f64().max(f64::EPSILON);
+        println!("Requests:    {} ({} failed)", last.requests, last.errors);
+        if let Some(e) = &self.last_error {
+            println!("Last error:  {}", e);
+        }
+        println!("Duration:    {:.2}s", secs);
+        println!(
+            "Throughput:  {:.2} req/s",
+            (last.requests - last.errors) as f64 / secs
+        );
+
+        let steady = self.steady();
+        let hours = steady
+            .last()
+            .zip(steady.first())
+            .map(|(last, first)| (last.elapsed - first.elapsed).as_secs_f64() / 3600.0)
+            .filter(|&hours| hours > 0.0);
+        println!(
+            "Drift since {:.0}s:",
+            steady.first().map_or(0.0, |s| s.elapsed.as_secs_f64())
+        );
+        match self.memory_growth() {
+            Some(g) => println!(
+                "  memory     {:>+10.2} MiB ({:+.1} MiB/h, limit {:.1} MiB)",
+                mib(g),
+                hours.map_or(0.0, |hours| mib(g) / hours),
+                mib(self.max_memory_growth as i64)
+            ),
+            None => println!("  memory     not available on this platform"),
+        }
+        match self.fd_growth() {
+            Some(g) => println!(
+                "  open fds   {:>+10} ({:+.1}/h, limit {})",
+                g,
+                hours.map_or(0.0, |hours| g as f64 / hours),
+                self.max_fd_growth
+            ),
+            None => println!("  open fds   not available on this platform"),
+        }
+    }
+}
+
+fn mib(bytes: i64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+/// How much a reading grew over `samples`, by the slope of a least-squares
+/// line through them rather than first to last, so one spike at either end
+/// doesn't decide it.
+fn growth(samples: &[Sample], value: impl Fn(&Usage) -> Option<f64>) -> Option<f64> {
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|sample| Some((sample.elapsed.as_secs_f64(), value(&sample.usage)?)))
+        .collect::<Option<_>>()?;
+    let (first, last) = (points.first()?.0, points.last()?.0);
+    if last <= first {
+        return Some(0.0);
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    Some(covariance / variance * (last - first))
+}
+
+/// Send the corpus through the normal rerank path for the whole duration,
+/// or until interrupted, printing memory and file descriptor readings every
+/// `interval`. Unlike `bench`, it keeps no per-request data, so the run
+/// itself doesn't grow.
+pub async fn run(state: Arc<AppState>, options: SoakOptions) -> SoakReport {
+    let corpus = Arc::new(options.corpus);
+    let requests = Arc::new(AtomicUsize::new(0));
+    let errors = Arc::new(AtomicUsize::new(0));
+    let last_error = Arc::new(std::sync::Mutex::new(None));
+    let stop = Arc::new(AtomicBool::new(false));
+    let concurrency = options.concurrency.max(1);
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let started = Instant::now();
+
+    let load = tokio::spawn({
+        let (requests, errors, last_error, stop, semaphore) = (
+            requests.clone(),
+            errors.clone(),
+            last_error.clone(),
+            stop.clone(),
+            semaphore.clone(),
+        );
+        let mut ticker = options
+            .rps
+            .map(|rps| tokio::time::interval(Duration::from_secs_f64(1.0 / rps)));
+        async move {
+            let mut sent = 0;
+            while !stop.load(Ordering::Relaxed) {
+                if let Some(ticker) = ticker.as_mut() {
+                    ticker.tick().await;
+                }
+                let Ok(permit) = semaphore.clone().acquire_owned().await else {
+                    break;
+                };
+                let (corpus, state, requests, errors, last_error) = (
+                    corpus.clone(),
+                    state.clone(),
+                    requests.clone(),
+                    errors.clone(),
+                    last_error.clone(),
+                );
+                tokio::spawn(async move {
+                    let req = &corpus[sent % corpus.len()];
+                    let result = rerank::rerank(&state, req, &RequestContext::default()).await;
+                    drop(permit);
+                    if let Err(e) = result {
+                        errors.fetch_add(1, Ordering::Relaxed);
+                        *last_error.lock().unwrap() = Some(e.to_string());
+                    }
+                    requests.fetch_add(1, Ordering::Relaxed);
+                });
+                sent += 1;
+            }
+        }
+    });
+
+    println!(
+        "{:>8} {:>10} {:>8} {:>12} {:>8}",
+        "elapsed", "requests", "errors", "rss", "fds"
+    );
+    let take = |elapsed| Sample {
+        elapsed,
+        requests: requests.load(Ordering::Relaxed),
+        errors: errors.load(Ordering::Relaxed),
+        usage: Usage::current(),
+    };
+    let mut samples = vec![take(Duration::ZERO)];
+    let mut interrupted = Box::pin(tokio::signal::ctrl_c());
+    let mut next = started;
+    let mut done = false;
+    while !done {
+        next = (next + options.interval).min(started + options.duration);
+        tokio::select! {
+            _ = tokio::time::sleep_until(next.into()) => done = next >= started + options.duration,
+            _ = &mut interrupted => {
+                println!("Interrupted, stopping");
+                done = true;
+            }
+        }
+        let sample = take(started.elapsed());
+        println!(
+            "{:>7.0}s {:>10} {:>8} {:>12} {:>8}",
+            sample.elapsed.as_secs_f64(),
+            sample.requests,
+            sample.errors,
+            sample
+                .usage
+                .rss_bytes
+                .map_or("-".to_string(), |rss| format!("{:.1} MiB", mib(rss as i64))),
+            sample
+                .usage
+                .open_fds
+                .map_or("-".to_string(), |fds| fds.to_string())
+        );
+        samples.push(sample);
+    }
+
+    // Let requests in flight finish, so the totals add up
+    stop.store(true, Ordering::Relaxed);
+    let _ = semaphore.acquire_many(concurrency as u32).await;
+    load.abort();
+    if let Some(last) = samples.last_mut() {
+        last.requests = requests.load(Ordering::Relaxed);
+        last.errors = errors.load(Ordering::Relaxed);
+    }
+
+    let last_error = last_error.lock().unwrap().take();
+    SoakReport {
+        samples,
+        warmup: options.warmup,
+        max_memory_growth: options.max_memory_growth,
+        max_fd_growth: options.max_fd_growth,
+        last_error,
+    }
+}
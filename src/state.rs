@@ -0,0 +1,318 @@
+use crate::admission::{ClientLimit, LatencyShedder, MemoryBudget, RouteSlots};
+use crate::audit::AuditLog;
+use crate::backends::BackendPool;
+use crate::cache::ScoreCache;
+use crate::calibration::Curves;
+use crate::config::Config;
+use crate::context::PayloadSampler;
+use crate::dns::DnsCache;
+use crate::fault::Faults;
+use crate::google::GoogleAuth;
+use crate::idempotency::IdempotencyStore;
+use crate::metrics::{ErrorStats, UpstreamStats};
+use crate::plugins::Plugins;
+use crate::queue::PriorityQueue;
+use crate::ratelimit::RateLimiter;
+use crate::retry::RetryBudget;
+use crate::rewrite::QueryRewriter;
+use crate::usage::KeyUsage;
+use anyhow::Context;
+use reqwest::Url;
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Shared state handed to every request: the configuration, the TEI
+/// backends and pooled HTTP clients for talking to them and to other
+/// services.
+pub struct AppState {
+    pub config: Config,
+    client: RwLock<reqwest::Client>,
+    service_client: reqwest::Client,
+    pub dns: DnsCache,
+    pub backends: BackendPool,
+    /// Dedicated backends for requests in particular languages, keyed by
+    /// ISO 639-3 code.
+    pub language_backends: BTreeMap<String, BackendPool>,
+    /// The first stage of a cascade, with `cascade_endpoint`.
+    pub cascade: Option<BackendPool>,
+    pub cache: ScoreCache,
+    /// Isotonic calibration curves fitted to `calibration_labels_path`.
+    pub calibration: Curves,
+    pub memory: Arc<MemoryBudget>,
+    pub client_limit: Arc<ClientLimit>,
+    pub route_slots: RouteSlots,
+    pub latency: LatencyShedder,
+    pub queue: Arc<PriorityQueue>,
+    pub retry_budget: RetryBudget,
+    pub idempotency: IdempotencyStore,
+    pub upstream: UpstreamStats,
+    pub errors: ErrorStats,
+    pub usage: KeyUsage,
+    /// Access tokens for the upstream, with `tei_google_auth`.
+    pub google_auth: Option<GoogleAuth>,
+    pub rate_limiter: RateLimiter,
+    pub faults: Faults,
+    pub payload_sampler: PayloadSampler,
+    pub audit: AuditLog,
+    pub plugins: Plugins,
+    pub query_rewriter: QueryRewriter,
+    #[cfg(feature = "pgvector")]
+    pub postgres: crate::retrieve::pgvector::Postgres,
+}
+
+impl AppState {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let client = build_client(&config, None)?;
+        let service_client = build_service_client(&config)?;
+
+        // Discovered pools start empty and fill in once discovery reports
+        let backends = BackendPool::new(
+            config.load_balancing,
+            config.backend_weights.clone(),
+            config.breaker(),
+        );
+        if config.discovery.is_none() {
+            backends.replace(vec![config.endpoint_url()]);
+        }
+
+        let language_backends = config
+            .language_backends
+            .iter()
+            .map(|(language, url)| {
+                let pool = BackendPool::new(
+                    config.load_balancing,
+                    config.backend_weights.clone(),
+                    config.breaker(),
+                );
+                pool.replace(vec![url.clone()]);
+                (language.clone(), pool)
+            })
+            .collect();
+
+        let cascade = config.cascade_endpoint.as_ref().map(|url| {
+            let pool = BackendPool::new(
+                config.load_balancing,
+                config.backend_weights.clone(),
+                config.breaker(),
+            );
+            pool.replace(vec![url.clone()]);
+            pool
+        });
+
+        let cache = ScoreCache::open(&config)?;
+        let calibration = Curves::load(&config)?;
+        let audit = AuditLog::open(&config)?;
+        let plugins = Plugins::load(&config)?;
+        let query_rewriter = QueryRewriter::new(&config)?;
+        let memory = Arc::new(MemoryBudget::new(config.memory_budget_bytes));
+        let client_limit = Arc::new(ClientLimit::new(config.max_requests_per_ip));
+        let route_slots = RouteSlots::new(&config.route_limits);
+        let latency = LatencyShedder::new(
+            Duration::from_millis(config.shed_latency_p99_ms),
+            Duration::from_secs(config.shed_latency_window_secs),
+            config.shed_latency_fraction,
+        );
+        let queue = Arc::new(PriorityQueue::new(config.max_concurrent_requests));
+        let retry_budget = RetryBudget::new(config.tei_retry_budget);
+        let usage = KeyUsage::new(Duration::from_secs(config.top_window_secs));
+        let rate_limiter = RateLimiter::new(
+            config.rate_limit,
+            Duration::from_secs(config.rate_limit_window_secs),
+        );
+        let faults = Faults::new(config.fault_injection, config.fault_settings());
+        let payload_sampler =
+            PayloadSampler::new(config.payload_log_sample_rate, config.payload_log_on_header);
+        let idempotency = IdempotencyStore::new(
+            Duration::from_secs(config.idempotency_ttl_secs),
+            config.idempotency_max_entries,
+        );
+        let google_auth = config.tei_google_auth.then(GoogleAuth::new).transpose()?;
+
+        Ok(Self {
+            config,
+            client: RwLock::new(client),
+            service_client,
+            dns: DnsCache::default(),
+            backends,
+            language_backends,
+            cascade,
+            cache,
+            calibration,
+            memory,
+            client_limit,
+            route_slots,
+            latency,
+            queue,
+            retry_budget,
+            idempotency,
+            upstream: UpstreamStats::default(),
+            errors: ErrorStats::default(),
+            usage,
+            google_auth,
+            rate_limiter,
+            faults,
+            payload_sampler,
+            audit,
+            plugins,
+            query_rewriter,
+            #[cfg(feature = "pgvector")]
+            postgres: Default::default(),
+        })
+    }
+
+    /// The current upstream client. Cheap to clone; it may be swapped out
+    /// when the backend's addresses change.
+    pub fn client(&self) -> reqwest::Client {
+        self.client.read().unwrap().clone()
+    }
+
+    pub fn replace_client(&self, client: reqwest::Client) {
+        *self.client.write().unwrap() = client;
+    }
+
+    /// The client for services other than the TEI backends, like webhooks
+    /// and retrievers, which the upstream TLS and HTTP/2 prior knowledge
+    /// settings don't apply to.
+    pub fn service_client(&self) -> reqwest::Client {
+        self.service_client.clone()
+    }
+}
+
+/// Build the upstream client, optionally pinning `host` to addresses that
+/// were resolved by the proxy itself.
+pub fn build_client(
+    config: &Config,
+    resolved: Option<(&str, &[SocketAddr])>,
+) -> anyhow::Result<reqwest::Client> {
+    let mut builder = client_builder(config)?;
+    if config.upstream_http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+
+    if let Some(path) = &config.upstream_ca_file {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("failed to read CA file {}", path.display()))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("invalid CA file {}", path.display()))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if config.upstream_tls_skip_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    // Called under its TLS server name, the endpoint still has to be
+    // reached at its own addresses
+    match (&config.upstream_tls_server_name, resolved) {
+        (Some(name), Some((_, addrs))) => builder = builder.resolve_to_addrs(name, addrs),
+        (Some(name), None) => {
+            let addrs = endpoint_addrs(&config.tei_endpoint)?;
+            builder = builder.resolve_to_addrs(name, &addrs);
+        }
+        (None, Some((host, addrs))) => builder = builder.resolve_to_addrs(host, addrs),
+        (None, None) => {}
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Build the client for other services: the same connection settings as
+/// the upstream client, but with the system's certificate checks and HTTP
+/// versions negotiated as usual.
+fn build_service_client(config: &Config) -> anyhow::Result<reqwest::Client> {
+    Ok(client_builder(config)?.build()?)
+}
+
+/// Connection, pooling and proxy settings shared by both clients.
+///
+/// Without an explicit `upstream_proxy`, reqwest picks up `HTTP_PROXY`,
+/// `HTTPS_PROXY` and `NO_PROXY` from the environment.
+fn client_builder(config: &Config) -> anyhow::Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.tei_timeout_secs))
+        .tcp_nodelay(config.upstream_tcp_nodelay)
+        .pool_idle_timeout(secs(config.upstream_pool_idle_timeout_secs))
+        .tcp_keepalive(secs(config.upstream_tcp_keepalive_secs))
+        .http2_keep_alive_interval(secs(config.upstream_http2_keepalive_secs))
+        .http2_adaptive_window(config.upstream_http2_adaptive_window);
+    if config.upstream_connect_timeout_ms > 0 {
+        builder =
+            builder.connect_timeout(Duration::from_millis(config.upstream_connect_timeout_ms));
+    }
+    if let Some(read_timeout) = secs(config.upstream_read_timeout_secs) {
+        builder = builder.read_timeout(read_timeout);
+    }
+    if let Some(max_idle) = config.upstream_pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+
+    // Checked first: a backend's own proxy wins over the shared one
+    if !config.backend_proxies.is_empty() {
+        let routes: Vec<(Url, Url)> = config
+            .backend_proxies
+            .iter()
+            .filter_map(|(backend, proxy)| {
+                Some((Url::parse(backend).ok()?, Url::parse(proxy).ok()?))
+            })
+            .collect();
+        builder = builder.proxy(reqwest::Proxy::custom(move |url| {
+            routes
+                .iter()
+                .find(|(backend, _)| backend.origin() == url.origin())
+                .map(|(_, proxy)| proxy.clone())
+        }));
+    }
+
+    if let Some(proxy) = &config.upstream_proxy {
+        let no_proxy = config
+            .upstream_no_proxy
+            .as_deref()
+            .and_then(reqwest::NoProxy::from_string);
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(no_proxy));
+    } else if !config.backend_proxies.is_empty() {
+        // Adding any proxy turns off reqwest's environment lookup, so put
+        // HTTP_PROXY/HTTPS_PROXY back for the remaining backends
+        if let Some(value) = env_proxy("HTTP_PROXY") {
+            let proxy = reqwest::Proxy::http(value)?;
+            builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+        }
+        if let Some(value) = env_proxy("HTTPS_PROXY") {
+            let proxy = reqwest::Proxy::https(value)?;
+            builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+        }
+    }
+
+    Ok(builder)
+}
+
+/// The addresses `endpoint`'s host resolves to, looked up by the system.
+fn endpoint_addrs(endpoint: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    let url = Url::parse(endpoint)?;
+    let host = url
+        .host_str()
+        .context("tei_endpoint has no host")?
+        .trim_matches(['[', ']']);
+    let port = url
+        .port_or_known_default()
+        .context("tei_endpoint has no port")?;
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {}", host))?
+        .collect();
+    Ok(addrs)
+}
+
+/// Seconds to a duration, with 0 meaning "disabled".
+fn secs(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value))
+}
+
+fn env_proxy(name: &str) -> Option<String> {
+    std::env::var(name)
+        .or_else(|_| std::env::var(name.to_lowercase()))
+        .ok()
+        .filter(|v| !v.is_empty())
+}
@@ -0,0 +1,123 @@
+use crate::config::Routes;
+use crate::state::AppState;
+use log::{info, warn};
+use std::net::TcpListener;
+use std::time::Duration;
+
+/// How often to re-check TEI while holding back readiness.
+const BACKEND_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Listening sockets passed in by systemd socket activation, each with the
+/// routes named by its `FileDescriptorName=` (`all` when unnamed). Empty
+/// when the proxy wasn't socket-activated.
+#[cfg(target_os = "linux")]
+pub fn listeners() -> Vec<(TcpListener, Routes)> {
+    use std::env;
+    use std::os::unix::io::FromRawFd;
+
+    // sd_listen_fds(3): descriptors start at 3
+    const LISTEN_FDS_START: i32 = 3;
+
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+    // The sockets are ours alone; don't advertise them to child processes
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    if pid != Some(std::process::id()) {
+        return Vec::new();
+    }
+
+    let mut names = names.split(':');
+    (0..count)
+        .map(|i| {
+            // SAFETY: systemd passes these descriptors to this process to own
+            let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START + i) };
+            let routes = names
+                .next()
+                .and_then(|name| name.parse().ok())
+                .unwrap_or_default();
+            (listener, routes)
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listeners() -> Vec<(TcpListener, Routes)> {
+    Vec::new()
+}
+
+/// Whether systemd expects readiness notifications (`Type=notify`).
+pub fn is_notify_enabled() -> bool {
+    cfg!(target_os = "linux") && std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Send an sd_notify(3) message such as `READY=1`. Does nothing when not
+/// running under systemd.
+pub fn notify(message: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, message) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(path: &std::ffi::OsStr, message: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    // A leading '@' names a socket in the abstract namespace
+    let address = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(message.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_path: &std::ffi::OsStr, _message: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Hold back readiness until at least one TEI backend answers its health
+/// check, so systemd doesn't consider a restart complete while requests
+/// would still fail.
+pub async fn wait_for_backends(state: &AppState) {
+    loop {
+        for backend in state.backends.snapshot().iter() {
+            let mut request = state
+                .client()
+                .get(format!("{}/health", backend.url))
+                .timeout(BACKEND_CHECK_INTERVAL);
+            if let Some(token) = &state.config.tei_api_key {
+                request = request.bearer_auth(token);
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    info!("✅ TEI backend {} is healthy", backend.url);
+                    return;
+                }
+                Ok(response) => warn!(
+                    "TEI backend {} is not ready yet: {}",
+                    backend.url,
+                    response.status()
+                ),
+                Err(e) => warn!("TEI backend {} is not ready yet: {}", backend.url, e),
+            }
+        }
+
+        notify("STATUS=Waiting for a healthy TEI backend");
+        tokio::time::sleep(BACKEND_CHECK_INTERVAL).await;
+    }
+}
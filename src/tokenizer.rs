@@ -0,0 +1,82 @@
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank;
+use crate::state::AppState;
+use log::{error, info, log, warn};
+use serde_json::Value;
+
+/// Forward a request to TEI's `/tokenize` or `/decode` on one of the
+/// backends, so clients can size chunks with the reranker's own tokenizer.
+/// The body goes through as the client sent it.
+pub async fn forward(
+    state: &AppState,
+    ctx: &RequestContext,
+    path: &str,
+    body: &Value,
+) -> Result<Value, ApiError> {
+    let pool = &state.backends;
+    let backend = pool.pick(path).ok_or_else(|| {
+        error!("No TEI backends available");
+        ApiError::NoBackends(pool.statuses())
+    })?;
+
+    if let Some(level) = ctx.payload_log_level() {
+        match serde_json::to_string_pretty(body) {
+            Ok(json_str) => log!(level, "📤 TEI {} Request:\n{}", path, json_str),
+            Err(e) => warn!("❌ Failed to serialize {} request for logging: {}", path, e),
+        }
+    }
+    info!("🔤 Forwarding {} to TEI endpoint: {}", path, backend.url);
+
+    let request = state
+        .client()
+        .post(format!("{}{}", backend.url, path))
+        .timeout(state.config.limits_for(ctx.route, None).timeout)
+        .json(body);
+    let request = rerank::authenticate(state, request).await?;
+    let response = request.send().await.map_err(|e| {
+        error!("TEI {} request failed: {}", path, e);
+        if e.is_connect() {
+            state.dns.invalidate();
+        }
+        pool.record_failure(&backend, e.to_string());
+        if e.is_timeout() {
+            ApiError::Timeout("TEI service did not answer in time".to_string())
+        } else {
+            ApiError::TEIUnavailable(format!("Failed to connect to TEI service: {}", e))
+        }
+    })?;
+
+    let status = response.status();
+    state.upstream.record_status(status.as_u16());
+    let text = response.text().await.map_err(|e| {
+        error!("Failed to read TEI {} response body: {}", path, e);
+        ApiError::TEIUnavailable("Failed to read response from TEI service".to_string())
+    })?;
+    if !status.is_success() {
+        error!("TEI returned error {} for {}: {}", status, path, text);
+        // TEI rejects inputs it can't tokenize, e.g. too long ones
+        if status.is_client_error() {
+            backend.succeeded();
+            return Err(ApiError::BadRequest(format!(
+                "TEI rejected the request: {}",
+                text
+            )));
+        }
+        pool.record_failure(&backend, format!("TEI returned {}", status));
+        return Err(ApiError::TEIUnavailable(format!(
+            "TEI service error {}: {}",
+            status, text
+        )));
+    }
+    backend.succeeded();
+
+    let value: Value = serde_json::from_str(&text).map_err(|e| {
+        error!("Failed to parse TEI {} response: {}", path, e);
+        ApiError::TEIError(format!("Invalid response from TEI service: {}", text))
+    })?;
+    if let Some(level) = ctx.payload_log_level() {
+        log!(level, "📨 TEI {} Response:\n{}", path, value);
+    }
+    Ok(value)
+}
@@ -0,0 +1,106 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// W3C Trace Context (`traceparent`/`tracestate`) continued through the
+/// proxy: the caller's trace with a span of our own, forwarded to TEI.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    trace_id: u128,
+    /// The caller's span, which the proxy's span is a child of.
+    pub parent_id: u64,
+    /// The proxy's span; TEI sees it as its parent.
+    pub span_id: u64,
+    flags: u8,
+    state: Option<String>,
+}
+
+impl TraceContext {
+    /// Continue the trace in an incoming `traceparent` header. Malformed
+    /// or unsupported values are ignored, as the spec requires.
+    pub fn continue_from(traceparent: &str, tracestate: Option<String>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        // Later versions may append fields; version 00 must have exactly four
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let trace_id = hex(trace_id, 32).filter(|&id| id != 0)?;
+        let parent_id = hex(parent_id, 16).filter(|&id| id != 0)? as u64;
+        let flags = hex(flags, 2)? as u8;
+
+        Some(Self {
+            trace_id,
+            parent_id,
+            span_id: new_span_id(),
+            flags,
+            state: tracestate.filter(|state| !state.trim().is_empty()),
+        })
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    /// A child span of this one, e.g. for one of several TEI calls.
+    pub fn child(&self) -> Self {
+        Self {
+            parent_id: self.span_id,
+            span_id: new_span_id(),
+            ..self.clone()
+        }
+    }
+
+    /// `traceparent` for the request to TEI, naming this span as the
+    /// parent.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.flags
+        )
+    }
+
+    /// `tracestate` to pass along unchanged, if the caller sent one.
+    pub fn tracestate(&self) -> Option<&str> {
+        self.state.as_deref()
+    }
+}
+
+/// Parse a lowercase hex field of exactly `len` digits (at most 32).
+fn hex(value: &str, len: usize) -> Option<u128> {
+    if value.len() != len
+        || !value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    u128::from_str_radix(value, 16).ok()
+}
+
+/// A random ID for requests that don't bring their own `X-Request-Id`.
+pub fn new_request_id() -> String {
+    format!("{:016x}", new_span_id())
+}
+
+/// A random, non-zero span ID.
+fn new_span_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    loop {
+        // RandomState is randomly keyed, which is plenty for span IDs
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(elapsed.as_nanos());
+        }
+        let id = hasher.finish();
+        if id != 0 {
+            return id;
+        }
+    }
+}
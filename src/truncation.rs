@@ -0,0 +1,187 @@
+use crate::backends::BackendPool;
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank;
+use crate::state::AppState;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+/// Room for the special tokens a cross-encoder puts around a pair, e.g.
+/// `[CLS] query [SEP] document [SEP]`, or four with XLM-RoBERTa's.
+const SPECIAL_TOKENS: usize = 4;
+
+/// How documents that don't fit the model next to the query are cut down.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    /// Left to TEI, which may cut off the query too.
+    #[default]
+    Off,
+    /// Keep the start of the document.
+    Head,
+    /// Keep the end of the document.
+    Tail,
+    /// Keep the start and the end of the document, dropping the middle.
+    Middle,
+}
+
+impl FromStr for Truncation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Truncation::Off),
+            "head" => Ok(Truncation::Head),
+            "tail" => Ok(Truncation::Tail),
+            "middle" => Ok(Truncation::Middle),
+            other => Err(format!(
+                "unknown truncation '{}' (expected off, head, tail or middle)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Truncation::Off => "off",
+            Truncation::Head => "head",
+            Truncation::Tail => "tail",
+            Truncation::Middle => "middle",
+        })
+    }
+}
+
+/// One token from TEI's `/tokenize`, with its byte offsets in the input.
+#[derive(Deserialize)]
+struct Token {
+    start: Option<usize>,
+    stop: Option<usize>,
+}
+
+/// The documents, each cut down to the tokens the model has left after
+/// the query. Only documents that may not fit are tokenized, on one of
+/// `pool`'s backends; if that fails they're left to TEI to truncate.
+pub async fn fit<'a>(
+    state: &AppState,
+    ctx: &RequestContext,
+    pool: &BackendPool,
+    query: &str,
+    documents: Vec<&'a str>,
+) -> Result<Vec<Cow<'a, str>>, ApiError> {
+    let truncation = state.config.truncation;
+    let mut fitted: Vec<Cow<str>> = documents.iter().map(|&text| Cow::Borrowed(text)).collect();
+    let Some(info) = pool.info().filter(|_| truncation != Truncation::Off) else {
+        return Ok(fitted);
+    };
+
+    // Tokens are rarely shorter than a character, so shorter pairs are
+    // taken to fit, as when asking TEI to truncate
+    let query_length = query.chars().count();
+    let long: Vec<usize> = (0..documents.len())
+        .filter(|&i| query_length + documents[i].chars().count() > info.max_input_length)
+        .collect();
+    if long.is_empty() {
+        return Ok(fitted);
+    }
+
+    let inputs: Vec<&str> = std::iter::once(query)
+        .chain(long.iter().map(|&i| documents[i]))
+        .collect();
+    let tokens = match tokenize(state, ctx, pool, query, &inputs).await {
+        Ok(tokens) if tokens.len() == inputs.len() => tokens,
+        Ok(_) => {
+            warn!("TEI tokenized the wrong number of inputs, leaving truncation to TEI");
+            return Ok(fitted);
+        }
+        Err(e) => {
+            warn!("Failed to tokenize, leaving truncation to TEI: {}", e);
+            return Ok(fitted);
+        }
+    };
+
+    let query_tokens = tokens[0].len();
+    let Some(budget) = info
+        .max_input_length
+        .checked_sub(query_tokens + SPECIAL_TOKENS)
+        .filter(|&budget| budget > 0)
+    else {
+        return Err(ApiError::BadRequest(format!(
+            "Query is too long: {} tokens, max: {}",
+            query_tokens,
+            info.max_input_length.saturating_sub(SPECIAL_TOKENS + 1)
+        )));
+    };
+
+    let mut truncated = 0;
+    for (&index, tokens) in long.iter().zip(&tokens[1..]) {
+        if tokens.len() <= budget {
+            continue;
+        }
+        if let Some(text) = cut(documents[index], tokens, budget, truncation) {
+            fitted[index] = Cow::Owned(text);
+            truncated += 1;
+        }
+    }
+    if truncated > 0 {
+        debug!(
+            "✂️ Truncated {} documents to {} tokens ({})",
+            truncated, budget, truncation
+        );
+    }
+    Ok(fitted)
+}
+
+/// `text` cut down to `budget` of its `tokens`, or `None` if TEI didn't
+/// say where they are.
+fn cut(text: &str, tokens: &[Token], budget: usize, truncation: Truncation) -> Option<String> {
+    let head = |count: usize| match count {
+        0 => Some(""),
+        count => text.get(..tokens[count - 1].stop?),
+    };
+    let tail = |count: usize| match count {
+        0 => Some(""),
+        count => text.get(tokens[tokens.len() - count].start?..),
+    };
+    match truncation {
+        Truncation::Off => None,
+        Truncation::Head => head(budget).map(str::to_string),
+        Truncation::Tail => tail(budget).map(str::to_string),
+        // One token spared for the space joining the two halves
+        Truncation::Middle => {
+            let kept = budget - 1;
+            let (start, end) = (head(kept - kept / 2)?, tail(kept / 2)?);
+            Some(format!("{} {}", start.trim_end(), end.trim_start()))
+        }
+    }
+}
+
+/// Tokenize `inputs` without special tokens, as the backend's model does.
+async fn tokenize(
+    state: &AppState,
+    ctx: &RequestContext,
+    pool: &BackendPool,
+    key: &str,
+    inputs: &[&str],
+) -> Result<Vec<Vec<Token>>, String> {
+    let backend = pool
+        .pick(key)
+        .ok_or_else(|| "no TEI backends available".to_string())?;
+    let request = state
+        .client()
+        .post(format!("{}/tokenize", backend.url))
+        .timeout(state.config.limits_for(ctx.route, None).timeout)
+        .json(&serde_json::json!({ "inputs": inputs, "add_special_tokens": false }));
+    let request = rerank::authenticate(state, request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("TEI returned {}", response.status()));
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,164 @@
+use crate::context::RequestContext;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets the rolling window is split into; usage ages out a bucket at a
+/// time.
+const BUCKETS: u32 = 60;
+
+/// What a key's requests added up to.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub requests: u64,
+    pub errors: u64,
+    pub documents: u64,
+    pub latency_micros: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.documents += other.documents;
+        self.latency_micros += other.latency_micros;
+    }
+}
+
+/// What `/admin/top` ranks keys by.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TopOrder {
+    #[default]
+    Requests,
+    Documents,
+    Errors,
+    /// Total time spent serving the key's requests.
+    Latency,
+}
+
+/// One key's usage over the window, as listed by `/admin/top`.
+#[derive(Serialize, Debug)]
+pub struct TopEntry {
+    pub key: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub documents: u64,
+    pub total_latency_ms: f64,
+    pub mean_latency_ms: f64,
+}
+
+#[derive(Debug, Default)]
+struct KeyStats {
+    total: Counts,
+    /// Usage per bucket, oldest first, keyed by bucket number.
+    recent: VecDeque<(u64, Counts)>,
+}
+
+/// Request totals per API key, plus a rolling window of recent usage to
+/// find the heaviest consumers for capacity planning and abuse
+/// investigations. Keys appear by name (or a short prefix).
+#[derive(Debug)]
+pub struct KeyUsage {
+    window: Duration,
+    bucket: Duration,
+    started: Instant,
+    keys: Mutex<BTreeMap<String, KeyStats>>,
+}
+
+impl KeyUsage {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            bucket: (window / BUCKETS).max(Duration::from_secs(1)),
+            started: Instant::now(),
+            keys: Mutex::default(),
+        }
+    }
+
+    fn current_bucket(&self) -> u64 {
+        (self.started.elapsed().as_nanos() / self.bucket.as_nanos()) as u64
+    }
+
+    /// Count a finished request against its key; requests without one
+    /// (authentication off) aren't tracked.
+    pub fn record(&self, ctx: &RequestContext, documents: usize, failed: bool, latency: Duration) {
+        let Some(key) = &ctx.api_key else {
+            return;
+        };
+        let counts = Counts {
+            requests: 1,
+            errors: u64::from(failed),
+            documents: documents as u64,
+            latency_micros: latency.as_micros() as u64,
+        };
+        let bucket = self.current_bucket();
+
+        let mut keys = self.keys.lock().unwrap();
+        let stats = keys.entry(key.label()).or_default();
+        stats.total.add(&counts);
+        match stats.recent.back_mut() {
+            Some((last, recent)) if *last == bucket => recent.add(&counts),
+            _ => stats.recent.push_back((bucket, counts)),
+        }
+        let oldest = bucket.saturating_sub(BUCKETS as u64 - 1);
+        while stats.recent.front().is_some_and(|(b, _)| *b < oldest) {
+            stats.recent.pop_front();
+        }
+    }
+
+    /// Every key's usage since startup.
+    pub fn totals(&self) -> Vec<(String, Counts)> {
+        let keys = self.keys.lock().unwrap();
+        keys.iter()
+            .map(|(key, stats)| (key.clone(), stats.total))
+            .collect()
+    }
+
+    /// The `limit` heaviest keys over the window, by `order`.
+    pub fn top(&self, order: TopOrder, limit: usize) -> Vec<TopEntry> {
+        let oldest = self.current_bucket().saturating_sub(BUCKETS as u64 - 1);
+        let keys = self.keys.lock().unwrap();
+        let mut recent: Vec<(String, Counts)> = keys
+            .iter()
+            .map(|(key, stats)| {
+                let mut counts = Counts::default();
+                for (_, bucket) in stats.recent.iter().filter(|(b, _)| *b >= oldest) {
+                    counts.add(bucket);
+                }
+                (key.clone(), counts)
+            })
+            .filter(|(_, counts)| counts.requests > 0)
+            .collect();
+        drop(keys);
+
+        recent.sort_by_key(|(_, counts)| {
+            std::cmp::Reverse(match order {
+                TopOrder::Requests => counts.requests,
+                TopOrder::Documents => counts.documents,
+                TopOrder::Errors => counts.errors,
+                TopOrder::Latency => counts.latency_micros,
+            })
+        });
+        recent
+            .into_iter()
+            .take(limit)
+            .map(|(key, counts)| {
+                let total_latency_ms = counts.latency_micros as f64 / 1000.0;
+                TopEntry {
+                    key,
+                    requests: counts.requests,
+                    errors: counts.errors,
+                    documents: counts.documents,
+                    total_latency_ms,
+                    mean_latency_ms: total_latency_ms / counts.requests as f64,
+                }
+            })
+            .collect()
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
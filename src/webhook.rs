@@ -0,0 +1,132 @@
+use crate::context::RequestContext;
+use crate::error::ApiError;
+use crate::rerank::OpenWebUIRequest;
+use crate::state::AppState;
+use log::{debug, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What happens to a request when the preprocessing webhook can't be
+/// reached or answers with an error.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Forward the request unchanged.
+    Open,
+    /// Reject the request with `503`.
+    #[default]
+    Closed,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "open" => Ok(FailurePolicy::Open),
+            "closed" => Ok(FailurePolicy::Closed),
+            other => Err(format!(
+                "unknown failure policy '{}' (expected open or closed)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailurePolicy::Open => "open",
+            FailurePolicy::Closed => "closed",
+        })
+    }
+}
+
+/// What the webhook is sent.
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    request_id: &'a str,
+    api_key: Option<String>,
+    request: &'a OpenWebUIRequest,
+}
+
+/// Hand the request to `preprocess_webhook_url`, which may return a
+/// rewritten one (e.g. with the query rewritten or documents filtered), or
+/// `204 No Content` to leave it unchanged.
+pub async fn preprocess(
+    state: &AppState,
+    ctx: &RequestContext,
+    req: OpenWebUIRequest,
+) -> Result<OpenWebUIRequest, ApiError> {
+    let config = &state.config;
+    let Some(url) = &config.preprocess_webhook_url else {
+        return Ok(req);
+    };
+
+    let body = WebhookRequest {
+        request_id: &ctx.request_id,
+        api_key: ctx.api_key.as_ref().map(|key| key.label()),
+        request: &req,
+    };
+    let result = call(
+        state,
+        url,
+        &ctx.request_id,
+        &body,
+        Duration::from_millis(config.preprocess_webhook_timeout_ms),
+    )
+    .await;
+
+    match result {
+        Ok(Some(rewritten)) => {
+            debug!("🪝 Preprocessing webhook rewrote the request");
+            Ok(rewritten)
+        }
+        Ok(None) => Ok(req),
+        Err(e) => match config.preprocess_webhook_failure {
+            FailurePolicy::Open => {
+                warn!("Preprocessing webhook failed, forwarding unchanged: {}", e);
+                Ok(req)
+            }
+            FailurePolicy::Closed => {
+                warn!("Preprocessing webhook failed, rejecting request: {}", e);
+                Err(ApiError::Unavailable(
+                    "Request preprocessing is unavailable".to_string(),
+                ))
+            }
+        },
+    }
+}
+
+async fn call(
+    state: &AppState,
+    url: &str,
+    request_id: &str,
+    body: &WebhookRequest<'_>,
+    timeout: Duration,
+) -> Result<Option<OpenWebUIRequest>, String> {
+    let response = state
+        .service_client()
+        .post(url)
+        .timeout(timeout)
+        .header("x-request-id", request_id)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let status = response.status();
+    if status == StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        return Err(format!("webhook returned {}", status));
+    }
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    crate::json::decode(&bytes)
+        .map(Some)
+        .map_err(|e| format!("invalid webhook response: {}", e))
+}
@@ -0,0 +1,334 @@
+//! The rerank routes end to end: the proxy binary in front of a fake TEI.
+
+mod support;
+
+use serde_json::{json, Value};
+use std::time::Duration;
+use support::{indexes, FakeTei, Proxy};
+
+fn request() -> Value {
+    json!({
+        "query": "capital of france",
+        "documents": ["Berlin is in Germany", "Paris is the capital of France", "France"],
+    })
+}
+
+#[tokio::test]
+async fn ranks_documents_by_tei_score() {
+    let tei = FakeTei::start().await;
+    tei.set_scores(vec![0.1, 0.9, 0.5]);
+    let proxy = Proxy::start(&tei, &[]).await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(indexes(&body), [1, 2, 0]);
+    assert_eq!(body["results"][0]["relevance_score"], json!(0.9));
+
+    let sent = tei.last_request();
+    assert_eq!(sent["query"], "capital of france");
+    assert_eq!(sent["texts"], request()["documents"]);
+}
+
+#[tokio::test]
+async fn pages_through_results() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[]).await;
+
+    let mut request = request();
+    request["offset"] = json!(1);
+    request["limit"] = json!(1);
+    let body: Value = proxy
+        .post("/rerank", &request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(indexes(&body), [1]);
+    assert_eq!(body["total"], 3);
+}
+
+#[tokio::test]
+async fn answers_pinecone_requests_in_its_shape() {
+    let tei = FakeTei::start().await;
+    tei.set_scores(vec![0.2, 0.7]);
+    let proxy = Proxy::start(&tei, &[]).await;
+
+    let request = json!({
+        "model": "bge-reranker-v2-m3",
+        "query": "capital of france",
+        "documents": [{ "text": "Berlin" }, { "text": "Paris" }],
+        "top_n": 1,
+        "return_documents": true,
+    });
+    let response = proxy
+        .post("/pinecone/rerank", &request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(
+        body["data"],
+        json!([{ "index": 1, "score": 0.7, "document": { "text": "Paris" } }])
+    );
+    assert_eq!(tei.last_request()["texts"], json!(["Berlin", "Paris"]));
+}
+
+#[tokio::test]
+async fn caches_scores_between_requests() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[("TEI_PROXY_CACHE_MAX_ENTRIES", "100")]).await;
+
+    let first = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(first.headers()["x-cache"], "MISS");
+    let second = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(second.headers()["x-cache"], "HIT");
+    assert_eq!(tei.rerank_calls(), 1);
+
+    let forced = proxy
+        .post("/rerank", &request())
+        .header("cache-control", "no-cache")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(forced.headers()["x-cache"], "MISS");
+    assert_eq!(tei.rerank_calls(), 2);
+}
+
+#[tokio::test]
+async fn tei_errors_are_bad_gateway() {
+    let tei = FakeTei::start().await;
+    tei.fail_with(500);
+    let proxy = Proxy::start(&tei, &[("TEI_MAX_RETRIES", "0")]).await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(response.status(), 502);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "tei_error");
+}
+
+#[tokio::test]
+async fn slow_tei_times_out() {
+    let tei = FakeTei::start().await;
+    tei.set_delay(Duration::from_secs(3));
+    let proxy = Proxy::start(&tei, &[("TEI_TIMEOUT_SECS", "1"), ("TEI_MAX_RETRIES", "0")]).await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(response.status(), 504);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "timeout");
+}
+
+#[tokio::test]
+async fn falls_back_to_bm25_when_tei_fails() {
+    let tei = FakeTei::start().await;
+    tei.fail_with(503);
+    let proxy = Proxy::start(
+        &tei,
+        &[("TEI_BM25_FALLBACK", "true"), ("TEI_MAX_RETRIES", "0")],
+    )
+    .await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-degraded"], "bm25");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(indexes(&body)[0], 1);
+    assert_eq!(body["meta"]["degraded"], "bm25");
+}
+
+#[tokio::test]
+async fn degraded_responses_are_not_kept_by_clients() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(
+        &tei,
+        &[
+            ("TEI_BM25_FALLBACK", "true"),
+            ("TEI_MAX_RETRIES", "0"),
+            ("TEI_PROXY_CACHE_MAX_ENTRIES", "100"),
+            ("TEI_PROXY_CACHE_TTL_SECS", "60"),
+        ],
+    )
+    .await;
+
+    let scored = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(scored.headers()["cache-control"], "private, max-age=60");
+
+    tei.fail_with(503);
+    let degraded = proxy
+        .post("/rerank", &request())
+        .header("cache-control", "no-cache")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(degraded.headers()["x-degraded"], "bm25");
+    assert_eq!(degraded.headers()["cache-control"], "no-store");
+}
+
+#[tokio::test]
+async fn passes_tei_client_errors_through_despite_bm25_fallback() {
+    let tei = FakeTei::start().await;
+    tei.fail_with(422);
+    let proxy = Proxy::start(
+        &tei,
+        &[("TEI_BM25_FALLBACK", "true"), ("TEI_MAX_RETRIES", "0")],
+    )
+    .await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(response.status(), 502);
+    assert!(response.headers().get("x-degraded").is_none());
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "tei_error");
+}
+
+#[tokio::test]
+async fn rejects_oversized_batches_without_calling_tei() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[("MAX_CLIENT_BATCH_SIZE", "2")]).await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert!(response.status().is_client_error(), "{}", response.status());
+    assert_eq!(tei.rerank_calls(), 0);
+}
+
+#[tokio::test]
+async fn requires_api_key_when_configured() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[("TEI_PROXY_API_KEYS", "sk-test")]).await;
+
+    let anonymous = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(anonymous.status(), 401);
+    let wrong = proxy
+        .post("/rerank", &request())
+        .bearer_auth("sk-wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong.status(), 401);
+    let authorized = proxy
+        .post("/rerank", &request())
+        .bearer_auth("sk-test")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(authorized.status(), 200);
+    assert_eq!(tei.rerank_calls(), 1);
+}
+
+#[tokio::test]
+async fn rejects_invalid_json() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[]).await;
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/rerank"))
+        .header("content-type", "application/json")
+        .body("{\"query\": ")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(tei.rerank_calls(), 0);
+}
+
+#[tokio::test]
+async fn only_serves_rerank_at_its_exact_path() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[]).await;
+
+    let response = proxy
+        .post("/rerank/anything", &request())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(tei.rerank_calls(), 0);
+}
+
+#[tokio::test]
+async fn reports_server_timing() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[]).await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    let timing = response.headers()["server-timing"].to_str().unwrap();
+    for metric in [
+        "queue;dur=",
+        "upstream;dur=",
+        "postprocess;dur=",
+        "total;dur=",
+    ] {
+        assert!(timing.contains(metric), "{}", timing);
+    }
+}
+
+#[tokio::test]
+async fn counts_upstream_requests_in_metrics() {
+    let tei = FakeTei::start().await;
+    let proxy = Proxy::start(&tei, &[]).await;
+
+    proxy.post("/rerank", &request()).send().await.unwrap();
+    let metrics = proxy
+        .get("/metrics")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        metrics.contains("rerank_proxy_upstream_requests_total 1"),
+        "{}",
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn route_limits_override_the_timeout_and_body_size() {
+    let tei = FakeTei::start().await;
+    tei.set_delay(Duration::from_secs(3));
+    let limits = r#"{"rerank": {"timeout_secs": 1, "max_request_bytes": 256}}"#;
+    let proxy = Proxy::start(
+        &tei,
+        &[("TEI_PROXY_ROUTE_LIMITS", limits), ("TEI_MAX_RETRIES", "0")],
+    )
+    .await;
+
+    let response = proxy.post("/rerank", &request()).send().await.unwrap();
+    assert_eq!(response.status(), 504);
+
+    let mut large = request();
+    large["documents"] = json!(["x".repeat(300)]);
+    let response = proxy.post("/rerank", &large).send().await.unwrap();
+    assert_eq!(response.status(), 413);
+}
+
+#[tokio::test]
+async fn route_limits_cap_concurrent_requests() {
+    let tei = FakeTei::start().await;
+    tei.set_delay(Duration::from_millis(500));
+    let limits = r#"{"rerank": {"max_concurrent_requests": 1}}"#;
+    let proxy = Proxy::start(&tei, &[("TEI_PROXY_ROUTE_LIMITS", limits)]).await;
+
+    let body = request();
+    let (first, second) = tokio::join!(proxy.post("/rerank", &body).send(), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        proxy.post("/rerank", &body).send().await
+    });
+    assert_eq!(first.unwrap().status(), 200);
+    assert_eq!(second.unwrap().status(), 503);
+
+    // Other groups keep their own slots
+    let metrics = proxy.get("/metrics").send().await.unwrap();
+    assert_eq!(metrics.status(), 200);
+    let metrics = metrics.text().await.unwrap();
+    assert!(
+        metrics.contains("rerank_proxy_route_limited_total{route=\"rerank\"} 1"),
+        "{}",
+        metrics
+    );
+}
@@ -0,0 +1,217 @@
+//! Test support: a fake TEI server running in the test process, and the
+//! proxy binary started against it, so tests go through the same filter
+//! chain, listeners and config loading as a real deployment.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::{Ipv4Addr, TcpListener};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use warp::http::StatusCode;
+use warp::Filter;
+
+/// How the fake TEI answers `/rerank`.
+#[derive(Debug, Default, Clone)]
+struct Behavior {
+    /// Scores by document position, 0 past the end; `1 / (i + 1)` if unset.
+    scores: Option<Vec<f64>>,
+    /// Answer with this status and an error body instead of scores.
+    status: Option<u16>,
+    /// Wait this long before answering.
+    delay: Duration,
+}
+
+#[derive(Deserialize)]
+struct RerankRequest {
+    texts: Vec<String>,
+}
+
+/// A TEI stand-in on an ephemeral loopback port, for as long as the test's
+/// runtime lives. Its answers can be changed while the test runs.
+pub struct FakeTei {
+    url: String,
+    behavior: Arc<Mutex<Behavior>>,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl FakeTei {
+    pub async fn start() -> FakeTei {
+        let behavior = Arc::new(Mutex::new(Behavior::default()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let info = warp::path("info").and(warp::get()).map(|| {
+            warp::reply::json(&json!({
+                "model_id": "fake-reranker",
+                "max_client_batch_size": 1000,
+                "max_input_length": 8192,
+            }))
+        });
+        let rerank = warp::path("rerank")
+            .and(warp::post())
+            .and(warp::body::json())
+            .then({
+                let behavior = behavior.clone();
+                let requests = requests.clone();
+                move |body: Value| {
+                    let behavior = behavior.lock().unwrap().clone();
+                    requests.lock().unwrap().push(body.clone());
+                    async move {
+                        tokio::time::sleep(behavior.delay).await;
+                        answer(&behavior, body)
+                    }
+                }
+            });
+
+        let (address, server) =
+            warp::serve(info.or(rerank)).bind_ephemeral((Ipv4Addr::LOCALHOST, 0));
+        tokio::spawn(server);
+        FakeTei {
+            url: format!("http://{}", address),
+            behavior,
+            requests,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Score documents by position from now on.
+    pub fn set_scores(&self, scores: Vec<f64>) {
+        self.behavior.lock().unwrap().scores = Some(scores);
+    }
+
+    /// Answer every `/rerank` with `status` from now on.
+    pub fn fail_with(&self, status: u16) {
+        self.behavior.lock().unwrap().status = Some(status);
+    }
+
+    /// Take `delay` to answer every `/rerank` from now on.
+    pub fn set_delay(&self, delay: Duration) {
+        self.behavior.lock().unwrap().delay = delay;
+    }
+
+    /// How many `/rerank` calls the proxy made.
+    pub fn rerank_calls(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    /// The body of the last `/rerank` call.
+    pub fn last_request(&self) -> Value {
+        self.requests
+            .lock()
+            .unwrap()
+            .last()
+            .cloned()
+            .expect("TEI was never called")
+    }
+}
+
+fn answer(behavior: &Behavior, body: Value) -> warp::reply::Response {
+    use warp::Reply;
+
+    if let Some(status) = behavior.status {
+        let status = StatusCode::from_u16(status).unwrap();
+        return warp::reply::with_status(
+            warp::reply::json(&json!({ "error": "fake failure", "error_type": "Backend" })),
+            status,
+        )
+        .into_response();
+    }
+    let Ok(request) = serde_json::from_value::<RerankRequest>(body) else {
+        return StatusCode::UNPROCESSABLE_ENTITY.into_response();
+    };
+
+    let mut ranks: Vec<(usize, f64)> = (0..request.texts.len())
+        .map(|i| {
+            let score = match &behavior.scores {
+                Some(scores) => scores.get(i).copied().unwrap_or(0.0),
+                None => 1.0 / (i + 1) as f64,
+            };
+            (i, score)
+        })
+        .collect();
+    // TEI answers best first
+    ranks.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let ranks: Vec<Value> = ranks
+        .into_iter()
+        .map(|(index, score)| json!({ "index": index, "score": score }))
+        .collect();
+    warp::reply::json(&ranks).into_response()
+}
+
+/// The proxy binary, listening on a free loopback port with only the given
+/// environment, and killed when dropped.
+pub struct Proxy {
+    url: String,
+    client: reqwest::Client,
+    _child: Child,
+}
+
+impl Proxy {
+    /// Start the proxy in front of `tei` and wait until it's healthy.
+    pub async fn start(tei: &FakeTei, env: &[(&str, &str)]) -> Proxy {
+        let port = free_port();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rerank-proxy"))
+            .env_clear()
+            .env("TEI_ENDPOINT", tei.url())
+            .env("TEI_PROXY_BIND_ADDRESS", "127.0.0.1")
+            .env("TEI_PROXY_PORT", port.to_string())
+            .envs(env.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("failed to start the proxy");
+
+        let url = format!("http://127.0.0.1:{}", port);
+        let client = reqwest::Client::new();
+        for _ in 0..100 {
+            if let Some(status) = child.try_wait().unwrap() {
+                panic!("the proxy exited at startup with {}", status);
+            }
+            let health = client.get(format!("{}/health", url)).send().await;
+            if health.is_ok_and(|response| response.status().is_success()) {
+                return Proxy {
+                    url,
+                    client,
+                    _child: child,
+                };
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("the proxy didn't become healthy");
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.get(self.url(path))
+    }
+
+    pub fn post(&self, path: &str, body: &Value) -> reqwest::RequestBuilder {
+        self.client.post(self.url(path)).json(body)
+    }
+}
+
+/// A port nothing listens on right now.
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|address| address.port())
+        .expect("no free port")
+}
+
+/// The `index` of each result in a native `/rerank` response, best first.
+pub fn indexes(body: &Value) -> Vec<u64> {
+    body["results"]
+        .as_array()
+        .expect("no results")
+        .iter()
+        .map(|result| result["index"].as_u64().unwrap())
+        .collect()
+}